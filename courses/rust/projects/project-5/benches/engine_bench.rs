@@ -0,0 +1,78 @@
+use criterion::{
+    criterion_group, criterion_main, BatchSize, Bencher, Benchmark, Criterion,
+    ParameterizedBenchmark,
+};
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{KvStore, KvsEngine, LsmKvsEngine, SledKvsEngine};
+use rand::prelude::*;
+use std::path::Path;
+use tempfile::TempDir;
+use tokio::prelude::*;
+
+const CONCURRENCY: u32 = 4;
+
+fn open_kvs(path: &Path) -> KvStore<RayonThreadPool> {
+    KvStore::open(path, CONCURRENCY).unwrap()
+}
+
+fn open_sled(path: &Path) -> SledKvsEngine<RayonThreadPool> {
+    SledKvsEngine::new(sled::open(path).unwrap(), CONCURRENCY).unwrap()
+}
+
+fn open_lsm(path: &Path) -> LsmKvsEngine<RayonThreadPool> {
+    LsmKvsEngine::open(path, CONCURRENCY).unwrap()
+}
+
+fn set_keys<E: KvsEngine>(engine: &E, count: usize) {
+    for i in 1..count {
+        engine
+            .set(format!("key{}", i).into_bytes(), b"value".to_vec())
+            .wait()
+            .unwrap();
+    }
+}
+
+fn bench_set<E: KvsEngine>(b: &mut Bencher, open: fn(&Path) -> E) {
+    b.iter_batched(
+        || {
+            let temp_dir = TempDir::new().unwrap();
+            (open(temp_dir.path()), temp_dir)
+        },
+        |(engine, _temp_dir)| set_keys(&engine, 1 << 12),
+        BatchSize::SmallInput,
+    )
+}
+
+fn bench_get<E: KvsEngine>(b: &mut Bencher, open: fn(&Path) -> E, i: usize) {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = open(temp_dir.path());
+    set_keys(&engine, 1 << i);
+    let mut rng = SmallRng::from_seed([0; 16]);
+    b.iter(|| {
+        engine
+            .get(format!("key{}", rng.gen_range(1, 1 << i)).into_bytes())
+            .wait()
+            .unwrap();
+    })
+}
+
+fn set_bench(c: &mut Criterion) {
+    c.bench(
+        "set_bench",
+        Benchmark::new("kvs", |b| bench_set(b, open_kvs))
+            .with_function("sled", |b| bench_set(b, open_sled))
+            .with_function("lsm", |b| bench_set(b, open_lsm)),
+    );
+}
+
+fn get_bench(c: &mut Criterion) {
+    c.bench(
+        "get_bench",
+        ParameterizedBenchmark::new("kvs", |b, &i| bench_get(b, open_kvs, i), vec![8, 12, 16])
+            .with_function("sled", |b, &i| bench_get(b, open_sled, i))
+            .with_function("lsm", |b, &i| bench_get(b, open_lsm, i)),
+    );
+}
+
+criterion_group!(benches, set_bench, get_bench);
+criterion_main!(benches);
@@ -1,7 +1,12 @@
 use clap::AppSettings;
-use kvs::{KvsClient, Result};
+use kvs::{KvsClient, KvsError, Result, WatchEvent, WatchSeq};
+use std::fs;
+use std::io::{self, BufRead};
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
+use std::str::FromStr;
+use std::time::Duration;
 use structopt::StructOpt;
 use tokio::prelude::*;
 
@@ -19,10 +24,25 @@ struct Opt {
 
 #[derive(StructOpt, Debug)]
 enum Command {
-    #[structopt(name = "get", about = "Get the string value of a given string key")]
+    #[structopt(name = "get", about = "Get the value of a given key")]
     Get {
-        #[structopt(name = "KEY", help = "A string key")]
+        #[structopt(name = "KEY", help = "A key")]
         key: String,
+        #[structopt(
+            long,
+            help = "Writes the raw value to a file instead of printing it",
+            value_name = "PATH",
+            parse(from_os_str)
+        )]
+        output: Option<PathBuf>,
+        #[structopt(
+            long,
+            help = "Sets how keys and values are written on the command line",
+            value_name = "FORMAT",
+            default_value = "utf8",
+            raw(possible_values = "&[\"utf8\", \"hex\", \"base64\"]")
+        )]
+        format: Format,
         #[structopt(
             long,
             help = "Sets the server address",
@@ -32,12 +52,38 @@ enum Command {
         )]
         addr: SocketAddr,
     },
-    #[structopt(name = "set", about = "Set the value of a string key to a string")]
+    #[structopt(name = "set", about = "Set the value of a key")]
     Set {
-        #[structopt(name = "KEY", help = "A string key")]
+        #[structopt(name = "KEY", help = "A key")]
         key: String,
-        #[structopt(name = "VALUE", help = "The string value of the key")]
-        value: String,
+        #[structopt(
+            name = "VALUE",
+            help = "The value of the key",
+            required_unless = "value_file"
+        )]
+        value: Option<String>,
+        #[structopt(
+            long = "value-file",
+            help = "Reads the raw value from a file",
+            value_name = "PATH",
+            conflicts_with = "VALUE",
+            parse(from_os_str)
+        )]
+        value_file: Option<PathBuf>,
+        #[structopt(
+            long,
+            help = "Makes the key expire after the given number of seconds",
+            value_name = "SECONDS"
+        )]
+        ttl: Option<u64>,
+        #[structopt(
+            long,
+            help = "Sets how keys and values are written on the command line",
+            value_name = "FORMAT",
+            default_value = "utf8",
+            raw(possible_values = "&[\"utf8\", \"hex\", \"base64\"]")
+        )]
+        format: Format,
         #[structopt(
             long,
             help = "Sets the server address",
@@ -47,10 +93,180 @@ enum Command {
         )]
         addr: SocketAddr,
     },
-    #[structopt(name = "rm", about = "Remove a given string key")]
+    #[structopt(name = "rm", about = "Remove a given key")]
     Remove {
-        #[structopt(name = "KEY", help = "A string key")]
+        #[structopt(name = "KEY", help = "A key")]
         key: String,
+        #[structopt(
+            long,
+            help = "Sets how keys and values are written on the command line",
+            value_name = "FORMAT",
+            default_value = "utf8",
+            raw(possible_values = "&[\"utf8\", \"hex\", \"base64\"]")
+        )]
+        format: Format,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "cas",
+        about = "Set the value of a key if its current value is the expected one"
+    )]
+    CompareAndSwap {
+        #[structopt(name = "KEY", help = "A key")]
+        key: String,
+        #[structopt(
+            long,
+            help = "The expected current value. The key is expected to be missing if \
+                    it is not given",
+            value_name = "VALUE"
+        )]
+        expected: Option<String>,
+        #[structopt(
+            long,
+            help = "The new value. The key is removed if it is not given",
+            value_name = "VALUE"
+        )]
+        new: Option<String>,
+        #[structopt(
+            long,
+            help = "Sets how keys and values are written on the command line",
+            value_name = "FORMAT",
+            default_value = "utf8",
+            raw(possible_values = "&[\"utf8\", \"hex\", \"base64\"]")
+        )]
+        format: Format,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "scan",
+        about = "List the key/value pairs in a key range or with a key prefix"
+    )]
+    Scan {
+        #[structopt(name = "START", help = "The first key of the range")]
+        start: Option<String>,
+        #[structopt(name = "END", help = "The end of the range (exclusive)")]
+        end: Option<String>,
+        #[structopt(
+            long,
+            help = "Lists the keys starting with the prefix instead of a range",
+            value_name = "PREFIX",
+            conflicts_with = "START"
+        )]
+        prefix: Option<String>,
+        #[structopt(
+            long,
+            help = "Sets the maximum number of pairs to list",
+            value_name = "N"
+        )]
+        limit: Option<usize>,
+        #[structopt(
+            long,
+            help = "Sets how keys and values are written on the command line",
+            value_name = "FORMAT",
+            default_value = "utf8",
+            raw(possible_values = "&[\"utf8\", \"hex\", \"base64\"]")
+        )]
+        format: Format,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "mget",
+        about = "Print the key/value pairs of the keys read from stdin, one per line"
+    )]
+    MultiGet {
+        #[structopt(
+            long,
+            help = "Sets how keys and values are written on the command line",
+            value_name = "FORMAT",
+            default_value = "utf8",
+            raw(possible_values = "&[\"utf8\", \"hex\", \"base64\"]")
+        )]
+        format: Format,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "mset",
+        about = "Set the key/value pairs read from stdin, one tab-separated pair per line"
+    )]
+    MultiSet {
+        #[structopt(
+            long,
+            help = "Sets how keys and values are written on the command line",
+            value_name = "FORMAT",
+            default_value = "utf8",
+            raw(possible_values = "&[\"utf8\", \"hex\", \"base64\"]")
+        )]
+        format: Format,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "watch",
+        about = "Print the changes of the keys with a prefix as they are written"
+    )]
+    Watch {
+        #[structopt(name = "PREFIX", help = "The prefix of the keys to watch")]
+        prefix: Option<String>,
+        #[structopt(
+            long,
+            help = "Prints the changes after the given position first",
+            value_name = "GEN:POS",
+            parse(try_from_str)
+        )]
+        from: Option<WatchSeq>,
+        #[structopt(
+            long,
+            help = "Sets how keys and values are written on the command line",
+            value_name = "FORMAT",
+            default_value = "utf8",
+            raw(possible_values = "&[\"utf8\", \"hex\", \"base64\"]")
+        )]
+        format: Format,
+        #[structopt(
+            long,
+            help = "Sets the server address",
+            value_name = "IP:PORT",
+            default_value = "127.0.0.1:4000",
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(name = "stats", about = "Print the counters of the storage engine")]
+    Stats {
         #[structopt(
             long,
             help = "Sets the server address",
@@ -72,24 +288,217 @@ fn main() {
 
 fn run(opt: Opt) -> Result<()> {
     match opt.command {
-        Command::Get { key, addr } => {
+        Command::Get {
+            key,
+            output,
+            format,
+            addr,
+        } => {
+            let key = format.decode(&key)?;
             let client = KvsClient::connect(addr);
             if let (Some(value), _) = client.and_then(move |client| client.get(key)).wait()? {
-                println!("{}", value);
+                match output {
+                    Some(path) => fs::write(path, value)?,
+                    None => println!("{}", format.encode(&value)?),
+                }
             } else {
                 println!("Key not found");
             }
         }
-        Command::Set { key, value, addr } => {
+        Command::Set {
+            key,
+            value,
+            value_file,
+            ttl,
+            format,
+            addr,
+        } => {
+            let key = format.decode(&key)?;
+            let value = match value_file {
+                Some(path) => fs::read(path)?,
+                None => format.decode(&value.unwrap_or_default())?,
+            };
+            let client = KvsClient::connect(addr);
+            match ttl {
+                Some(ttl) => client
+                    .and_then(move |client| {
+                        client.set_with_ttl(key, value, Duration::from_secs(ttl))
+                    })
+                    .wait()?,
+                None => client
+                    .and_then(move |client| client.set(key, value))
+                    .wait()?,
+            };
+        }
+        Command::Remove { key, format, addr } => {
+            let key = format.decode(&key)?;
+            let client = KvsClient::connect(addr);
+            client.and_then(move |client| client.remove(key)).wait()?;
+        }
+        Command::CompareAndSwap {
+            key,
+            expected,
+            new,
+            format,
+            addr,
+        } => {
+            let key = format.decode(&key)?;
+            let expected = expected.map(|value| format.decode(&value)).transpose()?;
+            let new = new.map(|value| format.decode(&value)).transpose()?;
+            let client = KvsClient::connect(addr);
+            let (swapped, _) = client
+                .and_then(move |client| client.compare_and_swap(key, expected, new))
+                .wait()?;
+            if !swapped {
+                return Err(KvsError::StringError(
+                    "Current value does not match".to_owned(),
+                ));
+            }
+        }
+        Command::Scan {
+            start,
+            end,
+            prefix,
+            limit,
+            format,
+            addr,
+        } => {
+            let client = KvsClient::connect(addr);
+            let (pairs, _) = match prefix {
+                Some(prefix) => {
+                    let prefix = format.decode(&prefix)?;
+                    client
+                        .and_then(move |client| client.scan_prefix(prefix, limit))
+                        .wait()?
+                }
+                None => {
+                    let start = format.decode(&start.unwrap_or_default())?;
+                    let end = end.map(|end| format.decode(&end)).transpose()?;
+                    client
+                        .and_then(move |client| client.scan(start, end, limit))
+                        .wait()?
+                }
+            };
+            for (key, value) in pairs {
+                println!("{}\t{}", format.encode(&key)?, format.encode(&value)?);
+            }
+        }
+        Command::MultiGet { format, addr } => {
+            let keys = io::stdin()
+                .lock()
+                .lines()
+                .map(|line| format.decode(&line?))
+                .collect::<Result<Vec<_>>>()?;
+            let client = KvsClient::connect(addr);
+            let request_keys = keys.clone();
+            let (values, _) = client
+                .and_then(move |client| client.multi_get(request_keys))
+                .wait()?;
+            // the keys that are not found are left out
+            for (key, value) in keys.iter().zip(values) {
+                if let Some(value) = value {
+                    println!("{}\t{}", format.encode(key)?, format.encode(&value)?);
+                }
+            }
+        }
+        Command::MultiSet { format, addr } => {
+            let pairs = io::stdin()
+                .lock()
+                .lines()
+                .map(|line| {
+                    let line = line?;
+                    let mut parts = line.splitn(2, '\t');
+                    match (parts.next(), parts.next()) {
+                        (Some(key), Some(value)) => {
+                            Ok((format.decode(key)?, format.decode(value)?))
+                        }
+                        _ => Err(KvsError::StringError(format!("Invalid pair: {}", line))),
+                    }
+                })
+                .collect::<Result<Vec<_>>>()?;
             let client = KvsClient::connect(addr);
             client
-                .and_then(move |client| client.set(key, value))
+                .and_then(move |client| client.multi_set(pairs))
                 .wait()?;
         }
-        Command::Remove { key, addr } => {
+        Command::Watch {
+            prefix,
+            from,
+            format,
+            addr,
+        } => {
+            let prefix = format.decode(&prefix.unwrap_or_default())?;
             let client = KvsClient::connect(addr);
-            client.and_then(move |client| client.remove(key)).wait()?;
+            let (_, changes) = client
+                .and_then(move |client| client.watch(prefix, from))
+                .wait()?;
+            // every line starts with the position to resume from
+            for event in changes.wait() {
+                match event? {
+                    WatchEvent::Set {
+                        seq, key, value, ..
+                    } => println!(
+                        "{}\tset\t{}\t{}",
+                        seq,
+                        format.encode(&key)?,
+                        format.encode(&value)?
+                    ),
+                    WatchEvent::Remove { seq, key } => {
+                        println!("{}\trm\t{}", seq, format.encode(&key)?)
+                    }
+                }
+            }
+        }
+        Command::Stats { addr } => {
+            let client = KvsClient::connect(addr);
+            let (stats, _) = client.and_then(move |client| client.stats()).wait()?;
+            println!("cache_hits\t{}", stats.cache_hits);
+            println!("cache_misses\t{}", stats.cache_misses);
+            println!("cache_size\t{}", stats.cache_size);
         }
     }
     Ok(())
 }
+
+/// How keys and values are written on the command line
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Utf8,
+    Hex,
+    Base64,
+}
+
+impl Format {
+    /// Decodes a key or value given on the command line.
+    fn decode(self, s: &str) -> Result<Vec<u8>> {
+        match self {
+            Format::Utf8 => Ok(s.as_bytes().to_vec()),
+            Format::Hex => hex::decode(s)
+                .map_err(|e| KvsError::StringError(format!("Invalid hex string: {}", e))),
+            Format::Base64 => base64::decode(s)
+                .map_err(|e| KvsError::StringError(format!("Invalid base64 string: {}", e))),
+        }
+    }
+
+    /// Encodes a key or value to be printed.
+    fn encode(self, bytes: &[u8]) -> Result<String> {
+        match self {
+            Format::Utf8 => Ok(String::from_utf8(bytes.to_vec())?),
+            Format::Hex => Ok(hex::encode(bytes)),
+            Format::Base64 => Ok(base64::encode(bytes)),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Format> {
+        match s {
+            "utf8" => Ok(Format::Utf8),
+            "hex" => Ok(Format::Hex),
+            "base64" => Ok(Format::Base64),
+            _ => Err(KvsError::StringError(format!("Invalid format: {}", s))),
+        }
+    }
+}
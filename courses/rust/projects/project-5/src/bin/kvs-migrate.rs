@@ -0,0 +1,209 @@
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate clap;
+
+use kvs::thread_pool::*;
+use kvs::{
+    KvStore, KvStoreOptions, KvsEngine, KvsError, LsmKvsEngine, LsmOptions, Result, SledKvsEngine,
+    SyncPolicy, WriteBatch,
+};
+use log::LevelFilter;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use structopt::StructOpt;
+use tokio::prelude::*;
+
+/// The number of pairs read from the source and written to the target at a time
+const BATCH_SIZE: usize = 1000;
+
+#[derive(StructOpt, Debug)]
+#[structopt(
+    name = "kvs-migrate",
+    about = "Copies all live keys of a stopped kvs-server data directory into a new \
+             directory of another storage engine. Expiry times are not copied."
+)]
+struct Opt {
+    #[structopt(name = "FROM", help = "The source data directory", parse(from_os_str))]
+    from: PathBuf,
+    #[structopt(
+        name = "TO",
+        help = "The target data directory. It is created if it does not exist and must \
+                be empty otherwise",
+        parse(from_os_str)
+    )]
+    to: PathBuf,
+    #[structopt(
+        long,
+        help = "Sets the storage engine of the target directory",
+        value_name = "ENGINE-NAME",
+        raw(possible_values = "&Engine::variants()")
+    )]
+    engine: Engine,
+    #[structopt(
+        long = "from-engine",
+        help = "Sets the storage engine of the source directory if it has no engine file",
+        value_name = "ENGINE-NAME",
+        raw(possible_values = "&Engine::variants()")
+    )]
+    from_engine: Option<Engine>,
+}
+
+arg_enum! {
+    #[allow(non_camel_case_types)]
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    enum Engine {
+        kvs,
+        sled,
+        lsm
+    }
+}
+
+fn main() {
+    env_logger::builder().filter_level(LevelFilter::Info).init();
+    let opt = Opt::from_args();
+    if let Err(e) = run(opt) {
+        error!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
+    if !opt.from.is_dir() {
+        return Err(KvsError::StringError(format!(
+            "{} is not a directory",
+            opt.from.display()
+        )));
+    }
+    let from_engine = match (read_engine(&opt.from)?, opt.from_engine) {
+        (Some(engine), Some(from_engine)) if engine != from_engine => {
+            return Err(KvsError::StringError("Wrong engine!".to_owned()));
+        }
+        (Some(engine), _) | (None, Some(engine)) => engine,
+        (None, None) => {
+            return Err(KvsError::StringError(format!(
+                "The engine of {} is unknown",
+                opt.from.display()
+            )));
+        }
+    };
+    if opt.to.exists() && fs::read_dir(&opt.to)?.next().is_some() {
+        return Err(KvsError::StringError(format!(
+            "{} is not empty",
+            opt.to.display()
+        )));
+    }
+    fs::create_dir_all(&opt.to)?;
+    info!(
+        "Migrating {} ({}) to {} ({})",
+        opt.from.display(),
+        from_engine,
+        opt.to.display(),
+        opt.engine
+    );
+
+    let count = match from_engine {
+        Engine::kvs => migrate_from(open_kvs(&opt.from)?, &opt.to, opt.engine)?,
+        Engine::sled => migrate_from(open_sled(&opt.from)?, &opt.to, opt.engine)?,
+        Engine::lsm => migrate_from(open_lsm(&opt.from)?, &opt.to, opt.engine)?,
+    };
+
+    // the engine file is written last, so an interrupted migration is not mistaken
+    // for a complete one
+    fs::write(opt.to.join("engine"), format!("{}", opt.engine))?;
+    info!("Migrated {} keys", count);
+    Ok(())
+}
+
+fn migrate_from<S: KvsEngine>(source: S, to: &Path, engine: Engine) -> Result<u64> {
+    match engine {
+        Engine::kvs => migrate(&source, &open_kvs(to)?),
+        Engine::sled => migrate(&source, &open_sled(to)?),
+        Engine::lsm => migrate(&source, &open_lsm(to)?),
+    }
+}
+
+/// Copies all live pairs of `source` to `target` and checks that `target` has the
+/// same number of keys afterwards.
+///
+/// Returns the number of keys copied.
+fn migrate<S: KvsEngine, T: KvsEngine>(source: &S, target: &T) -> Result<u64> {
+    let mut copied = 0;
+    for_each_page(source, |pairs| {
+        copied += pairs.len() as u64;
+        let mut batch = WriteBatch::new();
+        for (key, value) in pairs {
+            batch.set(key, value);
+        }
+        target.write_batch(batch).wait()
+    })?;
+
+    let mut migrated = 0;
+    for_each_page(target, |pairs| {
+        migrated += pairs.len() as u64;
+        Ok(())
+    })?;
+    if migrated != copied {
+        return Err(KvsError::StringError(format!(
+            "{} keys were copied but the target has {} keys",
+            copied, migrated
+        )));
+    }
+    Ok(copied)
+}
+
+/// Scans all live pairs of `engine` in pages of at most `BATCH_SIZE` pairs and
+/// passes each page to `f`.
+fn for_each_page<E, F>(engine: &E, mut f: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: FnMut(Vec<(Vec<u8>, Vec<u8>)>) -> Result<()>,
+{
+    let mut start = Some(Vec::new());
+    while let Some(from) = start.take() {
+        let pairs: Vec<_> = engine.scan(from, None, Some(BATCH_SIZE)).collect().wait()?;
+        if pairs.len() == BATCH_SIZE {
+            start = pairs.last().map(|(key, _)| next_key(key));
+        }
+        f(pairs)?;
+    }
+    Ok(())
+}
+
+/// Returns the smallest key greater than `key`.
+fn next_key(key: &[u8]) -> Vec<u8> {
+    let mut next = key.to_vec();
+    next.push(0);
+    next
+}
+
+// the copied pairs are synced before the engine file is written
+fn open_kvs(path: &Path) -> Result<KvStore<RayonThreadPool>> {
+    let options = KvStoreOptions::new().sync_policy(SyncPolicy::Always);
+    KvStore::open_with_options(path, num_cpus::get() as u32, options)
+}
+
+fn open_sled(path: &Path) -> Result<SledKvsEngine<RayonThreadPool>> {
+    SledKvsEngine::new(sled::open(path)?, num_cpus::get() as u32)
+}
+
+fn open_lsm(path: &Path) -> Result<LsmKvsEngine<RayonThreadPool>> {
+    let options = LsmOptions::new().sync_policy(SyncPolicy::Always);
+    LsmKvsEngine::open_with_options(path, num_cpus::get() as u32, options)
+}
+
+fn read_engine(path: &Path) -> Result<Option<Engine>> {
+    let engine = path.join("engine");
+    if !engine.exists() {
+        return Ok(None);
+    }
+
+    match fs::read_to_string(engine)?.parse() {
+        Ok(engine) => Ok(Some(engine)),
+        Err(e) => {
+            warn!("The content of engine file is invalid: {}", e);
+            Ok(None)
+        }
+    }
+}
@@ -3,21 +3,32 @@ extern crate log;
 #[macro_use]
 extern crate clap;
 
+use clap::AppSettings;
 use kvs::thread_pool::*;
-use kvs::{KvStore, KvsEngine, KvsServer, Result, SledKvsEngine};
+use kvs::{
+    Compression, IndexBackend, KvStore, KvStoreOptions, KvsClient, KvsEngine, KvsError, KvsServer,
+    LsmKvsEngine, LsmOptions, MemoryKvsEngine, Result, SledKvsEngine, SyncPolicy,
+};
 use log::LevelFilter;
 use std::env;
 use std::env::current_dir;
 use std::fs;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::process::exit;
 use structopt::StructOpt;
+use tokio::prelude::*;
 
 const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
 const DEFAULT_ENGINE: Engine = Engine::kvs;
 
 #[derive(StructOpt, Debug)]
-#[structopt(name = "kvs-server")]
+#[structopt(
+    name = "kvs-server",
+    raw(global_settings = "&[\
+                           AppSettings::DisableHelpSubcommand,\
+                           AppSettings::VersionlessSubcommands]")
+)]
 struct Opt {
     #[structopt(
         long,
@@ -34,6 +45,121 @@ struct Opt {
         raw(possible_values = "&Engine::variants()")
     )]
     engine: Option<Engine>,
+    #[structopt(
+        long,
+        help = "Sets when writes are synced to disk: never, always, group or an interval \
+                such as 100ms",
+        value_name = "POLICY"
+    )]
+    sync: Option<SyncPolicy>,
+    #[structopt(
+        long = "cache-capacity",
+        help = "Sets the size in bytes of the value cache of the kvs engine",
+        value_name = "BYTES"
+    )]
+    cache_capacity: Option<u64>,
+    #[structopt(
+        long,
+        help = "Sets how the kvs engine compresses values: none, lz4 or zstd",
+        value_name = "COMPRESSION"
+    )]
+    compression: Option<Compression>,
+    #[structopt(
+        long,
+        help = "Sets where the kvs engine keeps the index of its keys: memory or disk",
+        value_name = "INDEX"
+    )]
+    index: Option<IndexBackend>,
+    #[structopt(
+        long = "memory-limit",
+        help = "Sets the size in bytes of the data the memory engine keeps before it evicts \
+                the least recently used keys",
+        value_name = "BYTES"
+    )]
+    memory_limit: Option<u64>,
+    #[structopt(
+        long = "replica-of",
+        help = "Makes the server a read-only replica of the server at the address",
+        value_name = "IP:PORT",
+        parse(try_from_str)
+    )]
+    replica_of: Option<SocketAddr>,
+    #[structopt(
+        long = "resp-addr",
+        help = "Also serves the RESP protocol of Redis on the address",
+        value_name = "IP:PORT",
+        parse(try_from_str)
+    )]
+    resp_addr: Option<SocketAddr>,
+    #[structopt(
+        long = "http-addr",
+        help = "Also serves HTTP on the address",
+        value_name = "IP:PORT",
+        parse(try_from_str)
+    )]
+    http_addr: Option<SocketAddr>,
+    #[structopt(
+        long = "backup-dir",
+        help = "Allows clients to write backups into new directories in the directory",
+        value_name = "PATH",
+        parse(from_os_str)
+    )]
+    backup_dir: Option<PathBuf>,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    #[structopt(
+        name = "backup",
+        about = "Makes a running server write a backup of its data to a new directory in \
+                 its backup directory"
+    )]
+    Backup {
+        #[structopt(
+            name = "NAME",
+            help = "The name of the backup directory in the backup directory of the server"
+        )]
+        name: String,
+        #[structopt(
+            long,
+            help = "Sets the address of the running server",
+            value_name = "IP:PORT",
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
+    #[structopt(
+        name = "restore",
+        about = "Installs a backup into the empty data directory in the current directory"
+    )]
+    Restore {
+        #[structopt(name = "PATH", help = "The backup directory", parse(from_os_str))]
+        path: PathBuf,
+        #[structopt(
+            long,
+            help = "Sets the storage engine of the backup",
+            value_name = "ENGINE-NAME",
+            raw(possible_values = "&Engine::variants()")
+        )]
+        engine: Option<Engine>,
+    },
+    #[structopt(
+        name = "promote",
+        about = "Makes a running replica stop replicating and accept writes"
+    )]
+    Promote {
+        #[structopt(
+            long,
+            help = "Sets the address of the running replica",
+            value_name = "IP:PORT",
+            raw(default_value = "DEFAULT_LISTENING_ADDRESS"),
+            parse(try_from_str)
+        )]
+        addr: SocketAddr,
+    },
 }
 
 arg_enum! {
@@ -41,23 +167,32 @@ arg_enum! {
     #[derive(Debug, Copy, Clone, PartialEq, Eq)]
     enum Engine {
         kvs,
-        sled
+        sled,
+        lsm,
+        memory
     }
 }
 
 fn main() {
     env_logger::builder().filter_level(LevelFilter::Info).init();
     let mut opt = Opt::from_args();
-    let res = current_engine().and_then(move |curr_engine| {
-        if opt.engine.is_none() {
-            opt.engine = curr_engine;
-        }
-        if curr_engine.is_some() && opt.engine != curr_engine {
-            error!("Wrong engine!");
-            exit(1);
-        }
-        run(opt)
-    });
+    let res = match opt.command.take() {
+        Some(Command::Backup { name, addr }) => backup(name, addr),
+        Some(Command::Restore { path, engine }) => restore(path, engine),
+        Some(Command::Promote { addr }) => promote(addr),
+        // the memory engine keeps no data in the directory, so it runs anywhere
+        None if opt.engine == Some(Engine::memory) => run(opt),
+        None => current_engine().and_then(move |curr_engine| {
+            if opt.engine.is_none() {
+                opt.engine = curr_engine;
+            }
+            if curr_engine.is_some() && opt.engine != curr_engine {
+                error!("Wrong engine!");
+                exit(1);
+            }
+            run(opt)
+        }),
+    };
     if let Err(e) = res {
         error!("{}", e);
         exit(1);
@@ -69,29 +204,127 @@ fn run(opt: Opt) -> Result<()> {
     info!("kvs-server {}", env!("CARGO_PKG_VERSION"));
     info!("Storage engine: {}", engine);
     info!("Listening on {}", opt.addr);
+    if let Some(resp_addr) = opt.resp_addr {
+        info!("Serving RESP on {}", resp_addr);
+    }
+    if let Some(http_addr) = opt.http_addr {
+        info!("Serving HTTP on {}", http_addr);
+    }
 
     // write engine to engine file
-    fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
+    if engine != Engine::memory {
+        fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
+    }
 
     let concurrency = num_cpus::get() as u32;
     match engine {
-        Engine::kvs => run_with(
-            KvStore::<RayonThreadPool>::open(env::current_dir()?, concurrency)?,
-            opt.addr,
-        ),
-        Engine::sled => run_with(
-            SledKvsEngine::<RayonThreadPool>::new(
-                sled::Db::start_default(env::current_dir()?)?,
-                concurrency,
-            )?,
-            opt.addr,
-        ),
+        Engine::kvs => {
+            let options = KvStoreOptions::new()
+                .sync_policy(opt.sync.unwrap_or_default())
+                .cache_capacity(opt.cache_capacity.unwrap_or(0))
+                .compression(opt.compression.unwrap_or_default())
+                .index_backend(opt.index.unwrap_or_default());
+            run_with(
+                KvStore::<RayonThreadPool>::open_with_options(
+                    env::current_dir()?,
+                    concurrency,
+                    options,
+                )?,
+                &opt,
+            )
+        }
+        Engine::sled => {
+            let mut config = sled::Config::new().path(env::current_dir()?);
+            match opt.sync {
+                Some(SyncPolicy::Interval(interval)) => {
+                    config = config.flush_every_ms(Some(interval.as_millis() as u64))
+                }
+                // sled flushes in the background unless it is turned off
+                Some(SyncPolicy::Never) => config = config.flush_every_ms(None),
+                _ => {}
+            }
+            run_with(
+                SledKvsEngine::<RayonThreadPool>::with_sync_policy(
+                    config.open()?,
+                    concurrency,
+                    opt.sync.unwrap_or(SyncPolicy::Always),
+                )?,
+                &opt,
+            )
+        }
+        Engine::lsm => {
+            let options = LsmOptions::new().sync_policy(opt.sync.unwrap_or_default());
+            run_with(
+                LsmKvsEngine::<RayonThreadPool>::open_with_options(
+                    env::current_dir()?,
+                    concurrency,
+                    options,
+                )?,
+                &opt,
+            )
+        }
+        Engine::memory => {
+            let engine = match opt.memory_limit {
+                Some(limit) => MemoryKvsEngine::with_capacity(limit),
+                None => MemoryKvsEngine::new(),
+            };
+            run_with(engine, &opt)
+        }
     }
 }
 
-pub fn run_with<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
-    let server = KvsServer::new(engine);
-    server.run(addr)
+fn run_with<E: KvsEngine>(engine: E, opt: &Opt) -> Result<()> {
+    let mut server = KvsServer::new(engine);
+    if let Some(primary) = opt.replica_of {
+        server = server.replica_of(primary);
+    }
+    if let Some(resp_addr) = opt.resp_addr {
+        server = server.resp_addr(resp_addr);
+    }
+    if let Some(http_addr) = opt.http_addr {
+        server = server.http_addr(http_addr);
+    }
+    if let Some(backup_dir) = &opt.backup_dir {
+        // relative to the current directory, which is the data directory
+        let backup_dir = current_dir()?.join(backup_dir);
+        fs::create_dir_all(&backup_dir)?;
+        server = server.backup_dir(backup_dir);
+    }
+    server.run(opt.addr)
+}
+
+fn backup(name: String, addr: SocketAddr) -> Result<()> {
+    KvsClient::connect(addr)
+        .and_then(move |client| client.backup(name))
+        .wait()?;
+    Ok(())
+}
+
+fn promote(addr: SocketAddr) -> Result<()> {
+    KvsClient::connect(addr)
+        .and_then(move |client| client.promote())
+        .wait()?;
+    Ok(())
+}
+
+fn restore(path: PathBuf, engine: Option<Engine>) -> Result<()> {
+    let curr_engine = current_engine()?;
+    let engine = engine.or(curr_engine).unwrap_or(DEFAULT_ENGINE);
+    if curr_engine.is_some() && Some(engine) != curr_engine {
+        return Err(KvsError::StringError("Wrong engine!".to_owned()));
+    }
+    match engine {
+        Engine::kvs => KvStore::<RayonThreadPool>::restore(&path, current_dir()?)?,
+        Engine::sled => SledKvsEngine::<RayonThreadPool>::restore(&path, current_dir()?)?,
+        Engine::lsm => LsmKvsEngine::<RayonThreadPool>::restore(&path, current_dir()?)?,
+        Engine::memory => {
+            return Err(KvsError::Unsupported(
+                "The memory engine does not support backups".to_owned(),
+            ))
+        }
+    }
+    fs::write(current_dir()?.join("engine"), format!("{}", engine))?;
+    Ok(())
 }
 
 fn current_engine() -> Result<Option<Engine>> {
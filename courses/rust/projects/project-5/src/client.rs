@@ -1,88 +1,391 @@
+use self::connection::{Connection, Exchange};
 use crate::common::{Request, Response};
-use crate::KvsError;
+use crate::{EngineStats, KvsError, WatchEvent, WatchSeq, WatchStream, WriteBatch};
 use std::net::SocketAddr;
-use tokio::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
-use tokio::io::{ReadHalf, WriteHalf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::net::TcpStream;
+use tokio::prelude::future::Loop;
 use tokio::prelude::*;
-use tokio_serde_json::{ReadJson, WriteJson};
+
+pub use self::pool::{ClientPoolOptions, KvsClientPool};
+
+mod connection;
+mod pool;
+
+/// Key/value pairs returned by a scan
+type KvPairs = Vec<(Vec<u8>, Vec<u8>)>;
 
 /// Key value store client
+///
+/// The clones of a client share its connection and can send requests without
+/// waiting for each other. The requests on a key are served in the order they are
+/// sent, and the others may complete in any order.
+#[derive(Clone)]
 pub struct KvsClient {
-    read_json: ReadJson<FramedRead<ReadHalf<TcpStream>, LengthDelimitedCodec>, Response>,
-    write_json: WriteJson<FramedWrite<WriteHalf<TcpStream>, LengthDelimitedCodec>, Request>,
+    conn: Arc<Mutex<Connection>>,
+    // the transaction the requests run in
+    txn: Option<u64>,
 }
 
 impl KvsClient {
     /// Connect to `addr` to access `KvsServer`.
     pub fn connect(addr: SocketAddr) -> impl Future<Item = Self, Error = KvsError> {
         TcpStream::connect(&addr)
-            .map(|tcp| {
-                let (read_half, write_half) = tcp.split();
-                let read_json =
-                    ReadJson::new(FramedRead::new(read_half, LengthDelimitedCodec::new()));
-                let write_json =
-                    WriteJson::new(FramedWrite::new(write_half, LengthDelimitedCodec::new()));
-                KvsClient {
-                    read_json,
-                    write_json,
-                }
+            .map(|tcp| KvsClient {
+                conn: Arc::new(Mutex::new(Connection::new(tcp))),
+                txn: None,
             })
             .map_err(|e| e.into())
     }
 
     /// Get the value of a given key from the server.
-    pub fn get(self, key: String) -> impl Future<Item = (Option<String>, Self), Error = KvsError> {
+    pub fn get(
+        self,
+        key: Vec<u8>,
+    ) -> impl Future<Item = (Option<Vec<u8>>, Self), Error = KvsError> {
         self.send_request(Request::Get { key })
             .and_then(move |(resp, client)| match resp {
                 Some(Response::Get(value)) => Ok((value, client)),
                 Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
                 Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
-                None => Err(KvsError::StringError("No response received".to_owned())),
+                None => Err(KvsError::Disconnected),
             })
     }
 
-    /// Set the value of a string key in the server.
-    pub fn set(self, key: String, value: String) -> impl Future<Item = Self, Error = KvsError> {
+    /// Set the value of a key in the server.
+    pub fn set(self, key: Vec<u8>, value: Vec<u8>) -> impl Future<Item = Self, Error = KvsError> {
         self.send_request(Request::Set { key, value })
             .and_then(move |(resp, client)| match resp {
                 Some(Response::Set) => Ok(client),
                 Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
                 Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
-                None => Err(KvsError::StringError("No response received".to_owned())),
+                None => Err(KvsError::Disconnected),
             })
     }
 
-    /// Remove a string key in the server.
-    pub fn remove(self, key: String) -> impl Future<Item = Self, Error = KvsError> {
+    /// Set the value of a key that expires after `ttl` in the server.
+    pub fn set_with_ttl(
+        self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> impl Future<Item = Self, Error = KvsError> {
+        self.send_request(Request::SetWithTtl { key, value, ttl })
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::Set) => Ok(client),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::Disconnected),
+            })
+    }
+
+    /// Remove a key in the server.
+    pub fn remove(self, key: Vec<u8>) -> impl Future<Item = Self, Error = KvsError> {
         self.send_request(Request::Remove { key })
             .and_then(move |(resp, client)| match resp {
                 Some(Response::Remove) => Ok(client),
                 Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
                 Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
-                None => Err(KvsError::StringError("No response received".to_owned())),
+                None => Err(KvsError::Disconnected),
+            })
+    }
+
+    /// Set the value of a key to `new` in the server if its current value is
+    /// `expected`, and return whether the value was swapped.
+    pub fn compare_and_swap(
+        self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> impl Future<Item = (bool, Self), Error = KvsError> {
+        self.send_request(Request::CompareAndSwap { key, expected, new })
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::CompareAndSwap(swapped)) => Ok((swapped, client)),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::Disconnected),
+            })
+    }
+
+    /// Set the value of a key in the server if it does not exist, and return
+    /// whether the value was set.
+    pub fn set_if_absent(
+        self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> impl Future<Item = (bool, Self), Error = KvsError> {
+        self.send_request(Request::SetIfAbsent { key, value })
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::SetIfAbsent(set)) => Ok((set, client)),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::Disconnected),
             })
     }
 
+    /// Get the values of many keys from the server in one request.
+    ///
+    /// The values are returned in the order of the keys.
+    pub fn multi_get(
+        self,
+        keys: Vec<Vec<u8>>,
+    ) -> impl Future<Item = (Vec<Option<Vec<u8>>>, Self), Error = KvsError> {
+        self.send_request(Request::MultiGet { keys })
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::MultiGet(values)) => Ok((values, client)),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::Disconnected),
+            })
+    }
+
+    /// Set the values of many keys in the server in one request.
+    ///
+    /// Unlike `write_batch`, the pairs are set independently, so some of them may
+    /// be set if the request fails. The last value of a key given twice wins.
+    pub fn multi_set(
+        self,
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> impl Future<Item = Self, Error = KvsError> {
+        self.send_request(Request::MultiSet { pairs })
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::MultiSet) => Ok(client),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::Disconnected),
+            })
+    }
+
+    /// Apply all writes in a batch atomically in the server.
+    pub fn write_batch(self, batch: WriteBatch) -> impl Future<Item = Self, Error = KvsError> {
+        self.send_request(Request::WriteBatch { batch }).and_then(
+            move |(resp, client)| match resp {
+                Some(Response::WriteBatch) => Ok(client),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::Disconnected),
+            },
+        )
+    }
+
+    /// Write a backup of the server data to a new directory `name` in the backup
+    /// directory of the server.
+    pub fn backup(self, name: String) -> impl Future<Item = Self, Error = KvsError> {
+        self.send_request(Request::Backup { name })
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::Backup) => Ok(client),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::Disconnected),
+            })
+    }
+
+    /// Get the counters of the storage engine of the server.
+    pub fn stats(self) -> impl Future<Item = (EngineStats, Self), Error = KvsError> {
+        self.send_request(Request::Stats)
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::Stats(stats)) => Ok((stats, client)),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::Disconnected),
+            })
+    }
+
+    /// Begin a transaction.
+    ///
+    /// The returned client runs `get`, `set` and `remove` in the transaction until
+    /// it is committed or aborted, and its other requests fail. Only the returned
+    /// client and its clones run in the transaction, so other clients sharing the
+    /// connection, including `self` and its clones, do not see it. The writes are
+    /// buffered in the server and applied by `commit` if none of the values read
+    /// has changed.
+    pub fn begin(self) -> impl Future<Item = Self, Error = KvsError> {
+        self.send_request(Request::Begin)
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::Begin(txn)) => Ok(KvsClient {
+                    txn: Some(txn),
+                    ..client
+                }),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::Disconnected),
+            })
+    }
+
+    /// Commit the transaction of the client, and return whether it is committed.
+    /// A transaction that conflicts with other writes is aborted.
+    ///
+    /// The returned client runs its requests outside of a transaction.
+    pub fn commit(self) -> impl Future<Item = (bool, Self), Error = KvsError> {
+        self.send_request(Request::Commit)
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::Commit(committed)) => Ok((
+                    committed,
+                    KvsClient {
+                        txn: None,
+                        ..client
+                    },
+                )),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::Disconnected),
+            })
+    }
+
+    /// Abort the transaction of the client and drop its writes.
+    ///
+    /// The returned client runs its requests outside of a transaction.
+    pub fn abort(self) -> impl Future<Item = Self, Error = KvsError> {
+        self.send_request(Request::Abort)
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::Abort) => Ok(KvsClient {
+                    txn: None,
+                    ..client
+                }),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::Disconnected),
+            })
+    }
+
+    /// Get the key/value pairs with keys in the range `[start, end)` from the server.
+    ///
+    /// The range is unbounded above if `end` is `None`.
+    pub fn scan(
+        self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> impl Future<Item = (KvPairs, Self), Error = KvsError> {
+        self.scan_request(Request::Scan { start, end, limit })
+    }
+
+    /// Get the key/value pairs with keys starting with `prefix` from the server.
+    pub fn scan_prefix(
+        self,
+        prefix: Vec<u8>,
+        limit: Option<usize>,
+    ) -> impl Future<Item = (KvPairs, Self), Error = KvsError> {
+        self.scan_request(Request::ScanPrefix { prefix, limit })
+    }
+
+    /// Watch the changes of the keys starting with `prefix` in the server.
+    ///
+    /// The future resolves once the watch is registered, to the position of the last
+    /// change before the watch and the stream of the later changes. If `from` is
+    /// given, the changes after `from` are streamed first. The stream ends when the
+    /// server closes the connection.
+    pub fn watch(
+        self,
+        prefix: Vec<u8>,
+        from: Option<WatchSeq>,
+    ) -> impl Future<Item = (WatchSeq, WatchStream), Error = KvsError> {
+        Exchange::new(self.conn, self.txn, Request::Watch { prefix, from })
+            .into_future()
+            .map_err(|(err, _)| err)
+            .and_then(move |(resp, responses)| match resp {
+                Some(Response::Watch(start)) => Ok((start, Self::changes(responses))),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::Disconnected),
+            })
+    }
+
+    /// Get a copy of all pairs in the server and watch the changes after it.
+    ///
+    /// The copied pairs arrive as chunks of `WatchEvent::Set` changes in key order,
+    /// and each chunk is passed to `install`. The next chunk is only read once the
+    /// future returned by `install` resolves. The returned future resolves to the
+    /// position of the copy and the stream of the later changes.
+    pub fn replicate<F, T>(
+        self,
+        install: F,
+    ) -> impl Future<Item = (WatchSeq, WatchStream), Error = KvsError>
+    where
+        F: FnMut(Vec<WatchEvent>) -> T,
+        T: IntoFuture<Item = (), Error = KvsError>,
+    {
+        let responses = Exchange::new(self.conn, self.txn, Request::Replicate);
+        // the copy arrives in chunks until the watch is acknowledged
+        future::loop_fn((responses, install), |(responses, mut install)| {
+            responses
+                .into_future()
+                .map_err(|(err, _)| err)
+                .and_then(move |(resp, responses)| match resp {
+                    Some(Response::Copy(chunk)) => future::Either::A(
+                        install(chunk)
+                            .into_future()
+                            .map(move |()| Loop::Continue((responses, install))),
+                    ),
+                    Some(Response::Watch(seq)) => {
+                        future::Either::B(future::ok(Loop::Break((seq, Self::changes(responses)))))
+                    }
+                    Some(Response::Err(msg)) => {
+                        future::Either::B(future::err(KvsError::StringError(msg)))
+                    }
+                    Some(_) => future::Either::B(future::err(KvsError::StringError(
+                        "Invalid response".to_owned(),
+                    ))),
+                    None => future::Either::B(future::err(KvsError::Disconnected)),
+                })
+        })
+    }
+
+    /// Make a replica server stop replicating and accept writes.
+    pub fn promote(self) -> impl Future<Item = Self, Error = KvsError> {
+        self.send_request(Request::Promote)
+            .and_then(move |(resp, client)| match resp {
+                Some(Response::Promote) => Ok(client),
+                Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                None => Err(KvsError::Disconnected),
+            })
+    }
+
+    /// Maps the responses after a watch is acknowledged to the changes.
+    fn changes(responses: Exchange) -> WatchStream {
+        Box::new(responses.and_then(|resp| match resp {
+            Response::Change(event) => Ok(event),
+            Response::Err(msg) => Err(KvsError::StringError(msg)),
+            _ => Err(KvsError::StringError("Invalid response".to_owned())),
+        }))
+    }
+
+    /// Sends a scan and collects the chunks of pairs that arrive until the end of
+    /// the scan.
+    fn scan_request(self, req: Request) -> impl Future<Item = (KvPairs, Self), Error = KvsError> {
+        let responses = Exchange::new(Arc::clone(&self.conn), self.txn, req);
+        future::loop_fn((responses, Vec::new()), |(responses, mut pairs)| {
+            responses
+                .into_future()
+                .map_err(|(err, _)| err)
+                .and_then(move |(resp, responses)| match resp {
+                    Some(Response::Scan(chunk)) => {
+                        pairs.extend(chunk);
+                        Ok(Loop::Continue((responses, pairs)))
+                    }
+                    Some(Response::ScanEnd) => Ok(Loop::Break(pairs)),
+                    Some(Response::Err(msg)) => Err(KvsError::StringError(msg)),
+                    Some(_) => Err(KvsError::StringError("Invalid response".to_owned())),
+                    None => Err(KvsError::Disconnected),
+                })
+        })
+        .map(move |pairs| (pairs, self))
+    }
+
+    /// Returns whether the client shares its connection with `other`.
+    pub(crate) fn same_connection(&self, other: &KvsClient) -> bool {
+        Arc::ptr_eq(&self.conn, &other.conn)
+    }
+
     fn send_request(
         self,
         req: Request,
     ) -> impl Future<Item = (Option<Response>, Self), Error = KvsError> {
-        let read_json = self.read_json;
-        self.write_json
-            .send(req)
-            .and_then(move |write_json| {
-                read_json
-                    .into_future()
-                    .map(move |(resp, read_json)| {
-                        let client = KvsClient {
-                            read_json,
-                            write_json,
-                        };
-                        (resp, client)
-                    })
-                    .map_err(|(err, _)| err)
-            })
-            .map_err(|e| e.into())
+        Exchange::new(Arc::clone(&self.conn), self.txn, req)
+            .into_future()
+            .map(move |(resp, _)| (resp, self))
+            .map_err(|(err, _)| err)
     }
 }
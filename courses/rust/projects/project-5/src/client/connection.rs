@@ -0,0 +1,190 @@
+//! A connection shared by the clones of a `KvsClient`.
+//!
+//! Requests are tagged with IDs, so many of them can be sent without waiting for
+//! the earlier responses. There is no task driving the connection: whichever
+//! exchange is polled reads the responses that have arrived and hands the ones of
+//! other requests to their exchanges. Only the last task that found the socket
+//! not ready is woken by it, so an exchange wakes the others whenever it stops
+//! waiting, and one of them takes over.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use tokio::codec::{FramedRead, FramedWrite};
+use tokio::io::{ReadHalf, WriteHalf};
+use tokio::net::TcpStream;
+use tokio::prelude::task::{self, Task};
+use tokio::prelude::*;
+
+use crate::common::{Frame, MessageCodec, Request, Response};
+use crate::KvsError;
+
+type Codec = MessageCodec<Frame<Request>, Frame<Response>>;
+
+/// A connection to a `KvsServer` with requests in flight.
+pub(super) struct Connection {
+    requests: FramedWrite<WriteHalf<TcpStream>, Codec>,
+    responses: FramedRead<ReadHalf<TcpStream>, Codec>,
+    next_id: u64,
+    // the exchanges in flight by request ID
+    exchanges: HashMap<u64, Pending>,
+    closed: bool,
+}
+
+/// The responses received for an exchange and the task waiting for them.
+#[derive(Default)]
+struct Pending {
+    responses: VecDeque<Response>,
+    task: Option<Task>,
+}
+
+impl Connection {
+    pub fn new(tcp: TcpStream) -> Connection {
+        let (read_half, write_half) = tcp.split();
+        Connection {
+            requests: FramedWrite::new(write_half, MessageCodec::new()),
+            responses: FramedRead::new(read_half, MessageCodec::new()),
+            next_id: 0,
+            exchanges: HashMap::new(),
+            closed: false,
+        }
+    }
+
+    /// Sends the request of an exchange in the transaction `txn`.
+    fn poll_send(
+        &mut self,
+        id: u64,
+        txn: Option<u64>,
+        request: &mut Option<Request>,
+    ) -> Poll<(), KvsError> {
+        if let Some(message) = request.take() {
+            let frame = Frame { id, txn, message };
+            if let AsyncSink::NotReady(frame) = self.requests.start_send(frame)? {
+                *request = Some(frame.message);
+                self.wait(id);
+                // the buffer is full, so flush it first
+                if self.requests.poll_complete()?.is_not_ready() {
+                    return Ok(Async::NotReady);
+                }
+                return self.poll_send(id, txn, request);
+            }
+        }
+        if self.requests.poll_complete()?.is_not_ready() {
+            self.wait(id);
+            return Ok(Async::NotReady);
+        }
+        Ok(Async::Ready(()))
+    }
+
+    /// Returns the next response of an exchange, reading the responses that have
+    /// arrived for other exchanges on the way.
+    fn poll_response(&mut self, id: u64) -> Poll<Option<Response>, KvsError> {
+        loop {
+            let pending = self.exchanges.entry(id).or_default();
+            if let Some(resp) = pending.responses.pop_front() {
+                return Ok(Async::Ready(Some(resp)));
+            }
+            if self.closed {
+                return Ok(Async::Ready(None));
+            }
+            match self.responses.poll() {
+                Ok(Async::Ready(Some(frame))) => {
+                    // responses to dropped exchanges are discarded
+                    if let Some(pending) = self.exchanges.get_mut(&frame.id) {
+                        pending.responses.push_back(frame.message);
+                        if let Some(task) = pending.task.take() {
+                            task.notify();
+                        }
+                    }
+                }
+                Ok(Async::Ready(None)) => self.closed = true,
+                Ok(Async::NotReady) => {
+                    self.wait(id);
+                    return Ok(Async::NotReady);
+                }
+                Err(e) => {
+                    self.closed = true;
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    fn wait(&mut self, id: u64) {
+        self.exchanges.entry(id).or_default().task = Some(task::current());
+    }
+
+    /// Wakes the exchanges waiting on the connection.
+    fn wake_all(&mut self) {
+        for pending in self.exchanges.values_mut() {
+            if let Some(task) = pending.task.take() {
+                task.notify();
+            }
+        }
+    }
+}
+
+/// The stream of responses to a request sent on a shared connection.
+///
+/// The request is sent when the stream is first polled.
+pub(super) struct Exchange {
+    conn: Arc<Mutex<Connection>>,
+    id: u64,
+    txn: Option<u64>,
+    request: Option<Request>,
+    sent: bool,
+}
+
+impl Exchange {
+    /// Creates the exchange of `request` in the transaction `txn`.
+    pub fn new(conn: Arc<Mutex<Connection>>, txn: Option<u64>, request: Request) -> Exchange {
+        let id = {
+            let mut conn = conn.lock().unwrap();
+            let id = conn.next_id;
+            conn.next_id += 1;
+            conn.exchanges.insert(id, Pending::default());
+            id
+        };
+        Exchange {
+            conn,
+            id,
+            txn,
+            request: Some(request),
+            sent: false,
+        }
+    }
+}
+
+impl Stream for Exchange {
+    type Item = Response;
+    type Error = KvsError;
+
+    fn poll(&mut self) -> Poll<Option<Response>, KvsError> {
+        let mut conn = self.conn.lock().unwrap();
+        if !self.sent {
+            if conn
+                .poll_send(self.id, self.txn, &mut self.request)?
+                .is_not_ready()
+            {
+                return Ok(Async::NotReady);
+            }
+            self.sent = true;
+            conn.wake_all();
+        }
+        let res = conn.poll_response(self.id);
+        if let Ok(Async::NotReady) = res {
+            return res;
+        }
+        conn.wake_all();
+        res
+    }
+}
+
+impl Drop for Exchange {
+    fn drop(&mut self) {
+        if let Ok(mut conn) = self.conn.lock() {
+            conn.exchanges.remove(&self.id);
+            conn.wake_all();
+        }
+    }
+}
@@ -0,0 +1,461 @@
+//! A pool of connections to a `KvsServer`.
+//!
+//! The pool keeps a fixed number of connection slots. A slot is emptied when a
+//! request on its connection fails or a health check does not get an answer, and a
+//! task reconnects it with exponential backoff. Requests use the connected slots in
+//! turn and wait for a connection if there is none.
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::prelude::future::Loop;
+use tokio::prelude::*;
+use tokio::runtime::{Runtime, TaskExecutor};
+use tokio::sync::oneshot;
+use tokio::timer::{Delay, Interval, Timeout};
+
+use super::KvPairs;
+use crate::{EngineStats, KvsClient, KvsError, Result, WriteBatch};
+
+/// Options of a `KvsClientPool`.
+///
+/// ```rust
+/// # use kvs::ClientPoolOptions;
+/// # use std::time::Duration;
+/// let options = ClientPoolOptions::new()
+///     .size(8)
+///     .request_timeout(Duration::from_secs(1))
+///     .backoff(Duration::from_millis(10), Duration::from_secs(1))
+///     .get_retries(3);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ClientPoolOptions {
+    size: usize,
+    request_timeout: Duration,
+    health_check_interval: Duration,
+    min_backoff: Duration,
+    max_backoff: Duration,
+    get_retries: u32,
+}
+
+impl Default for ClientPoolOptions {
+    fn default() -> ClientPoolOptions {
+        ClientPoolOptions {
+            size: 4,
+            request_timeout: Duration::from_secs(5),
+            health_check_interval: Duration::from_secs(1),
+            min_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(5),
+            get_retries: 2,
+        }
+    }
+}
+
+impl ClientPoolOptions {
+    /// Creates the default options.
+    pub fn new() -> ClientPoolOptions {
+        ClientPoolOptions::default()
+    }
+
+    /// Sets the number of connections of the pool.
+    ///
+    /// The default size is 4.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is 0.
+    pub fn size(mut self, size: usize) -> ClientPoolOptions {
+        assert!(size > 0, "the pool needs at least one connection");
+        self.size = size;
+        self
+    }
+
+    /// Sets how long a request waits for a connection, and then for its
+    /// response, before it fails.
+    ///
+    /// The default timeout is 5 seconds.
+    pub fn request_timeout(mut self, request_timeout: Duration) -> ClientPoolOptions {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    /// Sets how often the connections are checked.
+    ///
+    /// The default interval is 1 second.
+    pub fn health_check_interval(mut self, health_check_interval: Duration) -> ClientPoolOptions {
+        self.health_check_interval = health_check_interval;
+        self
+    }
+
+    /// Sets the delay before the second attempt to reconnect a failed connection,
+    /// which doubles after every failed attempt up to `max`.
+    ///
+    /// The default delays are 50 milliseconds and 5 seconds.
+    pub fn backoff(mut self, min: Duration, max: Duration) -> ClientPoolOptions {
+        self.min_backoff = min;
+        self.max_backoff = max;
+        self
+    }
+
+    /// Sets how many times `get`, `multi_get` and scans are retried after a connection failure
+    /// or a timeout.
+    ///
+    /// The default is 2 retries.
+    pub fn get_retries(mut self, get_retries: u32) -> ClientPoolOptions {
+        self.get_retries = get_retries;
+        self
+    }
+}
+
+/// A pool of connections to a `KvsServer`.
+///
+/// Requests are spread over the connections of the pool, which are checked
+/// periodically and reconnected with exponential backoff when they fail. Reads
+/// are safe to repeat, so they are retried after a connection failure or a
+/// timeout. Writes fail, because they may have been applied.
+///
+/// The pool runs its connections on its own runtime, so its futures can be run by
+/// any executor or waited for in blocking code.
+///
+/// ```no_run
+/// # use kvs::{ClientPoolOptions, KvsClientPool, Result};
+/// # use tokio::prelude::*;
+/// # fn main() -> Result<()> {
+/// let addr = "127.0.0.1:4000".parse().unwrap();
+/// let pool = KvsClientPool::new(addr, ClientPoolOptions::new().size(2))?;
+/// pool.set(b"key".to_vec(), b"value".to_vec()).wait()?;
+/// assert_eq!(pool.get(b"key".to_vec()).wait()?, Some(b"value".to_vec()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct KvsClientPool {
+    shared: Arc<Shared>,
+    executor: TaskExecutor,
+    // shut down when the last clone of the pool is dropped
+    _runtime: Arc<Runtime>,
+}
+
+struct Shared {
+    addr: SocketAddr,
+    options: ClientPoolOptions,
+    state: Mutex<PoolState>,
+    // the slot the next request starts looking for a connection at
+    next: AtomicUsize,
+}
+
+struct PoolState {
+    // `None` while the slot is reconnecting
+    slots: Vec<Option<KvsClient>>,
+    // the requests waiting for a connection
+    waiters: Vec<oneshot::Sender<()>>,
+}
+
+impl KvsClientPool {
+    /// Creates a pool of connections to the server at `addr`.
+    ///
+    /// The connections are opened in the background, and the first requests wait
+    /// for them.
+    pub fn new(addr: SocketAddr, options: ClientPoolOptions) -> Result<KvsClientPool> {
+        let runtime = Runtime::new()?;
+        let executor = runtime.executor();
+        let shared = Arc::new(Shared {
+            addr,
+            state: Mutex::new(PoolState {
+                slots: vec![None; options.size],
+                waiters: Vec::new(),
+            }),
+            options,
+            next: AtomicUsize::new(0),
+        });
+        for index in 0..shared.options.size {
+            let shared = Arc::clone(&shared);
+            executor.spawn(future::lazy(move || Shared::connect(shared, index)));
+        }
+        let health_shared = Arc::clone(&shared);
+        executor.spawn(future::lazy(move || Shared::check_health(health_shared)));
+        Ok(KvsClientPool {
+            shared,
+            executor,
+            _runtime: Arc::new(runtime),
+        })
+    }
+
+    /// Get the value of a given key from the server.
+    pub fn get(&self, key: Vec<u8>) -> impl Future<Item = Option<Vec<u8>>, Error = KvsError> {
+        self.request(self.shared.options.get_retries, move |client| {
+            client.get(key.clone()).map(|(value, _)| value)
+        })
+    }
+
+    /// Set the value of a key in the server.
+    pub fn set(&self, key: Vec<u8>, value: Vec<u8>) -> impl Future<Item = (), Error = KvsError> {
+        self.request(0, move |client| {
+            client.set(key.clone(), value.clone()).map(|_| ())
+        })
+    }
+
+    /// Set the value of a key that expires after `ttl` in the server.
+    pub fn set_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> impl Future<Item = (), Error = KvsError> {
+        self.request(0, move |client| {
+            client
+                .set_with_ttl(key.clone(), value.clone(), ttl)
+                .map(|_| ())
+        })
+    }
+
+    /// Remove a key in the server.
+    pub fn remove(&self, key: Vec<u8>) -> impl Future<Item = (), Error = KvsError> {
+        self.request(0, move |client| client.remove(key.clone()).map(|_| ()))
+    }
+
+    /// Set the value of a key to `new` in the server if its current value is
+    /// `expected`, and return whether the value was swapped.
+    pub fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> impl Future<Item = bool, Error = KvsError> {
+        self.request(0, move |client| {
+            client
+                .compare_and_swap(key.clone(), expected.clone(), new.clone())
+                .map(|(swapped, _)| swapped)
+        })
+    }
+
+    /// Set the value of a key in the server if it does not exist, and return
+    /// whether the value was set.
+    pub fn set_if_absent(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> impl Future<Item = bool, Error = KvsError> {
+        self.request(0, move |client| {
+            client
+                .set_if_absent(key.clone(), value.clone())
+                .map(|(set, _)| set)
+        })
+    }
+
+    /// Get the values of many keys from the server in one request.
+    pub fn multi_get(
+        &self,
+        keys: Vec<Vec<u8>>,
+    ) -> impl Future<Item = Vec<Option<Vec<u8>>>, Error = KvsError> {
+        self.request(self.shared.options.get_retries, move |client| {
+            client.multi_get(keys.clone()).map(|(values, _)| values)
+        })
+    }
+
+    /// Set the values of many keys in the server in one request.
+    pub fn multi_set(
+        &self,
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> impl Future<Item = (), Error = KvsError> {
+        self.request(0, move |client| client.multi_set(pairs.clone()).map(|_| ()))
+    }
+
+    /// Apply all writes in a batch atomically in the server.
+    pub fn write_batch(&self, batch: WriteBatch) -> impl Future<Item = (), Error = KvsError> {
+        self.request(0, move |client| {
+            client.write_batch(batch.clone()).map(|_| ())
+        })
+    }
+
+    /// Get the key/value pairs with keys in the range `[start, end)` from the server.
+    ///
+    /// The range is unbounded above if `end` is `None`.
+    pub fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> impl Future<Item = KvPairs, Error = KvsError> {
+        self.request(self.shared.options.get_retries, move |client| {
+            client
+                .scan(start.clone(), end.clone(), limit)
+                .map(|(pairs, _)| pairs)
+        })
+    }
+
+    /// Get the key/value pairs with keys starting with `prefix` from the server.
+    pub fn scan_prefix(
+        &self,
+        prefix: Vec<u8>,
+        limit: Option<usize>,
+    ) -> impl Future<Item = KvPairs, Error = KvsError> {
+        self.request(self.shared.options.get_retries, move |client| {
+            client
+                .scan_prefix(prefix.clone(), limit)
+                .map(|(pairs, _)| pairs)
+        })
+    }
+
+    /// Get the counters of the storage engine of the server.
+    pub fn stats(&self) -> impl Future<Item = EngineStats, Error = KvsError> {
+        self.request(0, |client| client.stats().map(|(stats, _)| stats))
+    }
+
+    /// Runs a request on a connection of the pool, and retries it on another
+    /// connection up to `retries` times after a connection failure or a timeout.
+    fn request<T, F, R>(&self, retries: u32, request: F) -> impl Future<Item = T, Error = KvsError>
+    where
+        F: Fn(KvsClient) -> R + Send + Sync + 'static,
+        R: Future<Item = T, Error = KvsError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let shared = Arc::clone(&self.shared);
+        let request = Arc::new(request);
+        let attempts = future::lazy(move || {
+            future::loop_fn(0, move |attempt| {
+                let shared = Arc::clone(&shared);
+                let request = Arc::clone(&request);
+                let timeout = shared.options.request_timeout;
+                Timeout::new(Shared::client(&shared), timeout)
+                    .map_err(|e| {
+                        e.into_inner().unwrap_or_else(|| {
+                            KvsError::StringError("No connection to the server".to_owned())
+                        })
+                    })
+                    .and_then(move |(index, client)| {
+                        Timeout::new(request(client.clone()), timeout).then(move |res| {
+                            let err = match res.map_err(|e| e.into_inner()) {
+                                Ok(value) => return Ok(Loop::Break(value)),
+                                Err(Some(err @ KvsError::Io(_)))
+                                | Err(Some(err @ KvsError::Disconnected)) => {
+                                    Shared::reconnect(&shared, index, &client);
+                                    err
+                                }
+                                Err(Some(err)) => return Err(err),
+                                Err(None) => KvsError::StringError("Request timed out".to_owned()),
+                            };
+                            if attempt < retries {
+                                Ok(Loop::Continue(attempt + 1))
+                            } else {
+                                Err(err)
+                            }
+                        })
+                    })
+            })
+        });
+        let (tx, rx) = oneshot::channel();
+        self.executor.spawn(attempts.then(move |res| {
+            if tx.send(res).is_err() {
+                debug!("Receiving end is dropped");
+            }
+            Ok(())
+        }));
+        rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+            .flatten()
+    }
+}
+
+impl Shared {
+    /// Returns a connected client and its slot, waiting for a connection if there
+    /// is none.
+    fn client(
+        shared: &Arc<Shared>,
+    ) -> Box<dyn Future<Item = (usize, KvsClient), Error = KvsError> + Send> {
+        let mut state = shared.state.lock().unwrap();
+        let size = state.slots.len();
+        let start = shared.next.fetch_add(1, Ordering::Relaxed);
+        for i in 0..size {
+            let index = (start + i) % size;
+            if let Some(client) = &state.slots[index] {
+                return Box::new(future::ok((index, client.clone())));
+            }
+        }
+        let (tx, rx) = oneshot::channel();
+        state.waiters.push(tx);
+        let shared = Arc::clone(shared);
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .and_then(move |()| Shared::client(&shared)),
+        )
+    }
+
+    /// Drops the failed connection of a slot and reconnects it, unless the slot has
+    /// been reconnected already.
+    fn reconnect(shared: &Arc<Shared>, index: usize, failed: &KvsClient) {
+        {
+            let mut state = shared.state.lock().unwrap();
+            match &state.slots[index] {
+                Some(client) if client.same_connection(failed) => state.slots[index] = None,
+                _ => return,
+            }
+        }
+        warn!("Lost a connection to {}", shared.addr);
+        tokio::spawn(Shared::connect(Arc::clone(shared), index));
+    }
+
+    /// Connects a slot, retrying with exponential backoff.
+    fn connect(shared: Arc<Shared>, index: usize) -> impl Future<Item = (), Error = ()> {
+        // the first attempt is made right away
+        future::loop_fn(None, move |backoff: Option<Duration>| {
+            let shared = Arc::clone(&shared);
+            let delay = Delay::new(Instant::now() + backoff.unwrap_or_default());
+            delay.then(move |_| {
+                let connect = KvsClient::connect(shared.addr);
+                Timeout::new(connect, shared.options.request_timeout).then(move |res| match res {
+                    Ok(client) => {
+                        let mut state = shared.state.lock().unwrap();
+                        state.slots[index] = Some(client);
+                        for waiter in state.waiters.drain(..) {
+                            let _ = waiter.send(());
+                        }
+                        Ok(Loop::Break(()))
+                    }
+                    Err(e) => {
+                        let options = &shared.options;
+                        let backoff = match backoff {
+                            Some(backoff) => (backoff * 2).min(options.max_backoff),
+                            None => options.min_backoff,
+                        };
+                        debug!(
+                            "Failed to connect to {}: {:?}, retrying in {:?}",
+                            shared.addr, e, backoff
+                        );
+                        Ok(Loop::Continue(Some(backoff)))
+                    }
+                })
+            })
+        })
+    }
+
+    /// Checks the connected slots periodically, and reconnects the ones that do not
+    /// answer in time.
+    fn check_health(shared: Arc<Shared>) -> impl Future<Item = (), Error = ()> {
+        Interval::new_interval(shared.options.health_check_interval)
+            .map_err(|e| error!("Timer error: {}", e))
+            .for_each(move |_| {
+                let clients: Vec<(usize, KvsClient)> = {
+                    let state = shared.state.lock().unwrap();
+                    let slots = state.slots.iter().enumerate();
+                    slots
+                        .filter_map(|(index, client)| Some((index, client.clone()?)))
+                        .collect()
+                };
+                for (index, client) in clients {
+                    let shared = Arc::clone(&shared);
+                    // any answer shows the connection is alive
+                    let check =
+                        Timeout::new(client.clone().stats(), shared.options.request_timeout);
+                    tokio::spawn(check.then(move |res| {
+                        if res.is_err() {
+                            Shared::reconnect(&shared, index, &client);
+                        }
+                        Ok(())
+                    }));
+                }
+                Ok(())
+            })
+    }
+}
@@ -1,16 +1,159 @@
+use crate::{EngineStats, KvsError, WatchEvent, WatchSeq, WriteBatch};
+use bytes::BytesMut;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::marker::PhantomData;
+use std::time::Duration;
+use tokio::codec::{Decoder, Encoder, LengthDelimitedCodec};
+
+/// The maximum size of a request or response frame
+const MAX_FRAME_LENGTH: usize = 64 * 1024 * 1024;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
-    Get { key: String },
-    Set { key: String, value: String },
-    Remove { key: String },
+    Get {
+        key: Vec<u8>,
+    },
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    SetWithTtl {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    CompareAndSwap {
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    },
+    SetIfAbsent {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    MultiGet {
+        keys: Vec<Vec<u8>>,
+    },
+    /// Sets the pairs independently, so a failed request may have set some of them
+    MultiSet {
+        pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    },
+    Scan {
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    },
+    ScanPrefix {
+        prefix: Vec<u8>,
+        limit: Option<usize>,
+    },
+    WriteBatch {
+        batch: WriteBatch,
+    },
+    Backup {
+        name: String,
+    },
+    Stats,
+    Begin,
+    Commit,
+    Abort,
+    /// Streams the changes of the keys starting with `prefix` until the connection
+    /// is closed
+    Watch {
+        prefix: Vec<u8>,
+        from: Option<WatchSeq>,
+    },
+    /// Streams a copy of all pairs followed by the later changes until the
+    /// connection is closed
+    Replicate,
+    Promote,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Response {
-    Get(Option<String>),
+    Get(Option<Vec<u8>>),
     Set,
     Remove,
+    CompareAndSwap(bool),
+    SetIfAbsent(bool),
+    /// The values in the order of the keys
+    MultiGet(Vec<Option<Vec<u8>>>),
+    MultiSet,
+    /// A chunk of the pairs of a scan, followed by `ScanEnd` after the last chunk
+    Scan(Vec<(Vec<u8>, Vec<u8>)>),
+    ScanEnd,
+    WriteBatch,
+    Backup,
+    Stats(EngineStats),
+    /// The ID of the transaction
+    Begin(u64),
+    Commit(bool),
+    Abort,
+    /// The watch is registered after the change at the position
+    Watch(WatchSeq),
+    Change(WatchEvent),
+    /// A chunk of the pairs copied for a replica, followed by `Watch` after the
+    /// last chunk
+    Copy(Vec<WatchEvent>),
+    Promote,
     Err(String),
 }
+
+/// A request or response tagged with the ID of the request.
+///
+/// A client can send many requests on a connection without waiting, and the
+/// server answers them in any order. All responses to a request carry its ID.
+///
+/// Many transactions can run on a connection, so a request names the transaction
+/// it runs in by the ID the server returned for `Request::Begin`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Frame<T> {
+    pub id: u64,
+    // `None` outside of transactions and in responses
+    pub txn: Option<u64>,
+    pub message: T,
+}
+
+/// Encodes messages of type `E` and decodes messages of type `D` as
+/// length-delimited bincode frames, so keys and values are sent as raw bytes.
+pub struct MessageCodec<E, D> {
+    frames: LengthDelimitedCodec,
+    _marker: PhantomData<fn(E) -> D>,
+}
+
+impl<E, D> MessageCodec<E, D> {
+    pub fn new() -> MessageCodec<E, D> {
+        let mut frames = LengthDelimitedCodec::new();
+        frames.set_max_frame_length(MAX_FRAME_LENGTH);
+        MessageCodec {
+            frames,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<E: Serialize, D> Encoder for MessageCodec<E, D> {
+    type Item = E;
+    type Error = KvsError;
+
+    fn encode(&mut self, msg: E, dst: &mut BytesMut) -> Result<(), KvsError> {
+        let frame = bincode::serialize(&msg)?;
+        Ok(self.frames.encode(frame.into(), dst)?)
+    }
+}
+
+impl<E, D: DeserializeOwned> Decoder for MessageCodec<E, D> {
+    type Item = D;
+    type Error = KvsError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<D>, KvsError> {
+        match self.frames.decode(src)? {
+            Some(frame) => Ok(Some(bincode::deserialize(&frame)?)),
+            None => Ok(None),
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use serde::{Deserialize, Serialize};
+
+/// A group of writes that are applied atomically by `KvsEngine::write_batch`.
+///
+/// Either all or none of the writes in a batch survive a crash. Removing a key
+/// that does not exist is not an error in a batch.
+///
+/// ```rust
+/// # use kvs::WriteBatch;
+/// let mut batch = WriteBatch::new();
+/// batch.set(b"key1".to_vec(), b"value1".to_vec());
+/// batch.remove(b"key2".to_vec());
+/// assert_eq!(batch.len(), 2);
+/// ```
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+/// A single write in a `WriteBatch`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum BatchOp {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    Remove { key: Vec<u8> },
+}
+
+impl WriteBatch {
+    /// Creates an empty `WriteBatch`.
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// Sets the value of a key when the batch is applied.
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.ops.push(BatchOp::Set { key, value });
+    }
+
+    /// Removes a given key when the batch is applied.
+    pub fn remove(&mut self, key: Vec<u8>) {
+        self.ops.push(BatchOp::Remove { key });
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns `true` if the batch contains no writes.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Returns the keys written by the batch.
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &[u8]> {
+        self.ops.iter().map(|op| match op {
+            BatchOp::Set { key, .. } | BatchOp::Remove { key } => key.as_slice(),
+        })
+    }
+
+    pub(crate) fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
@@ -3,29 +3,71 @@ use std::collections::BTreeMap;
 use std::ffi::OsStr;
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::ops::Range;
+use std::mem;
+use std::ops::{Bound, Range};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
 
 use crossbeam::queue::ArrayQueue;
 use crossbeam_skiplist::SkipMap;
-use serde::{Deserialize, Serialize};
-use serde_json::Deserializer;
 use tokio::prelude::*;
 use tokio::sync::oneshot;
 
-use super::KvsEngine;
+use self::cache::ValueCache;
+pub use self::compression::Compression;
+use self::disk_index::{DiskIndex, IndexFile};
+use self::format::{Command, LogFormat, ValueCompression};
+use self::hint::HintEntry;
+pub use self::index::IndexBackend;
+use self::index::{KeyIndex, KeyRange, MemoryIndex};
+use self::snapshot::History;
+pub use self::snapshot::Snapshot;
+use self::transaction::ReadSet;
+pub use self::transaction::Transaction;
+use self::watch::{CatchUp, WatchReceiver, Watchers};
+use super::{
+    expiry_time, install_file, now_millis, scan_in_batches, CopyStream, EngineStats, KvsEngine,
+    ScanBatch, SyncPolicy, WatchSeq, WatchStream, WriteBatch,
+};
 use crate::thread_pool::ThreadPool;
 use crate::{KvsError, Result};
 
+mod cache;
+mod compression;
+mod disk_index;
+mod format;
+mod hint;
+mod index;
+mod snapshot;
+mod transaction;
+mod watch;
+
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+const COMPRESSION_THRESHOLD: usize = 512;
+const INDEX_MEMORY_LIMIT: usize = 1_000_000;
 
-/// The `KvStore` stores string key/value pairs.
+/// The `KvStore` stores key/value pairs of arbitrary bytes.
 ///
 /// Key/value pairs are persisted to disk in log files. Log files are named after
 /// monotonically increasing generation numbers with a `log` extension name.
-/// A skip list in memory stores the keys and the value locations for fast query.
+/// Each command is stored as a checksummed binary record, and a corrupted tail of
+/// a log file is truncated when the store is opened. Log files of concatenated
+/// JSON commands written by older versions are converted on open.
+///
+/// A compaction also writes a hint file listing the record locations of the
+/// compacted log, or an index file with `IndexBackend::Disk`, so opening the
+/// store does not have to replay it.
+/// A skip list in memory stores the keys and the value locations for fast query,
+/// or the keys can be kept in sorted index files, see
+/// `KvStoreOptions::index_backend`.
+/// Recently read values can be kept in an LRU cache, see
+/// `KvStoreOptions::cache_capacity`, and values can be compressed in the log, see
+/// `KvStoreOptions::compression`.
+/// Changes can be watched as they are written, and a watcher can resume from the
+/// log files that have not been compacted yet, see `KvsEngine::watch`.
 ///
 /// ```rust
 /// # use kvs::{KvStore, Result};
@@ -35,9 +77,9 @@ const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 /// use std::env::current_dir;
 /// use kvs::KvsEngine;
 /// let mut store: KvStore<RayonThreadPool> = KvStore::open(current_dir()?, 2)?;
-/// store.set("key".to_owned(), "value".to_owned()).wait()?;
-/// let val = store.get("key".to_owned()).wait()?;
-/// assert_eq!(val, Some("value".to_owned()));
+/// store.set(b"key".to_vec(), b"value".to_vec()).wait()?;
+/// let val = store.get(b"key".to_vec()).wait()?;
+/// assert_eq!(val, Some(b"value".to_vec()));
 /// # Ok(())
 /// # }
 /// ```
@@ -46,12 +88,134 @@ pub struct KvStore<P: ThreadPool> {
     // directory for the log and other data
     path: Arc<PathBuf>,
     // map generation number to the file reader
-    index: Arc<SkipMap<String, CommandPos>>,
+    index: Arc<dyn KeyIndex>,
+    // index entries replaced while snapshots are alive
+    history: Arc<History>,
+    cache: Arc<ValueCache>,
     writer: Arc<Mutex<KvStoreWriter>>,
+    syncer: Arc<LogSyncer>,
+    // waits for the background compaction when the last handle is dropped
+    _compaction: Arc<CompactionGuard>,
     thread_pool: P,
     reader_pool: Arc<ArrayQueue<KvStoreReader>>,
 }
 
+/// Options for opening a `KvStore`.
+///
+/// ```rust
+/// # use kvs::{Compression, KvStoreOptions, SyncPolicy};
+/// let options = KvStoreOptions::new()
+///     .sync_policy(SyncPolicy::GroupCommit)
+///     .compaction_threshold(64 * 1024 * 1024)
+///     .garbage_ratio(0.5)
+///     .cache_capacity(16 * 1024 * 1024)
+///     .compression(Compression::Lz4);
+/// ```
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    sync_policy: SyncPolicy,
+    compaction_threshold: u64,
+    garbage_ratio: Option<f64>,
+    cache_capacity: u64,
+    compression: ValueCompression,
+    index_backend: IndexBackend,
+    index_memory_limit: usize,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> KvStoreOptions {
+        KvStoreOptions {
+            sync_policy: SyncPolicy::default(),
+            compaction_threshold: COMPACTION_THRESHOLD,
+            garbage_ratio: None,
+            cache_capacity: 0,
+            compression: ValueCompression {
+                compression: Compression::None,
+                threshold: COMPRESSION_THRESHOLD,
+            },
+            index_backend: IndexBackend::Memory,
+            index_memory_limit: INDEX_MEMORY_LIMIT,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Creates the default options.
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions::default()
+    }
+
+    /// Sets when writes are synced to the disk.
+    ///
+    /// The default policy is `SyncPolicy::Never`.
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> KvStoreOptions {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// Sets how many bytes of stale commands the log may contain before a
+    /// compaction starts.
+    ///
+    /// The default threshold is 1 MiB.
+    pub fn compaction_threshold(mut self, compaction_threshold: u64) -> KvStoreOptions {
+        self.compaction_threshold = compaction_threshold;
+        self
+    }
+
+    /// Requires stale commands to also make up at least `garbage_ratio` of the
+    /// total log size before a compaction starts.
+    ///
+    /// By default only the compaction threshold is checked.
+    pub fn garbage_ratio(mut self, garbage_ratio: f64) -> KvStoreOptions {
+        self.garbage_ratio = Some(garbage_ratio);
+        self
+    }
+
+    /// Caches values read by `get` in an LRU cache of at most `cache_capacity`
+    /// bytes of keys and values.
+    ///
+    /// The cache is disabled by default.
+    pub fn cache_capacity(mut self, cache_capacity: u64) -> KvStoreOptions {
+        self.cache_capacity = cache_capacity;
+        self
+    }
+
+    /// Sets how values written to the log are compressed.
+    ///
+    /// Values are stored raw by default. Records written with other settings can
+    /// still be read.
+    pub fn compression(mut self, compression: Compression) -> KvStoreOptions {
+        self.compression.compression = compression;
+        self
+    }
+
+    /// Sets the size in bytes below which values are stored raw even if
+    /// compression is enabled.
+    ///
+    /// The default threshold is 512 bytes.
+    pub fn compression_threshold(mut self, compression_threshold: usize) -> KvStoreOptions {
+        self.compression.threshold = compression_threshold;
+        self
+    }
+
+    /// Sets where the index of the keys is kept.
+    ///
+    /// The index is kept in memory by default.
+    pub fn index_backend(mut self, index_backend: IndexBackend) -> KvStoreOptions {
+        self.index_backend = index_backend;
+        self
+    }
+
+    /// Sets how many changed keys `IndexBackend::Disk` keeps in memory before a
+    /// compaction rebuilds its index file.
+    ///
+    /// The default limit is 1,000,000 keys.
+    pub fn index_memory_limit(mut self, index_memory_limit: usize) -> KvStoreOptions {
+        self.index_memory_limit = index_memory_limit;
+        self
+    }
+}
+
 impl<P: ThreadPool> KvStore<P> {
     /// Opens a `KvStore` with the given path.
     ///
@@ -63,24 +227,74 @@ impl<P: ThreadPool> KvStore<P> {
     ///
     /// It propagates I/O or deserialization errors during the log replay.
     pub fn open(path: impl Into<PathBuf>, concurrency: u32) -> Result<Self> {
+        KvStore::open_with_options(path, concurrency, KvStoreOptions::default())
+    }
+
+    /// Opens a `KvStore` with the given path and options.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during the log replay.
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        concurrency: u32,
+        options: KvStoreOptions,
+    ) -> Result<Self> {
         let path = Arc::new(path.into());
         fs::create_dir_all(&*path)?;
 
         let mut readers = BTreeMap::new();
-        let index = Arc::new(SkipMap::new());
+        let history = Arc::new(SkipMap::new());
+        let cache = Arc::new(ValueCache::new(options.cache_capacity));
 
+        // compaction files left by interrupted compactions are incomplete, and the
+        // log files they were copied from are still there
+        remove_partial_logs(&path)?;
         let gen_list = sorted_gen_list(&path)?;
+        for &gen in &gen_list {
+            let file_path = log_path(&path, gen);
+            if format::read_header(&mut File::open(&file_path)?)? == LogFormat::Legacy {
+                migrate_legacy_log(&path, gen)?;
+            }
+        }
+
+        // the log files covered by an index file are not loaded
+        let (index, indexed_gen): (Arc<dyn KeyIndex>, u64) = match options.index_backend {
+            IndexBackend::Memory => (Arc::new(MemoryIndex::new()), 0),
+            IndexBackend::Disk => {
+                let index = DiskIndex::open(&path, &gen_list, options.index_memory_limit)?;
+                let indexed_gen = index.file_gen();
+                (Arc::new(index), indexed_gen)
+            }
+        };
         let mut uncompacted = 0;
+        let mut archived = 0;
+        let mut expiring = ExpiryTracker::new(now_millis());
 
         for &gen in &gen_list {
-            let mut reader = BufReaderWithPos::new(File::open(log_path(&path, gen))?)?;
-            uncompacted += load(gen, &mut reader, &*index)?;
+            let file_path = log_path(&path, gen);
+            let log_len = fs::metadata(&file_path)?.len();
+            if gen <= indexed_gen {
+                archived += log_len;
+                continue;
+            }
+            let mut reader = BufReaderWithPos::new(File::open(&file_path)?)?;
+            uncompacted += match hint::read(&hint_path(&path, gen), log_len)? {
+                Some(entries) => load_hint(gen, entries, &*index, &mut expiring)?,
+                None => {
+                    let is_newest = Some(&gen) == gen_list.last();
+                    load(&path, gen, is_newest, &mut reader, &*index, &mut expiring)?
+                }
+            };
+            archived += fs::metadata(&file_path)?.len();
             readers.insert(gen, reader);
         }
 
         let current_gen = gen_list.last().unwrap_or(&0) + 1;
         let writer = new_log_file(&path, current_gen)?;
+        let syncer = LogSyncer::new(options.sync_policy, writer.get_ref().try_clone()?)?;
         let safe_point = Arc::new(AtomicU64::new(0));
+        let compaction = Arc::new(CompactionStatus::default());
 
         let reader = KvStoreReader {
             path: Arc::clone(&path),
@@ -93,8 +307,19 @@ impl<P: ThreadPool> KvStore<P> {
             writer,
             current_gen,
             uncompacted,
+            archived,
+            expiring,
+            seq: 0,
+            syncer: Arc::clone(&syncer),
+            compaction: Arc::clone(&compaction),
+            options,
             path: Arc::clone(&path),
             index: Arc::clone(&index),
+            history: Arc::clone(&history),
+            cache: Arc::clone(&cache),
+            snapshots: BTreeMap::new(),
+            stale_gen: None,
+            watchers: Watchers::default(),
         };
 
         let thread_pool = P::new(concurrency)?;
@@ -107,26 +332,215 @@ impl<P: ThreadPool> KvStore<P> {
         Ok(KvStore {
             path,
             index,
+            history,
+            cache,
             writer: Arc::new(Mutex::new(writer)),
+            syncer,
+            _compaction: Arc::new(CompactionGuard(compaction)),
             thread_pool,
             reader_pool,
         })
     }
-}
 
-impl<P: ThreadPool> KvsEngine for KvStore<P> {
-    /// Sets the value of a string key to a string.
+    /// Installs a backup written by `backup_to` into the data directory `path`.
     ///
-    /// If the key already exists, the previous value will be overwritten.
+    /// Every record of the backup is checked before any file is copied. The log
+    /// files are copied under temporary names and renamed after they are synced,
+    /// so the store is only restored once the whole backup is in place.
     ///
     /// # Errors
     ///
-    /// It propagates I/O or serialization errors during writing the log.
-    fn set(&self, key: String, value: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+    /// It returns `KvsError::CorruptedLog` if the backup is damaged and
+    /// `KvsError::StringError` if `path` already contains log files.
+    pub fn restore(backup: impl AsRef<Path>, path: impl Into<PathBuf>) -> Result<()> {
+        let backup = backup.as_ref();
+        let path = path.into();
+        let gen_list = sorted_gen_list(backup)?;
+        if gen_list.is_empty() {
+            return Err(KvsError::StringError(format!(
+                "{} contains no log files",
+                backup.display()
+            )));
+        }
+        for &gen in &gen_list {
+            verify_backup_log(backup, gen)?;
+        }
+
+        fs::create_dir_all(&path)?;
+        if !sorted_gen_list(&path)?.is_empty() {
+            return Err(KvsError::StringError(format!(
+                "{} already contains log files",
+                path.display()
+            )));
+        }
+        // a log file is renamed after its hint file because a hint file is
+        // ignored without its log
+        for &gen in &gen_list {
+            let hint_file = hint_path(backup, gen);
+            if hint_file.exists() {
+                install_file(&hint_file, &hint_path(&path, gen))?;
+            }
+            install_file(&log_path(backup, gen), &log_path(&path, gen))?;
+        }
+        info!("Restored {} from {}", path.display(), backup.display());
+        Ok(())
+    }
+
+    /// Returns how many times the active log has been synced to the disk since the
+    /// store was opened.
+    pub fn sync_count(&self) -> u64 {
+        self.syncer.sync_count.load(Ordering::SeqCst)
+    }
+
+    /// Creates a read-only snapshot of the store.
+    ///
+    /// Reads through the snapshot see the store as it was after the last write
+    /// before the snapshot. Compaction keeps the log files the snapshot may read
+    /// until all snapshots are dropped.
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, KvsEngine, Result};
+    /// # use kvs::thread_pool::{ThreadPool, RayonThreadPool};
+    /// # use tokio::prelude::*;
+    /// # fn try_main() -> Result<()> {
+    /// use std::env::current_dir;
+    /// let store: KvStore<RayonThreadPool> = KvStore::open(current_dir()?, 2)?;
+    /// store.set(b"key".to_vec(), b"old".to_vec()).wait()?;
+    /// let snapshot = store.snapshot();
+    /// store.set(b"key".to_vec(), b"new".to_vec()).wait()?;
+    /// assert_eq!(snapshot.get(b"key".to_vec()).wait()?, Some(b"old".to_vec()));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn snapshot(&self) -> Snapshot<P> {
+        let seq = self.writer.lock().unwrap().register_snapshot();
+        Snapshot::new(self.clone(), seq)
+    }
+
+    /// Runs `f` in an optimistic transaction and commits its writes atomically.
+    ///
+    /// Reads in the transaction see the store as of a snapshot taken when `f` starts,
+    /// without locking. Writes are buffered in the transaction and see the reads of
+    /// the same transaction. At commit, the index entries of the keys read in the
+    /// snapshot are validated against the live ones under the writer lock, and the
+    /// writes are appended as a single batch. If another write has changed any of
+    /// the keys read since the snapshot, `f` is run again.
+    ///
+    /// An error returned by `f` aborts the transaction.
+    ///
+    /// ```rust
+    /// # use kvs::{KvStore, Result};
+    /// # use kvs::thread_pool::{ThreadPool, RayonThreadPool};
+    /// # use tokio::prelude::*;
+    /// # fn try_main() -> Result<()> {
+    /// use std::env::current_dir;
+    /// let store: KvStore<RayonThreadPool> = KvStore::open(current_dir()?, 2)?;
+    /// store
+    ///     .transaction(|txn| {
+    ///         let value = txn.get(b"from".to_vec())?.unwrap_or_default();
+    ///         txn.set(b"to".to_vec(), value);
+    ///         txn.remove(b"from".to_vec())
+    ///     })
+    ///     .wait()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn transaction<F, T>(&self, f: F) -> Box<dyn Future<Item = T, Error = KvsError> + Send>
+    where
+        F: Fn(&mut Transaction) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let store = self.clone();
+        let (tx, rx) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            let reader = store.reader_pool.pop().unwrap();
+            let res = loop {
+                // the reads of an attempt are pinned to a snapshot, so they all see
+                // the store at the same point
+                let snapshot = store.snapshot();
+                let mut txn =
+                    Transaction::new(&*store.index, &store.history, snapshot.seq(), &reader);
+                let output = match f(&mut txn) {
+                    Ok(output) => output,
+                    Err(e) => break Err(e),
+                };
+                let (reads, batch) = txn.into_parts();
+                drop(snapshot);
+                match run_write(
+                    &store.writer,
+                    &store.syncer,
+                    &store.thread_pool,
+                    move |writer| writer.commit(reads, batch),
+                ) {
+                    Ok(true) => break Ok(output),
+                    Ok(false) => debug!("Retrying the conflicting transaction"),
+                    Err(e) => break Err(e),
+                }
+            };
+            store.reader_pool.push(reader).unwrap();
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    /// Runs a write with the `KvStoreWriter` in the thread pool and waits until the
+    /// write is synced as required by the `SyncPolicy`.
+    ///
+    /// If the write leaves enough stale commands in the log, a compaction is
+    /// started in the thread pool.
+    fn write<F>(&self, write: F) -> Box<dyn Future<Item = (), Error = KvsError> + Send>
+    where
+        F: FnOnce(&mut KvStoreWriter) -> Result<u64> + Send + 'static,
+    {
+        self.write_with(move |writer| write(writer).map(|seq| ((), seq)))
+    }
+
+    /// Like `write`, but the write also returns an output besides the sequence
+    /// number, which is yielded after the write is synced.
+    fn write_with<F, T>(&self, write: F) -> Box<dyn Future<Item = T, Error = KvsError> + Send>
+    where
+        F: FnOnce(&mut KvStoreWriter) -> Result<(T, u64)> + Send + 'static,
+        T: Send + 'static,
+    {
         let writer = self.writer.clone();
+        let syncer = self.syncer.clone();
+        let thread_pool = self.thread_pool.clone();
+        let (tx, rx) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            let res = run_write(&writer, &syncer, &thread_pool, write);
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    /// Reads the values of the index entries chosen by `select` in the thread pool
+    /// and yields them as key/value pairs.
+    fn read_entries<F>(&self, select: F) -> ScanBatch
+    where
+        F: FnOnce(&dyn KeyIndex) -> Result<Vec<(Vec<u8>, CommandPos)>> + Send + 'static,
+    {
+        let reader_pool = self.reader_pool.clone();
+        let index = self.index.clone();
         let (tx, rx) = oneshot::channel();
         self.thread_pool.spawn(move || {
-            let res = writer.lock().unwrap().set(key, value);
+            let reader = reader_pool.pop().unwrap();
+            let res = select(&*index).and_then(|entries| {
+                entries
+                    .into_iter()
+                    .map(|(key, cmd_pos)| Ok((key, reader.read_value(cmd_pos)?)))
+                    .collect::<Result<Vec<_>>>()
+            });
+            reader_pool.push(reader).unwrap();
             if tx.send(res).is_err() {
                 error!("Receiving end is dropped");
             }
@@ -137,30 +551,99 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
         )
     }
 
-    /// Gets the string value of a given string key.
+    /// Yields the pairs of the index entries chosen by `select` from `start` in key
+    /// order, reading the index and the values in batches as the stream is polled.
     ///
-    /// Returns `None` if the given key does not exist.
-    fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
+    /// `select` returns the entries of at most the given number of keys from the
+    /// given start bound.
+    fn scan_with<F>(
+        &self,
+        start: Bound<Vec<u8>>,
+        limit: Option<usize>,
+        select: F,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send>
+    where
+        F: Fn(&dyn KeyIndex, Bound<Vec<u8>>, usize) -> Result<Vec<(Vec<u8>, CommandPos)>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let store = self.clone();
+        let select = Arc::new(select);
+        scan_in_batches(start, limit, move |start, len| {
+            let select = Arc::clone(&select);
+            store.read_entries(move |index| select(index, start, len))
+        })
+    }
+}
+
+impl<P: ThreadPool> KvsEngine for KvStore<P> {
+    /// Sets the value of a key.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn set(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(move |writer| writer.set(key, value, None))
+    }
+
+    /// Sets the value of a key that expires after `ttl`.
+    ///
+    /// The absolute expiry time is stored in the log record, so the key stays
+    /// expired after the store is reopened. Expired records are dropped by the
+    /// next compaction.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn set_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let expires_at = expiry_time(ttl);
+        self.write(move |writer| writer.set(key, value, Some(expires_at)))
+    }
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist or has expired. The value is
+    /// read from the cache if it is enabled and holds the value.
+    fn get(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send> {
         let reader_pool = self.reader_pool.clone();
         let index = self.index.clone();
+        let cache = self.cache.clone();
         let (tx, rx) = oneshot::channel();
         self.thread_pool.spawn(move || {
-            let res = (|| {
-                if let Some(cmd_pos) = index.get(&key) {
-                    let reader = reader_pool.pop().unwrap();
-                    let res = if let Command::Set { value, .. } =
-                        reader.read_command(*cmd_pos.value())?
-                    {
-                        Ok(Some(value))
-                    } else {
-                        Err(KvsError::UnexpectedCommandType)
-                    };
-                    reader_pool.push(reader).unwrap();
-                    res
-                } else {
-                    Ok(None)
-                }
-            })();
+            let live = index
+                .get(&key)
+                .map(|cmd_pos| cmd_pos.filter(|cmd_pos| !cmd_pos.is_expired(now_millis())));
+            let res = match live {
+                Err(e) => Err(e),
+                Ok(Some(cmd_pos)) => match cache.get(&key, cmd_pos) {
+                    Some(value) => Ok(Some(value)),
+                    None => {
+                        let reader = reader_pool.pop().unwrap();
+                        let res = reader.read_value(cmd_pos);
+                        reader_pool.push(reader).unwrap();
+                        res.map(|value| {
+                            cache.insert(key, cmd_pos, value.clone());
+                            Some(value)
+                        })
+                    }
+                },
+                Ok(None) => Ok(None),
+            };
             if tx.send(res).is_err() {
                 error!("Receiving end is dropped");
             }
@@ -175,14 +658,183 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
     ///
     /// # Error
     ///
-    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    /// It returns `KvsError::KeyNotFound` if the given key is not found or has
+    /// expired.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn remove(&self, key: Vec<u8>) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(move |writer| writer.remove(key))
+    }
+
+    /// Sets the value of a key to `new` if its current value is `expected`.
+    ///
+    /// The current value is compared under the writer lock, so no other write can
+    /// change it before the swap.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during reading or writing the log.
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Box<dyn Future<Item = bool, Error = KvsError> + Send> {
+        self.write_with(move |writer| writer.compare_and_swap(key, expected, new))
+    }
+
+    /// Applies all writes in the batch atomically if every key in `reads` still has
+    /// the value read.
+    ///
+    /// The values are compared under the writer lock.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during reading or writing the log.
+    fn commit_transaction(
+        &self,
+        reads: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = bool, Error = KvsError> + Send> {
+        self.write_with(move |writer| writer.commit_if_values(reads, batch))
+    }
+
+    /// Applies all writes in the batch atomically.
+    ///
+    /// The writes are appended to the log as a single batch record. A batch record
+    /// that is cut off by a crash is ignored when the log is replayed.
+    ///
+    /// # Errors
     ///
     /// It propagates I/O or serialization errors during writing the log.
-    fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+    fn write_batch(
+        &self,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(move |writer| writer.write_batch(batch))
+    }
+
+    /// Scans key/value pairs whose keys are in the range `[start, end)`.
+    ///
+    /// The keys and value locations are taken from the index in batches as the
+    /// stream is polled, so the scan neither blocks concurrent writers nor holds all
+    /// pairs in memory.
+    fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send> {
+        if let Some(end) = &end {
+            if *end <= start {
+                return Box::new(stream::empty());
+            }
+        }
+        let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+        self.scan_with(Bound::Included(start), limit, move |index, start, len| {
+            live_range(index, (start, end.clone()), |_| true, len)
+        })
+    }
+
+    /// Scans the keys in the range `[start, end)` from the index, so the log is not
+    /// read.
+    fn scan_keys(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = Vec<u8>, Error = KvsError> + Send> {
+        if let Some(end) = &end {
+            if *end <= start {
+                return Box::new(stream::empty());
+            }
+        }
+        let range = (
+            Bound::Included(start),
+            end.map_or(Bound::Unbounded, Bound::Excluded),
+        );
+        let index = self.index.clone();
+        let (tx, rx) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            let res = live_range(&*index, range, |_| true, limit.unwrap_or(usize::MAX))
+                .map(|entries| entries.into_iter().map(|(key, _)| key).collect::<Vec<_>>());
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten()
+                .map(stream::iter_ok)
+                .flatten_stream(),
+        )
+    }
+
+    /// Scans key/value pairs whose keys start with `prefix`.
+    fn scan_prefix(
+        &self,
+        prefix: Vec<u8>,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send> {
+        let start = Bound::Included(prefix.clone());
+        self.scan_with(start, limit, move |index, start, len| {
+            let range = (start, Bound::Unbounded);
+            live_range(index, range, |key| key.starts_with(&prefix), len)
+        })
+    }
+
+    /// Writes a compacted copy of the store to a new directory at `path`.
+    ///
+    /// The copy is read through a snapshot, so writes go on during the backup and
+    /// the log files it reads are kept until it finishes. The live records are
+    /// copied to a single log file with a hint file, which is synced before the
+    /// directory is renamed to `path`.
+    fn backup_to(&self, path: PathBuf) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let snapshot = self.snapshot();
+        let reader_pool = self.reader_pool.clone();
+        let (tx, rx) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            let reader = reader_pool.pop().unwrap();
+            let res = snapshot
+                .entries()
+                .and_then(|entries| write_backup(&path, entries, &reader));
+            reader_pool.push(reader).unwrap();
+            drop(snapshot);
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    /// Streams the changes of the keys starting with `prefix` in commit order.
+    ///
+    /// Changes are sent to the watcher when they are written to the log, before
+    /// they are synced. The changes after `from` are read from the log files, so
+    /// they are only available until their log file is compacted. The stream ends
+    /// if the watcher falls more than a few thousand changes behind, and the watcher
+    /// can resume from the last change it has received.
+    fn watch(
+        &self,
+        prefix: Vec<u8>,
+        from: Option<WatchSeq>,
+    ) -> Box<dyn Future<Item = (WatchSeq, WatchStream), Error = KvsError> + Send> {
         let writer = self.writer.clone();
         let (tx, rx) = oneshot::channel();
         self.thread_pool.spawn(move || {
-            let res = writer.lock().unwrap().remove(key);
+            let registered = writer.lock().unwrap().watch(prefix.clone(), from);
+            // the log files are read after the writer lock is released
+            let res = registered.and_then(|(until, catch_up, receiver)| {
+                let missed = match catch_up {
+                    Some(catch_up) => catch_up.read(&prefix)?,
+                    None => Vec::new(),
+                };
+                let changes = stream::iter_ok(missed).chain(receiver);
+                Ok((until, Box::new(changes) as WatchStream))
+            });
             if tx.send(res).is_err() {
                 error!("Receiving end is dropped");
             }
@@ -192,6 +844,80 @@ impl<P: ThreadPool> KvsEngine for KvStore<P> {
                 .flatten(),
         )
     }
+
+    /// Takes a consistent copy of all pairs and streams the changes after it.
+    ///
+    /// The copy is read through a snapshot registered together with the watcher, so
+    /// every change is either in the copy or in the stream. Each chunk of the copy
+    /// is read from the snapshot when the stream is polled for it.
+    fn copy_and_watch(
+        &self,
+    ) -> Box<dyn Future<Item = (WatchSeq, CopyStream, WatchStream), Error = KvsError> + Send> {
+        let (seq, until, receiver) = self.writer.lock().unwrap().copy_and_watch();
+        let snapshot = Snapshot::new(self.clone(), seq);
+        Box::new(future::ok((
+            until,
+            snapshot.copy(until),
+            Box::new(receiver) as WatchStream,
+        )))
+    }
+
+    /// Returns the counters of the value cache.
+    fn stats(&self) -> EngineStats {
+        self.cache.stats()
+    }
+}
+
+/// Returns the index entries of at most `limit` keys in `range` for which
+/// `in_range` holds that have not expired.
+///
+/// `in_range` must hold for a prefix of the keys in `range`.
+fn live_range<F>(
+    index: &dyn KeyIndex,
+    range: KeyRange,
+    in_range: F,
+    limit: usize,
+) -> Result<Vec<(Vec<u8>, CommandPos)>>
+where
+    F: Fn(&[u8]) -> bool,
+{
+    let now = now_millis();
+    index
+        .range(range)
+        .take_while(|entry| entry.as_ref().map_or(true, |(key, _)| in_range(key)))
+        .filter(|entry| entry.as_ref().map_or(true, |(_, pos)| !pos.is_expired(now)))
+        .take(limit)
+        .collect()
+}
+
+/// Runs a write with the `KvStoreWriter` in the current thread and waits until the
+/// write is synced as required by the `SyncPolicy`.
+///
+/// If the write leaves enough stale commands in the log, a compaction is started
+/// in the thread pool.
+fn run_write<P, F, T>(
+    writer: &Arc<Mutex<KvStoreWriter>>,
+    syncer: &LogSyncer,
+    thread_pool: &P,
+    write: F,
+) -> Result<T>
+where
+    P: ThreadPool,
+    F: FnOnce(&mut KvStoreWriter) -> Result<(T, u64)>,
+{
+    // the writer lock is released before waiting for the sync so that
+    // other writers can share the same sync
+    let (output, seq) = {
+        let mut guard = writer.lock().unwrap();
+        let (output, seq) = write(&mut guard)?;
+        if let Some(compaction) = guard.start_compaction()? {
+            let writer = Arc::clone(writer);
+            thread_pool.spawn(move || compaction.run(&writer));
+        }
+        (output, seq)
+    };
+    syncer.wait_synced(seq)?;
+    Ok(output)
 }
 
 /// A single thread reader.
@@ -245,12 +971,22 @@ impl KvStoreReader {
         f(cmd_reader)
     }
 
-    // Read the log file at the given `CommandPos` and deserialize it to `Command`.
+    // Read the record at the given `CommandPos` and decode it to `Command`.
     fn read_command(&self, cmd_pos: CommandPos) -> Result<Command> {
-        self.read_and(cmd_pos, |cmd_reader| {
-            Ok(serde_json::from_reader(cmd_reader)?)
+        self.read_and(cmd_pos, |mut cmd_reader| {
+            format::read_record(&mut cmd_reader)?
+                .ok_or_else(|| KvsError::CorruptedLog("missing record".to_owned()))
         })
     }
+
+    // Read the value of the `set` command at the given `CommandPos`.
+    fn read_value(&self, cmd_pos: CommandPos) -> Result<Vec<u8>> {
+        if let Command::Set { value, .. } = self.read_command(cmd_pos)? {
+            Ok(value)
+        } else {
+            Err(KvsError::UnexpectedCommandType)
+        }
+    }
 }
 
 impl Clone for KvStoreReader {
@@ -271,78 +1007,386 @@ struct KvStoreWriter {
     // the number of bytes representing "stale" commands that could be
     // deleted during a compaction
     uncompacted: u64,
+    // the total size of the log files before the active one
+    archived: u64,
+    expiring: ExpiryTracker,
+    // sequence number of the last write
+    seq: u64,
+    syncer: Arc<LogSyncer>,
+    compaction: Arc<CompactionStatus>,
+    options: KvStoreOptions,
     path: Arc<PathBuf>,
-    index: Arc<SkipMap<String, CommandPos>>,
+    index: Arc<dyn KeyIndex>,
+    history: Arc<History>,
+    cache: Arc<ValueCache>,
+    // the number of live snapshots at each sequence number
+    snapshots: BTreeMap<u64, usize>,
+    // generation of the last compaction whose stale log files are kept for snapshots
+    stale_gen: Option<u64>,
+    watchers: Watchers,
 }
 
 impl KvStoreWriter {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let cmd = Command::set(key, value);
+    /// Sets the value of a key and returns the sequence number of the write.
+    fn set(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<u64> {
+        let cmd = Command::set(key, value, expires_at);
         let pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &cmd)?;
-        self.writer.flush()?;
-        if let Command::Set { key, .. } = cmd {
-            if let Some(old_cmd) = self.index.get(&key) {
-                self.uncompacted += old_cmd.value().len;
-            }
-            self.index
-                .insert(key, (self.current_gen, pos..self.writer.pos).into());
-        }
-
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact()?;
+        format::write_record(&mut self.writer, &cmd, self.options.compression)?;
+        let seq = self.flush()?;
+        self.watchers.publish(self.watch_seq(), &cmd);
+        if let Some(key) = cmd.key() {
+            self.preserve(key, seq)?;
+            self.cache.remove(key);
         }
-        Ok(())
+        self.uncompacted += apply_command(
+            &*self.index,
+            &mut self.expiring,
+            self.current_gen,
+            cmd,
+            pos..self.writer.pos,
+        )?;
+        Ok(seq)
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if self.index.contains_key(&key) {
+    /// Removes a key and returns the sequence number of the write.
+    fn remove(&mut self, key: Vec<u8>) -> Result<u64> {
+        if self.live_pos(&key)?.is_some() {
             let cmd = Command::remove(key);
             let pos = self.writer.pos;
-            serde_json::to_writer(&mut self.writer, &cmd)?;
-            self.writer.flush()?;
+            format::write_record(&mut self.writer, &cmd, self.options.compression)?;
+            let seq = self.flush()?;
+            self.watchers.publish(self.watch_seq(), &cmd);
             if let Command::Remove { key } = cmd {
-                let old_cmd = self.index.remove(&key).expect("key not found");
-                self.uncompacted += old_cmd.value().len;
+                self.preserve(&key, seq)?;
+                self.cache.remove(&key);
+                let old_cmd = self
+                    .index
+                    .remove(&key, self.current_gen)?
+                    .expect("key not found");
+                self.uncompacted += self.expiring.remove(&key, old_cmd);
                 // the "remove" command itself can be deleted in the next compaction
                 // so we add its length to `uncompacted`
                 self.uncompacted += self.writer.pos - pos;
             }
-
-            if self.uncompacted > COMPACTION_THRESHOLD {
-                self.compact()?;
-            }
-            Ok(())
+            Ok(seq)
         } else {
             Err(KvsError::KeyNotFound)
         }
     }
 
-    /// Clears stale entries in the log.
-    fn compact(&mut self) -> Result<()> {
+    /// Sets the value of a key to `new` if its current value is `expected`.
+    ///
+    /// Returns whether the value was swapped and the sequence number of the last
+    /// write, so that a failed swap is only acknowledged after the write it
+    /// observed is synced.
+    fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<(bool, u64)> {
+        let current = self.live_value(&key)?;
+        if current != expected {
+            return Ok((false, self.seq));
+        }
+        let seq = match new {
+            Some(value) => self.set(key, value, None)?,
+            None if current.is_some() => self.remove(key)?,
+            // the key stays missing
+            None => self.seq,
+        };
+        Ok((true, seq))
+    }
+
+    /// Applies the writes of a transaction if none of the keys it read has changed.
+    ///
+    /// `reads` holds the live index entry of each key when it was read. Returns
+    /// whether the transaction is committed and the sequence number of the last
+    /// write.
+    fn commit(&mut self, reads: ReadSet, batch: WriteBatch) -> Result<(bool, u64)> {
+        // an entry moved by a compaction is also a conflict, and the transaction
+        // is retried
+        for (key, pos) in &reads {
+            if self.live_pos(key)? != *pos {
+                return Ok((false, self.seq));
+            }
+        }
+        Ok((true, self.write_batch(batch)?))
+    }
+
+    /// Applies the writes of a transaction if every key it read still has the
+    /// value read.
+    fn commit_if_values(
+        &mut self,
+        reads: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        batch: WriteBatch,
+    ) -> Result<(bool, u64)> {
+        for (key, value) in reads {
+            if self.live_value(&key)? != value {
+                return Ok((false, self.seq));
+            }
+        }
+        Ok((true, self.write_batch(batch)?))
+    }
+
+    /// Registers a snapshot at the last write and returns its sequence number.
+    fn register_snapshot(&mut self) -> u64 {
+        *self.snapshots.entry(self.seq).or_insert(0) += 1;
+        self.seq
+    }
+
+    /// Unregisters a snapshot and drops the history no other snapshot can read.
+    ///
+    /// Stale log files kept for the snapshots are removed after the last snapshot
+    /// is dropped.
+    fn release_snapshot(&mut self, seq: u64) {
+        if let Some(count) = self.snapshots.get_mut(&seq) {
+            *count -= 1;
+            if *count == 0 {
+                self.snapshots.remove(&seq);
+            }
+        }
+        match self.snapshots.keys().next() {
+            Some(&oldest) => {
+                for entry in self.history.iter() {
+                    if entry.key().1 <= oldest {
+                        entry.remove();
+                    }
+                }
+            }
+            None => {
+                self.history.clear();
+                if let Some(gen) = self.stale_gen.take() {
+                    self.remove_stale_logs(gen);
+                }
+            }
+        }
+    }
+
+    /// Saves the index entry of `key` before it is changed by the write `seq`, so
+    /// that snapshots before the write can still read it.
+    ///
+    /// It must be called before the index is changed.
+    fn preserve(&self, key: &[u8], seq: u64) -> Result<()> {
+        if self.snapshots.is_empty() {
+            return Ok(());
+        }
+        let change = (key.to_vec(), seq);
+        // a batch may change the same key twice
+        if !self.history.contains_key(&change) {
+            let cmd_pos = self.index.get(key)?;
+            self.history.insert(change, cmd_pos);
+        }
+        Ok(())
+    }
+
+    /// Returns the index entry of a key if it exists and has not expired.
+    fn live_pos(&self, key: &[u8]) -> Result<Option<CommandPos>> {
+        let cmd_pos = self.index.get(key)?;
+        Ok(cmd_pos.filter(|cmd_pos| !cmd_pos.is_expired(now_millis())))
+    }
+
+    /// Reads the value of a key if it exists and has not expired.
+    fn live_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.live_pos(key)? {
+            Some(cmd_pos) => Ok(Some(self.reader.read_value(cmd_pos)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Applies a batch of writes and returns the sequence number of the write.
+    fn write_batch(&mut self, batch: WriteBatch) -> Result<u64> {
+        if batch.is_empty() {
+            return Ok(self.seq);
+        }
+        let ops = batch.into_ops();
+        let pos = self.writer.pos;
+        format::write_record(
+            &mut self.writer,
+            &Command::batch(ops.len()),
+            self.options.compression,
+        )?;
+        // the batch header is not needed after the batch is applied
+        self.uncompacted += self.writer.pos - pos;
+
+        let mut cmds = Vec::with_capacity(ops.len());
+        for op in ops {
+            let cmd = Command::from(op);
+            let pos = self.writer.pos;
+            format::write_record(&mut self.writer, &cmd, self.options.compression)?;
+            cmds.push((cmd, pos..self.writer.pos));
+        }
+        let seq = self.flush()?;
+
+        for (cmd, range) in cmds {
+            let watch_seq = WatchSeq {
+                gen: self.current_gen,
+                pos: range.end,
+            };
+            self.watchers.publish(watch_seq, &cmd);
+            if let Some(key) = cmd.key() {
+                self.preserve(key, seq)?;
+                self.cache.remove(key);
+            }
+            self.uncompacted += apply_command(
+                &*self.index,
+                &mut self.expiring,
+                self.current_gen,
+                cmd,
+                range,
+            )?;
+        }
+        Ok(seq)
+    }
+
+    /// Registers a watcher of the keys starting with `prefix`.
+    ///
+    /// Returns the position of the end of the active log, the log files to catch up
+    /// on if the watcher resumes from `from`, and the receiver of the later changes.
+    fn watch(
+        &mut self,
+        prefix: Vec<u8>,
+        from: Option<WatchSeq>,
+    ) -> Result<(WatchSeq, Option<CatchUp>, WatchReceiver)> {
+        let until = self.watch_seq();
+        // the compaction file of a running compaction precedes the active log
+        let compacting = Some(self.current_gen - 1).filter(|_| self.compaction.is_running());
+        let catch_up = from
+            .map(|from| CatchUp::open(&self.path, from, until, compacting))
+            .transpose()?;
+        Ok((until, catch_up, self.watchers.register(prefix)))
+    }
+
+    /// Registers a snapshot and a watcher of all keys at the same write.
+    ///
+    /// Returns the sequence number of the snapshot, the position of the end of the
+    /// active log and the receiver of the later changes.
+    fn copy_and_watch(&mut self) -> (u64, WatchSeq, WatchReceiver) {
+        let seq = self.register_snapshot();
+        (seq, self.watch_seq(), self.watchers.register(Vec::new()))
+    }
+
+    /// Returns the position of the end of the active log.
+    fn watch_seq(&self) -> WatchSeq {
+        WatchSeq {
+            gen: self.current_gen,
+            pos: self.writer.pos,
+        }
+    }
+
+    /// Flushes the active log to the operating system and returns the sequence
+    /// number of the write.
+    fn flush(&mut self) -> Result<u64> {
+        self.writer.flush()?;
+        self.seq += 1;
+        self.syncer.written(self.seq);
+        Ok(self.seq)
+    }
+
+    /// Returns whether the log contains enough stale commands to be compacted, or
+    /// the index needs to be rebuilt by a compaction.
+    ///
+    /// Entries that have expired since the last check are counted as stale first.
+    fn needs_compaction(&mut self) -> bool {
+        self.uncompacted += self.expiring.expire(now_millis());
+        if self.index.needs_rebuild() {
+            return true;
+        }
+        if self.uncompacted <= self.options.compaction_threshold {
+            return false;
+        }
+        if let Some(garbage_ratio) = self.options.garbage_ratio {
+            let log_size = self.archived + self.writer.pos;
+            return self.uncompacted as f64 >= garbage_ratio * log_size as f64;
+        }
+        true
+    }
+
+    /// Starts a compaction if it is needed and no other compaction is running.
+    ///
+    /// Writes are switched to a new log file. The returned `Compaction` rewrites the
+    /// older log files in the background.
+    fn start_compaction(&mut self) -> Result<Option<Compaction>> {
+        if !self.needs_compaction() || !self.compaction.start() {
+            return Ok(None);
+        }
+        let res = self.rotate();
+        if res.is_err() {
+            self.compaction.finish();
+        }
+        res.map(Some)
+    }
+
+    /// Switches writes to a new log file and prepares the compaction of the older ones.
+    fn rotate(&mut self) -> Result<Compaction> {
         // increase current gen by 2. current_gen + 1 is for the compaction file
         let compaction_gen = self.current_gen + 1;
         self.current_gen += 2;
-        self.writer = new_log_file(&self.path, self.current_gen)?;
+        let writer = new_log_file(&self.path, self.current_gen)?;
+        self.syncer.rotate(writer.get_ref().try_clone()?)?;
+        self.archived += self.writer.pos;
+        self.writer = writer;
 
-        let mut compaction_writer = new_log_file(&self.path, compaction_gen)?;
+        Ok(Compaction {
+            gen: compaction_gen,
+            uncompacted: self.uncompacted,
+            expired_until: self.expiring.counted_until,
+            sync: self.syncer.policy != SyncPolicy::Never,
+            reader: self.reader.clone(),
+            path: Arc::clone(&self.path),
+            index: Arc::clone(&self.index),
+            status: Arc::clone(&self.compaction),
+        })
+    }
 
-        let mut new_pos = 0; // pos in the new log file
-        for entry in self.index.iter() {
-            let len = self.reader.read_and(*entry.value(), |mut entry_reader| {
-                Ok(io::copy(&mut entry_reader, &mut compaction_writer)?)
-            })?;
-            self.index.insert(
-                entry.key().clone(),
-                (compaction_gen, new_pos..new_pos + len).into(),
-            );
-            new_pos += len;
+    /// Points the index to the compaction file and removes the stale log files
+    /// unless a snapshot is alive.
+    ///
+    /// An index with files installs the index file of the compaction. Otherwise
+    /// only entries that have not been changed since they were copied or dropped
+    /// are updated.
+    fn finish_compaction(
+        &mut self,
+        compaction: &Compaction,
+        compacted: CompactedIndex,
+        compaction_size: u64,
+    ) -> Result<()> {
+        match compacted {
+            CompactedIndex::File(file) => self.index.install(file),
+            CompactedIndex::Entries { moved, expired } => {
+                for (key, old_pos, new_pos) in moved {
+                    if self.index.get(&key)? == Some(old_pos) {
+                        self.cache.relocate(&key, old_pos, new_pos);
+                        self.index.insert(key, new_pos);
+                    }
+                }
+                for (key, old_pos) in expired {
+                    if self.index.get(&key)? == Some(old_pos) {
+                        self.cache.remove(&key);
+                        self.index.remove(&key, compaction.gen)?;
+                    }
+                }
+            }
         }
-        compaction_writer.flush()?;
 
-        self.reader
-            .safe_point
-            .store(compaction_gen, Ordering::SeqCst);
+        // snapshots may still read the stale log files
+        if self.snapshots.is_empty() {
+            self.remove_stale_logs(compaction.gen);
+        } else {
+            self.stale_gen = Some(compaction.gen);
+        }
+
+        // stale commands written during the compaction are still in the log
+        self.uncompacted -= compaction.uncompacted;
+        self.archived = compaction_size;
+        Ok(())
+    }
+
+    /// Removes the log files older than the compaction generation `gen`.
+    ///
+    /// The index must contain no entries with generation number less than `gen`.
+    fn remove_stale_logs(&mut self, gen: u64) {
+        self.reader.safe_point.store(gen, Ordering::SeqCst);
         self.reader.close_stale_handles();
 
         // remove stale log files
@@ -352,36 +1396,468 @@ impl KvStoreWriter {
         // are closed. On Windows, the deletions below will fail and stale files are expected
         // to be deleted in the next compaction.
 
-        let stale_gens = sorted_gen_list(&self.path)?
-            .into_iter()
-            .filter(|&gen| gen < compaction_gen);
-        for stale_gen in stale_gens {
-            let file_path = log_path(&self.path, stale_gen);
-            if let Err(e) = fs::remove_file(&file_path) {
-                error!("{:?} cannot be deleted: {}", file_path, e);
+        match sorted_gen_list(&self.path) {
+            Ok(gen_list) => {
+                for stale_gen in gen_list.into_iter().filter(|&stale| stale < gen) {
+                    let file_path = log_path(&self.path, stale_gen);
+                    if let Err(e) = fs::remove_file(&file_path) {
+                        error!("{:?} cannot be deleted: {}", file_path, e);
+                    }
+                    for file_path in &[
+                        hint_path(&self.path, stale_gen),
+                        index_path(&self.path, stale_gen),
+                    ] {
+                        if file_path.exists() {
+                            if let Err(e) = fs::remove_file(file_path) {
+                                error!("{:?} cannot be deleted: {}", file_path, e);
+                            }
+                        }
+                    }
+                }
+            }
+            Err(e) => error!("Stale log files cannot be listed: {}", e),
+        }
+    }
+}
+
+/// A compaction of the log files older than the compaction generation.
+///
+/// It runs in the thread pool while new writes go to a newer log file. Live
+/// entries of the older log files are copied to the compaction file, and the
+/// index is updated under the writer lock afterwards. An index with files gets a
+/// new index file of the compaction file instead. Entries that had expired when
+/// the compaction started are dropped.
+struct Compaction {
+    // generation of the compaction file
+    gen: u64,
+    // the number of stale bytes in the log files being compacted
+    uncompacted: u64,
+    // entries expiring at or before this time are counted in `uncompacted`
+    expired_until: u64,
+    // whether the compaction file must be synced
+    sync: bool,
+    reader: KvStoreReader,
+    path: Arc<PathBuf>,
+    index: Arc<dyn KeyIndex>,
+    status: Arc<CompactionStatus>,
+}
+
+/// The index of a finished compaction
+enum CompactedIndex {
+    /// The index file of the compaction file
+    File(IndexFile),
+    /// The old and new locations of the copied entries and the dropped entries
+    /// that had expired, to be updated in the index
+    Entries {
+        moved: Vec<(Vec<u8>, CommandPos, CommandPos)>,
+        expired: Vec<(Vec<u8>, CommandPos)>,
+    },
+}
+
+impl Compaction {
+    /// Runs the compaction and marks it as finished.
+    fn run(self, writer: &Mutex<KvStoreWriter>) {
+        if let Err(e) = self.compact(writer) {
+            error!("Failed to compact the log: {}", e);
+            let tmp_path = partial_log_path(&self.path, self.gen);
+            if tmp_path.exists() {
+                if let Err(e) = fs::remove_file(&tmp_path) {
+                    error!("{:?} cannot be deleted: {}", tmp_path, e);
+                }
+            }
+        }
+        self.status.finish();
+    }
+
+    fn compact(&self, writer: &Mutex<KvStoreWriter>) -> Result<()> {
+        // the compaction file is written under a temporary name and renamed once it is
+        // complete, so a crash during the compaction leaves no torn log file behind
+        let tmp_path = partial_log_path(&self.path, self.gen);
+        let mut compaction_writer = BufWriterWithPos::new(File::create(&tmp_path)?)?;
+        format::write_header(&mut compaction_writer)?;
+        // an index with files gets the new entries written to its file instead
+        let mut index_writer = self.index.create_file(self.gen, self.sync)?;
+
+        let mut moved: Vec<(Vec<u8>, CommandPos, CommandPos)> = Vec::new();
+        let mut expired = Vec::new();
+        let mut new_pos = compaction_writer.pos; // pos in the new log file
+        for entry in self.index.range((Bound::Unbounded, Bound::Unbounded)) {
+            let (key, old_pos) = entry?;
+            // entries written after the compaction started are in newer log files
+            if old_pos.gen >= self.gen {
+                continue;
+            }
+            if old_pos.is_expired(self.expired_until) {
+                if index_writer.is_none() {
+                    expired.push((key, old_pos));
+                }
+                continue;
+            }
+            let len = self.reader.read_and(old_pos, |mut entry_reader| {
+                Ok(io::copy(&mut entry_reader, &mut compaction_writer)?)
+            })?;
+            let cmd_pos = CommandPos::new(self.gen, new_pos..new_pos + len, old_pos.expires_at);
+            match &mut index_writer {
+                Some(index_writer) => index_writer.add(&key, cmd_pos)?,
+                None => moved.push((key, old_pos, cmd_pos)),
+            }
+            new_pos += len;
+        }
+        compaction_writer.flush()?;
+        if self.sync {
+            // the compaction file must be durable before stale files are removed
+            compaction_writer.get_ref().sync_data()?;
+        }
+        fs::rename(&tmp_path, log_path(&self.path, self.gen))?;
+        let compacted = match index_writer {
+            Some(index_writer) => CompactedIndex::File(index_writer.finish(new_pos)?),
+            None => {
+                hint::write(
+                    &hint_path(&self.path, self.gen),
+                    new_pos,
+                    moved.iter().map(|(key, _, new_pos)| {
+                        (
+                            key.as_slice(),
+                            new_pos.pos..new_pos.pos + new_pos.len,
+                            new_pos.expires_at,
+                        )
+                    }),
+                    self.sync,
+                )?;
+                CompactedIndex::Entries { moved, expired }
+            }
+        };
+
+        writer
+            .lock()
+            .unwrap()
+            .finish_compaction(self, compacted, new_pos)
+    }
+}
+
+/// Writes the records at `entries` to a log file and a hint file in a new data
+/// directory at `path`.
+///
+/// The files are written to a temporary directory that is renamed to `path` after
+/// they are synced, so an interrupted backup leaves nothing at `path`.
+fn write_backup(
+    path: &Path,
+    entries: Vec<(Vec<u8>, CommandPos)>,
+    reader: &KvStoreReader,
+) -> Result<()> {
+    if path.exists() {
+        return Err(KvsError::InvalidArgument(format!(
+            "{} already exists",
+            path.display()
+        )));
+    }
+    let mut tmp_name = path.file_name().unwrap_or_default().to_owned();
+    tmp_name.push(".partial");
+    let tmp_path = path.with_file_name(tmp_name);
+    if tmp_path.exists() {
+        // left by an interrupted backup
+        fs::remove_dir_all(&tmp_path)?;
+    }
+    fs::create_dir_all(&tmp_path)?;
+
+    let mut backup_writer = new_log_file(&tmp_path, 1)?;
+    let mut hint_entries = Vec::new();
+    let mut new_pos = backup_writer.pos;
+    for (key, cmd_pos) in entries {
+        let len = reader.read_and(cmd_pos, |mut entry_reader| {
+            Ok(io::copy(&mut entry_reader, &mut backup_writer)?)
+        })?;
+        hint_entries.push((key, new_pos..new_pos + len, cmd_pos.expires_at));
+        new_pos += len;
+    }
+    backup_writer.flush()?;
+    backup_writer.get_ref().sync_data()?;
+    hint::write(
+        &hint_path(&tmp_path, 1),
+        new_pos,
+        hint_entries
+            .iter()
+            .map(|(key, range, expires_at)| (key.as_slice(), range.clone(), *expires_at)),
+        true,
+    )?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+/// Checks that a log file of a backup and its hint file are intact.
+fn verify_backup_log(backup: &Path, gen: u64) -> Result<()> {
+    let file_path = log_path(backup, gen);
+    let mut reader = BufReaderWithPos::new(File::open(&file_path)?)?;
+    if format::read_header(&mut reader)? == LogFormat::Legacy {
+        return Err(KvsError::CorruptedLog(format!(
+            "{}.log of the backup is not in the binary format",
+            gen
+        )));
+    }
+    let mut batch_remaining = 0;
+    while let Some(cmd) = format::read_record(&mut reader)? {
+        match cmd {
+            Command::Batch { len } => batch_remaining = len,
+            _ if batch_remaining > 0 => batch_remaining -= 1,
+            _ => (),
+        }
+    }
+    if batch_remaining > 0 {
+        return Err(KvsError::CorruptedLog(format!(
+            "incomplete batch at the end of {}.log of the backup",
+            gen
+        )));
+    }
+    let log_len = fs::metadata(&file_path)?.len();
+    let hint_file = hint_path(backup, gen);
+    if hint_file.exists() && hint::read(&hint_file, log_len)?.is_none() {
+        return Err(KvsError::CorruptedLog(format!(
+            "{}.hint of the backup does not match its log",
+            gen
+        )));
+    }
+    Ok(())
+}
+
+/// Tracks whether a compaction is running.
+#[derive(Default)]
+struct CompactionStatus {
+    running: Mutex<bool>,
+    // notified when a compaction finishes
+    finished: Condvar,
+}
+
+impl CompactionStatus {
+    /// Marks a compaction as running.
+    ///
+    /// Returns `false` if another compaction is already running.
+    fn start(&self) -> bool {
+        let mut running = self.running.lock().unwrap();
+        !mem::replace(&mut *running, true)
+    }
+
+    fn is_running(&self) -> bool {
+        *self.running.lock().unwrap()
+    }
+
+    fn finish(&self) {
+        *self.running.lock().unwrap() = false;
+        self.finished.notify_all();
+    }
+
+    /// Blocks until the running compaction finishes.
+    fn wait(&self) {
+        let mut running = self.running.lock().unwrap();
+        while *running {
+            running = self.finished.wait(running).unwrap();
+        }
+    }
+}
+
+/// Waits for the running compaction when the last `KvStore` handle is dropped,
+/// so that no compaction is left running on a closed store.
+struct CompactionGuard(Arc<CompactionStatus>);
+
+impl Drop for CompactionGuard {
+    fn drop(&mut self) {
+        self.0.wait();
+    }
+}
+
+/// Syncs the active log file to the disk according to the `SyncPolicy`.
+///
+/// Every write flushed to the active log gets an increasing sequence number. The
+/// syncer tracks the last written and the last synced sequence numbers, so a single
+/// sync acknowledges all writes flushed before it.
+struct LogSyncer {
+    policy: SyncPolicy,
+    state: Mutex<SyncState>,
+    // notified when a sync finishes
+    synced_cond: Condvar,
+    sync_count: AtomicU64,
+}
+
+struct SyncState {
+    // handle of the active log file
+    file: Arc<File>,
+    // sequence number of the last write flushed to the active log
+    written: u64,
+    // sequence number of the last write synced to the disk
+    synced: u64,
+    // whether a writer is syncing on behalf of the others
+    syncing: bool,
+}
+
+impl LogSyncer {
+    /// Creates a `LogSyncer` for the active log `file`.
+    ///
+    /// A background thread is started for `SyncPolicy::Interval`. It exits after the
+    /// `LogSyncer` is dropped.
+    fn new(policy: SyncPolicy, file: File) -> Result<Arc<LogSyncer>> {
+        let syncer = Arc::new(LogSyncer {
+            policy,
+            state: Mutex::new(SyncState {
+                file: Arc::new(file),
+                written: 0,
+                synced: 0,
+                syncing: false,
+            }),
+            synced_cond: Condvar::new(),
+            sync_count: AtomicU64::new(0),
+        });
+        if let SyncPolicy::Interval(interval) = policy {
+            let syncer = Arc::downgrade(&syncer);
+            thread::Builder::new()
+                .name("kvs-log-sync".to_owned())
+                .spawn(move || loop {
+                    thread::sleep(interval);
+                    match syncer.upgrade() {
+                        Some(syncer) => {
+                            if let Err(e) = syncer.sync_written() {
+                                error!("Failed to sync the log: {}", e);
+                            }
+                        }
+                        None => break,
+                    }
+                })?;
+        }
+        Ok(syncer)
+    }
+
+    /// Records that the write with sequence number `seq` is flushed to the active log.
+    fn written(&self, seq: u64) {
+        if self.policy != SyncPolicy::Never {
+            self.state.lock().unwrap().written = seq;
+        }
+    }
+
+    /// Switches to a new active log file.
+    ///
+    /// The old file is synced first so that writers waiting for it are not lost.
+    fn rotate(&self, file: File) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if self.policy != SyncPolicy::Never && state.synced < state.written {
+            state.file.sync_data()?;
+            self.sync_count.fetch_add(1, Ordering::SeqCst);
+            state.synced = state.written;
+            self.synced_cond.notify_all();
+        }
+        state.file = Arc::new(file);
+        Ok(())
+    }
+
+    /// Waits until the write with sequence number `seq` is synced as required by
+    /// the policy.
+    fn wait_synced(&self, seq: u64) -> Result<()> {
+        match self.policy {
+            SyncPolicy::Never | SyncPolicy::Interval(_) => Ok(()),
+            SyncPolicy::Always => self.sync_written(),
+            SyncPolicy::GroupCommit => {
+                let mut state = self.state.lock().unwrap();
+                while state.synced < seq {
+                    if state.syncing {
+                        state = self.synced_cond.wait(state).unwrap();
+                        continue;
+                    }
+                    // Sync on behalf of all writers that have flushed their writes.
+                    // Writers arriving during the sync wait for the next one.
+                    state.syncing = true;
+                    let file = Arc::clone(&state.file);
+                    let target = state.written;
+                    drop(state);
+                    let res = file.sync_data();
+                    self.sync_count.fetch_add(1, Ordering::SeqCst);
+                    state = self.state.lock().unwrap();
+                    state.syncing = false;
+                    if res.is_ok() && state.synced < target {
+                        state.synced = target;
+                    }
+                    self.synced_cond.notify_all();
+                    res?;
+                }
+                Ok(())
             }
         }
-        self.uncompacted = 0;
+    }
 
+    /// Syncs all writes flushed to the active log so far.
+    fn sync_written(&self) -> Result<()> {
+        let (file, target) = {
+            let state = self.state.lock().unwrap();
+            if state.synced >= state.written {
+                return Ok(());
+            }
+            (Arc::clone(&state.file), state.written)
+        };
+        file.sync_data()?;
+        self.sync_count.fetch_add(1, Ordering::SeqCst);
+        let mut state = self.state.lock().unwrap();
+        if state.synced < target {
+            state.synced = target;
+            self.synced_cond.notify_all();
+        }
         Ok(())
     }
 }
 
+impl Drop for LogSyncer {
+    fn drop(&mut self) {
+        if self.policy != SyncPolicy::Never {
+            if let Err(e) = self.sync_written() {
+                error!("Failed to sync the log: {}", e);
+            }
+        }
+    }
+}
+
 /// Create a new log file with given generation number and add the reader to the readers map.
 ///
 /// Returns the writer to the log.
 fn new_log_file(path: &Path, gen: u64) -> Result<BufWriterWithPos<File>> {
     let path = log_path(&path, gen);
-    let writer = BufWriterWithPos::new(
+    let mut writer = BufWriterWithPos::new(
         OpenOptions::new()
             .create(true)
             .write(true)
             .append(true)
             .open(&path)?,
     )?;
+    if writer.get_ref().metadata()?.len() == 0 {
+        format::write_header(&mut writer)?;
+        writer.flush()?;
+    }
     Ok(writer)
 }
 
+/// Rewrites a log file of concatenated JSON commands in the binary format.
+///
+/// The converted log is written to a temporary file first and then renamed over
+/// the legacy one, so a crash during the migration leaves the legacy log intact.
+fn migrate_legacy_log(path: &Path, gen: u64) -> Result<()> {
+    let file_path = log_path(path, gen);
+    let tmp_path = path.join(format!("{}.log.migrating", gen));
+    let reader = BufReader::new(File::open(&file_path)?);
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    format::convert_legacy(reader, &mut writer)?;
+    writer.flush()?;
+    writer.get_ref().sync_data()?;
+    fs::rename(&tmp_path, &file_path)?;
+    info!("Converted {}.log to the binary log format", gen);
+    Ok(())
+}
+
+/// Removes the compaction files left by interrupted compactions.
+fn remove_partial_logs(path: &Path) -> Result<()> {
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(OsStr::to_str).unwrap_or_default();
+        if name.ends_with(".log.partial") {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
 /// Returns sorted generation numbers in the given directory
 fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
     let mut gen_list: Vec<u64> = fs::read_dir(&path)?
@@ -401,36 +1877,154 @@ fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
 
 /// Load the whole log file and store value locations in the index map.
 ///
+/// A record or batch cut off or garbled at the end of the newest log file is the
+/// result of a crash during writing. It has never been acknowledged, so it is
+/// ignored and the file is truncated to the last complete record. Any other
+/// corrupted record is an error, and the file is left untouched.
+///
 /// Returns how many bytes can be saved after a compaction.
 fn load(
+    path: &Path,
     gen: u64,
+    is_newest: bool,
     reader: &mut BufReaderWithPos<File>,
-    index: &SkipMap<String, CommandPos>,
+    index: &dyn KeyIndex,
+    expiring: &mut ExpiryTracker,
 ) -> Result<u64> {
     // To make sure we read from the beginning of the file
-    let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = Deserializer::from_reader(reader).into_iter::<Command>();
+    reader.seek(SeekFrom::Start(0))?;
+    match format::read_header(reader)? {
+        LogFormat::Binary => (),
+        LogFormat::Empty => return Ok(0),
+        LogFormat::Legacy => {
+            return Err(KvsError::CorruptedLog(format!(
+                "{}.log is not converted",
+                gen
+            )))
+        }
+    }
+    let mut pos = reader.pos;
     let mut uncompacted = 0; // number of bytes that can be saved after a compaction
-    while let Some(cmd) = stream.next() {
-        let new_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set { key, .. } => {
-                if let Some(old_cmd) = index.get(&key) {
-                    uncompacted += old_cmd.value().len;
-                }
-                index.insert(key, (gen, pos..new_pos).into());
+
+    // commands of the batch being read, the number of commands still missing
+    // and the position of the batch header
+    let mut batch = Vec::new();
+    let mut batch_remaining = 0;
+    let mut batch_pos = pos;
+    let log_len = fs::metadata(log_path(path, gen))?.len();
+    let mut corrupted = false;
+    loop {
+        let cmd = match format::read_record(reader) {
+            Ok(Some(cmd)) => cmd,
+            Ok(None) => break,
+            // only the last record of the newest log file can be torn by a crash
+            Err(KvsError::CorruptedLog(e)) if is_newest && reader.pos >= log_len => {
+                warn!("Ignoring corrupted record at {} of {}.log: {}", pos, gen, e);
+                corrupted = true;
+                break;
+            }
+            Err(KvsError::CorruptedLog(e)) => {
+                return Err(KvsError::CorruptedLog(format!(
+                    "{} at {} of {}.log",
+                    e, pos, gen
+                )))
             }
-            Command::Remove { key } => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.value().len;
+            Err(e) => return Err(e),
+        };
+        let new_pos = reader.pos;
+        if let Command::Batch { len } = cmd {
+            // the batch header itself can be deleted in the next compaction
+            uncompacted += new_pos - pos;
+            batch.clear();
+            batch_remaining = len;
+            batch_pos = pos;
+        } else if batch_remaining > 0 {
+            batch.push((cmd, pos..new_pos));
+            batch_remaining -= 1;
+            if batch_remaining == 0 {
+                for (cmd, range) in batch.drain(..) {
+                    uncompacted += apply_command(index, expiring, gen, cmd, range)?;
                 }
-                // the "remove" command itself can be deleted in the next compaction
-                // so we add its length to `uncompacted`
-                uncompacted += new_pos - pos;
             }
+        } else {
+            uncompacted += apply_command(index, expiring, gen, cmd, pos..new_pos)?;
         }
         pos = new_pos;
     }
+    if batch_remaining > 0 {
+        if !is_newest {
+            return Err(KvsError::CorruptedLog(format!(
+                "incomplete batch at {} of {}.log",
+                batch_pos, gen
+            )));
+        }
+        warn!("Ignoring incomplete batch at the end of {}.log", gen);
+        pos = batch_pos;
+        corrupted = true;
+    }
+    if corrupted {
+        OpenOptions::new()
+            .write(true)
+            .open(log_path(path, gen))?
+            .set_len(pos)?;
+    }
+    Ok(uncompacted)
+}
+
+/// Store the record locations listed in a hint file in the index map.
+///
+/// Returns how many bytes can be saved after a compaction.
+fn load_hint(
+    gen: u64,
+    entries: Vec<HintEntry>,
+    index: &dyn KeyIndex,
+    expiring: &mut ExpiryTracker,
+) -> Result<u64> {
+    let mut uncompacted = 0;
+    for (key, range, expires_at) in entries {
+        let cmd_pos = CommandPos::new(gen, range, expires_at);
+        if let Some(old_cmd) = index.get(&key)? {
+            uncompacted += expiring.remove(&key, old_cmd);
+        }
+        uncompacted += expiring.insert(&key, cmd_pos);
+        index.insert(key, cmd_pos);
+    }
+    Ok(uncompacted)
+}
+
+/// Applies a `set` or `remove` command at `range` of the log to the index map.
+///
+/// Returns how many bytes become stale.
+fn apply_command(
+    index: &dyn KeyIndex,
+    expiring: &mut ExpiryTracker,
+    gen: u64,
+    cmd: Command,
+    range: Range<u64>,
+) -> Result<u64> {
+    let mut uncompacted = 0;
+    match cmd {
+        Command::Set {
+            key, expires_at, ..
+        } => {
+            let cmd_pos = CommandPos::new(gen, range, expires_at);
+            if let Some(old_cmd) = index.get(&key)? {
+                uncompacted += expiring.remove(&key, old_cmd);
+            }
+            // a record that has already expired is stale right away
+            uncompacted += expiring.insert(&key, cmd_pos);
+            index.insert(key, cmd_pos);
+        }
+        Command::Remove { key } => {
+            if let Some(old_cmd) = index.remove(&key, gen)? {
+                uncompacted += expiring.remove(&key, old_cmd);
+            }
+            // the "remove" command itself can be deleted in the next compaction
+            // so we add its length to `uncompacted`
+            uncompacted += range.end - range.start;
+        }
+        Command::Batch { .. } => unreachable!("nested batch"),
+    }
     Ok(uncompacted)
 }
 
@@ -438,39 +2032,107 @@ fn log_path(dir: &Path, gen: u64) -> PathBuf {
     dir.join(format!("{}.log", gen))
 }
 
-/// Struct representing a command
-#[derive(Serialize, Deserialize, Debug)]
-enum Command {
-    Set { key: String, value: String },
-    Remove { key: String },
+fn partial_log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log.partial", gen))
 }
 
-impl Command {
-    fn set(key: String, value: String) -> Command {
-        Command::Set { key, value }
-    }
+fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint", gen))
+}
 
-    fn remove(key: String) -> Command {
-        Command::Remove { key }
-    }
+fn index_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.index", gen))
 }
 
-/// Represents the position and length of a json-serialized command in the log
-#[derive(Debug, Clone, Copy)]
+/// Represents the position and length of a record in the log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 struct CommandPos {
     gen: u64,
     pos: u64,
     len: u64,
+    // expiry time in milliseconds since the UNIX epoch
+    expires_at: Option<u64>,
 }
 
-impl From<(u64, Range<u64>)> for CommandPos {
-    fn from((gen, range): (u64, Range<u64>)) -> Self {
+impl CommandPos {
+    fn new(gen: u64, range: Range<u64>, expires_at: Option<u64>) -> Self {
         CommandPos {
             gen,
             pos: range.start,
             len: range.end - range.start,
+            expires_at,
         }
     }
+
+    /// Returns whether the entry has expired at `now`.
+    fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+    }
+}
+
+/// Tracks the index entries with an expiry time, so that their records are
+/// counted as stale once they expire.
+struct ExpiryTracker {
+    // entries that have not expired yet, keyed by expiry time and key, with
+    // the length of their records
+    pending: BTreeMap<(u64, Vec<u8>), u64>,
+    // entries expiring at or before this time are already counted as stale
+    counted_until: u64,
+}
+
+impl ExpiryTracker {
+    fn new(now: u64) -> Self {
+        ExpiryTracker {
+            pending: BTreeMap::new(),
+            counted_until: now,
+        }
+    }
+
+    /// Tracks a new index entry.
+    ///
+    /// Returns how many bytes become stale, which is the whole record if it has
+    /// already expired.
+    fn insert(&mut self, key: &[u8], cmd_pos: CommandPos) -> u64 {
+        match cmd_pos.expires_at {
+            Some(_) if cmd_pos.is_expired(self.counted_until) => cmd_pos.len,
+            Some(expires_at) => {
+                self.pending.insert((expires_at, key.to_vec()), cmd_pos.len);
+                0
+            }
+            None => 0,
+        }
+    }
+
+    /// Stops tracking an index entry that is overwritten or removed.
+    ///
+    /// Returns how many bytes become stale.
+    fn remove(&mut self, key: &[u8], cmd_pos: CommandPos) -> u64 {
+        match cmd_pos.expires_at {
+            // already counted when it expired
+            Some(_) if cmd_pos.is_expired(self.counted_until) => 0,
+            Some(expires_at) => {
+                self.pending.remove(&(expires_at, key.to_vec()));
+                cmd_pos.len
+            }
+            None => cmd_pos.len,
+        }
+    }
+
+    /// Counts the entries that have expired at `now` as stale.
+    ///
+    /// Returns how many bytes become stale.
+    fn expire(&mut self, now: u64) -> u64 {
+        if now <= self.counted_until {
+            return 0;
+        }
+        self.counted_until = now;
+        match self.pending.keys().next() {
+            Some((expires_at, _)) if *expires_at <= now => (),
+            _ => return 0,
+        }
+        let pending = self.pending.split_off(&(now + 1, Vec::new()));
+        mem::replace(&mut self.pending, pending).values().sum()
+    }
 }
 
 struct BufReaderWithPos<R: Read + Seek> {
@@ -516,6 +2178,10 @@ impl<W: Write + Seek> BufWriterWithPos<W> {
             pos,
         })
     }
+
+    fn get_ref(&self) -> &W {
+        self.writer.get_ref()
+    }
 }
 
 impl<W: Write + Seek> Write for BufWriterWithPos<W> {
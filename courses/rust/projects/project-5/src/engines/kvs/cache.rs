@@ -0,0 +1,184 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use super::CommandPos;
+use crate::EngineStats;
+
+/// The number of shards of a `ValueCache`
+const SHARDS: usize = 16;
+
+/// A sharded LRU cache of values read from the log.
+///
+/// Each value is cached with the index entry it was read from, and a lookup only
+/// hits if the key still has the same index entry. So a value read concurrently
+/// with a write of the same key is never returned after the write. The byte budget
+/// is split evenly between the shards, and each shard evicts its least recently
+/// used values when it is over budget.
+///
+/// A cache with a zero budget caches nothing and counts nothing.
+pub(super) struct ValueCache {
+    shards: Vec<Mutex<Shard>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Shard {
+    capacity: u64,
+    // the number of bytes of the cached keys and values
+    size: u64,
+    // increases on every access
+    tick: u64,
+    entries: HashMap<Vec<u8>, CacheEntry>,
+    // keys ordered by their last access
+    lru: BTreeMap<u64, Vec<u8>>,
+}
+
+struct CacheEntry {
+    cmd_pos: CommandPos,
+    value: Vec<u8>,
+    // the last access
+    tick: u64,
+}
+
+impl ValueCache {
+    /// Creates a cache of at most `capacity` bytes of keys and values.
+    pub(super) fn new(capacity: u64) -> ValueCache {
+        let shards = if capacity == 0 {
+            Vec::new()
+        } else {
+            (0..SHARDS)
+                .map(|_| Mutex::new(Shard::new(capacity / SHARDS as u64)))
+                .collect()
+        };
+        ValueCache {
+            shards,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the cached value of `key` if it was read at `cmd_pos`.
+    pub(super) fn get(&self, key: &[u8], cmd_pos: CommandPos) -> Option<Vec<u8>> {
+        let shard = self.shard(key)?;
+        let value = shard.lock().unwrap().get(key, cmd_pos);
+        let counter = if value.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    /// Caches the value of `key` read at `cmd_pos`.
+    pub(super) fn insert(&self, key: Vec<u8>, cmd_pos: CommandPos, value: Vec<u8>) {
+        if let Some(shard) = self.shard(&key) {
+            shard.lock().unwrap().insert(key, cmd_pos, value);
+        }
+    }
+
+    /// Drops the cached value of `key`.
+    pub(super) fn remove(&self, key: &[u8]) {
+        if let Some(shard) = self.shard(key) {
+            shard.lock().unwrap().remove(key);
+        }
+    }
+
+    /// Moves the cached value of `key` from `old_pos` to `new_pos` after a
+    /// compaction copied its record.
+    pub(super) fn relocate(&self, key: &[u8], old_pos: CommandPos, new_pos: CommandPos) {
+        if let Some(shard) = self.shard(key) {
+            let mut shard = shard.lock().unwrap();
+            if let Some(entry) = shard.entries.get_mut(key) {
+                if entry.cmd_pos == old_pos {
+                    entry.cmd_pos = new_pos;
+                }
+            }
+        }
+    }
+
+    /// Returns the cache counters.
+    pub(super) fn stats(&self) -> EngineStats {
+        EngineStats {
+            cache_hits: self.hits.load(Ordering::Relaxed),
+            cache_misses: self.misses.load(Ordering::Relaxed),
+            cache_size: self
+                .shards
+                .iter()
+                .map(|shard| shard.lock().unwrap().size)
+                .sum(),
+        }
+    }
+
+    fn shard(&self, key: &[u8]) -> Option<&Mutex<Shard>> {
+        if self.shards.is_empty() {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        Some(&self.shards[hasher.finish() as usize % self.shards.len()])
+    }
+}
+
+impl Shard {
+    fn new(capacity: u64) -> Shard {
+        Shard {
+            capacity,
+            size: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+        }
+    }
+
+    fn get(&mut self, key: &[u8], cmd_pos: CommandPos) -> Option<Vec<u8>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self
+            .entries
+            .get_mut(key)
+            .filter(|entry| entry.cmd_pos == cmd_pos)?;
+        let key = self
+            .lru
+            .remove(&entry.tick)
+            .expect("cached key not in LRU list");
+        entry.tick = tick;
+        let value = entry.value.clone();
+        self.lru.insert(tick, key);
+        Some(value)
+    }
+
+    fn insert(&mut self, key: Vec<u8>, cmd_pos: CommandPos, value: Vec<u8>) {
+        let charge = (key.len() + value.len()) as u64;
+        if charge > self.capacity {
+            return;
+        }
+        self.remove(&key);
+        self.tick += 1;
+        self.size += charge;
+        self.lru.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            CacheEntry {
+                cmd_pos,
+                value,
+                tick: self.tick,
+            },
+        );
+        while self.size > self.capacity {
+            let (_, key) = self.lru.iter().next().expect("over budget with no entries");
+            let key = key.clone();
+            self.remove(&key);
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.tick);
+            self.size -= (key.len() + entry.value.len()) as u64;
+        }
+    }
+}
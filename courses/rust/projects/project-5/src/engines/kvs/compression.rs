@@ -0,0 +1,80 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::{KvsError, Result};
+
+const CODEC_LZ4: u8 = 1;
+const CODEC_ZSTD: u8 = 2;
+
+/// Specifies how `KvStore` compresses the values it writes to the log.
+///
+/// The algorithm is recorded in each compressed record, so a log written with
+/// different settings stays readable. Values shorter than the compression
+/// threshold and values that do not shrink are stored raw.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// Values are stored raw.
+    #[default]
+    None,
+    /// Values are compressed with LZ4, which is fast but compresses less.
+    Lz4,
+    /// Values are compressed with zstd at its default level.
+    Zstd,
+}
+
+impl Compression {
+    /// Compresses `value` and returns the codec id and the compressed bytes.
+    ///
+    /// Returns `None` if the value is not compressed.
+    pub(super) fn compress(self, value: &[u8]) -> Option<(u8, Vec<u8>)> {
+        let (codec, compressed) = match self {
+            Compression::None => return None,
+            Compression::Lz4 => (CODEC_LZ4, lz4_flex::compress_prepend_size(value)),
+            Compression::Zstd => (CODEC_ZSTD, zstd::bulk::compress(value, 0).ok()?),
+        };
+        if compressed.len() < value.len() {
+            Some((codec, compressed))
+        } else {
+            None
+        }
+    }
+}
+
+/// Decompresses a value compressed with the codec `codec`.
+///
+/// # Errors
+///
+/// It returns `KvsError::CorruptedLog` if the codec is unknown or the value cannot
+/// be decompressed.
+pub(super) fn decompress(codec: u8, compressed: &[u8]) -> Result<Vec<u8>> {
+    let value = match codec {
+        CODEC_LZ4 => lz4_flex::decompress_size_prepended(compressed).map_err(|e| e.to_string()),
+        CODEC_ZSTD => zstd::stream::decode_all(compressed).map_err(|e| e.to_string()),
+        codec => Err(format!("unknown compression codec {}", codec)),
+    };
+    value.map_err(|e| KvsError::CorruptedLog(format!("cannot decompress the value: {}", e)))
+}
+
+impl FromStr for Compression {
+    type Err = KvsError;
+
+    /// Parses `none`, `lz4` or `zstd`.
+    fn from_str(s: &str) -> Result<Compression> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(KvsError::StringError(format!("Invalid compression: {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Compression::None => write!(f, "none"),
+            Compression::Lz4 => write!(f, "lz4"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
@@ -0,0 +1,574 @@
+//! Index files of `IndexBackend::Disk`.
+//!
+//! A compaction writes `<gen>.index` next to the compaction file `<gen>.log`. It
+//! lists the location of every record in the log in key order, split into pages
+//! of about 4 KiB, followed by a page table with the first key of every page:
+//!
+//! ```text
+//! | magic `KVSI` | version: u32 |
+//! | crc32 of the entries: u32 | key length: u32 | key | pos: u64 | len: u64 | expires at: u64 | ... |
+//! | ... more pages |
+//! | first key length: u32 | first key | page offset: u64 | ... one per page |
+//! | log length: u64 | entry count: u64 | page table offset: u64 |
+//! | crc32 of the page table and the three fields above: u32 |
+//! ```
+//!
+//! All integers are little endian. The expiry time is in milliseconds since the
+//! UNIX epoch, or 0 if the key does not expire. Only the page table is kept in
+//! memory, and a lookup reads a single page. An index file whose page table does
+//! not match its log is ignored and the log is replayed instead.
+
+use std::cmp::Ordering;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::iter::Peekable;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::vec;
+
+use crossbeam::atomic::AtomicCell;
+use crossbeam_skiplist::SkipMap;
+
+use super::hint::{take, to_array};
+use super::index::{Entries, IndexEntry, KeyIndex, KeyRange};
+use super::{hint_path, index_path, log_path, CommandPos};
+use crate::{KvsError, Result};
+
+const MAGIC: &[u8; 4] = b"KVSI";
+const VERSION: u32 = 1;
+const HEADER_LEN: u64 = 8;
+const FOOTER_LEN: u64 = 28;
+const PAGE_SIZE: usize = 4096;
+
+/// An index that keeps the entries of the latest compaction file in an index
+/// file, and only the entries changed since then in memory.
+///
+/// A compaction copies all live records to the compaction file, so it rebuilds
+/// the index file from the merged entries and drops the older entries from
+/// memory.
+pub(super) struct DiskIndex {
+    dir: PathBuf,
+    state: RwLock<Arc<IndexState>>,
+    // the number of changed entries kept in memory before a rebuild is needed
+    memory_limit: usize,
+}
+
+/// An index file with the entries changed after it was written.
+///
+/// It is replaced as a whole when a compaction finishes, so a reader that has
+/// cloned it sees a consistent index.
+struct IndexState {
+    file: Option<IndexFile>,
+    delta: SkipMap<Vec<u8>, AtomicCell<Delta>>,
+}
+
+impl IndexState {
+    /// Records a change of `key`.
+    ///
+    /// A changed entry is updated in place, because replacing it in the map
+    /// removes it first and a reader could fall back to the index file.
+    fn change(&self, key: Vec<u8>, change: Delta) {
+        match self.delta.get(&key) {
+            Some(entry) => entry.value().store(change),
+            None => {
+                self.delta.insert(key, AtomicCell::new(change));
+            }
+        }
+    }
+}
+
+/// An entry changed after the index file was written
+#[derive(Debug, Clone, Copy)]
+enum Delta {
+    Set(CommandPos),
+    /// The key was removed by a `remove` record in the log file of the generation
+    Removed(u64),
+}
+
+impl Delta {
+    /// Returns the generation of the log file with the record of the change.
+    fn gen(&self) -> u64 {
+        match self {
+            Delta::Set(cmd_pos) => cmd_pos.gen,
+            Delta::Removed(gen) => *gen,
+        }
+    }
+
+    fn cmd_pos(&self) -> Option<CommandPos> {
+        match self {
+            Delta::Set(cmd_pos) => Some(*cmd_pos),
+            Delta::Removed(_) => None,
+        }
+    }
+}
+
+impl DiskIndex {
+    /// Opens the index of the data directory `dir` with the log files `gen_list`.
+    ///
+    /// The latest valid index file is used unless a compaction without an index
+    /// file has run after it. The log files after the index file must be loaded
+    /// into the index afterwards, see `file_gen`.
+    pub(super) fn open(dir: &Path, gen_list: &[u64], memory_limit: usize) -> Result<DiskIndex> {
+        remove_partial_files(dir)?;
+        let mut file = None;
+        for &gen in gen_list.iter().rev() {
+            let log_len = fs::metadata(log_path(dir, gen))?.len();
+            if let Some(index_file) = IndexFile::open(&index_path(dir, gen), gen, log_len)? {
+                file = Some(index_file);
+                break;
+            }
+            // a compaction file with a hint was written without an index, so it
+            // holds entries missing from older index files
+            if hint_path(dir, gen).exists() {
+                break;
+            }
+        }
+        Ok(DiskIndex {
+            dir: dir.to_owned(),
+            state: RwLock::new(Arc::new(IndexState {
+                file,
+                delta: SkipMap::new(),
+            })),
+            memory_limit,
+        })
+    }
+
+    /// Returns the generation of the log file covered by the index file, or 0 if
+    /// there is no index file.
+    ///
+    /// The index holds the entries of this and all older log files.
+    pub(super) fn file_gen(&self) -> u64 {
+        self.state().file.as_ref().map_or(0, |file| file.gen)
+    }
+
+    fn state(&self) -> Arc<IndexState> {
+        Arc::clone(&self.state.read().unwrap())
+    }
+}
+
+impl KeyIndex for DiskIndex {
+    fn get(&self, key: &[u8]) -> Result<Option<CommandPos>> {
+        let state = self.state();
+        if let Some(entry) = state.delta.get(key) {
+            return Ok(entry.value().load().cmd_pos());
+        }
+        match &state.file {
+            Some(file) => file.get(key),
+            None => Ok(None),
+        }
+    }
+
+    fn insert(&self, key: Vec<u8>, cmd_pos: CommandPos) {
+        self.state().change(key, Delta::Set(cmd_pos));
+    }
+
+    fn remove(&self, key: &[u8], gen: u64) -> Result<Option<CommandPos>> {
+        let state = self.state();
+        let old = self.get(key)?;
+        if old.is_some() {
+            // the index file may still hold the key
+            state.change(key.to_vec(), Delta::Removed(gen));
+        }
+        Ok(old)
+    }
+
+    fn range(&self, range: KeyRange) -> Entries<'_> {
+        Box::new(StateEntries::new(self.state(), range))
+    }
+
+    fn needs_rebuild(&self) -> bool {
+        self.state().delta.len() > self.memory_limit
+    }
+
+    fn create_file(&self, gen: u64, sync: bool) -> Result<Option<IndexFileWriter>> {
+        Ok(Some(IndexFileWriter::create(&self.dir, gen, sync)?))
+    }
+
+    fn install(&self, file: IndexFile) {
+        let mut state = self.state.write().unwrap();
+        // changes written after the compaction started are newer than the file
+        let delta = SkipMap::new();
+        for entry in state.delta.iter() {
+            let change = entry.value().load();
+            if change.gen() > file.gen {
+                delta.insert(entry.key().clone(), AtomicCell::new(change));
+            }
+        }
+        *state = Arc::new(IndexState {
+            file: Some(file),
+            delta,
+        });
+    }
+}
+
+/// The entries of an `IndexState` in a range, with the changed entries merged
+/// over the entries of the index file.
+struct StateEntries {
+    state: Arc<IndexState>,
+    range: KeyRange,
+    // the next changed entry and the bound of the changed entries after it
+    next_delta: Option<(Vec<u8>, Delta)>,
+    delta_from: Bound<Vec<u8>>,
+    delta_done: bool,
+    // the remaining entries of the last page read and the next page to read
+    page: Peekable<vec::IntoIter<IndexEntry>>,
+    next_page: usize,
+    failed: bool,
+}
+
+impl StateEntries {
+    fn new(state: Arc<IndexState>, range: KeyRange) -> StateEntries {
+        let next_page = match (&state.file, &range.0) {
+            (Some(file), Bound::Included(key)) | (Some(file), Bound::Excluded(key)) => {
+                file.page_of(key).unwrap_or(0)
+            }
+            _ => 0,
+        };
+        StateEntries {
+            delta_from: range.0.clone(),
+            state,
+            range,
+            next_delta: None,
+            delta_done: false,
+            page: Vec::new().into_iter().peekable(),
+            next_page,
+            failed: false,
+        }
+    }
+
+    /// Looks up the next changed entry if it is not looked up yet.
+    fn peek_delta(&mut self) {
+        if self.next_delta.is_some() || self.delta_done {
+            return;
+        }
+        let range = (self.delta_from.clone(), self.range.1.clone());
+        match self.state.delta.range(range).next() {
+            Some(entry) => {
+                self.delta_from = Bound::Excluded(entry.key().clone());
+                self.next_delta = Some((entry.key().clone(), entry.value().load()));
+            }
+            None => self.delta_done = true,
+        }
+    }
+
+    /// Reads pages of the index file until an entry in the range is found or the
+    /// range is passed.
+    fn fill_page(&mut self) -> Result<()> {
+        let state = Arc::clone(&self.state);
+        let file = match &state.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        while self.page.peek().is_none() && self.next_page < file.pages.len() {
+            let entries = file.read_page(self.next_page)?;
+            self.next_page += 1;
+            if let Some((key, _)) = entries.last() {
+                if past_end(key, &self.range.1) {
+                    self.next_page = file.pages.len();
+                }
+            }
+            let range = &self.range;
+            self.page = entries
+                .into_iter()
+                .filter(|(key, _)| range.contains(key))
+                .collect::<Vec<_>>()
+                .into_iter()
+                .peekable();
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for StateEntries {
+    type Item = Result<IndexEntry>;
+
+    fn next(&mut self) -> Option<Result<IndexEntry>> {
+        if self.failed {
+            return None;
+        }
+        loop {
+            self.peek_delta();
+            if let Err(e) = self.fill_page() {
+                self.failed = true;
+                return Some(Err(e));
+            }
+            let order = match (&self.next_delta, self.page.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((delta_key, _)), Some((file_key, _))) => delta_key.cmp(file_key),
+            };
+            if order != Ordering::Less {
+                let entry = self.page.next().unwrap();
+                if order == Ordering::Greater {
+                    return Some(Ok(entry));
+                }
+                // the changed entry replaces the entry of the file
+            }
+            let (key, delta) = self.next_delta.take().unwrap();
+            if let Delta::Set(cmd_pos) = delta {
+                return Some(Ok((key, cmd_pos)));
+            }
+        }
+    }
+}
+
+/// Returns whether `key` is after the end bound `end`.
+fn past_end(key: &[u8], end: &Bound<Vec<u8>>) -> bool {
+    match end {
+        Bound::Included(end) => key > end.as_slice(),
+        Bound::Excluded(end) => key >= end.as_slice(),
+        Bound::Unbounded => false,
+    }
+}
+
+/// An index file of a compaction file
+pub(super) struct IndexFile {
+    // generation of the compaction file
+    gen: u64,
+    file: Mutex<File>,
+    // the first key and the offset of every page
+    pages: Vec<(Vec<u8>, u64)>,
+    table_offset: u64,
+}
+
+impl IndexFile {
+    /// Opens the index file of the log file `gen` of `log_len` bytes.
+    ///
+    /// Returns `None` if the index file does not exist or is not valid for the log.
+    fn open(path: &Path, gen: u64, log_len: u64) -> Result<Option<IndexFile>> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let len = file.metadata()?.len();
+        let pages = if len < HEADER_LEN + FOOTER_LEN {
+            None
+        } else {
+            let mut header = [0; HEADER_LEN as usize];
+            file.read_exact(&mut header)?;
+            let mut footer = [0; FOOTER_LEN as usize];
+            file.seek(SeekFrom::Start(len - FOOTER_LEN))?;
+            file.read_exact(&mut footer)?;
+            let table_offset = u64::from_le_bytes(to_array(&footer[16..24]).unwrap());
+            if &header[..4] == MAGIC
+                && header[4..] == VERSION.to_le_bytes()
+                && footer[..8] == log_len.to_le_bytes()
+                && table_offset >= HEADER_LEN
+                && table_offset <= len - FOOTER_LEN
+            {
+                let mut table = vec![0; (len - table_offset) as usize];
+                file.seek(SeekFrom::Start(table_offset))?;
+                file.read_exact(&mut table)?;
+                decode_table(&table).map(|pages| (pages, table_offset))
+            } else {
+                None
+            }
+        };
+        match pages {
+            Some((pages, table_offset)) => Ok(Some(IndexFile {
+                gen,
+                file: Mutex::new(file),
+                pages,
+                table_offset,
+            })),
+            None => {
+                warn!("Ignoring invalid index file {:?}", path);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Returns the entry of `key` in the index file.
+    fn get(&self, key: &[u8]) -> Result<Option<CommandPos>> {
+        let page = match self.page_of(key) {
+            Some(page) => page,
+            None => return Ok(None),
+        };
+        let entries = self.read_page(page)?;
+        Ok(entries
+            .binary_search_by(|(entry_key, _)| entry_key.as_slice().cmp(key))
+            .ok()
+            .map(|i| entries[i].1))
+    }
+
+    /// Returns the page that would contain `key`.
+    fn page_of(&self, key: &[u8]) -> Option<usize> {
+        match self
+            .pages
+            .binary_search_by(|(first_key, _)| first_key.as_slice().cmp(key))
+        {
+            Ok(page) => Some(page),
+            Err(0) => None,
+            Err(page) => Some(page - 1),
+        }
+    }
+
+    /// Reads the entries of a page.
+    fn read_page(&self, page: usize) -> Result<Vec<IndexEntry>> {
+        let start = self.pages[page].1;
+        let end = self
+            .pages
+            .get(page + 1)
+            .map_or(self.table_offset, |(_, offset)| *offset);
+        let mut buf = vec![0; (end - start) as usize];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(start))?;
+            file.read_exact(&mut buf)?;
+        }
+        decode_page(&buf, self.gen).ok_or_else(|| {
+            KvsError::CorruptedLog(format!("corrupted page at {} of {}.index", start, self.gen))
+        })
+    }
+}
+
+fn decode_page(buf: &[u8], gen: u64) -> Option<Vec<IndexEntry>> {
+    let mut body = buf;
+    let crc = u32::from_le_bytes(to_array(take(&mut body, 4)?)?);
+    if crc32fast::hash(body) != crc {
+        return None;
+    }
+    let mut entries = Vec::new();
+    while !body.is_empty() {
+        let key_len = u32::from_le_bytes(to_array(take(&mut body, 4)?)?);
+        let key = take(&mut body, key_len as usize)?.to_vec();
+        let pos = u64::from_le_bytes(to_array(take(&mut body, 8)?)?);
+        let len = u64::from_le_bytes(to_array(take(&mut body, 8)?)?);
+        let expires_at = u64::from_le_bytes(to_array(take(&mut body, 8)?)?);
+        let expires_at = Some(expires_at).filter(|&t| t != 0);
+        entries.push((key, CommandPos::new(gen, pos..pos + len, expires_at)));
+    }
+    Some(entries)
+}
+
+fn decode_table(buf: &[u8]) -> Option<Vec<(Vec<u8>, u64)>> {
+    let (body, crc) = buf.split_at(buf.len() - 4);
+    if crc32fast::hash(body) != u32::from_le_bytes(to_array(crc)?) {
+        return None;
+    }
+    let (mut table, _) = body.split_at(body.len() - 24);
+    let mut pages = Vec::new();
+    while !table.is_empty() {
+        let key_len = u32::from_le_bytes(to_array(take(&mut table, 4)?)?);
+        let key = take(&mut table, key_len as usize)?.to_vec();
+        let offset = u64::from_le_bytes(to_array(take(&mut table, 8)?)?);
+        pages.push((key, offset));
+    }
+    Some(pages)
+}
+
+/// Writes the index file of a compaction file.
+///
+/// The entries must be added in key order. The file is written under a temporary
+/// name and renamed when it is finished.
+pub(super) struct IndexFileWriter {
+    gen: u64,
+    path: PathBuf,
+    tmp_path: PathBuf,
+    writer: BufWriter<File>,
+    // offset of the next page
+    pos: u64,
+    // entries of the page being written
+    page: Vec<u8>,
+    pages: Vec<(Vec<u8>, u64)>,
+    count: u64,
+    sync: bool,
+}
+
+impl IndexFileWriter {
+    fn create(dir: &Path, gen: u64, sync: bool) -> Result<IndexFileWriter> {
+        let path = index_path(dir, gen);
+        let mut tmp_name = path.file_name().unwrap_or_default().to_owned();
+        tmp_name.push(".partial");
+        let tmp_path = path.with_file_name(tmp_name);
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        Ok(IndexFileWriter {
+            gen,
+            path,
+            tmp_path,
+            writer,
+            pos: HEADER_LEN,
+            page: Vec::new(),
+            pages: Vec::new(),
+            count: 0,
+            sync,
+        })
+    }
+
+    /// Adds the entry of the next key.
+    pub(super) fn add(&mut self, key: &[u8], cmd_pos: CommandPos) -> Result<()> {
+        if self.page.is_empty() {
+            self.pages.push((key.to_vec(), self.pos));
+        }
+        self.page
+            .extend_from_slice(&(key.len() as u32).to_le_bytes());
+        self.page.extend_from_slice(key);
+        self.page.extend_from_slice(&cmd_pos.pos.to_le_bytes());
+        self.page.extend_from_slice(&cmd_pos.len.to_le_bytes());
+        self.page
+            .extend_from_slice(&cmd_pos.expires_at.unwrap_or(0).to_le_bytes());
+        self.count += 1;
+        if self.page.len() >= PAGE_SIZE {
+            self.write_page()?;
+        }
+        Ok(())
+    }
+
+    fn write_page(&mut self) -> Result<()> {
+        let crc = crc32fast::hash(&self.page);
+        self.writer.write_all(&crc.to_le_bytes())?;
+        self.writer.write_all(&self.page)?;
+        self.pos += 4 + self.page.len() as u64;
+        self.page.clear();
+        Ok(())
+    }
+
+    /// Writes the page table of the compaction file of `log_len` bytes and
+    /// renames the index file to its final name.
+    pub(super) fn finish(mut self, log_len: u64) -> Result<IndexFile> {
+        if !self.page.is_empty() {
+            self.write_page()?;
+        }
+        let mut table = Vec::new();
+        for (key, offset) in &self.pages {
+            table.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            table.extend_from_slice(key);
+            table.extend_from_slice(&offset.to_le_bytes());
+        }
+        table.extend_from_slice(&log_len.to_le_bytes());
+        table.extend_from_slice(&self.count.to_le_bytes());
+        table.extend_from_slice(&self.pos.to_le_bytes());
+        let crc = crc32fast::hash(&table);
+        table.extend_from_slice(&crc.to_le_bytes());
+        self.writer.write_all(&table)?;
+        self.writer.flush()?;
+        if self.sync {
+            self.writer.get_ref().sync_data()?;
+        }
+        fs::rename(&self.tmp_path, &self.path)?;
+        Ok(IndexFile {
+            gen: self.gen,
+            file: Mutex::new(File::open(&self.path)?),
+            pages: self.pages,
+            table_offset: self.pos,
+        })
+    }
+}
+
+/// Removes the index files left by interrupted compactions.
+fn remove_partial_files(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().and_then(OsStr::to_str).unwrap_or_default();
+        if name.ends_with(".index.partial") {
+            fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,367 @@
+//! The on-disk format of the log files.
+//!
+//! Every log file starts with a header of the magic bytes `KVSL` and a little
+//! endian `u32` format version. The header is followed by records:
+//!
+//! ```text
+//! | payload length: u32 | crc32 of payload: u32 | payload |
+//! ```
+//!
+//! The payload is a single encoded `Command`. The checksum detects records that
+//! were torn by a crash or corrupted on the disk. A `set` command with a TTL
+//! stores its absolute expiry time in milliseconds since the UNIX epoch.
+//!
+//! The value of a `set` command may be compressed. Such a record stores the id of
+//! the compression codec, and the value is decompressed when the record is read.
+//!
+//! Log files written by older versions contain concatenated JSON commands and no
+//! header. They can be converted with `convert_legacy`.
+
+use std::io::{self, Read, Write};
+
+use serde::Deserialize;
+use serde_json::Deserializer;
+
+use super::compression::{self, Compression};
+use crate::engines::batch::BatchOp;
+use crate::{KvsError, Result};
+
+const MAGIC: &[u8; 4] = b"KVSL";
+const VERSION: u32 = 1;
+pub const HEADER_LEN: usize = 8;
+
+const TAG_SET: u8 = 0;
+const TAG_REMOVE: u8 = 1;
+const TAG_BATCH: u8 = 2;
+const TAG_SET_EXPIRING: u8 = 3;
+const TAG_SET_COMPRESSED: u8 = 4;
+
+/// How the values of `set` commands are compressed when they are written.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ValueCompression {
+    pub compression: Compression,
+    /// Values shorter than this are stored raw
+    pub threshold: usize,
+}
+
+/// The format of a log file, detected from its header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Binary records after a valid header
+    Binary,
+    /// Concatenated JSON commands without a header
+    Legacy,
+    /// No complete header. The file was cut off by a crash right after it
+    /// was created, so it contains no commands.
+    Empty,
+}
+
+/// Struct representing a command
+#[derive(Debug, Clone)]
+pub enum Command {
+    Set {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        /// Expiry time in milliseconds since the UNIX epoch
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    /// Header of a batch followed by `len` `set` or `remove` commands
+    Batch {
+        len: u64,
+    },
+}
+
+/// A command in a legacy log file, where keys and values were strings
+#[derive(Deserialize)]
+enum LegacyCommand {
+    Set { key: String, value: String },
+    Remove { key: String },
+    Batch { len: u64 },
+}
+
+impl Command {
+    pub fn set(key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Command {
+        Command::Set {
+            key,
+            value,
+            expires_at,
+        }
+    }
+
+    pub fn remove(key: Vec<u8>) -> Command {
+        Command::Remove { key }
+    }
+
+    pub fn batch(len: usize) -> Command {
+        Command::Batch { len: len as u64 }
+    }
+
+    /// Returns the key written by a `set` or `remove` command.
+    pub fn key(&self) -> Option<&[u8]> {
+        match self {
+            Command::Set { key, .. } | Command::Remove { key } => Some(key),
+            Command::Batch { .. } => None,
+        }
+    }
+
+    fn encode(&self, buf: &mut Vec<u8>, compression: ValueCompression) {
+        if let Command::Set {
+            key,
+            value,
+            expires_at,
+        } = self
+        {
+            if value.len() >= compression.threshold {
+                if let Some((codec, compressed)) = compression.compression.compress(value) {
+                    // an expiry time of 0 stands for none
+                    buf.push(TAG_SET_COMPRESSED);
+                    buf.push(codec);
+                    encode_bytes(key, buf);
+                    encode_bytes(&compressed, buf);
+                    buf.extend_from_slice(&expires_at.unwrap_or(0).to_le_bytes());
+                    return;
+                }
+            }
+        }
+        match self {
+            Command::Set {
+                key,
+                value,
+                expires_at: None,
+            } => {
+                buf.push(TAG_SET);
+                encode_bytes(key, buf);
+                encode_bytes(value, buf);
+            }
+            Command::Set {
+                key,
+                value,
+                expires_at: Some(expires_at),
+            } => {
+                buf.push(TAG_SET_EXPIRING);
+                encode_bytes(key, buf);
+                encode_bytes(value, buf);
+                buf.extend_from_slice(&expires_at.to_le_bytes());
+            }
+            Command::Remove { key } => {
+                buf.push(TAG_REMOVE);
+                encode_bytes(key, buf);
+            }
+            Command::Batch { len } => {
+                buf.push(TAG_BATCH);
+                buf.extend_from_slice(&len.to_le_bytes());
+            }
+        }
+    }
+
+    fn decode(mut buf: &[u8]) -> Result<Command> {
+        let buf = &mut buf;
+        let cmd = match take(buf, 1)?[0] {
+            TAG_SET => Command::Set {
+                key: decode_bytes(buf)?,
+                value: decode_bytes(buf)?,
+                expires_at: None,
+            },
+            TAG_SET_EXPIRING => Command::Set {
+                key: decode_bytes(buf)?,
+                value: decode_bytes(buf)?,
+                expires_at: Some(decode_u64(buf)?),
+            },
+            TAG_SET_COMPRESSED => {
+                let codec = take(buf, 1)?[0];
+                let key = decode_bytes(buf)?;
+                let value = compression::decompress(codec, &decode_bytes(buf)?)?;
+                let expires_at = Some(decode_u64(buf)?).filter(|&expires_at| expires_at != 0);
+                Command::Set {
+                    key,
+                    value,
+                    expires_at,
+                }
+            }
+            TAG_REMOVE => Command::Remove {
+                key: decode_bytes(buf)?,
+            },
+            TAG_BATCH => Command::Batch {
+                len: decode_u64(buf)?,
+            },
+            tag => return Err(corrupted(format!("unknown command tag {}", tag))),
+        };
+        if !buf.is_empty() {
+            return Err(corrupted("trailing bytes in the record"));
+        }
+        Ok(cmd)
+    }
+}
+
+impl From<LegacyCommand> for Command {
+    fn from(cmd: LegacyCommand) -> Command {
+        match cmd {
+            LegacyCommand::Set { key, value } => {
+                Command::set(key.into_bytes(), value.into_bytes(), None)
+            }
+            LegacyCommand::Remove { key } => Command::remove(key.into_bytes()),
+            LegacyCommand::Batch { len } => Command::Batch { len },
+        }
+    }
+}
+
+impl From<BatchOp> for Command {
+    fn from(op: BatchOp) -> Command {
+        match op {
+            BatchOp::Set { key, value } => Command::set(key, value, None),
+            BatchOp::Remove { key } => Command::remove(key),
+        }
+    }
+}
+
+/// Writes the header of a new log file.
+pub fn write_header<W: Write>(writer: &mut W) -> Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    Ok(())
+}
+
+/// Reads the header at the beginning of a log file.
+///
+/// # Errors
+///
+/// It returns `KvsError::UnsupportedLogVersion` if the file is written in a newer
+/// format.
+pub fn read_header<R: Read>(reader: &mut R) -> Result<LogFormat> {
+    let mut header = [0; HEADER_LEN];
+    let len = read_full(reader, &mut header)?;
+    if len < HEADER_LEN {
+        // legacy commands are always longer than the header
+        return if header[..len].iter().zip(MAGIC).all(|(a, b)| a == b) {
+            Ok(LogFormat::Empty)
+        } else {
+            Ok(LogFormat::Legacy)
+        };
+    }
+    if &header[..4] != MAGIC {
+        return Ok(LogFormat::Legacy);
+    }
+    let mut version = [0; 4];
+    version.copy_from_slice(&header[4..]);
+    match u32::from_le_bytes(version) {
+        VERSION => Ok(LogFormat::Binary),
+        version => Err(KvsError::UnsupportedLogVersion(version)),
+    }
+}
+
+/// Writes a command as a record.
+///
+/// The value of a `set` command is compressed as `compression` specifies.
+pub fn write_record<W: Write>(
+    writer: &mut W,
+    cmd: &Command,
+    compression: ValueCompression,
+) -> Result<()> {
+    let mut payload = Vec::new();
+    cmd.encode(&mut payload, compression);
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+    writer.write_all(&payload)?;
+    Ok(())
+}
+
+/// Reads the next record.
+///
+/// Returns `None` if the reader is at the end.
+///
+/// # Errors
+///
+/// It returns `KvsError::CorruptedLog` if the record is incomplete or its checksum
+/// does not match.
+pub fn read_record<R: Read>(reader: &mut R) -> Result<Option<Command>> {
+    let mut header = [0; 8];
+    match read_full(reader, &mut header)? {
+        0 => return Ok(None),
+        8 => (),
+        _ => return Err(corrupted("incomplete record header")),
+    }
+    let mut len = [0; 4];
+    len.copy_from_slice(&header[..4]);
+    let len = u32::from_le_bytes(len) as u64;
+    let mut crc = [0; 4];
+    crc.copy_from_slice(&header[4..]);
+    let crc = u32::from_le_bytes(crc);
+
+    // the length may be corrupted, so the buffer is not allocated up front
+    let mut payload = Vec::new();
+    reader.take(len).read_to_end(&mut payload)?;
+    if payload.len() as u64 != len {
+        return Err(corrupted("incomplete record"));
+    }
+    if crc32fast::hash(&payload) != crc {
+        return Err(corrupted("checksum mismatch"));
+    }
+    Command::decode(&payload).map(Some)
+}
+
+/// Converts concatenated JSON commands of a legacy log file to the binary format.
+///
+/// A command cut off at the end of the legacy log is dropped.
+pub fn convert_legacy<R: Read, W: Write>(reader: R, writer: &mut W) -> Result<()> {
+    write_header(writer)?;
+    for cmd in Deserializer::from_reader(reader).into_iter::<LegacyCommand>() {
+        match cmd {
+            Ok(cmd) => write_record(writer, &cmd.into(), ValueCompression::default())?,
+            Err(ref e) if e.is_eof() => {
+                warn!("Ignoring incomplete command at the end of the legacy log");
+                break;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
+fn encode_bytes(bytes: &[u8], buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn decode_bytes(buf: &mut &[u8]) -> Result<Vec<u8>> {
+    let mut len = [0; 4];
+    len.copy_from_slice(take(buf, 4)?);
+    Ok(take(buf, u32::from_le_bytes(len) as usize)?.to_vec())
+}
+
+fn decode_u64(buf: &mut &[u8]) -> Result<u64> {
+    let mut n = [0; 8];
+    n.copy_from_slice(take(buf, 8)?);
+    Ok(u64::from_le_bytes(n))
+}
+
+/// Splits `len` bytes off the front of `buf`.
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if buf.len() < len {
+        return Err(corrupted("record is too short"));
+    }
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+    Ok(head)
+}
+
+/// Reads until `buf` is full or the reader is at the end, and returns how many
+/// bytes are read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(len)
+}
+
+fn corrupted(msg: impl Into<String>) -> KvsError {
+    KvsError::CorruptedLog(msg.into())
+}
@@ -0,0 +1,117 @@
+//! Hint files of compacted log files.
+//!
+//! A compaction writes `<gen>.hint` next to the compaction file `<gen>.log`. It
+//! lists the location of every record in the log, so the index can be rebuilt
+//! without reading the values:
+//!
+//! ```text
+//! | magic `KVSH` | version: u32 | log length: u64 |
+//! | key length: u32 | key | pos: u64 | len: u64 | expires at: u64 | ...
+//! | crc32 of all preceding bytes: u32 |
+//! ```
+//!
+//! All integers are little endian. The expiry time is in milliseconds since the
+//! UNIX epoch, or 0 if the key does not expire. A hint whose checksum, version or
+//! log length does not match is ignored and the log is replayed instead.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use crate::Result;
+
+const MAGIC: &[u8; 4] = b"KVSH";
+const VERSION: u32 = 2;
+
+/// A key, the range of its record in the log and its expiry time
+pub type HintEntry = (Vec<u8>, Range<u64>, Option<u64>);
+
+/// Writes the hint of a log file of `log_len` bytes containing `entries`.
+pub fn write<'a, I>(path: &Path, log_len: u64, entries: I, sync: bool) -> Result<()>
+where
+    I: IntoIterator<Item = (&'a [u8], Range<u64>, Option<u64>)>,
+{
+    let mut buf = Vec::new();
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&VERSION.to_le_bytes());
+    buf.extend_from_slice(&log_len.to_le_bytes());
+    for (key, range, expires_at) in entries {
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(&range.start.to_le_bytes());
+        buf.extend_from_slice(&(range.end - range.start).to_le_bytes());
+        buf.extend_from_slice(&expires_at.unwrap_or(0).to_le_bytes());
+    }
+    let crc = crc32fast::hash(&buf);
+    buf.extend_from_slice(&crc.to_le_bytes());
+
+    let mut file = File::create(path)?;
+    file.write_all(&buf)?;
+    if sync {
+        file.sync_data()?;
+    }
+    Ok(())
+}
+
+/// Reads the hint of a log file of `log_len` bytes.
+///
+/// Returns `None` if the hint does not exist or is not valid for the log.
+pub fn read(path: &Path, log_len: u64) -> Result<Option<Vec<HintEntry>>> {
+    let buf = match fs::read(path) {
+        Ok(buf) => buf,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let entries = decode(&buf, log_len);
+    if entries.is_none() {
+        warn!("Ignoring invalid hint file {:?}", path);
+    }
+    Ok(entries)
+}
+
+fn decode(buf: &[u8], log_len: u64) -> Option<Vec<HintEntry>> {
+    if buf.len() < 4 {
+        return None;
+    }
+    let (mut body, crc) = buf.split_at(buf.len() - 4);
+    if crc32fast::hash(body) != u32::from_le_bytes(to_array(crc)?) {
+        return None;
+    }
+    if take(&mut body, 4)? != MAGIC
+        || u32::from_le_bytes(to_array(take(&mut body, 4)?)?) != VERSION
+        || u64::from_le_bytes(to_array(take(&mut body, 8)?)?) != log_len
+    {
+        return None;
+    }
+
+    let mut entries = Vec::new();
+    while !body.is_empty() {
+        let key_len = u32::from_le_bytes(to_array(take(&mut body, 4)?)?);
+        let key = take(&mut body, key_len as usize)?.to_vec();
+        let pos = u64::from_le_bytes(to_array(take(&mut body, 8)?)?);
+        let len = u64::from_le_bytes(to_array(take(&mut body, 8)?)?);
+        let expires_at = u64::from_le_bytes(to_array(take(&mut body, 8)?)?);
+        entries.push((key, pos..pos + len, Some(expires_at).filter(|&t| t != 0)));
+    }
+    Some(entries)
+}
+
+/// Splits `len` bytes off the front of `buf`.
+pub fn take<'a>(buf: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if buf.len() < len {
+        return None;
+    }
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+    Some(head)
+}
+
+pub fn to_array<A: Default + AsMut<[u8]>>(bytes: &[u8]) -> Option<A> {
+    let mut array = A::default();
+    if array.as_mut().len() != bytes.len() {
+        return None;
+    }
+    array.as_mut().copy_from_slice(bytes);
+    Some(array)
+}
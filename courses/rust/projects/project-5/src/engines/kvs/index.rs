@@ -0,0 +1,142 @@
+use std::fmt;
+use std::ops::Bound;
+use std::str::FromStr;
+
+use crossbeam::atomic::AtomicCell;
+use crossbeam_skiplist::SkipMap;
+
+use super::disk_index::{IndexFile, IndexFileWriter};
+use super::CommandPos;
+use crate::{KvsError, Result};
+
+/// A key with the location of its latest `set` record
+pub(super) type IndexEntry = (Vec<u8>, CommandPos);
+
+/// A range of keys given by its start and end bounds
+pub(super) type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+/// Index entries in key order
+pub(super) type Entries<'a> = Box<dyn Iterator<Item = Result<IndexEntry>> + 'a>;
+
+/// Specifies where `KvStore` keeps the index of its keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexBackend {
+    /// Every key and the location of its value are kept in a skip list in memory.
+    #[default]
+    Memory,
+    /// Keys are kept in a sorted index file written by each compaction, with the
+    /// first key of every page of the file in memory. Only the keys changed since
+    /// the last compaction are kept in memory, so stores with more keys than fit
+    /// in memory can be opened.
+    Disk,
+}
+
+/// The index of a `KvStore`, mapping every live key to the location of its
+/// latest `set` record.
+///
+/// Reads may run concurrently with each other and with a single writer. All
+/// changes are made under the writer lock.
+pub(super) trait KeyIndex: Send + Sync {
+    /// Returns the index entry of `key`.
+    fn get(&self, key: &[u8]) -> Result<Option<CommandPos>>;
+
+    /// Points `key` to a `set` record.
+    fn insert(&self, key: Vec<u8>, cmd_pos: CommandPos);
+
+    /// Removes the entry of `key` for a `remove` record in the log file `gen`,
+    /// and returns the removed entry.
+    fn remove(&self, key: &[u8], gen: u64) -> Result<Option<CommandPos>>;
+
+    /// Iterates the entries of the keys in `range` in key order.
+    fn range(&self, range: KeyRange) -> Entries<'_>;
+
+    /// Returns whether the index keeps so many entries in memory that it should be
+    /// rebuilt by a compaction.
+    fn needs_rebuild(&self) -> bool {
+        false
+    }
+
+    /// Creates the index file of the compaction file `gen`.
+    ///
+    /// Returns `None` if the index has no files and is updated entry by entry
+    /// after a compaction.
+    fn create_file(&self, _gen: u64, _sync: bool) -> Result<Option<IndexFileWriter>> {
+        Ok(None)
+    }
+
+    /// Replaces the index with the index file of a finished compaction.
+    ///
+    /// Entries of log files older than the compaction file are dropped from
+    /// memory, because the index file holds all of them that are still live.
+    fn install(&self, _file: IndexFile) {}
+}
+
+/// An index kept in memory as a whole
+///
+/// Positions are updated in place, because replacing an entry of the map
+/// removes it before the new one is linked and readers could miss the key.
+pub(super) struct MemoryIndex {
+    map: SkipMap<Vec<u8>, AtomicCell<CommandPos>>,
+}
+
+impl MemoryIndex {
+    pub(super) fn new() -> MemoryIndex {
+        MemoryIndex {
+            map: SkipMap::new(),
+        }
+    }
+}
+
+impl KeyIndex for MemoryIndex {
+    fn get(&self, key: &[u8]) -> Result<Option<CommandPos>> {
+        Ok(self.map.get(key).map(|entry| entry.value().load()))
+    }
+
+    fn insert(&self, key: Vec<u8>, cmd_pos: CommandPos) {
+        // the index is only changed by the writer, so the entry cannot be
+        // removed in between
+        match self.map.get(&key) {
+            Some(entry) => entry.value().store(cmd_pos),
+            None => {
+                self.map.insert(key, AtomicCell::new(cmd_pos));
+            }
+        }
+    }
+
+    fn remove(&self, key: &[u8], _gen: u64) -> Result<Option<CommandPos>> {
+        Ok(self.map.remove(key).map(|entry| entry.value().load()))
+    }
+
+    fn range(&self, range: KeyRange) -> Entries<'_> {
+        Box::new(
+            self.map
+                .range(range)
+                .map(|entry| Ok((entry.key().clone(), entry.value().load()))),
+        )
+    }
+}
+
+impl FromStr for IndexBackend {
+    type Err = KvsError;
+
+    /// Parses `memory` or `disk`.
+    fn from_str(s: &str) -> Result<IndexBackend> {
+        match s {
+            "memory" => Ok(IndexBackend::Memory),
+            "disk" => Ok(IndexBackend::Disk),
+            _ => Err(KvsError::StringError(format!(
+                "Invalid index backend: {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for IndexBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IndexBackend::Memory => write!(f, "memory"),
+            IndexBackend::Disk => write!(f, "disk"),
+        }
+    }
+}
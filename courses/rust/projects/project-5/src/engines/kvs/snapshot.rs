@@ -0,0 +1,286 @@
+use std::ops::Bound;
+
+use crossbeam_skiplist::SkipMap;
+use tokio::prelude::*;
+use tokio::sync::oneshot;
+
+use super::index::{KeyIndex, KeyRange};
+use super::{now_millis, CommandPos, KvStore};
+use crate::thread_pool::ThreadPool;
+use crate::{CopyStream, KvsError, Result, WatchEvent, WatchSeq};
+
+/// The maximum number of pairs in a chunk of a copy
+const COPY_CHUNK_LEN: usize = 1024;
+
+/// The size in bytes of the log records read for a chunk of a copy
+const COPY_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// The chunk of a copy and the start of the next chunk, `None` after the last one.
+type CopyChunk = (Vec<WatchEvent>, Option<Bound<Vec<u8>>>);
+
+/// Index entries replaced while snapshots are alive.
+///
+/// It maps a key and the sequence number of the write that changed it to the
+/// index entry of the key before the write, or `None` if the key did not exist.
+pub(super) type History = SkipMap<(Vec<u8>, u64), Option<CommandPos>>;
+
+/// A read-only view of a `KvStore` at the time it was created.
+///
+/// It is created by `KvStore::snapshot` and pinned to the sequence number of the
+/// last write before it. Later writes are not visible through the snapshot, and
+/// the log files it may read are kept until all snapshots are dropped.
+///
+/// Keys are checked for expiry at the time they are read.
+pub struct Snapshot<P: ThreadPool> {
+    store: KvStore<P>,
+    seq: u64,
+}
+
+impl<P: ThreadPool> Snapshot<P> {
+    pub(super) fn new(store: KvStore<P>, seq: u64) -> Snapshot<P> {
+        Snapshot { store, seq }
+    }
+
+    /// Returns the sequence number of the last write visible in the snapshot.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Gets the value of a given key in the snapshot.
+    ///
+    /// Returns `None` if the given key did not exist or has expired.
+    pub fn get(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send> {
+        let history = self.store.history.clone();
+        let seq = self.seq;
+        Box::new(
+            self.store
+                .read_entries(move |index| {
+                    let cmd_pos = index.get(&key)?;
+                    Ok(live_at(&history, seq, &key, cmd_pos)
+                        .map(|cmd_pos| (key, cmd_pos))
+                        .into_iter()
+                        .collect())
+                })
+                .map(|pairs| pairs.into_iter().next().map(|(_, value)| value)),
+        )
+    }
+
+    /// Scans key/value pairs in the snapshot whose keys are in the range
+    /// `[start, end)`.
+    pub fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send> {
+        if let Some(end) = &end {
+            if *end <= start {
+                return Box::new(stream::empty());
+            }
+        }
+        let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+        self.scan_keys((Bound::Included(start), end), |_| true, limit)
+    }
+
+    /// Scans key/value pairs in the snapshot whose keys start with `prefix`.
+    pub fn scan_prefix(
+        &self,
+        prefix: Vec<u8>,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send> {
+        let range = (Bound::Included(prefix.clone()), Bound::Unbounded);
+        self.scan_keys(range, move |key| key.starts_with(&prefix), limit)
+    }
+
+    /// Scans the keys in `range` for which `in_range` holds.
+    fn scan_keys<F>(
+        &self,
+        range: KeyRange,
+        in_range: F,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send>
+    where
+        F: Fn(&[u8]) -> bool + Send + 'static,
+    {
+        let history = self.store.history.clone();
+        let seq = self.seq;
+        Box::new(
+            self.store
+                .read_entries(move |index| {
+                    live_entries(index, &history, seq, range, in_range, limit)
+                })
+                .map(stream::iter_ok)
+                .flatten_stream(),
+        )
+    }
+
+    /// Streams all pairs in the snapshot in key order as chunks of
+    /// `WatchEvent::Set` changes at `until`.
+    ///
+    /// A chunk is only read when the stream is polled for it, so the copy is never
+    /// held in memory as a whole. The snapshot is kept until the stream is dropped.
+    pub(super) fn copy(self, until: WatchSeq) -> CopyStream {
+        let chunks = stream::unfold(Some(Bound::Unbounded), move |start| {
+            start.map(|start| self.copy_chunk(start, until))
+        });
+        Box::new(chunks.filter(|chunk| !chunk.is_empty()))
+    }
+
+    /// Reads the chunk of the copy whose keys start at `start`.
+    fn copy_chunk(
+        &self,
+        start: Bound<Vec<u8>>,
+        until: WatchSeq,
+    ) -> Box<dyn Future<Item = CopyChunk, Error = KvsError> + Send> {
+        let index = self.store.index.clone();
+        let history = self.store.history.clone();
+        let reader_pool = self.store.reader_pool.clone();
+        let seq = self.seq;
+        let (tx, rx) = oneshot::channel();
+        self.store.thread_pool.spawn(move || {
+            let reader = reader_pool.pop().unwrap();
+            let range = (start, Bound::Unbounded);
+            let limit = Some(COPY_CHUNK_LEN);
+            let res =
+                live_entries(&*index, &history, seq, range, |_| true, limit).and_then(|entries| {
+                    let read_all = entries.len() < COPY_CHUNK_LEN;
+                    let mut chunk = Vec::new();
+                    let mut size = 0;
+                    for (key, cmd_pos) in entries {
+                        if size >= COPY_CHUNK_SIZE {
+                            // the rest is read again by the next chunk
+                            return Ok(next_chunk(chunk));
+                        }
+                        size += cmd_pos.len;
+                        chunk.push(WatchEvent::Set {
+                            seq: until,
+                            value: reader.read_value(cmd_pos)?,
+                            key,
+                            expires_at: cmd_pos.expires_at,
+                        });
+                    }
+                    if read_all {
+                        Ok((chunk, None))
+                    } else {
+                        Ok(next_chunk(chunk))
+                    }
+                });
+            reader_pool.push(reader).unwrap();
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    /// Returns the live index entries of all keys in the snapshot in key order.
+    pub(super) fn entries(&self) -> Result<Vec<(Vec<u8>, CommandPos)>> {
+        let range = (Bound::Unbounded, Bound::Unbounded);
+        live_entries(
+            &*self.store.index,
+            &self.store.history,
+            self.seq,
+            range,
+            |_| true,
+            None,
+        )
+    }
+}
+
+impl<P: ThreadPool> Drop for Snapshot<P> {
+    fn drop(&mut self) {
+        self.store.writer.lock().unwrap().release_snapshot(self.seq);
+    }
+}
+
+/// Returns the index entries of the keys in `range` for which `in_range` holds
+/// that are live at the write `seq`.
+///
+/// `in_range` must hold for a prefix of the keys in `range`. Keys removed since
+/// the snapshot are only in the history, so the keys of both the index and the
+/// history are merged.
+fn live_entries<F>(
+    index: &dyn KeyIndex,
+    history: &History,
+    seq: u64,
+    range: KeyRange,
+    in_range: F,
+    limit: Option<usize>,
+) -> Result<Vec<(Vec<u8>, CommandPos)>>
+where
+    F: Fn(&[u8]) -> bool,
+{
+    let history_range = (
+        range.0.clone().map(|key| (key, 0)),
+        range.1.clone().map(|key| (key, 0)),
+    );
+    let mut history_keys = history
+        .range(history_range)
+        .map(|entry| entry.key().0.clone())
+        .peekable();
+    let mut index_keys = index.range(range).map(|entry| entry.map(|(key, _)| key));
+    let mut next_index_key = index_keys.next().transpose()?;
+    let mut entries = Vec::new();
+    // the keys of both are merged in key order, so only the keys up to the limit
+    // are read
+    while entries.len() < limit.unwrap_or(usize::MAX) {
+        let key = match (&next_index_key, history_keys.peek()) {
+            (Some(index_key), Some(history_key)) if history_key < index_key => {
+                history_keys.next().unwrap()
+            }
+            (Some(_), _) => {
+                let key = next_index_key.take().unwrap();
+                next_index_key = index_keys.next().transpose()?;
+                key
+            }
+            (None, Some(_)) => history_keys.next().unwrap(),
+            (None, None) => break,
+        };
+        // a key has an entry in the history for every write since the snapshot
+        while history_keys.peek() == Some(&key) {
+            history_keys.next();
+        }
+        if !in_range(&key) {
+            break;
+        }
+        let cmd_pos = index.get(&key)?;
+        if let Some(cmd_pos) = live_at(history, seq, &key, cmd_pos) {
+            entries.push((key, cmd_pos));
+        }
+    }
+    Ok(entries)
+}
+
+/// Returns a chunk of a copy that is followed by the keys after its last key.
+fn next_chunk(chunk: Vec<WatchEvent>) -> CopyChunk {
+    let next = chunk
+        .last()
+        .map(|event| Bound::Excluded(event.key().to_vec()));
+    (chunk, next)
+}
+
+/// Returns the index entry of `key` at the write `seq` if it is live.
+///
+/// `cmd_pos` is the current index entry of the key. It must be read before the
+/// history, because a write saves the old entry to the history before it changes
+/// the index.
+pub(super) fn live_at(
+    history: &History,
+    seq: u64,
+    key: &[u8],
+    cmd_pos: Option<CommandPos>,
+) -> Option<CommandPos> {
+    let changes = (key.to_vec(), seq + 1)..=(key.to_vec(), u64::MAX);
+    let cmd_pos = match history.range(changes).next() {
+        // the first change after the snapshot saved the entry at the snapshot
+        Some(change) => *change.value(),
+        None => cmd_pos,
+    };
+    cmd_pos.filter(|cmd_pos| !cmd_pos.is_expired(now_millis()))
+}
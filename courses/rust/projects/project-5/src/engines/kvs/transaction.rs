@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+
+use super::index::KeyIndex;
+use super::snapshot::{live_at, History};
+use super::{CommandPos, KvStoreReader};
+use crate::{KvsError, Result, WriteBatch};
+
+/// The keys read by a transaction with their live index entries in its snapshot
+pub(super) type ReadSet = Vec<(Vec<u8>, Option<CommandPos>)>;
+
+/// An optimistic transaction of a `KvStore`.
+///
+/// It is created by `KvStore::transaction`. Reads see the store as of a snapshot
+/// and record the index entries they observe, and writes are buffered until the
+/// transaction is committed.
+pub struct Transaction<'a> {
+    index: &'a dyn KeyIndex,
+    history: &'a History,
+    // sequence number of the snapshot the reads are pinned to
+    seq: u64,
+    reader: &'a KvStoreReader,
+    reads: BTreeMap<Vec<u8>, Read>,
+    // buffered writes, where `None` removes the key
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(super) fn new(
+        index: &'a dyn KeyIndex,
+        history: &'a History,
+        seq: u64,
+        reader: &'a KvStoreReader,
+    ) -> Transaction<'a> {
+        Transaction {
+            index,
+            history,
+            seq,
+            reader,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns the value written earlier in the transaction if there is one, or
+    /// the value in the snapshot of the transaction otherwise.
+    pub fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        if let Some(read) = self.reads.get(&key) {
+            return Ok(read.value.clone());
+        }
+        let cmd_pos = self.index.get(&key)?;
+        let live = live_at(self.history, self.seq, &key, cmd_pos);
+        let value = match live {
+            Some(cmd_pos) => Some(self.reader.read_value(cmd_pos)?),
+            None => None,
+        };
+        let read = Read {
+            cmd_pos: live,
+            value: value.clone(),
+        };
+        self.reads.insert(key, read);
+        Ok(value)
+    }
+
+    /// Sets the value of a key when the transaction is committed.
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Removes a given key when the transaction is committed.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    pub fn remove(&mut self, key: Vec<u8>) -> Result<()> {
+        if self.get(key.clone())?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    /// Returns the keys read with their index entries, and the writes as a batch.
+    pub(super) fn into_parts(self) -> (ReadSet, WriteBatch) {
+        let reads = self
+            .reads
+            .into_iter()
+            .map(|(key, read)| (key, read.cmd_pos))
+            .collect();
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            }
+        }
+        (reads, batch)
+    }
+}
+
+/// A key read by a transaction
+struct Read {
+    // the live index entry in the snapshot
+    cmd_pos: Option<CommandPos>,
+    value: Option<Vec<u8>>,
+}
@@ -0,0 +1,211 @@
+//! Watchers of the changes written by the `KvStoreWriter`.
+//!
+//! A watcher receives the changes of the keys with its prefix through a bounded
+//! channel right after they are written to the log. A watcher that resumes from
+//! an earlier position first reads the changes in between from the log files,
+//! which are opened when the watcher is registered so that a compaction cannot
+//! remove them under it.
+
+use std::fs::File;
+use std::io::{Seek, SeekFrom};
+use std::path::Path;
+use std::sync::{Arc, Weak};
+
+use tokio::prelude::*;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+
+use super::format::{self, Command};
+use super::{hint_path, index_path, log_path, sorted_gen_list, BufReaderWithPos};
+use crate::engines::{WatchEvent, WatchSeq};
+use crate::{KvsError, Result};
+
+/// The number of changes buffered for a watcher before it is dropped
+const WATCH_BUFFER: usize = 4096;
+
+/// The watchers registered with the `KvStoreWriter`.
+#[derive(Default)]
+pub(super) struct Watchers {
+    watchers: Vec<Watcher>,
+}
+
+struct Watcher {
+    prefix: Vec<u8>,
+    sender: Sender<WatchEvent>,
+    // dropped with the `WatchReceiver`
+    alive: Weak<()>,
+}
+
+/// The receiving end of a watcher.
+pub(super) struct WatchReceiver {
+    receiver: Receiver<WatchEvent>,
+    _alive: Arc<()>,
+}
+
+impl Stream for WatchReceiver {
+    type Item = WatchEvent;
+    type Error = KvsError;
+
+    fn poll(&mut self) -> Poll<Option<WatchEvent>, KvsError> {
+        self.receiver
+            .poll()
+            .map_err(|e| KvsError::StringError(format!("{}", e)))
+    }
+}
+
+impl Watchers {
+    /// Registers a watcher of the keys starting with `prefix` and returns the
+    /// receiver of its changes.
+    pub fn register(&mut self, prefix: Vec<u8>) -> WatchReceiver {
+        let (sender, receiver) = mpsc::channel(WATCH_BUFFER);
+        let alive = Arc::new(());
+        self.watchers.push(Watcher {
+            prefix,
+            sender,
+            alive: Arc::downgrade(&alive),
+        });
+        WatchReceiver {
+            receiver,
+            _alive: alive,
+        }
+    }
+
+    /// Sends the change of a command whose record ends at `seq` to the watchers of
+    /// its key.
+    ///
+    /// A watcher whose receiver is dropped or whose buffer is full is removed. The
+    /// stream of a watcher that falls behind ends, and it can resume from the last
+    /// change it has received.
+    pub fn publish(&mut self, seq: WatchSeq, cmd: &Command) {
+        let key = match cmd.key() {
+            Some(key) => key,
+            None => return,
+        };
+        if !self.watchers.iter().any(|w| key.starts_with(&w.prefix)) {
+            return;
+        }
+        let event = match to_event(seq, cmd.clone()) {
+            Some(event) => event,
+            None => return,
+        };
+        self.watchers.retain_mut(|watcher| {
+            if !key.starts_with(&watcher.prefix) {
+                return watcher.alive.strong_count() > 0;
+            }
+            match watcher.sender.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(e) => {
+                    if e.is_full() {
+                        warn!("Dropping a watcher that fell behind");
+                    }
+                    false
+                }
+            }
+        });
+    }
+}
+
+/// The log files holding the changes between a position a watcher resumes from
+/// and the position the watcher was registered at.
+pub(super) struct CatchUp {
+    from: WatchSeq,
+    until: WatchSeq,
+    files: Vec<(u64, File)>,
+}
+
+impl CatchUp {
+    /// Opens the log files holding the changes after `from` up to `until`.
+    ///
+    /// Compaction files hold no changes of their own, so they are skipped. The
+    /// compaction file of a running compaction is `compacting`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::StringError` if the log file of `from` has been
+    /// compacted, or `KvsError::InvalidArgument` if `from` is not a position in the
+    /// log.
+    pub fn open(
+        path: &Path,
+        from: WatchSeq,
+        until: WatchSeq,
+        compacting: Option<u64>,
+    ) -> Result<CatchUp> {
+        if from > until || from.pos < format::HEADER_LEN as u64 {
+            return Err(KvsError::InvalidArgument(format!(
+                "Invalid sequence number: {}",
+                from
+            )));
+        }
+        let mut files = Vec::new();
+        for gen in sorted_gen_list(path)? {
+            if gen < from.gen || gen > until.gen {
+                continue;
+            }
+            let compacted = Some(gen) == compacting
+                || hint_path(path, gen).exists()
+                || index_path(path, gen).exists();
+            if !compacted {
+                files.push((gen, File::open(log_path(path, gen))?));
+            }
+        }
+        if files.first().map(|(gen, _)| *gen) != Some(from.gen) {
+            return Err(KvsError::StringError(format!(
+                "The changes after {} have been compacted",
+                from
+            )));
+        }
+        Ok(CatchUp { from, until, files })
+    }
+
+    /// Reads the changes of the keys starting with `prefix`.
+    pub fn read(self, prefix: &[u8]) -> Result<Vec<WatchEvent>> {
+        let mut events = Vec::new();
+        for (gen, file) in self.files {
+            let mut reader = BufReaderWithPos::new(file)?;
+            if gen == self.from.gen {
+                reader.seek(SeekFrom::Start(self.from.pos))?;
+            } else {
+                format::read_header(&mut reader)?;
+            }
+            // the active log may be longer than when the watcher was registered
+            let end = if gen == self.until.gen {
+                self.until.pos
+            } else {
+                u64::MAX
+            };
+            while reader.pos < end {
+                let cmd = match format::read_record(&mut reader)? {
+                    Some(cmd) => cmd,
+                    None => break,
+                };
+                let seq = WatchSeq {
+                    gen,
+                    pos: reader.pos,
+                };
+                if let Some(event) = to_event(seq, cmd) {
+                    if event.key().starts_with(prefix) {
+                        events.push(event);
+                    }
+                }
+            }
+        }
+        Ok(events)
+    }
+}
+
+/// Returns the change of a `set` or `remove` command whose record ends at `seq`.
+fn to_event(seq: WatchSeq, cmd: Command) -> Option<WatchEvent> {
+    match cmd {
+        Command::Set {
+            key,
+            value,
+            expires_at,
+        } => Some(WatchEvent::Set {
+            seq,
+            key,
+            value,
+            expires_at,
+        }),
+        Command::Remove { key } => Some(WatchEvent::Remove { seq, key }),
+        Command::Batch { .. } => None,
+    }
+}
@@ -0,0 +1,1126 @@
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs;
+use std::mem;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use tokio::prelude::*;
+use tokio::sync::oneshot;
+
+use self::format::Entry;
+use self::manifest::{manifest_path, Manifest};
+use self::memtable::Memtable;
+use self::merge::{EntryIter, MergeIter};
+use self::table::{Table, TableBuilder};
+use self::wal::Wal;
+use super::batch::BatchOp;
+use super::{expiry_time, install_file, now_millis, KvsEngine, SyncPolicy, WriteBatch};
+use crate::thread_pool::ThreadPool;
+use crate::{KvsError, Result};
+
+mod bloom;
+mod format;
+mod manifest;
+mod memtable;
+mod merge;
+mod table;
+mod wal;
+
+const MEMTABLE_SIZE: usize = 4 * 1024 * 1024;
+const TABLE_SIZE: u64 = 2 * 1024 * 1024;
+const LEVEL0_LIMIT: usize = 4;
+const LEVEL_SIZE: u64 = 10 * 1024 * 1024;
+const BLOOM_BITS_PER_KEY: usize = 10;
+/// The number of levels including level 0. The last level is never compacted.
+const MAX_LEVELS: usize = 7;
+
+/// A key/value storage engine based on a log-structured merge-tree.
+///
+/// Writes go to a write-ahead log and an in-memory memtable. A full memtable is
+/// frozen and flushed in the background to an immutable sorted string table
+/// (SSTable) in level 0. SSTables have a sparse index of their blocks and a Bloom
+/// filter of their keys, so a read of a missing key rarely touches the disk.
+///
+/// Tables are organized in levels. The tables of level 0 may overlap and are
+/// merged into level 1 once there are too many of them. The tables of every other
+/// level cover disjoint key ranges, and a level that grows beyond its size limit,
+/// which grows tenfold from level to level, has a table merged into the next level.
+/// Deletions and expired values are dropped when they reach the last non-empty
+/// level. The live tables of every level are recorded in a manifest file.
+///
+/// ```rust
+/// # use kvs::{LsmKvsEngine, Result};
+/// # use kvs::thread_pool::{ThreadPool, RayonThreadPool};
+/// # use tokio::prelude::*;
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// use kvs::KvsEngine;
+/// let engine: LsmKvsEngine<RayonThreadPool> = LsmKvsEngine::open(current_dir()?, 2)?;
+/// engine.set(b"key".to_vec(), b"value".to_vec()).wait()?;
+/// let val = engine.get(b"key".to_vec()).wait()?;
+/// assert_eq!(val, Some(b"value".to_vec()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct LsmKvsEngine<P: ThreadPool> {
+    shared: Arc<Shared>,
+    // waits for the background work when the last handle is dropped
+    _background: Arc<BackgroundGuard>,
+    thread_pool: P,
+}
+
+/// Options for opening a `LsmKvsEngine`.
+///
+/// ```rust
+/// # use kvs::{LsmOptions, SyncPolicy};
+/// let options = LsmOptions::new()
+///     .sync_policy(SyncPolicy::Always)
+///     .memtable_size(16 * 1024 * 1024)
+///     .level0_limit(8);
+/// ```
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    sync_policy: SyncPolicy,
+    memtable_size: usize,
+    table_size: u64,
+    level0_limit: usize,
+    level_size: u64,
+    bloom_bits_per_key: usize,
+}
+
+impl Default for LsmOptions {
+    fn default() -> LsmOptions {
+        LsmOptions {
+            sync_policy: SyncPolicy::default(),
+            memtable_size: MEMTABLE_SIZE,
+            table_size: TABLE_SIZE,
+            level0_limit: LEVEL0_LIMIT,
+            level_size: LEVEL_SIZE,
+            bloom_bits_per_key: BLOOM_BITS_PER_KEY,
+        }
+    }
+}
+
+impl LsmOptions {
+    /// Creates the default options.
+    pub fn new() -> LsmOptions {
+        LsmOptions::default()
+    }
+
+    /// Sets when writes to the write-ahead log are synced to the disk.
+    ///
+    /// `SyncPolicy::Always` and `SyncPolicy::GroupCommit` sync every write, and
+    /// `SyncPolicy::Interval` syncs the first write after the interval has elapsed.
+    /// Tables and the manifest are synced unless the policy is `SyncPolicy::Never`,
+    /// which is the default.
+    pub fn sync_policy(mut self, sync_policy: SyncPolicy) -> LsmOptions {
+        self.sync_policy = sync_policy;
+        self
+    }
+
+    /// Sets how many bytes of writes the memtable takes before it is flushed to a
+    /// table.
+    ///
+    /// The default size is 4 MiB.
+    pub fn memtable_size(mut self, memtable_size: usize) -> LsmOptions {
+        self.memtable_size = memtable_size;
+        self
+    }
+
+    /// Sets the size in bytes after which a compaction starts a new table.
+    ///
+    /// The default size is 2 MiB.
+    pub fn table_size(mut self, table_size: u64) -> LsmOptions {
+        self.table_size = table_size;
+        self
+    }
+
+    /// Sets how many tables level 0 may hold before they are merged into level 1.
+    ///
+    /// The default limit is 4 tables.
+    pub fn level0_limit(mut self, level0_limit: usize) -> LsmOptions {
+        self.level0_limit = level0_limit;
+        self
+    }
+
+    /// Sets the size limit in bytes of level 1. The limit of every further level is
+    /// ten times the limit of the level above.
+    ///
+    /// The default limit is 10 MiB.
+    pub fn level_size(mut self, level_size: u64) -> LsmOptions {
+        self.level_size = level_size;
+        self
+    }
+
+    /// Sets how many bits per key the Bloom filters of new tables use.
+    ///
+    /// The default of 10 bits gives about 1% false positives.
+    pub fn bloom_bits_per_key(mut self, bloom_bits_per_key: usize) -> LsmOptions {
+        self.bloom_bits_per_key = bloom_bits_per_key;
+        self
+    }
+}
+
+impl<P: ThreadPool> LsmKvsEngine<P> {
+    /// Opens a `LsmKvsEngine` with the given path.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// Operations and the background flushes and compactions are run in the thread
+    /// pool. `concurrency` specifies the number of threads in the thread pool.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors and returns `KvsError::CorruptedLog` if the
+    /// manifest or a table is damaged.
+    pub fn open(path: impl Into<PathBuf>, concurrency: u32) -> Result<Self> {
+        LsmKvsEngine::open_with_options(path, concurrency, LsmOptions::default())
+    }
+
+    /// Opens a `LsmKvsEngine` with the given path and options.
+    ///
+    /// The write-ahead logs of memtables that were not flushed are replayed, and
+    /// files left by an interrupted flush or compaction are removed.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors and returns `KvsError::CorruptedLog` if the
+    /// manifest or a table is damaged.
+    pub fn open_with_options(
+        path: impl Into<PathBuf>,
+        concurrency: u32,
+        options: LsmOptions,
+    ) -> Result<Self> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+
+        let manifest = Manifest::load(&path)?;
+        let mut levels = Vec::new();
+        for ids in &manifest.levels {
+            levels.push(
+                ids.iter()
+                    .map(|&id| Ok(Arc::new(Table::open(&table_path(&path, id), id)?)))
+                    .collect::<Result<Vec<_>>>()?,
+            );
+        }
+        if levels.is_empty() {
+            levels.push(Vec::new());
+        }
+
+        // tables not in the manifest are left by an interrupted flush or compaction
+        let live: HashSet<u64> = manifest.table_ids().collect();
+        let table_ids = sorted_ids(&path, "sst")?;
+        for &id in &table_ids {
+            if !live.contains(&id) {
+                fs::remove_file(table_path(&path, id))?;
+            }
+        }
+        for id in sorted_ids(&path, "partial")? {
+            fs::remove_file(table::partial_path(&table_path(&path, id)))?;
+        }
+
+        let wal_ids = sorted_ids(&path, "wal")?;
+        let mut immutable = Vec::new();
+        for &id in &wal_ids {
+            if id <= manifest.flushed_wal {
+                // flushed, but not removed before a crash
+                fs::remove_file(wal_path(&path, id))?;
+                continue;
+            }
+            let memtable = Memtable::new(id);
+            wal::replay(&path, id, |key, entry| memtable.insert(key, entry))?;
+            immutable.push(Arc::new(memtable));
+        }
+
+        let wal_id = table_ids
+            .iter()
+            .chain(&wal_ids)
+            .fold(manifest.flushed_wal, |max, &id| max.max(id))
+            + 1;
+        let writer = LsmWriter {
+            wal: Wal::create(&path, wal_id)?,
+            last_sync: Instant::now(),
+        };
+        let version = Version {
+            memtable: Arc::new(Memtable::new(wal_id)),
+            immutable,
+            levels,
+            flushed_wal: manifest.flushed_wal,
+        };
+        let shared = Arc::new(Shared {
+            path,
+            options,
+            version: RwLock::new(Arc::new(version)),
+            writer: Mutex::new(writer),
+            next_id: AtomicU64::new(wal_id + 1),
+            background: BackgroundStatus::default(),
+            compact_pointers: Mutex::new(vec![Vec::new(); MAX_LEVELS]),
+        });
+
+        let thread_pool = P::new(concurrency)?;
+        // replayed memtables are flushed right away
+        schedule_background(&shared, &thread_pool);
+        Ok(LsmKvsEngine {
+            _background: Arc::new(BackgroundGuard(Arc::clone(&shared))),
+            shared,
+            thread_pool,
+        })
+    }
+
+    /// Installs a backup written by `backup_to` into the data directory `path`.
+    ///
+    /// Every block of the backup tables is checked before any file is copied. The
+    /// tables are copied under temporary names and renamed after they are synced,
+    /// and the manifest is installed last, so the engine is only restored once the
+    /// whole backup is in place.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::CorruptedLog` if the backup is damaged and
+    /// `KvsError::StringError` if `path` already contains data.
+    pub fn restore(backup: impl AsRef<Path>, path: impl Into<PathBuf>) -> Result<()> {
+        let backup = backup.as_ref();
+        let path = path.into();
+        if !manifest_path(backup).is_file() {
+            return Err(KvsError::StringError(format!(
+                "{} contains no manifest",
+                backup.display()
+            )));
+        }
+        let manifest = Manifest::load(backup)?;
+        for id in manifest.table_ids() {
+            Table::open(&table_path(backup, id), id)?.verify()?;
+        }
+
+        fs::create_dir_all(&path)?;
+        if manifest_path(&path).exists() || !sorted_ids(&path, "wal")?.is_empty() {
+            return Err(KvsError::StringError(format!(
+                "{} already contains data",
+                path.display()
+            )));
+        }
+        for id in manifest.table_ids() {
+            install_file(&table_path(backup, id), &table_path(&path, id))?;
+        }
+        install_file(&manifest_path(backup), &manifest_path(&path))?;
+        info!("Restored {} from {}", path.display(), backup.display());
+        Ok(())
+    }
+
+    /// Runs a write with the `LsmWriter` in the thread pool.
+    ///
+    /// If the write freezes the memtable, it is flushed in the background.
+    fn write<F, T>(&self, write: F) -> Box<dyn Future<Item = T, Error = KvsError> + Send>
+    where
+        F: FnOnce(&Shared, &mut LsmWriter) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let shared = self.shared.clone();
+        let thread_pool = self.thread_pool.clone();
+        let (tx, rx) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            let res = write(&shared, &mut shared.writer.lock().unwrap());
+            schedule_background(&shared, &thread_pool);
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    /// Runs a scan over the current version in the thread pool and yields the pairs
+    /// it collects.
+    fn scan_with<F>(
+        &self,
+        scan: F,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send>
+    where
+        F: FnOnce(&Version) -> Result<Vec<(Vec<u8>, Vec<u8>)>> + Send + 'static,
+    {
+        let shared = self.shared.clone();
+        let (tx, rx) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            let res = scan(&shared.version());
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten()
+                .map(stream::iter_ok)
+                .flatten_stream(),
+        )
+    }
+}
+
+impl<P: ThreadPool> KvsEngine for LsmKvsEngine<P> {
+    fn set(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(move |shared, writer| {
+            let entry = Entry::Value {
+                value,
+                expires_at: None,
+            };
+            shared.apply(writer, vec![(key, entry)])
+        })
+    }
+
+    /// Sets the value of a key that expires after `ttl`.
+    ///
+    /// The absolute expiry time is stored with the value, so the key stays expired
+    /// after the engine is reopened. Expired values are dropped when a compaction
+    /// merges them into the last level.
+    fn set_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let expires_at = Some(expiry_time(ttl));
+        self.write(move |shared, writer| {
+            shared.apply(writer, vec![(key, Entry::Value { value, expires_at })])
+        })
+    }
+
+    fn get(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send> {
+        let shared = self.shared.clone();
+        let (tx, rx) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            let res = shared.live_value(&key);
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+
+    /// Removes a given key by writing a deletion that hides its older values.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found or has
+    /// expired.
+    fn remove(&self, key: Vec<u8>) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(move |shared, writer| {
+            if shared.live_value(&key)?.is_none() {
+                return Err(KvsError::KeyNotFound);
+            }
+            shared.apply(writer, vec![(key, Entry::Deleted)])
+        })
+    }
+
+    /// Sets the value of a key to `new` if its current value is `expected`.
+    ///
+    /// The current value is compared under the writer lock, so no other write can
+    /// come in between.
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Box<dyn Future<Item = bool, Error = KvsError> + Send> {
+        self.write(move |shared, writer| {
+            let current = shared.live_value(&key)?;
+            if current != expected {
+                return Ok(false);
+            }
+            let entry = match new {
+                Some(value) => Entry::Value {
+                    value,
+                    expires_at: None,
+                },
+                // the key stays missing
+                None if current.is_none() => return Ok(true),
+                None => Entry::Deleted,
+            };
+            shared.apply(writer, vec![(key, entry)])?;
+            Ok(true)
+        })
+    }
+
+    /// Applies all writes in the batch atomically if every key in `reads` still has
+    /// the value read.
+    ///
+    /// The values are compared under the writer lock, and the batch is written as a
+    /// single record of the write-ahead log.
+    fn commit_transaction(
+        &self,
+        reads: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = bool, Error = KvsError> + Send> {
+        self.write(move |shared, writer| {
+            for (key, value) in &reads {
+                if shared.live_value(key)? != *value {
+                    return Ok(false);
+                }
+            }
+            shared.apply(writer, batch_entries(batch))?;
+            Ok(true)
+        })
+    }
+
+    /// Applies all writes in the batch atomically.
+    ///
+    /// The batch is written as a single record of the write-ahead log, which is
+    /// replayed either as a whole or not at all.
+    fn write_batch(
+        &self,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(move |shared, writer| shared.apply(writer, batch_entries(batch)))
+    }
+
+    fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send> {
+        self.scan_with(move |version| {
+            collect_pairs(
+                version,
+                Bound::Included(start),
+                |key| end.as_ref().map_or(true, |end| key < end.as_slice()),
+                limit,
+            )
+        })
+    }
+
+    fn scan_prefix(
+        &self,
+        prefix: Vec<u8>,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send> {
+        self.scan_with(move |version| {
+            collect_pairs(
+                version,
+                Bound::Included(prefix.clone()),
+                |key| key.starts_with(&prefix),
+                limit,
+            )
+        })
+    }
+
+    /// Writes the live key/value pairs to new tables in a new directory at `path`.
+    ///
+    /// The memtable is frozen when the backup starts, so the backup merges immutable
+    /// memtables and tables while writes go on. The files are written to a
+    /// temporary directory that is renamed to `path` after they are synced.
+    fn backup_to(&self, path: PathBuf) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let shared = self.shared.clone();
+        let thread_pool = self.thread_pool.clone();
+        let (tx, rx) = oneshot::channel();
+        self.thread_pool.spawn(move || {
+            let res = (|| -> Result<()> {
+                if path.exists() {
+                    return Err(KvsError::InvalidArgument(format!(
+                        "{} already exists",
+                        path.display()
+                    )));
+                }
+                let mut tmp_name = path.file_name().unwrap_or_default().to_owned();
+                tmp_name.push(".partial");
+                let tmp_path = path.with_file_name(tmp_name);
+                if tmp_path.exists() {
+                    // left by an interrupted backup
+                    fs::remove_dir_all(&tmp_path)?;
+                }
+                fs::create_dir_all(&tmp_path)?;
+
+                let version = {
+                    let mut writer = shared.writer.lock().unwrap();
+                    shared.rotate(&mut writer)?;
+                    shared.version()
+                };
+                schedule_background(&shared, &thread_pool);
+                // the new memtable takes the writes after the backup started
+                let frozen = Version {
+                    memtable: Arc::new(Memtable::new(0)),
+                    ..(*version).clone()
+                };
+                let mut next_id = 0;
+                let tables = write_tables(
+                    &tmp_path,
+                    frozen.iter(Bound::Unbounded),
+                    true,
+                    || {
+                        next_id += 1;
+                        next_id
+                    },
+                    &shared.options,
+                    true,
+                )?;
+                let manifest = Manifest {
+                    levels: vec![Vec::new(), tables.iter().map(|table| table.id()).collect()],
+                    flushed_wal: 0,
+                };
+                manifest.save(&tmp_path, true)?;
+                fs::rename(&tmp_path, &path)?;
+                Ok(())
+            })();
+            if tx.send(res).is_err() {
+                error!("Receiving end is dropped");
+            }
+        });
+        Box::new(
+            rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                .flatten(),
+        )
+    }
+}
+
+/// The state shared by all handles of an engine and its background work.
+struct Shared {
+    // directory for the logs, the tables and the manifest
+    path: PathBuf,
+    options: LsmOptions,
+    version: RwLock<Arc<Version>>,
+    writer: Mutex<LsmWriter>,
+    // the next id of a write-ahead log or a table
+    next_id: AtomicU64,
+    background: BackgroundStatus,
+    // the largest key of the last compaction of each level, so that compactions
+    // go round the key space
+    compact_pointers: Mutex<Vec<Vec<u8>>>,
+}
+
+/// Appends writes to the write-ahead log of the active memtable.
+struct LsmWriter {
+    wal: Wal,
+    last_sync: Instant,
+}
+
+/// The memtables and tables that make up the data at some point.
+///
+/// A version is never modified. Flushes, compactions and frozen memtables install
+/// a new version, and reads keep the version they started with.
+#[derive(Clone)]
+struct Version {
+    memtable: Arc<Memtable>,
+    // frozen memtables waiting to be flushed, from the oldest to the newest
+    immutable: Vec<Arc<Memtable>>,
+    // level 0 from the newest to the oldest table, the other levels by key
+    levels: Vec<Vec<Arc<Table>>>,
+    flushed_wal: u64,
+}
+
+impl Version {
+    /// Looks up the latest entry of `key`, searching from the newest to the oldest
+    /// data.
+    fn get(&self, key: &[u8]) -> Result<Option<Entry>> {
+        let memtables = Some(&self.memtable)
+            .into_iter()
+            .chain(self.immutable.iter().rev());
+        for memtable in memtables {
+            if let Some(entry) = memtable.get(key) {
+                return Ok(Some(entry));
+            }
+        }
+        for table in &self.levels[0] {
+            if let Some(entry) = table.get(key)? {
+                return Ok(Some(entry));
+            }
+        }
+        for level in &self.levels[1..] {
+            let i = level.partition_point(|table| table.largest() < key);
+            if let Some(table) = level.get(i) {
+                if let Some(entry) = table.get(key)? {
+                    return Ok(Some(entry));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Iterates the latest entries of the keys after `start` in key order.
+    fn iter(&self, start: Bound<Vec<u8>>) -> MergeIter<'_> {
+        let mut sources = vec![self.memtable.iter(start.clone())];
+        for memtable in self.immutable.iter().rev() {
+            sources.push(memtable.iter(start.clone()));
+        }
+        for table in &self.levels[0] {
+            sources.push(Box::new(table.iter(start.clone())));
+        }
+        for level in &self.levels[1..] {
+            sources.push(level_iter(level.clone(), start.clone()));
+        }
+        MergeIter::new(sources)
+    }
+
+    fn manifest(&self) -> Manifest {
+        Manifest {
+            levels: self
+                .levels
+                .iter()
+                .map(|level| level.iter().map(|table| table.id()).collect())
+                .collect(),
+            flushed_wal: self.flushed_wal,
+        }
+    }
+}
+
+/// Tables of a level merged with the level below
+struct Compaction {
+    level: usize,
+    // level 0 from the newest to the oldest table
+    inputs: Vec<Arc<Table>>,
+    // the overlapping tables of the level below
+    overlapping: Vec<Arc<Table>>,
+}
+
+impl Shared {
+    fn version(&self) -> Arc<Version> {
+        Arc::clone(&self.version.read().unwrap())
+    }
+
+    fn next_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Returns whether tables and the manifest are synced to the disk.
+    fn sync_files(&self) -> bool {
+        self.options.sync_policy != SyncPolicy::Never
+    }
+
+    /// Returns the value of `key` if it is neither missing nor expired.
+    fn live_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .version()
+            .get(key)?
+            .and_then(|entry| entry.live_value(now_millis())))
+    }
+
+    /// Appends the entries to the write-ahead log as a single record and inserts
+    /// them into the memtable, which is frozen once it is full.
+    fn apply(&self, writer: &mut LsmWriter, entries: Vec<(Vec<u8>, Entry)>) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        writer.wal.append(&entries)?;
+        match self.options.sync_policy {
+            SyncPolicy::Always | SyncPolicy::GroupCommit => writer.wal.sync()?,
+            SyncPolicy::Interval(interval) if writer.last_sync.elapsed() >= interval => {
+                writer.wal.sync()?;
+                writer.last_sync = Instant::now();
+            }
+            _ => (),
+        }
+        // only the writer replaces the memtable
+        let memtable = Arc::clone(&self.version().memtable);
+        for (key, entry) in entries {
+            memtable.insert(key, entry);
+        }
+        if memtable.size() >= self.options.memtable_size {
+            self.rotate(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Freezes the memtable and starts a new one with a new write-ahead log.
+    fn rotate(&self, writer: &mut LsmWriter) -> Result<()> {
+        let wal_id = self.next_id();
+        writer.wal = Wal::create(&self.path, wal_id)?;
+        let mut version = self.version.write().unwrap();
+        let mut new_version = (**version).clone();
+        let frozen = mem::replace(&mut new_version.memtable, Arc::new(Memtable::new(wal_id)));
+        new_version.immutable.push(frozen);
+        *version = Arc::new(new_version);
+        Ok(())
+    }
+
+    /// Returns whether a memtable has to be flushed or a level compacted.
+    fn needs_background(&self) -> bool {
+        let version = self.version();
+        !version.immutable.is_empty() || self.pick_compaction(&version).is_some()
+    }
+
+    /// Flushes frozen memtables and compacts levels until nothing is left to do.
+    fn run_background(&self) -> Result<()> {
+        loop {
+            let version = self.version();
+            if let Some(memtable) = version.immutable.first() {
+                self.flush(Arc::clone(memtable))?;
+            } else if let Some(compaction) = self.pick_compaction(&version) {
+                self.compact(compaction)?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Writes the oldest frozen memtable to a table of level 0 and removes its
+    /// write-ahead log.
+    fn flush(&self, memtable: Arc<Memtable>) -> Result<()> {
+        let table = if memtable.is_empty() {
+            None
+        } else {
+            let mut builder =
+                TableBuilder::create(&self.path, self.next_id(), self.options.bloom_bits_per_key)?;
+            for res in memtable.iter(Bound::Unbounded) {
+                let (key, entry) = res?;
+                builder.add(&key, &entry)?;
+            }
+            Some(Arc::new(builder.finish(self.sync_files())?))
+        };
+
+        let manifest = {
+            let mut version = self.version.write().unwrap();
+            let mut new_version = (**version).clone();
+            new_version
+                .immutable
+                .retain(|other| !Arc::ptr_eq(other, &memtable));
+            if let Some(table) = table {
+                new_version.levels[0].insert(0, table);
+            }
+            new_version.flushed_wal = memtable.wal_id();
+            let manifest = new_version.manifest();
+            *version = Arc::new(new_version);
+            manifest
+        };
+        manifest.save(&self.path, self.sync_files())?;
+        fs::remove_file(wal_path(&self.path, memtable.wal_id()))?;
+        debug!("Flushed memtable of {}.wal", memtable.wal_id());
+        Ok(())
+    }
+
+    /// Picks the tables of the next compaction.
+    ///
+    /// Level 0 is merged into level 1 as a whole when it holds too many tables.
+    /// A deeper level that exceeds its size limit has the table after the one it
+    /// compacted last merged into the next level.
+    fn pick_compaction(&self, version: &Version) -> Option<Compaction> {
+        let level0 = &version.levels[0];
+        if level0.len() >= self.options.level0_limit.max(1) {
+            let start = level0.iter().map(|table| table.smallest()).min().unwrap();
+            let end = level0.iter().map(|table| table.largest()).max().unwrap();
+            return Some(Compaction {
+                level: 0,
+                inputs: level0.clone(),
+                overlapping: overlapping_tables(version, 1, start, end),
+            });
+        }
+        let pointers = self.compact_pointers.lock().unwrap();
+        for level in 1..version.levels.len().min(MAX_LEVELS - 1) {
+            let tables = &version.levels[level];
+            let size: u64 = tables.iter().map(|table| table.size()).sum();
+            if size <= self.max_level_size(level) {
+                continue;
+            }
+            let table = tables
+                .iter()
+                .find(|table| table.smallest() > pointers[level].as_slice())
+                .unwrap_or(&tables[0]);
+            return Some(Compaction {
+                level,
+                inputs: vec![Arc::clone(table)],
+                overlapping: overlapping_tables(
+                    version,
+                    level + 1,
+                    table.smallest(),
+                    table.largest(),
+                ),
+            });
+        }
+        None
+    }
+
+    fn max_level_size(&self, level: usize) -> u64 {
+        self.options
+            .level_size
+            .saturating_mul(10u64.saturating_pow(level as u32 - 1))
+    }
+
+    /// Merges the tables of a compaction into new tables of the level below.
+    fn compact(&self, compaction: Compaction) -> Result<()> {
+        let output_level = compaction.level + 1;
+        let version = self.version();
+        // no older value of a key can be left below the output level
+        let last_level = version
+            .levels
+            .iter()
+            .skip(output_level + 1)
+            .all(Vec::is_empty);
+
+        let mut sources: Vec<EntryIter<'_>> = compaction
+            .inputs
+            .iter()
+            .map(|table| Box::new(table.iter(Bound::Unbounded)) as EntryIter<'_>)
+            .collect();
+        sources.push(level_iter(compaction.overlapping.clone(), Bound::Unbounded));
+        let outputs = write_tables(
+            &self.path,
+            MergeIter::new(sources),
+            last_level,
+            || self.next_id(),
+            &self.options,
+            self.sync_files(),
+        )?;
+
+        let removed: HashSet<u64> = compaction
+            .inputs
+            .iter()
+            .chain(&compaction.overlapping)
+            .map(|table| table.id())
+            .collect();
+        let manifest = {
+            let mut version = self.version.write().unwrap();
+            let mut new_version = (**version).clone();
+            for level in &mut new_version.levels {
+                level.retain(|table| !removed.contains(&table.id()));
+            }
+            if new_version.levels.len() <= output_level {
+                new_version.levels.resize(output_level + 1, Vec::new());
+            }
+            let level = &mut new_version.levels[output_level];
+            level.extend(outputs.into_iter().map(Arc::new));
+            level.sort_by(|a, b| a.smallest().cmp(b.smallest()));
+            let manifest = new_version.manifest();
+            *version = Arc::new(new_version);
+            manifest
+        };
+        manifest.save(&self.path, self.sync_files())?;
+
+        if compaction.level > 0 {
+            let largest = compaction.inputs[0].largest().to_vec();
+            self.compact_pointers.lock().unwrap()[compaction.level] = largest;
+        }
+        for id in removed {
+            // readers may still hold the table open
+            if let Err(e) = fs::remove_file(table_path(&self.path, id)) {
+                error!("Failed to remove {}.sst: {}", id, e);
+            }
+        }
+        debug!(
+            "Compacted {} tables of level {} into level {}",
+            compaction.inputs.len(),
+            compaction.level,
+            output_level
+        );
+        Ok(())
+    }
+}
+
+/// Starts the background work in the thread pool if a memtable has to be flushed
+/// or a level compacted and it is not running yet.
+fn schedule_background<P: ThreadPool>(shared: &Arc<Shared>, thread_pool: &P) {
+    if !shared.needs_background() || !shared.background.start() {
+        return;
+    }
+    let shared = Arc::clone(shared);
+    thread_pool.spawn(move || loop {
+        let res = shared.run_background();
+        shared.background.finish();
+        if let Err(e) = res {
+            error!("Background flush or compaction failed: {}", e);
+            return;
+        }
+        // a memtable may have been frozen after the last check
+        if !shared.needs_background() || !shared.background.start() {
+            return;
+        }
+    });
+}
+
+/// Writes the entries to new tables in `dir`, starting a new table whenever one
+/// reaches the table size. Deletions and expired values are dropped if `drop_dead`
+/// is true.
+fn write_tables<I, F>(
+    dir: &Path,
+    entries: I,
+    drop_dead: bool,
+    mut next_id: F,
+    options: &LsmOptions,
+    sync: bool,
+) -> Result<Vec<Table>>
+where
+    I: Iterator<Item = Result<(Vec<u8>, Entry)>>,
+    F: FnMut() -> u64,
+{
+    let now = now_millis();
+    let mut tables = Vec::new();
+    let mut builder: Option<TableBuilder> = None;
+    for res in entries {
+        let (key, entry) = res?;
+        if drop_dead && !entry.is_live(now) {
+            continue;
+        }
+        if builder.is_none() {
+            builder = Some(TableBuilder::create(
+                dir,
+                next_id(),
+                options.bloom_bits_per_key,
+            )?);
+        }
+        let current = builder.as_mut().unwrap();
+        current.add(&key, &entry)?;
+        if current.size() >= options.table_size {
+            tables.push(builder.take().unwrap().finish(sync)?);
+        }
+    }
+    if let Some(builder) = builder {
+        tables.push(builder.finish(sync)?);
+    }
+    Ok(tables)
+}
+
+/// Returns the tables of `level` that overlap `[start, end]`.
+fn overlapping_tables(
+    version: &Version,
+    level: usize,
+    start: &[u8],
+    end: &[u8],
+) -> Vec<Arc<Table>> {
+    version.levels.get(level).map_or_else(Vec::new, |tables| {
+        tables
+            .iter()
+            .filter(|table| table.overlaps(start, end))
+            .cloned()
+            .collect()
+    })
+}
+
+/// Iterates the entries of the disjoint tables of a level after `start` in key
+/// order, opening one table at a time.
+fn level_iter(tables: Vec<Arc<Table>>, start: Bound<Vec<u8>>) -> EntryIter<'static> {
+    let table_start = start.clone();
+    Box::new(
+        tables
+            .into_iter()
+            .filter(move |table| match &start {
+                Bound::Included(key) => table.largest() >= key.as_slice(),
+                Bound::Excluded(key) => table.largest() > key.as_slice(),
+                Bound::Unbounded => true,
+            })
+            .flat_map(move |table| table.iter(table_start.clone())),
+    )
+}
+
+/// Collects at most `limit` live key/value pairs from `start` on while `in_range`
+/// holds.
+fn collect_pairs<F>(
+    version: &Version,
+    start: Bound<Vec<u8>>,
+    in_range: F,
+    limit: Option<usize>,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>>
+where
+    F: Fn(&[u8]) -> bool,
+{
+    let now = now_millis();
+    let mut pairs = Vec::new();
+    for res in version.iter(start) {
+        if pairs.len() >= limit.unwrap_or(usize::MAX) {
+            break;
+        }
+        let (key, entry) = res?;
+        if !in_range(&key) {
+            break;
+        }
+        if let Some(value) = entry.live_value(now) {
+            pairs.push((key, value));
+        }
+    }
+    Ok(pairs)
+}
+
+/// Converts a `WriteBatch` to entries. Keys written by the batch no longer expire.
+fn batch_entries(batch: WriteBatch) -> Vec<(Vec<u8>, Entry)> {
+    batch
+        .into_ops()
+        .into_iter()
+        .map(|op| match op {
+            BatchOp::Set { key, value } => (
+                key,
+                Entry::Value {
+                    value,
+                    expires_at: None,
+                },
+            ),
+            BatchOp::Remove { key } => (key, Entry::Deleted),
+        })
+        .collect()
+}
+
+/// Tracks whether the background work is running.
+#[derive(Default)]
+struct BackgroundStatus {
+    running: Mutex<bool>,
+    // notified when the background work finishes
+    finished: Condvar,
+}
+
+impl BackgroundStatus {
+    /// Marks the background work as running.
+    ///
+    /// Returns `false` if it is already running.
+    fn start(&self) -> bool {
+        let mut running = self.running.lock().unwrap();
+        !mem::replace(&mut *running, true)
+    }
+
+    fn finish(&self) {
+        *self.running.lock().unwrap() = false;
+        self.finished.notify_all();
+    }
+
+    /// Blocks until the background work finishes.
+    fn wait(&self) {
+        let mut running = self.running.lock().unwrap();
+        while *running {
+            running = self.finished.wait(running).unwrap();
+        }
+    }
+}
+
+/// Waits for the background work when the last `LsmKvsEngine` handle is dropped,
+/// so that no flush or compaction is left running on a closed engine.
+struct BackgroundGuard(Arc<Shared>);
+
+impl Drop for BackgroundGuard {
+    fn drop(&mut self) {
+        self.0.background.wait();
+    }
+}
+
+/// Returns the sorted ids of the files in `dir` with the given extension.
+fn sorted_ids(dir: &Path, extension: &str) -> Result<Vec<u64>> {
+    let mut ids: Vec<u64> = fs::read_dir(dir)?
+        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some(extension.as_ref()))
+        .flat_map(|path| {
+            path.file_name()
+                .and_then(OsStr::to_str)
+                .and_then(|s| s.split('.').next())
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+fn wal_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.wal", id))
+}
+
+fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.sst", id))
+}
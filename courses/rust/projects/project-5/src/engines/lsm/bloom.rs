@@ -0,0 +1,80 @@
+//! Bloom filters of the SSTables.
+//!
+//! A filter is stored as the number of probes followed by the bit array:
+//!
+//! ```text
+//! | probes: u32 | bits |
+//! ```
+//!
+//! The probes are derived from a single 64-bit FNV-1a hash of the key by double
+//! hashing, so the filter does not depend on the hasher of the standard library.
+
+use super::format::read_u32;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// A Bloom filter over the keys of a table
+pub struct Bloom {
+    bits: Vec<u8>,
+    probes: u32,
+}
+
+impl Bloom {
+    /// Builds a filter of `bits_per_key` bits per key from the hashes of the keys.
+    pub fn build(hashes: &[u64], bits_per_key: usize) -> Bloom {
+        // ln(2) * bits per key probes give the lowest false positive rate
+        let probes = ((bits_per_key as f64 * 0.69) as u32).clamp(1, 30);
+        let len = (hashes.len() * bits_per_key).div_ceil(8).max(8);
+        let mut bloom = Bloom {
+            bits: vec![0; len],
+            probes,
+        };
+        for &hash in hashes {
+            for bit in bloom.bit_positions(hash) {
+                bloom.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        bloom
+    }
+
+    /// Returns whether the key with `hash` may be in the table.
+    ///
+    /// A false result means the key is definitely not in the table.
+    pub fn may_contain(&self, hash: u64) -> bool {
+        self.bit_positions(hash)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    fn bit_positions(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 8;
+        let delta = hash.rotate_left(31) | 1;
+        (0..u64::from(self.probes))
+            .map(move |i| (hash.wrapping_add(i.wrapping_mul(delta)) % len) as usize)
+    }
+
+    /// Appends the encoded filter to `buf`.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.probes.to_le_bytes());
+        buf.extend_from_slice(&self.bits);
+    }
+
+    /// Decodes a filter from all of `buf`.
+    pub fn decode(mut buf: &[u8]) -> Option<Bloom> {
+        let probes = read_u32(&mut buf)?;
+        if buf.is_empty() || probes == 0 {
+            return None;
+        }
+        Some(Bloom {
+            bits: buf.to_vec(),
+            probes,
+        })
+    }
+}
+
+/// Returns the FNV-1a hash of `key`.
+pub fn hash(key: &[u8]) -> u64 {
+    key.iter().fold(FNV_OFFSET, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+    })
+}
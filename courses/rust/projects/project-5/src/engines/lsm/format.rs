@@ -0,0 +1,104 @@
+//! The encoding of entries, shared by the write-ahead logs and the SSTables.
+//!
+//! ```text
+//! | key length: u32 | key | tag: u8 | expires at: u64 | value length: u32 | value |
+//! ```
+//!
+//! All integers are little endian. The expiry time in milliseconds since the UNIX
+//! epoch is only present for the tag of an expiring value, and a deletion has
+//! neither an expiry time nor a value.
+
+const TAG_VALUE: u8 = 0;
+const TAG_EXPIRING: u8 = 1;
+const TAG_DELETED: u8 = 2;
+
+/// The latest write of a key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    Value {
+        value: Vec<u8>,
+        /// Expiry time in milliseconds since the UNIX epoch
+        expires_at: Option<u64>,
+    },
+    /// A tombstone hiding the older values of the key
+    Deleted,
+}
+
+impl Entry {
+    /// Returns the value if the entry is a value that has not expired at `now`.
+    pub fn live_value(self, now: u64) -> Option<Vec<u8>> {
+        match self {
+            Entry::Value { value, expires_at } if expires_at.map_or(true, |t| t > now) => {
+                Some(value)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns whether the entry is a value that has not expired at `now`.
+    pub fn is_live(&self, now: u64) -> bool {
+        match self {
+            Entry::Value { expires_at, .. } => expires_at.map_or(true, |t| t > now),
+            Entry::Deleted => false,
+        }
+    }
+}
+
+/// Appends an encoded entry of `key` to `buf`.
+pub fn encode(buf: &mut Vec<u8>, key: &[u8], entry: &Entry) {
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key);
+    match entry {
+        Entry::Value { value, expires_at } => {
+            match expires_at {
+                Some(expires_at) => {
+                    buf.push(TAG_EXPIRING);
+                    buf.extend_from_slice(&expires_at.to_le_bytes());
+                }
+                None => buf.push(TAG_VALUE),
+            }
+            buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            buf.extend_from_slice(value);
+        }
+        Entry::Deleted => buf.push(TAG_DELETED),
+    }
+}
+
+/// Decodes the entry at the front of `buf` and advances `buf` past it.
+///
+/// Returns `None` if the entry is cut off or invalid.
+pub fn decode(buf: &mut &[u8]) -> Option<(Vec<u8>, Entry)> {
+    let key_len = read_u32(buf)?;
+    let key = take(buf, key_len as usize)?.to_vec();
+    let expires_at = match *take(buf, 1)?.first()? {
+        TAG_VALUE => None,
+        TAG_EXPIRING => Some(read_u64(buf)?),
+        TAG_DELETED => return Some((key, Entry::Deleted)),
+        _ => return None,
+    };
+    let value_len = read_u32(buf)?;
+    let value = take(buf, value_len as usize)?.to_vec();
+    Some((key, Entry::Value { value, expires_at }))
+}
+
+/// Splits `len` bytes off the front of `buf`.
+pub fn take<'a>(buf: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if buf.len() < len {
+        return None;
+    }
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+    Some(head)
+}
+
+pub fn read_u32(buf: &mut &[u8]) -> Option<u32> {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(take(buf, 4)?);
+    Some(u32::from_le_bytes(bytes))
+}
+
+pub fn read_u64(buf: &mut &[u8]) -> Option<u64> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(take(buf, 8)?);
+    Some(u64::from_le_bytes(bytes))
+}
@@ -0,0 +1,58 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result};
+
+const MANIFEST_FILE: &str = "MANIFEST";
+
+/// The set of live tables, stored as JSON in the file `MANIFEST`.
+///
+/// The manifest is replaced as a whole by writing a temporary file and renaming
+/// it, so a flush or a compaction takes effect atomically. Table files that are
+/// not listed in it are leftovers of an interrupted flush or compaction.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    /// The ids of the tables of every level. Level 0 is ordered from the newest
+    /// to the oldest table and the other levels by key.
+    pub levels: Vec<Vec<u64>>,
+    /// The id of the last write-ahead log whose memtable is flushed to a table
+    pub flushed_wal: u64,
+}
+
+impl Manifest {
+    /// Reads the manifest in `dir`, or returns an empty one if there is none.
+    pub fn load(dir: &Path) -> Result<Manifest> {
+        match fs::read(manifest_path(dir)) {
+            Ok(buf) => serde_json::from_slice(&buf)
+                .map_err(|e| KvsError::CorruptedLog(format!("invalid MANIFEST: {}", e))),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Manifest::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replaces the manifest in `dir`. It is synced to the disk first if `sync`
+    /// is true.
+    pub fn save(&self, dir: &Path, sync: bool) -> Result<()> {
+        let path = manifest_path(dir);
+        let tmp_path = path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&serde_json::to_vec(self)?)?;
+        if sync {
+            file.sync_all()?;
+        }
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    /// Returns the ids of all tables.
+    pub fn table_ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.levels.iter().flatten().copied()
+    }
+}
+
+pub fn manifest_path(dir: &Path) -> PathBuf {
+    dir.join(MANIFEST_FILE)
+}
@@ -0,0 +1,66 @@
+use std::ops::Bound;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crossbeam_skiplist::SkipMap;
+
+use super::format::Entry;
+use super::merge::EntryIter;
+
+/// The latest writes of the keys in memory, backed by a write-ahead log.
+///
+/// The active memtable takes the writes until it reaches its size limit. It is
+/// then frozen and flushed to an SSTable in the background.
+pub struct Memtable {
+    // id of the write-ahead log
+    wal_id: u64,
+    map: SkipMap<Vec<u8>, Entry>,
+    // the approximate number of bytes written to the memtable
+    size: AtomicUsize,
+}
+
+impl Memtable {
+    pub fn new(wal_id: u64) -> Memtable {
+        Memtable {
+            wal_id,
+            map: SkipMap::new(),
+            size: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn wal_id(&self) -> u64 {
+        self.wal_id
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Entry> {
+        self.map.get(key).map(|entry| entry.value().clone())
+    }
+
+    pub fn insert(&self, key: Vec<u8>, entry: Entry) {
+        let value_len = match &entry {
+            Entry::Value { value, .. } => value.len(),
+            Entry::Deleted => 0,
+        };
+        // the overhead of the entry in the skip list is a rough guess
+        self.size
+            .fetch_add(key.len() + value_len + 32, Ordering::SeqCst);
+        self.map.insert(key, entry);
+    }
+
+    /// Returns the approximate number of bytes written to the memtable.
+    pub fn size(&self) -> usize {
+        self.size.load(Ordering::SeqCst)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Iterates the entries of the keys after `start` in key order.
+    pub fn iter(&self, start: Bound<Vec<u8>>) -> EntryIter<'_> {
+        Box::new(
+            self.map
+                .range((start, Bound::Unbounded))
+                .map(|entry| Ok((entry.key().clone(), entry.value().clone()))),
+        )
+    }
+}
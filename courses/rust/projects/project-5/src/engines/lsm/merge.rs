@@ -0,0 +1,62 @@
+use super::format::Entry;
+use crate::Result;
+
+/// Entries in key order, with every key at most once
+pub type EntryIter<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Entry)>> + 'a>;
+
+/// Merges sorted sources of entries into a single sorted source.
+///
+/// The sources are given from the newest to the oldest. If several sources hold
+/// the same key, only the entry of the newest one is yielded.
+pub struct MergeIter<'a> {
+    sources: Vec<EntryIter<'a>>,
+    // the next entry of each source, or `None` if it has to be read
+    heads: Vec<Option<(Vec<u8>, Entry)>>,
+    exhausted: Vec<bool>,
+}
+
+impl<'a> MergeIter<'a> {
+    pub fn new(sources: Vec<EntryIter<'a>>) -> MergeIter<'a> {
+        let len = sources.len();
+        MergeIter {
+            sources,
+            heads: vec![None; len],
+            exhausted: vec![false; len],
+        }
+    }
+}
+
+impl<'a> Iterator for MergeIter<'a> {
+    type Item = Result<(Vec<u8>, Entry)>;
+
+    fn next(&mut self) -> Option<Result<(Vec<u8>, Entry)>> {
+        for i in 0..self.sources.len() {
+            if self.heads[i].is_none() && !self.exhausted[i] {
+                match self.sources[i].next() {
+                    Some(Ok(head)) => self.heads[i] = Some(head),
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => self.exhausted[i] = true,
+                }
+            }
+        }
+        // the first source with the smallest key is the newest
+        let mut newest: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            if let Some((key, _)) = head {
+                match newest {
+                    Some(j) if self.heads[j].as_ref().unwrap().0 <= *key => (),
+                    _ => newest = Some(i),
+                }
+            }
+        }
+        let newest = newest?;
+        let (key, entry) = self.heads[newest].take().unwrap();
+        for head in &mut self.heads {
+            if head.as_ref().is_some_and(|(other, _)| *other == key) {
+                // shadowed by the newer entry
+                *head = None;
+            }
+        }
+        Some(Ok((key, entry)))
+    }
+}
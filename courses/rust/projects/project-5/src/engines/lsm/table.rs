@@ -0,0 +1,379 @@
+//! Sorted string tables.
+//!
+//! An SSTable `<id>.sst` holds the entries of a range of keys in key order and is
+//! never modified after it is written. It starts with the magic bytes `KVST` and
+//! a little endian `u32` version, followed by the data blocks, the meta block and
+//! the footer:
+//!
+//! ```text
+//! data block: | crc32 of entries: u32 | entries |
+//! meta block: | bloom length: u32 | bloom | block count: u32 | block handles |
+//!             | largest key length: u32 | largest key |
+//! block handle: | offset: u64 | length: u32 | first key length: u32 | first key |
+//! footer: | meta offset: u64 | entry count: u64 | crc32 of meta block: u32 | KVST |
+//! ```
+//!
+//! The block handles form a sparse index holding the first key of every block, so
+//! a lookup reads a single block. The Bloom filter lets most lookups of missing
+//! keys skip the table without reading any block.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::vec;
+
+use super::bloom::{self, Bloom};
+use super::format::{self, read_u32, read_u64, take, Entry};
+use super::table_path;
+use crate::{KvsError, Result};
+
+const MAGIC: &[u8; 4] = b"KVST";
+const VERSION: u32 = 1;
+const HEADER_LEN: u64 = 8;
+const FOOTER_LEN: u64 = 24;
+/// The size in bytes after which a data block is closed
+const BLOCK_SIZE: usize = 4096;
+
+/// The location of a data block and its first key
+struct BlockHandle {
+    first_key: Vec<u8>,
+    offset: u64,
+    len: u32,
+}
+
+/// Writes the entries of a new table in key order.
+///
+/// The table is written under a temporary name and renamed when it is finished,
+/// so a table file with its final name is always complete.
+pub struct TableBuilder {
+    id: u64,
+    path: PathBuf,
+    tmp_path: PathBuf,
+    writer: BufWriter<File>,
+    pos: u64,
+    block: Vec<u8>,
+    blocks: Vec<BlockHandle>,
+    hashes: Vec<u64>,
+    last_key: Vec<u8>,
+    bits_per_key: usize,
+}
+
+impl TableBuilder {
+    /// Creates the table `<id>.sst` in `dir` with a Bloom filter of `bits_per_key`
+    /// bits per key.
+    pub fn create(dir: &Path, id: u64, bits_per_key: usize) -> Result<TableBuilder> {
+        let path = table_path(dir, id);
+        let tmp_path = partial_path(&path);
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        Ok(TableBuilder {
+            id,
+            path,
+            tmp_path,
+            writer,
+            pos: HEADER_LEN,
+            block: Vec::new(),
+            blocks: Vec::new(),
+            hashes: Vec::new(),
+            last_key: Vec::new(),
+            bits_per_key,
+        })
+    }
+
+    /// Adds the entry of a key, which must be greater than all keys added before.
+    pub fn add(&mut self, key: &[u8], entry: &Entry) -> Result<()> {
+        if self.block.is_empty() {
+            self.blocks.push(BlockHandle {
+                first_key: key.to_vec(),
+                offset: self.pos,
+                len: 0,
+            });
+            // room for the checksum
+            self.block.extend_from_slice(&[0; 4]);
+        }
+        format::encode(&mut self.block, key, entry);
+        self.hashes.push(bloom::hash(key));
+        self.last_key = key.to_vec();
+        if self.block.len() >= BLOCK_SIZE {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self) -> Result<()> {
+        let crc = crc32fast::hash(&self.block[4..]);
+        self.block[..4].copy_from_slice(&crc.to_le_bytes());
+        self.writer.write_all(&self.block)?;
+        let handle = self.blocks.last_mut().unwrap();
+        handle.len = self.block.len() as u32;
+        self.pos += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Returns the number of bytes written so far.
+    pub fn size(&self) -> u64 {
+        self.pos + self.block.len() as u64
+    }
+
+    /// Writes the meta block and the footer and renames the table to its final
+    /// name. The table is synced to the disk first if `sync` is true.
+    pub fn finish(mut self, sync: bool) -> Result<Table> {
+        if !self.block.is_empty() {
+            self.write_block()?;
+        }
+        let mut meta = Vec::new();
+        let bloom = Bloom::build(&self.hashes, self.bits_per_key);
+        let mut bloom_buf = Vec::new();
+        bloom.encode(&mut bloom_buf);
+        meta.extend_from_slice(&(bloom_buf.len() as u32).to_le_bytes());
+        meta.extend_from_slice(&bloom_buf);
+        meta.extend_from_slice(&(self.blocks.len() as u32).to_le_bytes());
+        for handle in &self.blocks {
+            meta.extend_from_slice(&handle.offset.to_le_bytes());
+            meta.extend_from_slice(&handle.len.to_le_bytes());
+            meta.extend_from_slice(&(handle.first_key.len() as u32).to_le_bytes());
+            meta.extend_from_slice(&handle.first_key);
+        }
+        meta.extend_from_slice(&(self.last_key.len() as u32).to_le_bytes());
+        meta.extend_from_slice(&self.last_key);
+
+        self.writer.write_all(&meta)?;
+        self.writer.write_all(&self.pos.to_le_bytes())?;
+        self.writer
+            .write_all(&(self.hashes.len() as u64).to_le_bytes())?;
+        self.writer
+            .write_all(&crc32fast::hash(&meta).to_le_bytes())?;
+        self.writer.write_all(MAGIC)?;
+        self.writer.flush()?;
+        if sync {
+            self.writer.get_ref().sync_all()?;
+        }
+        fs::rename(&self.tmp_path, &self.path)?;
+        Table::open(&self.path, self.id)
+    }
+}
+
+/// An SSTable opened for reading.
+///
+/// The sparse index and the Bloom filter are kept in memory.
+pub struct Table {
+    id: u64,
+    file: Mutex<File>,
+    blocks: Vec<BlockHandle>,
+    bloom: Bloom,
+    largest: Vec<u8>,
+    size: u64,
+}
+
+impl Table {
+    /// Opens the table at `path` and reads its meta block.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::CorruptedLog` if the header, the footer or the meta
+    /// block is invalid.
+    pub fn open(path: &Path, id: u64) -> Result<Table> {
+        let corrupted = || KvsError::CorruptedLog(format!("{}.sst is corrupted", id));
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len();
+        if size < HEADER_LEN + FOOTER_LEN {
+            return Err(corrupted());
+        }
+        let mut header = [0; HEADER_LEN as usize];
+        file.read_exact(&mut header)?;
+        if &header[..4] != MAGIC || header[4..] != VERSION.to_le_bytes() {
+            return Err(corrupted());
+        }
+        let mut footer = [0; FOOTER_LEN as usize];
+        file.seek(SeekFrom::Start(size - FOOTER_LEN))?;
+        file.read_exact(&mut footer)?;
+        let mut footer = &footer[..];
+        let meta_offset = read_u64(&mut footer).unwrap();
+        let _count = read_u64(&mut footer).unwrap();
+        let meta_crc = read_u32(&mut footer).unwrap();
+        if footer != MAGIC || meta_offset < HEADER_LEN || meta_offset > size - FOOTER_LEN {
+            return Err(corrupted());
+        }
+        let mut meta = vec![0; (size - FOOTER_LEN - meta_offset) as usize];
+        file.seek(SeekFrom::Start(meta_offset))?;
+        file.read_exact(&mut meta)?;
+        if crc32fast::hash(&meta) != meta_crc {
+            return Err(corrupted());
+        }
+        let (bloom, blocks, largest) = decode_meta(&meta).ok_or_else(corrupted)?;
+        Ok(Table {
+            id,
+            file: Mutex::new(file),
+            blocks,
+            bloom,
+            largest,
+            size,
+        })
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the size of the table file in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn smallest(&self) -> &[u8] {
+        self.blocks.first().map_or(&[], |handle| &handle.first_key)
+    }
+
+    pub fn largest(&self) -> &[u8] {
+        &self.largest
+    }
+
+    /// Returns whether the key range of the table overlaps `[start, end]`.
+    pub fn overlaps(&self, start: &[u8], end: &[u8]) -> bool {
+        self.smallest() <= end && self.largest() >= start
+    }
+
+    /// Looks up the entry of `key`.
+    pub fn get(&self, key: &[u8]) -> Result<Option<Entry>> {
+        if key < self.smallest()
+            || key > self.largest()
+            || !self.bloom.may_contain(bloom::hash(key))
+        {
+            return Ok(None);
+        }
+        // the last block whose first key is not greater than the key
+        let block = self
+            .blocks
+            .partition_point(|handle| handle.first_key.as_slice() <= key)
+            - 1;
+        Ok(self
+            .read_block(block)?
+            .into_iter()
+            .find(|(other, _)| other.as_slice() == key)
+            .map(|(_, entry)| entry))
+    }
+
+    /// Iterates the entries of the keys after `start` in key order.
+    pub fn iter(self: &Arc<Self>, start: Bound<Vec<u8>>) -> TableIter {
+        let block = match &start {
+            Bound::Included(key) | Bound::Excluded(key) => self
+                .blocks
+                .partition_point(|handle| handle.first_key <= *key)
+                .saturating_sub(1),
+            Bound::Unbounded => 0,
+        };
+        TableIter {
+            table: Arc::clone(self),
+            next_block: block,
+            entries: Vec::new().into_iter(),
+            start,
+        }
+    }
+
+    /// Reads every block of the table and checks its checksum.
+    pub fn verify(&self) -> Result<()> {
+        for block in 0..self.blocks.len() {
+            self.read_block(block)?;
+        }
+        Ok(())
+    }
+
+    fn read_block(&self, block: usize) -> Result<Vec<(Vec<u8>, Entry)>> {
+        let corrupted =
+            || KvsError::CorruptedLog(format!("block {} of {}.sst is corrupted", block, self.id));
+        let handle = &self.blocks[block];
+        let mut buf = vec![0; handle.len as usize];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(handle.offset))?;
+            file.read_exact(&mut buf)?;
+        }
+        let mut rest = &buf[..];
+        let crc = read_u32(&mut rest).ok_or_else(corrupted)?;
+        if crc32fast::hash(rest) != crc {
+            return Err(corrupted());
+        }
+        let mut entries = Vec::new();
+        while !rest.is_empty() {
+            entries.push(format::decode(&mut rest).ok_or_else(corrupted)?);
+        }
+        Ok(entries)
+    }
+}
+
+/// Iterates the entries of a table in key order, reading a block at a time.
+pub struct TableIter {
+    table: Arc<Table>,
+    next_block: usize,
+    entries: vec::IntoIter<(Vec<u8>, Entry)>,
+    // entries before the start are skipped in the first block
+    start: Bound<Vec<u8>>,
+}
+
+impl Iterator for TableIter {
+    type Item = Result<(Vec<u8>, Entry)>;
+
+    fn next(&mut self) -> Option<Result<(Vec<u8>, Entry)>> {
+        loop {
+            if let Some((key, entry)) = self.entries.next() {
+                let after_start = match &self.start {
+                    Bound::Included(start) => key >= *start,
+                    Bound::Excluded(start) => key > *start,
+                    Bound::Unbounded => true,
+                };
+                if after_start {
+                    self.start = Bound::Unbounded;
+                    return Some(Ok((key, entry)));
+                }
+                continue;
+            }
+            if self.next_block >= self.table.blocks.len() {
+                return None;
+            }
+            match self.table.read_block(self.next_block) {
+                Ok(entries) => self.entries = entries.into_iter(),
+                Err(e) => {
+                    // stop after the error
+                    self.next_block = self.table.blocks.len();
+                    return Some(Err(e));
+                }
+            }
+            self.next_block += 1;
+        }
+    }
+}
+
+fn decode_meta(mut meta: &[u8]) -> Option<(Bloom, Vec<BlockHandle>, Vec<u8>)> {
+    let bloom_len = read_u32(&mut meta)?;
+    let bloom = Bloom::decode(take(&mut meta, bloom_len as usize)?)?;
+    let count = read_u32(&mut meta)?;
+    let mut blocks = Vec::new();
+    for _ in 0..count {
+        let offset = read_u64(&mut meta)?;
+        let len = read_u32(&mut meta)?;
+        let key_len = read_u32(&mut meta)?;
+        let first_key = take(&mut meta, key_len as usize)?.to_vec();
+        blocks.push(BlockHandle {
+            first_key,
+            offset,
+            len,
+        });
+    }
+    let key_len = read_u32(&mut meta)?;
+    let largest = take(&mut meta, key_len as usize)?.to_vec();
+    if blocks.is_empty() || !meta.is_empty() {
+        return None;
+    }
+    Some((bloom, blocks, largest))
+}
+
+/// Returns the temporary path a table is written to before it is renamed to `path`.
+pub fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".partial");
+    path.with_file_name(name)
+}
@@ -0,0 +1,122 @@
+//! Write-ahead logs of the memtables.
+//!
+//! Every memtable has a log file `<id>.wal` that holds its writes until it is
+//! flushed to an SSTable. The log starts with the magic bytes `KVSW` and a little
+//! endian `u32` version, followed by a record for every write:
+//!
+//! ```text
+//! | payload length: u32 | crc32 of payload: u32 | payload |
+//! ```
+//!
+//! The payload holds all entries of the write, so a batch is either replayed
+//! as a whole or not at all.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use super::format::{self, read_u32, take, Entry};
+use super::wal_path;
+use crate::{KvsError, Result};
+
+const MAGIC: &[u8; 4] = b"KVSW";
+const VERSION: u32 = 1;
+const HEADER_LEN: usize = 8;
+
+/// The write-ahead log of the active memtable
+pub struct Wal {
+    writer: BufWriter<File>,
+}
+
+impl Wal {
+    /// Creates the log file `<id>.wal` in `dir`.
+    pub fn create(dir: &Path, id: u64) -> Result<Wal> {
+        let mut writer = BufWriter::new(File::create(wal_path(dir, id))?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.flush()?;
+        Ok(Wal { writer })
+    }
+
+    /// Appends the entries of a write as a single record and flushes it to the
+    /// operating system.
+    pub fn append(&mut self, entries: &[(Vec<u8>, Entry)]) -> Result<()> {
+        let mut payload = Vec::new();
+        for (key, entry) in entries {
+            format::encode(&mut payload, key, entry);
+        }
+        self.writer
+            .write_all(&(payload.len() as u32).to_le_bytes())?;
+        self.writer
+            .write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+        self.writer.write_all(&payload)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Syncs the log to the disk.
+    pub fn sync(&self) -> Result<()> {
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+}
+
+/// Replays the writes of the log file `<id>.wal` in `dir` in order.
+///
+/// A record cut off at the end of the file is the result of a crash during
+/// writing. It has never been acknowledged, so it is ignored and the file is
+/// truncated to the last complete record.
+pub fn replay<F>(dir: &Path, id: u64, mut apply: F) -> Result<()>
+where
+    F: FnMut(Vec<u8>, Entry),
+{
+    let path = wal_path(dir, id);
+    let buf = fs::read(&path)?;
+    if buf.len() < HEADER_LEN {
+        // cut off right after it was created
+        return Ok(());
+    }
+    if &buf[..4] != MAGIC || buf[4..HEADER_LEN] != VERSION.to_le_bytes() {
+        return Err(KvsError::CorruptedLog(format!(
+            "{}.wal has an invalid header",
+            id
+        )));
+    }
+    let mut rest = &buf[HEADER_LEN..];
+    while !rest.is_empty() {
+        match decode_record(&mut rest) {
+            Some(entries) => {
+                for (key, entry) in entries {
+                    apply(key, entry);
+                }
+            }
+            None => {
+                let pos = buf.len() - rest.len();
+                warn!("Ignoring corrupted record at {} of {}.wal", pos, id);
+                OpenOptions::new()
+                    .write(true)
+                    .open(&path)?
+                    .set_len(pos as u64)?;
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Decodes the record at the front of `buf` and advances `buf` past it.
+fn decode_record(buf: &mut &[u8]) -> Option<Vec<(Vec<u8>, Entry)>> {
+    let mut rest = *buf;
+    let len = read_u32(&mut rest)?;
+    let crc = read_u32(&mut rest)?;
+    let mut payload = take(&mut rest, len as usize)?;
+    if crc32fast::hash(payload) != crc {
+        return None;
+    }
+    let mut entries = Vec::new();
+    while !payload.is_empty() {
+        entries.push(format::decode(&mut payload)?);
+    }
+    *buf = rest;
+    Some(entries)
+}
@@ -0,0 +1,330 @@
+use super::batch::BatchOp;
+use super::{expiry_time, now_millis, KvsEngine, WriteBatch};
+use crate::{KvsError, Result};
+use crossbeam_skiplist::SkipMap;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::prelude::*;
+
+/// A key/value storage engine that keeps all pairs in memory.
+///
+/// Nothing is written to disk, so the data is lost when the last handle is
+/// dropped. The pairs are kept in a concurrent skip list, which serves reads and
+/// scans without locking. Writes take a lock, so compare-and-swap and transactions
+/// see no concurrent writes. Operations never block, so they run on the calling
+/// thread and the returned futures are already complete.
+///
+/// With a size limit, the least recently read or written keys are evicted once the
+/// keys and values take more than the limit. This makes the engine usable as a
+/// cache.
+///
+/// ```rust
+/// # use kvs::{MemoryKvsEngine, Result};
+/// # use tokio::prelude::*;
+/// # fn try_main() -> Result<()> {
+/// use kvs::KvsEngine;
+/// let engine = MemoryKvsEngine::new();
+/// engine.set(b"key".to_vec(), b"value".to_vec()).wait()?;
+/// let val = engine.get(b"key".to_vec()).wait()?;
+/// assert_eq!(val, Some(b"value".to_vec()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Default)]
+pub struct MemoryKvsEngine {
+    map: Arc<SkipMap<Vec<u8>, MemoryEntry>>,
+    // held by writes, and by reads if keys are evicted
+    lru: Arc<Mutex<LruState>>,
+    // the size limit in bytes of the keys and values
+    capacity: Option<u64>,
+}
+
+struct MemoryEntry {
+    value: Vec<u8>,
+    // expiry time in milliseconds since the UNIX epoch
+    expires_at: Option<u64>,
+    // the last access, which only changes under the LRU lock
+    tick: AtomicU64,
+}
+
+#[derive(Default)]
+struct LruState {
+    // the number of bytes of the keys and values
+    size: u64,
+    // increases on every access
+    tick: u64,
+    // keys ordered by their last access
+    keys: BTreeMap<u64, Vec<u8>>,
+}
+
+impl MemoryKvsEngine {
+    /// Creates an empty engine without a size limit.
+    pub fn new() -> MemoryKvsEngine {
+        MemoryKvsEngine::default()
+    }
+
+    /// Creates an empty engine that keeps at most `capacity` bytes of keys and
+    /// values.
+    ///
+    /// The least recently used keys are evicted when a write exceeds the limit. A
+    /// pair larger than the limit is evicted right after it is written.
+    pub fn with_capacity(capacity: u64) -> MemoryKvsEngine {
+        MemoryKvsEngine {
+            capacity: Some(capacity),
+            ..MemoryKvsEngine::default()
+        }
+    }
+
+    /// Returns the number of bytes of the keys and values, including the ones that
+    /// have expired but are not evicted yet.
+    pub fn size(&self) -> u64 {
+        self.lru.lock().unwrap().size
+    }
+
+    /// Runs a write under the LRU lock and returns its result as a future.
+    fn write<F, T>(&self, write: F) -> Box<dyn Future<Item = T, Error = KvsError> + Send>
+    where
+        F: FnOnce(&mut Writer) -> Result<T>,
+        T: Send + 'static,
+    {
+        let mut lru = self.lru.lock().unwrap();
+        let mut writer = Writer {
+            engine: self,
+            lru: &mut lru,
+        };
+        let res = write(&mut writer);
+        writer.evict();
+        Box::new(future::result(res))
+    }
+
+    /// Returns the value of `key` if it is neither missing nor expired.
+    fn live_value(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let entry = self.map.get(key)?;
+        let entry = entry.value();
+        if entry.expires_at.is_some_and(|t| t <= now_millis()) {
+            return None;
+        }
+        Some(entry.value.clone())
+    }
+
+    /// Collects at most `limit` unexpired pairs from `start` on while `in_range`
+    /// holds.
+    fn scan_with<F>(
+        &self,
+        start: Vec<u8>,
+        in_range: F,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send>
+    where
+        F: Fn(&[u8]) -> bool,
+    {
+        let now = now_millis();
+        let pairs: Vec<_> = self
+            .map
+            .range((Bound::Included(start), Bound::Unbounded))
+            .take_while(|entry| in_range(entry.key()))
+            .filter(|entry| entry.value().expires_at.map_or(true, |t| t > now))
+            .map(|entry| (entry.key().clone(), entry.value().value.clone()))
+            .take(limit.unwrap_or(usize::MAX))
+            .collect();
+        Box::new(stream::iter_ok(pairs))
+    }
+}
+
+/// Changes the map and its LRU order under the LRU lock.
+struct Writer<'a> {
+    engine: &'a MemoryKvsEngine,
+    lru: &'a mut LruState,
+}
+
+impl<'a> Writer<'a> {
+    fn live_value(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.engine.live_value(key)
+    }
+
+    fn insert(&mut self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) {
+        // the old entry is replaced in place, so readers never miss the key
+        if let Some(entry) = self.engine.map.get(&key) {
+            let entry = entry.value();
+            self.lru.keys.remove(&entry.tick.load(Ordering::SeqCst));
+            self.lru.size -= (key.len() + entry.value.len()) as u64;
+        }
+        self.lru.tick += 1;
+        self.lru.size += (key.len() + value.len()) as u64;
+        self.lru.keys.insert(self.lru.tick, key.clone());
+        let entry = MemoryEntry {
+            value,
+            expires_at,
+            tick: AtomicU64::new(self.lru.tick),
+        };
+        self.engine.map.insert(key, entry);
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(entry) = self.engine.map.remove(key) {
+            let entry = entry.value();
+            self.lru.keys.remove(&entry.tick.load(Ordering::SeqCst));
+            self.lru.size -= (key.len() + entry.value.len()) as u64;
+        }
+    }
+
+    fn apply(&mut self, batch: WriteBatch) {
+        for op in batch.into_ops() {
+            match op {
+                BatchOp::Set { key, value } => self.insert(key, value, None),
+                BatchOp::Remove { key } => self.remove(&key),
+            }
+        }
+    }
+
+    /// Evicts the least recently used keys until the size is within the limit.
+    fn evict(&mut self) {
+        let capacity = match self.engine.capacity {
+            Some(capacity) => capacity,
+            None => return,
+        };
+        while self.lru.size > capacity {
+            let key = match self.lru.keys.values().next() {
+                Some(key) => key.clone(),
+                None => break,
+            };
+            self.remove(&key);
+            debug!("Evicted {:?}", String::from_utf8_lossy(&key));
+        }
+    }
+}
+
+impl KvsEngine for MemoryKvsEngine {
+    fn set(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(move |writer| {
+            writer.insert(key, value, None);
+            Ok(())
+        })
+    }
+
+    fn set_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let expires_at = expiry_time(ttl);
+        self.write(move |writer| {
+            writer.insert(key, value, Some(expires_at));
+            Ok(())
+        })
+    }
+
+    /// Gets the value of a given key.
+    ///
+    /// With a size limit, the key becomes the most recently used one.
+    fn get(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send> {
+        if self.capacity.is_none() {
+            return Box::new(future::ok(self.live_value(&key)));
+        }
+        let mut lru = self.lru.lock().unwrap();
+        let value = self.live_value(&key);
+        if value.is_some() {
+            lru.tick += 1;
+            let tick = lru.tick;
+            let entry = self.map.get(&key).unwrap();
+            let last = entry.value().tick.swap(tick, Ordering::SeqCst);
+            lru.keys.remove(&last);
+            lru.keys.insert(tick, key);
+        }
+        Box::new(future::ok(value))
+    }
+
+    fn remove(&self, key: Vec<u8>) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(move |writer| {
+            if writer.live_value(&key).is_none() {
+                return Err(KvsError::KeyNotFound);
+            }
+            writer.remove(&key);
+            Ok(())
+        })
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Box<dyn Future<Item = bool, Error = KvsError> + Send> {
+        self.write(move |writer| {
+            if writer.live_value(&key) != expected {
+                return Ok(false);
+            }
+            match new {
+                Some(value) => writer.insert(key, value, None),
+                None => writer.remove(&key),
+            }
+            Ok(true)
+        })
+    }
+
+    fn commit_transaction(
+        &self,
+        reads: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = bool, Error = KvsError> + Send> {
+        self.write(move |writer| {
+            for (key, value) in &reads {
+                if writer.live_value(key) != *value {
+                    return Ok(false);
+                }
+            }
+            writer.apply(batch);
+            Ok(true)
+        })
+    }
+
+    fn write_batch(
+        &self,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(move |writer| {
+            writer.apply(batch);
+            Ok(())
+        })
+    }
+
+    fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send> {
+        self.scan_with(
+            start,
+            |key| end.as_ref().map_or(true, |end| key < end.as_slice()),
+            limit,
+        )
+    }
+
+    fn scan_prefix(
+        &self,
+        prefix: Vec<u8>,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send> {
+        self.scan_with(prefix.clone(), |key| key.starts_with(&prefix), limit)
+    }
+
+    /// Always fails, because the memory engine keeps no data to restore.
+    fn backup_to(&self, _path: PathBuf) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        Box::new(future::err(KvsError::Unsupported(
+            "The memory engine does not support backups".to_owned(),
+        )))
+    }
+}
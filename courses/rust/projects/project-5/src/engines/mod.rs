@@ -1,28 +1,268 @@
-pub use self::kvs::KvStore;
+pub use self::batch::WriteBatch;
+pub use self::kvs::{Compression, IndexBackend, KvStore, KvStoreOptions, Snapshot, Transaction};
+pub use self::lsm::{LsmKvsEngine, LsmOptions};
+pub use self::memory::MemoryKvsEngine;
 pub use self::sled::SledKvsEngine;
-use crate::KvsError;
+pub use self::sync::SyncPolicy;
+pub use self::watch::{CopyStream, WatchEvent, WatchSeq, WatchStream};
+use crate::{KvsError, Result};
 
-use tokio::prelude::Future;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::prelude::{future, stream, Future, Stream};
 
+mod batch;
 mod kvs;
+mod lsm;
+mod memory;
 mod sled;
+mod sync;
+mod watch;
+
+/// The maximum number of pairs a scan reads at a time
+const SCAN_BATCH_LEN: usize = 1024;
+
+/// A batch of pairs read by a scan
+type ScanBatch = Box<dyn Future<Item = Vec<(Vec<u8>, Vec<u8>)>, Error = KvsError> + Send>;
 
 /// Trait for a key value storage engine.
 pub trait KvsEngine: Clone + Send + 'static {
-    /// Sets the value of a string key to a string.
+    /// Sets the value of a key.
     ///
     /// If the key already exists, the previous value will be overwritten.
-    fn set(&self, key: String, value: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+    fn set(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Sets the value of a key that expires after `ttl`.
+    ///
+    /// An expired key is treated as missing. Overwriting the key with `set`
+    /// clears its expiry.
+    fn set_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
 
-    /// Gets the string value of a given string key.
+    /// Gets the value of a given key.
     ///
-    /// Returns `None` if the given key does not exist.
-    fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send>;
+    /// Returns `None` if the given key does not exist or has expired.
+    fn get(&self, key: Vec<u8>)
+        -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send>;
 
     /// Removes a given key.
     ///
     /// # Errors
     ///
-    /// It returns `KvsError::KeyNotFound` if the given key is not found.
-    fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+    /// It returns `KvsError::KeyNotFound` if the given key is not found or has
+    /// expired.
+    fn remove(&self, key: Vec<u8>) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Sets the value of a key to `new` if its current value is `expected`.
+    ///
+    /// `None` stands for a missing key, so a `None` `expected` value only matches a
+    /// missing or expired key and a `None` `new` value removes the key. The key does
+    /// not expire after it is swapped.
+    ///
+    /// Returns whether the value was swapped.
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Box<dyn Future<Item = bool, Error = KvsError> + Send>;
+
+    /// Sets the value of a key if it does not exist or has expired.
+    ///
+    /// Returns whether the value was set.
+    fn set_if_absent(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<dyn Future<Item = bool, Error = KvsError> + Send> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    /// Applies all writes in the batch atomically if every key in `reads` still has
+    /// the value read, where `None` stands for a missing or expired key.
+    ///
+    /// It commits an optimistic transaction whose reads and writes are tracked by
+    /// the caller. Returns whether the batch was applied.
+    fn commit_transaction(
+        &self,
+        reads: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = bool, Error = KvsError> + Send>;
+
+    /// Applies all writes in the batch atomically.
+    ///
+    /// After a crash, either all or none of the writes in the batch are visible.
+    fn write_batch(&self, batch: WriteBatch)
+        -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Scans key/value pairs whose keys are in the range `[start, end)`.
+    ///
+    /// Pairs are yielded in ascending byte order of the keys. The range is unbounded
+    /// above if `end` is `None`. At most `limit` pairs are yielded if `limit` is given.
+    ///
+    /// Engines may read the pairs in batches as the stream is polled, so a scan may
+    /// see some of the writes made while it runs.
+    fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send>;
+
+    /// Scans key/value pairs whose keys start with `prefix`.
+    ///
+    /// Pairs are yielded in ascending byte order of the keys. At most `limit` pairs are
+    /// yielded if `limit` is given.
+    fn scan_prefix(
+        &self,
+        prefix: Vec<u8>,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send>;
+
+    /// Scans the keys in the range `[start, end)` without their values.
+    ///
+    /// Keys are yielded like the pairs of `scan`. By default the pairs of `scan` are
+    /// read and their values dropped.
+    fn scan_keys(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = Vec<u8>, Error = KvsError> + Send> {
+        Box::new(self.scan(start, end, limit).map(|(key, _)| key))
+    }
+
+    /// Writes a consistent copy of the data to a new directory at `path`.
+    ///
+    /// The copy contains the data as of some point during the backup and can be
+    /// installed with the `restore` function of the engine. It fails if `path`
+    /// already exists.
+    fn backup_to(&self, path: PathBuf) -> Box<dyn Future<Item = (), Error = KvsError> + Send>;
+
+    /// Streams the changes of the keys starting with `prefix` in commit order.
+    ///
+    /// The returned future resolves once the watch is registered, to the position
+    /// of the last change before the watch and the stream of the later changes. If
+    /// `from` is given, the changes after `from` are streamed first, so a client
+    /// can resume from the last change it has seen. Keys that expire produce no
+    /// changes.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Unsupported` if the engine does not support watching,
+    /// `KvsError::InvalidArgument` if `from` is not a position of the engine, or
+    /// `KvsError::StringError` if the changes after `from` are no longer kept.
+    fn watch(
+        &self,
+        _prefix: Vec<u8>,
+        _from: Option<WatchSeq>,
+    ) -> Box<dyn Future<Item = (WatchSeq, WatchStream), Error = KvsError> + Send> {
+        Box::new(future::err(KvsError::Unsupported(
+            "The engine does not support watching".to_owned(),
+        )))
+    }
+
+    /// Takes a consistent copy of all pairs and streams the changes after it.
+    ///
+    /// The returned future resolves to the position of the copy, the copied pairs
+    /// as chunks of `WatchEvent::Set` changes at that position in key order, and the
+    /// stream of the later changes. A replica uses it to catch up with its primary.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Unsupported` if the engine does not support watching.
+    fn copy_and_watch(
+        &self,
+    ) -> Box<dyn Future<Item = (WatchSeq, CopyStream, WatchStream), Error = KvsError> + Send> {
+        Box::new(future::err(KvsError::Unsupported(
+            "The engine does not support watching".to_owned(),
+        )))
+    }
+
+    /// Returns the counters of the engine.
+    ///
+    /// Counters the engine does not keep are zero.
+    fn stats(&self) -> EngineStats {
+        EngineStats::default()
+    }
+}
+
+/// Counters of a storage engine.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EngineStats {
+    /// The number of reads served from the value cache
+    pub cache_hits: u64,
+    /// The number of reads that missed the value cache
+    pub cache_misses: u64,
+    /// The number of bytes of keys and values in the value cache
+    pub cache_size: u64,
+}
+
+/// Returns the current time in milliseconds since the UNIX epoch.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Returns the expiry time of a key that is set now and expires after `ttl`.
+fn expiry_time(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+/// Yields the pairs of a scan from `start` in key order, reading them in batches of
+/// at most `SCAN_BATCH_LEN` pairs as the stream is polled.
+///
+/// `read_batch` reads the live pairs from a start bound up to a number of pairs. A
+/// batch with fewer pairs ends the scan, and the next batch starts after the last
+/// key of the previous one.
+fn scan_in_batches<F>(
+    start: Bound<Vec<u8>>,
+    limit: Option<usize>,
+    mut read_batch: F,
+) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send>
+where
+    F: FnMut(Bound<Vec<u8>>, usize) -> ScanBatch + Send + 'static,
+{
+    let remaining = limit.unwrap_or(usize::MAX);
+    let batches = stream::unfold(Some((start, remaining)), move |next| {
+        let (start, remaining) = next?;
+        if remaining == 0 {
+            return None;
+        }
+        let len = remaining.min(SCAN_BATCH_LEN);
+        Some(read_batch(start, len).map(move |pairs| {
+            let next = match pairs.last() {
+                Some((key, _)) if pairs.len() == len => {
+                    Some((Bound::Excluded(key.clone()), remaining - len))
+                }
+                _ => None,
+            };
+            (pairs, next)
+        }))
+    });
+    Box::new(batches.map(stream::iter_ok).flatten())
+}
+
+/// Copies a file to a temporary name next to `to`, syncs it and renames it to `to`.
+fn install_file(from: &Path, to: &Path) -> Result<()> {
+    let mut tmp_name = to.file_name().unwrap_or_default().to_owned();
+    tmp_name.push(".restoring");
+    let tmp_path = to.with_file_name(tmp_name);
+    fs::copy(from, &tmp_path)?;
+    File::open(&tmp_path)?.sync_all()?;
+    fs::rename(&tmp_path, to)?;
+    Ok(())
 }
@@ -1,14 +1,41 @@
+use super::batch::BatchOp;
+use super::{expiry_time, now_millis, scan_in_batches};
 use crate::thread_pool::ThreadPool;
-use crate::{KvsEngine, KvsError, Result};
-use sled::Db;
+use crate::{KvsEngine, KvsError, Result, SyncPolicy, WriteBatch};
+use sled::transaction::{self, ConflictableTransactionResult, Transactional, TransactionalTree};
+use sled::{Batch, Db, Tree};
+use std::fs;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use tokio::prelude::*;
 use tokio::sync::oneshot;
 
+/// The name of the tree that maps keys with a TTL to their expiry time
+const EXPIRY_TREE: &str = "kvs_expiry";
+
+/// The number of copies a backup makes while writes go on before it stops writes
+/// for its copy
+const BACKUP_ATTEMPTS: usize = 3;
+
 /// Wrapper of `sled::Db`
+///
+/// Keys and values are stored in the default tree. The expiry times of keys set
+/// with a TTL are stored in a separate tree and updated in the same transaction.
 #[derive(Clone)]
 pub struct SledKvsEngine<P: ThreadPool> {
     pool: P,
     db: Db,
+    // expiry time in milliseconds since the UNIX epoch of each key with a TTL
+    expiry: Tree,
+    sync_policy: SyncPolicy,
+    // writes hold it shared, and a backup holds it exclusively to count the writes
+    // with none running, as sled has no snapshots
+    backup_lock: Arc<RwLock<()>>,
+    // the number of finished writes
+    writes: Arc<AtomicU64>,
 }
 
 impl<P: ThreadPool> SledKvsEngine<P> {
@@ -17,21 +44,110 @@ impl<P: ThreadPool> SledKvsEngine<P> {
     /// Operations are run in the given thread pool. `concurrency` specifies the number of
     /// threads in the thread pool.
     pub fn new(db: Db, concurrency: u32) -> Result<Self> {
+        SledKvsEngine::with_sync_policy(db, concurrency, SyncPolicy::Always)
+    }
+
+    /// Creates a `SledKvsEngine` from `sled::Db` which syncs writes as `sync_policy`
+    /// specifies.
+    ///
+    /// `SyncPolicy::Always` and `SyncPolicy::GroupCommit` flush the database after
+    /// every write. `SyncPolicy::Never` and `SyncPolicy::Interval` leave it to the
+    /// background flusher of sled, whose interval is set by
+    /// `sled::Config::flush_every_ms`. sled flushes every 500ms by default, so the
+    /// database should be opened with `flush_every_ms(None)` for `SyncPolicy::Never`.
+    pub fn with_sync_policy(db: Db, concurrency: u32, sync_policy: SyncPolicy) -> Result<Self> {
         let pool = P::new(concurrency)?;
-        Ok(SledKvsEngine { pool, db })
+        let expiry = db.open_tree(EXPIRY_TREE)?;
+        Ok(SledKvsEngine {
+            pool,
+            db,
+            expiry,
+            sync_policy,
+            backup_lock: Arc::new(RwLock::new(())),
+            writes: Arc::new(AtomicU64::new(0)),
+        })
     }
-}
 
-impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
-    fn set(&self, key: String, value: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+    /// Installs a backup written by `backup_to` into the sled database at `path`.
+    ///
+    /// The backup is read completely before the database, which must be empty, is
+    /// touched. The copy is verified against the checksum of the backup before it
+    /// is flushed, and the database is emptied again if it does not match.
+    ///
+    /// # Errors
+    ///
+    /// It propagates sled errors if the backup cannot be opened and returns
+    /// `KvsError::StringError` if the database at `path` is not empty or the copy
+    /// does not match the backup.
+    pub fn restore(backup: impl AsRef<Path>, path: impl AsRef<Path>) -> Result<()> {
+        let backup = backup.as_ref();
+        if !backup.is_dir() {
+            return Err(KvsError::StringError(format!(
+                "{} is not a directory",
+                backup.display()
+            )));
+        }
+        let backup_db = sled::open(backup)?;
+        // every pair is read first, as sled panics if the backup cannot be read
+        // during the import
+        let checksum = backup_db.checksum()?;
+        let db = sled::open(path.as_ref())?;
+        let trees = |db: &Db| -> sled::Result<Vec<Tree>> {
+            db.tree_names()
+                .iter()
+                .map(|name| db.open_tree(name))
+                .collect()
+        };
+        if !trees(&db)?.iter().all(Tree::is_empty) {
+            return Err(KvsError::StringError(format!(
+                "{} is not empty",
+                path.as_ref().display()
+            )));
+        }
+        db.import(backup_db.export());
+        if db.checksum()? != checksum {
+            for tree in trees(&db)? {
+                tree.clear()?;
+            }
+            db.flush()?;
+            return Err(KvsError::StringError(
+                "The restored data does not match the backup".to_owned(),
+            ));
+        }
+        db.flush()?;
+        info!(
+            "Restored {} from {}",
+            path.as_ref().display(),
+            backup.display()
+        );
+        Ok(())
+    }
+
+    /// Runs a write over the default and the expiry trees in a transaction in the
+    /// thread pool and syncs the database as the sync policy requires.
+    fn write<F, T>(&self, write: F) -> Box<dyn Future<Item = T, Error = KvsError> + Send>
+    where
+        F: Fn(&TransactionalTree, &TransactionalTree) -> ConflictableTransactionResult<T, KvsError>
+            + Send
+            + 'static,
+        T: Send + 'static,
+    {
         let db = self.db.clone();
+        let expiry = self.expiry.clone();
+        let sync_policy = self.sync_policy;
+        let backup_lock = self.backup_lock.clone();
+        let writes = self.writes.clone();
         let (tx, rx) = oneshot::channel();
         self.pool.spawn(move || {
-            let res = db
-                .set(key, value.into_bytes())
-                .and_then(|_| db.flush())
-                .map(|_| ())
-                .map_err(KvsError::from);
+            let _guard = backup_lock.read().unwrap();
+            let res = (&*db, &expiry)
+                .transaction(|(data, expiry)| write(data, expiry))
+                .map_err(KvsError::from)
+                .and_then(|output| {
+                    sync(&db, sync_policy)?;
+                    Ok(output)
+                });
+            writes.fetch_add(1, Ordering::SeqCst);
             if tx.send(res).is_err() {
                 error!("Receiving end is dropped");
             }
@@ -42,16 +158,86 @@ impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
         )
     }
 
-    fn get(&self, key: String) -> Box<dyn Future<Item = Option<String>, Error = KvsError> + Send> {
+    /// Runs a scan over `db` from `start` in batches in the thread pool as the
+    /// stream is polled, and yields the pairs they collect.
+    ///
+    /// `scan` collects at most the given number of pairs from the given start bound.
+    fn scan_with<F>(
+        &self,
+        start: Bound<Vec<u8>>,
+        limit: Option<usize>,
+        scan: F,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send>
+    where
+        F: Fn(&Db, &Tree, Bound<Vec<u8>>, usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        let db = self.db.clone();
+        let expiry = self.expiry.clone();
+        let pool = self.pool.clone();
+        let scan = Arc::new(scan);
+        scan_in_batches(start, limit, move |start, len| {
+            let db = db.clone();
+            let expiry = expiry.clone();
+            let scan = Arc::clone(&scan);
+            let (tx, rx) = oneshot::channel();
+            pool.spawn(move || {
+                let res = scan(&db, &expiry, start, len);
+                if tx.send(res).is_err() {
+                    error!("Receiving end is dropped");
+                }
+            });
+            Box::new(
+                rx.map_err(|e| KvsError::StringError(format!("{}", e)))
+                    .flatten(),
+            )
+        })
+    }
+}
+
+impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
+    fn set(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(move |data, expiry| {
+            data.insert(key.as_slice(), value.as_slice())?;
+            expiry.remove(key.as_slice())?;
+            Ok(())
+        })
+    }
+
+    fn set_with_ttl(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let expires_at = expiry_time(ttl);
+        self.write(move |data, expiry| {
+            data.insert(key.as_slice(), value.as_slice())?;
+            expiry.insert(key.as_slice(), &expires_at.to_be_bytes()[..])?;
+            Ok(())
+        })
+    }
+
+    fn get(
+        &self,
+        key: Vec<u8>,
+    ) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send> {
         let db = self.db.clone();
+        let expiry = self.expiry.clone();
         let (tx, rx) = oneshot::channel();
         self.pool.spawn(move || {
-            let res = (move || {
-                Ok(db
-                    .get(key)?
-                    .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
-                    .map(String::from_utf8)
-                    .transpose()?)
+            let res = (|| {
+                let value = db.get(&key)?;
+                if value.is_some() && is_expired(&expiry, &key, now_millis())? {
+                    return Ok(None);
+                }
+                Ok(value.map(|i_vec| i_vec.to_vec()))
             })();
             if tx.send(res).is_err() {
                 error!("Receiving end is dropped");
@@ -63,13 +249,180 @@ impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
         )
     }
 
-    fn remove(&self, key: String) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+    fn remove(&self, key: Vec<u8>) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        self.write(move |data, expiry| {
+            let expired = match expiry.get(key.as_slice())? {
+                Some(expires_at) => decode_expiry(&expires_at) <= now_millis(),
+                None => false,
+            };
+            if expired || data.remove(key.as_slice())?.is_none() {
+                return transaction::abort(KvsError::KeyNotFound);
+            }
+            expiry.remove(key.as_slice())?;
+            Ok(())
+        })
+    }
+
+    /// Sets the value of a key to `new` if its current value is `expected`.
+    ///
+    /// The value and the expiry of the key are checked and written in one
+    /// transaction over both trees, so a concurrent `set_with_ttl` cannot lose its
+    /// expiry and the swapped value is never read back as expired.
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Box<dyn Future<Item = bool, Error = KvsError> + Send> {
+        self.write(move |data, expiry| {
+            let expired = match expiry.get(key.as_slice())? {
+                Some(expires_at) => decode_expiry(&expires_at) <= now_millis(),
+                None => false,
+            };
+            let current = data.get(key.as_slice())?.filter(|_| !expired);
+            if current.as_deref() != expected.as_deref() {
+                return Ok(false);
+            }
+            match &new {
+                Some(value) => data.insert(key.as_slice(), value.as_slice())?,
+                None => data.remove(key.as_slice())?,
+            };
+            expiry.remove(key.as_slice())?;
+            Ok(true)
+        })
+    }
+
+    /// Applies all writes in the batch atomically if every key in `reads` still has
+    /// the value read.
+    ///
+    /// The values are compared in the same sled transaction that applies the batch.
+    fn commit_transaction(
+        &self,
+        reads: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = bool, Error = KvsError> + Send> {
+        let (data_batch, expiry_batch) = sled_batches(batch);
+        self.write(move |data, expiry| {
+            let now = now_millis();
+            for (key, value) in &reads {
+                let expired = match expiry.get(key.as_slice())? {
+                    Some(expires_at) => decode_expiry(&expires_at) <= now,
+                    None => false,
+                };
+                let current = data.get(key.as_slice())?.filter(|_| !expired);
+                if current.as_deref() != value.as_deref() {
+                    return Ok(false);
+                }
+            }
+            data.apply_batch(&data_batch)?;
+            expiry.apply_batch(&expiry_batch)?;
+            Ok(true)
+        })
+    }
+
+    fn write_batch(
+        &self,
+        batch: WriteBatch,
+    ) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
+        let (data_batch, expiry_batch) = sled_batches(batch);
+        self.write(move |data, expiry| {
+            data.apply_batch(&data_batch)?;
+            expiry.apply_batch(&expiry_batch)?;
+            Ok(())
+        })
+    }
+
+    fn scan(
+        &self,
+        start: Vec<u8>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send> {
+        if let Some(end) = &end {
+            if *end <= start {
+                return Box::new(stream::empty());
+            }
+        }
+        let start = Bound::Included(start);
+        let end = end.map_or(Bound::Unbounded, Bound::Excluded);
+        self.scan_with(start, limit, move |db, expiry, start, len| {
+            collect_pairs(db.range((start, end.clone())), expiry, len)
+        })
+    }
+
+    fn scan_prefix(
+        &self,
+        prefix: Vec<u8>,
+        limit: Option<usize>,
+    ) -> Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send> {
+        let start = Bound::Included(prefix.clone());
+        self.scan_with(start, limit, move |db, expiry, start, len| {
+            let pairs = db.range((start, Bound::Unbounded)).take_while(|res| {
+                res.as_ref()
+                    .map_or(true, |(key, _)| key.starts_with(&prefix))
+            });
+            collect_pairs(pairs, expiry, len)
+        })
+    }
+
+    /// Writes a copy of the database to a new sled database at `path`.
+    ///
+    /// Sled cannot read a consistent view of several trees while they are written,
+    /// so the database is copied while writes go on and the copy is only kept if no
+    /// write ran during it. After `BACKUP_ATTEMPTS` copies are spoiled by writes,
+    /// writes wait until the next copy is finished.
+    fn backup_to(&self, path: PathBuf) -> Box<dyn Future<Item = (), Error = KvsError> + Send> {
         let db = self.db.clone();
+        let backup_lock = self.backup_lock.clone();
+        let writes = self.writes.clone();
         let (tx, rx) = oneshot::channel();
         self.pool.spawn(move || {
-            let res = (|| {
-                db.del(key)?.ok_or(KvsError::KeyNotFound)?;
-                db.flush()?;
+            let res = (|| -> Result<()> {
+                if path.exists() {
+                    return Err(KvsError::InvalidArgument(format!(
+                        "{} already exists",
+                        path.display()
+                    )));
+                }
+                let mut tmp_name = path.file_name().unwrap_or_default().to_owned();
+                tmp_name.push(".partial");
+                let tmp_path = path.with_file_name(tmp_name);
+                if tmp_path.exists() {
+                    // left by an interrupted backup
+                    fs::remove_dir_all(&tmp_path)?;
+                }
+                let copy = || -> Result<Db> {
+                    let backup_db = sled::open(&tmp_path)?;
+                    backup_db.import(db.export());
+                    Ok(backup_db)
+                };
+                // the exclusive lock waits for the running writes
+                let count_writes = || {
+                    let _guard = backup_lock.write().unwrap();
+                    writes.load(Ordering::SeqCst)
+                };
+                let mut backup_db = None;
+                for _ in 0..BACKUP_ATTEMPTS {
+                    let start = count_writes();
+                    let copied = copy()?;
+                    if count_writes() == start {
+                        backup_db = Some(copied);
+                        break;
+                    }
+                    drop(copied);
+                    fs::remove_dir_all(&tmp_path)?;
+                }
+                let backup_db = match backup_db {
+                    Some(backup_db) => backup_db,
+                    None => {
+                        debug!("Stopping writes for the backup to {}", path.display());
+                        let _guard = backup_lock.write().unwrap();
+                        copy()?
+                    }
+                };
+                backup_db.flush()?;
+                drop(backup_db);
+                fs::rename(&tmp_path, &path)?;
                 Ok(())
             })();
             if tx.send(res).is_err() {
@@ -82,3 +435,69 @@ impl<P: ThreadPool> KvsEngine for SledKvsEngine<P> {
         )
     }
 }
+
+/// Flushes `db` after a write if the sync policy requires every write to be synced.
+fn sync(db: &Db, sync_policy: SyncPolicy) -> Result<()> {
+    match sync_policy {
+        SyncPolicy::Always | SyncPolicy::GroupCommit => {
+            db.flush()?;
+        }
+        SyncPolicy::Never | SyncPolicy::Interval(_) => {}
+    }
+    Ok(())
+}
+
+/// Converts a `WriteBatch` to a batch of the default tree and a batch of the
+/// expiry tree. Keys written by the batch no longer expire.
+fn sled_batches(batch: WriteBatch) -> (Batch, Batch) {
+    let mut data_batch = Batch::default();
+    let mut expiry_batch = Batch::default();
+    for op in batch.into_ops() {
+        match op {
+            BatchOp::Set { key, value } => {
+                expiry_batch.remove(key.as_slice());
+                data_batch.insert(key, value);
+            }
+            BatchOp::Remove { key } => {
+                expiry_batch.remove(key.as_slice());
+                data_batch.remove(key);
+            }
+        }
+    }
+    (data_batch, expiry_batch)
+}
+
+/// Returns whether `key` has expired at `now` according to the expiry tree.
+fn is_expired(expiry: &Tree, key: &[u8], now: u64) -> Result<bool> {
+    Ok(match expiry.get(key)? {
+        Some(expires_at) => decode_expiry(&expires_at) <= now,
+        None => false,
+    })
+}
+
+fn decode_expiry(bytes: &[u8]) -> u64 {
+    let mut expires_at = [0; 8];
+    expires_at.copy_from_slice(bytes);
+    u64::from_be_bytes(expires_at)
+}
+
+/// Collects at most `limit` unexpired key/value pairs from a sled iterator.
+fn collect_pairs<I, K, V>(iter: I, expiry: &Tree, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>>
+where
+    I: Iterator<Item = sled::Result<(K, V)>>,
+    K: AsRef<[u8]>,
+    V: AsRef<[u8]>,
+{
+    let now = now_millis();
+    let mut pairs = Vec::new();
+    for res in iter {
+        if pairs.len() >= limit {
+            break;
+        }
+        let (key, value) = res?;
+        if !is_expired(expiry, key.as_ref(), now)? {
+            pairs.push((key.as_ref().to_vec(), value.as_ref().to_vec()));
+        }
+    }
+    Ok(pairs)
+}
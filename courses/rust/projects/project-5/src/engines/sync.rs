@@ -0,0 +1,55 @@
+use crate::KvsError;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Specifies when writes are synced to the disk.
+///
+/// Writes are always handed to the operating system before they are acknowledged,
+/// so they survive a crash of the process. Syncing additionally makes them survive
+/// a power failure, at the cost of latency.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncPolicy {
+    /// Writes are never synced explicitly.
+    #[default]
+    Never,
+    /// Every write is synced before it is acknowledged.
+    Always,
+    /// Writes are synced in the background every given interval. Writes acknowledged
+    /// during the last interval may be lost on a power failure.
+    Interval(Duration),
+    /// Every write is synced before it is acknowledged, but writers that are waiting
+    /// at the same time share a single sync.
+    GroupCommit,
+}
+
+impl FromStr for SyncPolicy {
+    type Err = KvsError;
+
+    /// Parses `never`, `always`, `group` or an interval in milliseconds such as `100ms`.
+    fn from_str(s: &str) -> Result<SyncPolicy, KvsError> {
+        match s {
+            "never" => Ok(SyncPolicy::Never),
+            "always" => Ok(SyncPolicy::Always),
+            "group" => Ok(SyncPolicy::GroupCommit),
+            _ => s
+                .trim_end_matches("ms")
+                .parse()
+                .ok()
+                .filter(|_| s.ends_with("ms"))
+                .map(|ms| SyncPolicy::Interval(Duration::from_millis(ms)))
+                .ok_or_else(|| KvsError::StringError(format!("Invalid sync policy: {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for SyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncPolicy::Never => write!(f, "never"),
+            SyncPolicy::Always => write!(f, "always"),
+            SyncPolicy::Interval(interval) => write!(f, "{}ms", interval.as_millis()),
+            SyncPolicy::GroupCommit => write!(f, "group"),
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use crate::KvsError;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use tokio::prelude::Stream;
+
+/// The changes streamed by `KvsEngine::watch`.
+pub type WatchStream = Box<dyn Stream<Item = WatchEvent, Error = KvsError> + Send>;
+
+/// The chunks of pairs copied by `KvsEngine::copy_and_watch`.
+pub type CopyStream = Box<dyn Stream<Item = Vec<WatchEvent>, Error = KvsError> + Send>;
+
+/// The position of a change in the log of the engine.
+///
+/// Positions increase in commit order, and the position of the last change seen
+/// can be passed to `KvsEngine::watch` to resume watching after it. It is written
+/// as `GEN:POS`, where `GEN` is the generation of the log file and `POS` is the
+/// offset right after the record of the change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct WatchSeq {
+    /// The generation of the log file
+    pub gen: u64,
+    /// The offset in the log file after the record of the change
+    pub pos: u64,
+}
+
+/// A change of a key streamed by `KvsEngine::watch`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WatchEvent {
+    /// The value of a key was set.
+    Set {
+        /// The position of the change
+        seq: WatchSeq,
+        /// The key
+        key: Vec<u8>,
+        /// The new value
+        value: Vec<u8>,
+        /// Expiry time in milliseconds since the UNIX epoch
+        expires_at: Option<u64>,
+    },
+    /// A key was removed.
+    Remove {
+        /// The position of the change
+        seq: WatchSeq,
+        /// The key
+        key: Vec<u8>,
+    },
+}
+
+impl WatchEvent {
+    /// Returns the position of the change.
+    pub fn seq(&self) -> WatchSeq {
+        match self {
+            WatchEvent::Set { seq, .. } | WatchEvent::Remove { seq, .. } => *seq,
+        }
+    }
+
+    /// Returns the key that was changed.
+    pub fn key(&self) -> &[u8] {
+        match self {
+            WatchEvent::Set { key, .. } | WatchEvent::Remove { key, .. } => key,
+        }
+    }
+}
+
+impl FromStr for WatchSeq {
+    type Err = KvsError;
+
+    /// Parses a position written as `GEN:POS`.
+    fn from_str(s: &str) -> Result<WatchSeq, KvsError> {
+        let mut parts = s.splitn(2, ':').map(str::parse);
+        match (parts.next(), parts.next()) {
+            (Some(Ok(gen)), Some(Ok(pos))) => Ok(WatchSeq { gen, pos }),
+            _ => Err(KvsError::InvalidArgument(format!(
+                "Invalid sequence number: {}",
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for WatchSeq {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.gen, self.pos)
+    }
+}
@@ -1,4 +1,5 @@
 use failure::Fail;
+use sled::transaction::TransactionError;
 use std::io;
 use std::string::FromUtf8Error;
 
@@ -11,6 +12,9 @@ pub enum KvsError {
     /// Serialization or deserialization error
     #[fail(display = "serde_json error: {}", _0)]
     Serde(#[cause] serde_json::Error),
+    /// Bincode serialization or deserialization error
+    #[fail(display = "bincode error: {}", _0)]
+    Bincode(#[cause] bincode::Error),
     /// Removing non-existent key error
     #[fail(display = "Key not found")]
     KeyNotFound,
@@ -18,12 +22,27 @@ pub enum KvsError {
     /// It indicated a corrupted log or a program bug.
     #[fail(display = "Unexpected command type")]
     UnexpectedCommandType,
+    /// A log record is incomplete or does not match its checksum
+    #[fail(display = "Corrupted log: {}", _0)]
+    CorruptedLog(String),
+    /// The log file is written in an unknown format version
+    #[fail(display = "Unsupported log format version: {}", _0)]
+    UnsupportedLogVersion(u32),
     /// Key or value is invalid UTF-8 sequence
     #[fail(display = "UTF-8 error: {}", _0)]
     Utf8(#[cause] FromUtf8Error),
     /// Sled error
     #[fail(display = "sled error: {}", _0)]
     Sled(#[cause] sled::Error),
+    /// The connection to the server was closed before the response arrived
+    #[fail(display = "No response received")]
+    Disconnected,
+    /// The engine does not support the operation
+    #[fail(display = "{}", _0)]
+    Unsupported(String),
+    /// An argument of the operation is invalid
+    #[fail(display = "{}", _0)]
+    InvalidArgument(String),
     /// Error with a string message
     #[fail(display = "{}", _0)]
     StringError(String),
@@ -41,6 +60,12 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<bincode::Error> for KvsError {
+    fn from(err: bincode::Error) -> KvsError {
+        KvsError::Bincode(err)
+    }
+}
+
 impl From<FromUtf8Error> for KvsError {
     fn from(err: FromUtf8Error) -> KvsError {
         KvsError::Utf8(err)
@@ -53,5 +78,14 @@ impl From<sled::Error> for KvsError {
     }
 }
 
+impl From<TransactionError<KvsError>> for KvsError {
+    fn from(err: TransactionError<KvsError>) -> KvsError {
+        match err {
+            TransactionError::Abort(err) => err,
+            TransactionError::Storage(err) => KvsError::Sled(err),
+        }
+    }
+}
+
 /// Result type for kvs
 pub type Result<T> = std::result::Result<T, KvsError>;
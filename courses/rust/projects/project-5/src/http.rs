@@ -0,0 +1,221 @@
+//! A gateway serving the engine over HTTP.
+//!
+//! `GET`, `PUT` and `DELETE` on `/keys/{key}` read, write and remove a key. The
+//! value is the body of the request or response. `GET /keys?prefix=...&limit=...`
+//! lists the keys starting with the prefix as a JSON array of strings. Keys are
+//! percent-encoded in paths, queries and lists, and a listed key is encoded with
+//! every byte other than letters, digits and `-._~` written as `%XX`. A failed
+//! request is answered with a status code mapped from its error and a JSON body
+//! such as `{"error":"Key not found"}`.
+
+use crate::replica::Replication;
+use crate::{KvsEngine, KvsError};
+use hyper::header::{HeaderValue, ALLOW, CONTENT_TYPE};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+
+/// The maximum length of a value written
+const MAX_VALUE_LENGTH: usize = 64 * 1024 * 1024;
+
+type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+/// Serves the HTTP requests of a client.
+pub(crate) fn serve<E: KvsEngine>(
+    engine: E,
+    tcp: TcpStream,
+    replication: Arc<Replication>,
+) -> impl Future<Item = (), Error = hyper::Error> {
+    let service = service_fn(move |req| handle(&engine, &replication, req));
+    Http::new().serve_connection(tcp, service)
+}
+
+fn handle<E: KvsEngine>(
+    engine: &E,
+    replication: &Replication,
+    req: Request<Body>,
+) -> ResponseFuture {
+    let method = req.method().clone();
+    let path = req.uri().path().to_owned();
+    if path == "/keys" {
+        return match method {
+            Method::GET => list(engine, req.uri().query().unwrap_or("")),
+            _ => reply(method_not_allowed("GET")),
+        };
+    }
+    let key = match path.strip_prefix("/keys/") {
+        Some(key) => key,
+        None => return reply(error_response(StatusCode::NOT_FOUND, "Not found")),
+    };
+    let key = match percent_decode(key) {
+        Some(key) => key,
+        None => return reply(bad_request("Invalid percent-encoding in the key")),
+    };
+    match method {
+        Method::GET => respond(engine.get(key), |value| match value {
+            Some(value) => Response::builder()
+                .header(
+                    CONTENT_TYPE,
+                    HeaderValue::from_static("application/octet-stream"),
+                )
+                .body(Body::from(value))
+                .unwrap(),
+            None => kvs_error_response(&KvsError::KeyNotFound),
+        }),
+        Method::PUT | Method::DELETE if replication.is_running() => reply(error_response(
+            StatusCode::FORBIDDEN,
+            "The server is a read-only replica",
+        )),
+        Method::PUT => put(engine, key, req.into_body()),
+        Method::DELETE => respond(engine.remove(key), |()| no_content()),
+        _ => reply(method_not_allowed("GET, PUT, DELETE")),
+    }
+}
+
+/// Writes the body of the request as the value of `key`.
+fn put<E: KvsEngine>(engine: &E, key: Vec<u8>, body: Body) -> ResponseFuture {
+    let engine = engine.clone();
+    // the body is read to the end, but kept only if it is short enough
+    let value = body.fold(Some(Vec::new()), |value, chunk| {
+        Ok::<_, hyper::Error>(value.and_then(|mut value| {
+            if value.len() + chunk.len() > MAX_VALUE_LENGTH {
+                return None;
+            }
+            value.extend_from_slice(&chunk);
+            Some(value)
+        }))
+    });
+    Box::new(value.and_then(move |value| match value {
+        Some(value) => respond(engine.set(key, value), |()| no_content()),
+        None => reply(error_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "The value is too large",
+        )),
+    }))
+}
+
+/// Lists the keys with the `prefix` given in the query, at most `limit` of them.
+fn list<E: KvsEngine>(engine: &E, query: &str) -> ResponseFuture {
+    let mut prefix = Vec::new();
+    let mut limit = None;
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let (name, value) = match param.find('=') {
+            Some(i) => (&param[..i], &param[i + 1..]),
+            None => (param, ""),
+        };
+        let value = match percent_decode(&value.replace('+', " ")) {
+            Some(value) => value,
+            None => return reply(bad_request("Invalid percent-encoding in the query")),
+        };
+        match name {
+            "prefix" => prefix = value,
+            "limit" => match std::str::from_utf8(&value)
+                .ok()
+                .and_then(|n| n.parse().ok())
+            {
+                Some(n) => limit = Some(n),
+                None => return reply(bad_request("Invalid limit")),
+            },
+            _ => return reply(bad_request(&format!("Unknown query parameter '{}'", name))),
+        }
+    }
+    let keys = engine
+        .scan_prefix(prefix, limit)
+        .map(|(key, _)| percent_encode(&key))
+        .collect();
+    respond(keys, |keys: Vec<String>| {
+        json_response(StatusCode::OK, serde_json::to_string(&keys).unwrap())
+    })
+}
+
+/// Answers with the result of `fut` mapped by `f`, or the error `fut` fails with.
+fn respond<T, F>(
+    fut: impl Future<Item = T, Error = KvsError> + Send + 'static,
+    f: F,
+) -> ResponseFuture
+where
+    F: FnOnce(T) -> Response<Body> + Send + 'static,
+{
+    Box::new(fut.then(|res| {
+        Ok(match res {
+            Ok(res) => f(res),
+            Err(e) => kvs_error_response(&e),
+        })
+    }))
+}
+
+fn reply(resp: Response<Body>) -> ResponseFuture {
+    Box::new(future::ok(resp))
+}
+
+fn kvs_error_response(e: &KvsError) -> Response<Body> {
+    let status = match e {
+        KvsError::KeyNotFound => StatusCode::NOT_FOUND,
+        // the request cannot be served by the engine as it is
+        KvsError::Unsupported(_) | KvsError::InvalidArgument(_) => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    error_response(status, &e.to_string())
+}
+
+fn bad_request(msg: &str) -> Response<Body> {
+    error_response(StatusCode::BAD_REQUEST, msg)
+}
+
+fn method_not_allowed(allow: &'static str) -> Response<Body> {
+    let mut resp = error_response(StatusCode::METHOD_NOT_ALLOWED, "Method not allowed");
+    resp.headers_mut()
+        .insert(ALLOW, HeaderValue::from_static(allow));
+    resp
+}
+
+fn error_response(status: StatusCode, msg: &str) -> Response<Body> {
+    json_response(status, serde_json::json!({ "error": msg }).to_string())
+}
+
+fn json_response(status: StatusCode, json: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+        .body(Body::from(json))
+        .unwrap()
+}
+
+fn no_content() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Encodes every byte of `key` other than letters, digits and `-._~` as `%XX`.
+fn percent_encode(key: &[u8]) -> String {
+    let mut encoded = String::with_capacity(key.len());
+    for &b in key {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+/// Decodes the `%XX` escapes of `s`, or returns `None` if one is invalid.
+fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let mut bytes = s.bytes();
+    let mut decoded = Vec::with_capacity(s.len());
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let escape = [bytes.next()?, bytes.next()?];
+            decoded.extend(hex::decode(escape).ok()?);
+        } else {
+            decoded.push(b);
+        }
+    }
+    Some(decoded)
+}
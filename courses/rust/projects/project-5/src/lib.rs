@@ -4,8 +4,12 @@
 #[macro_use]
 extern crate log;
 
-pub use client::KvsClient;
-pub use engines::{KvStore, KvsEngine, SledKvsEngine};
+pub use client::{ClientPoolOptions, KvsClient, KvsClientPool};
+pub use engines::{
+    Compression, CopyStream, EngineStats, IndexBackend, KvStore, KvStoreOptions, KvsEngine,
+    LsmKvsEngine, LsmOptions, MemoryKvsEngine, SledKvsEngine, Snapshot, SyncPolicy, Transaction,
+    WatchEvent, WatchSeq, WatchStream, WriteBatch,
+};
 pub use error::{KvsError, Result};
 pub use server::KvsServer;
 
@@ -13,5 +17,8 @@ mod client;
 mod common;
 mod engines;
 mod error;
+mod http;
+mod replica;
+mod resp;
 mod server;
 pub mod thread_pool;
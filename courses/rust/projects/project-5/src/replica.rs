@@ -0,0 +1,236 @@
+use crate::engines::now_millis;
+use crate::{KvsClient, KvsEngine, KvsError, WatchEvent, WatchSeq, WatchStream, WriteBatch};
+use std::collections::BTreeSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::prelude::future::Loop;
+use tokio::prelude::*;
+use tokio::sync::oneshot;
+use tokio::timer::Delay;
+
+/// How long a replica waits before it reconnects to its primary
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The number of local keys read at once to find the ones missing in a copy
+const STALE_SCAN_LIMIT: usize = 1024;
+
+type KvsFuture<T> = Box<dyn Future<Item = T, Error = KvsError> + Send>;
+
+/// The replication of a replica server from its primary.
+///
+/// A replica first installs a copy of the data of the primary and then applies
+/// the changes of the primary as they are written. After a connection failure, it
+/// resumes after the last change it has applied, or installs a new copy if the
+/// primary has compacted the changes after it.
+#[derive(Default)]
+pub(crate) struct Replication {
+    // stops the replication, `None` if the server is not replicating
+    stop: Mutex<Option<oneshot::Sender<()>>>,
+}
+
+impl Replication {
+    /// Starts replicating the primary at `primary` to `engine` in the tokio runtime.
+    pub fn start<E: KvsEngine>(&self, engine: E, primary: SocketAddr) {
+        let (stop, stopped) = oneshot::channel();
+        *self.stop.lock().unwrap() = Some(stop);
+        info!("Replicating {}", primary);
+        tokio::spawn(follow(engine, primary).select2(stopped).then(move |_| {
+            info!("Stopped replicating {}", primary);
+            Ok(())
+        }));
+    }
+
+    /// Returns whether the server is replicating, and rejects writes.
+    pub fn is_running(&self) -> bool {
+        self.stop.lock().unwrap().is_some()
+    }
+
+    /// Stops the replication, so the server accepts writes.
+    ///
+    /// Returns `false` if the server was not replicating.
+    pub fn promote(&self) -> bool {
+        match self.stop.lock().unwrap().take() {
+            Some(stop) => {
+                let _ = stop.send(());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Replicates the primary to `engine` and reconnects after every failure.
+fn follow<E: KvsEngine>(engine: E, primary: SocketAddr) -> impl Future<Item = (), Error = ()> {
+    // the position of the last change applied
+    let applied = Arc::new(Mutex::new(None));
+    future::loop_fn((), move |()| {
+        sync(engine.clone(), primary, Arc::clone(&applied)).then(move |res| {
+            match res {
+                Ok(()) => warn!("The primary {} closed the connection", primary),
+                Err(e) => warn!("Failed to replicate {}: {}", primary, e),
+            }
+            Delay::new(Instant::now() + RETRY_INTERVAL).then(|_| Ok(Loop::Continue(())))
+        })
+    })
+}
+
+/// Connects to the primary and applies its changes until the connection fails.
+fn sync<E: KvsEngine>(
+    engine: E,
+    primary: SocketAddr,
+    applied: Arc<Mutex<Option<WatchSeq>>>,
+) -> KvsFuture<()> {
+    let from = *applied.lock().unwrap();
+    let resume_applied = Arc::clone(&applied);
+    let copy_engine = engine.clone();
+    let copy_applied = Arc::clone(&applied);
+    let changes = KvsClient::connect(primary).and_then(move |client| -> KvsFuture<WatchStream> {
+        match from {
+            Some(from) => Box::new(
+                client
+                    .watch(Vec::new(), Some(from))
+                    .map(|(_, changes)| changes)
+                    .map_err(move |e| {
+                        // the changes after the position may have been compacted
+                        *resume_applied.lock().unwrap() = None;
+                        e
+                    }),
+            ),
+            None => {
+                // the start of the local keys the copy has not replaced yet
+                let installed = Arc::new(Mutex::new(Vec::new()));
+                let chunk_installed = Arc::clone(&installed);
+                let chunk_engine = copy_engine.clone();
+                let copy = client.replicate(move |pairs| {
+                    let mut installed = chunk_installed.lock().unwrap();
+                    let (next, install) = install_chunk(&chunk_engine, installed.clone(), pairs);
+                    *installed = next;
+                    install
+                });
+                Box::new(copy.and_then(move |(seq, changes)| {
+                    // the local keys after the last copied key are missing in the copy
+                    let start = installed.lock().unwrap().clone();
+                    remove_stale(&copy_engine, start, None, BTreeSet::new()).map(move |()| {
+                        info!("Installed a copy at {}", seq);
+                        *copy_applied.lock().unwrap() = Some(seq);
+                        changes
+                    })
+                }))
+            }
+        }
+    });
+    Box::new(changes.and_then(move |changes| {
+        changes.for_each(move |event| {
+            let seq = event.seq();
+            let applied = Arc::clone(&applied);
+            apply_change(&engine, event).map(move |()| *applied.lock().unwrap() = Some(seq))
+        })
+    }))
+}
+
+/// Replaces the local pairs from `start` up to the last key of `pairs` with a
+/// chunk of the copy of the primary.
+///
+/// Returns the start of the keys after the chunk and the future of the install.
+fn install_chunk<E: KvsEngine>(
+    engine: &E,
+    start: Vec<u8>,
+    pairs: Vec<WatchEvent>,
+) -> (Vec<u8>, KvsFuture<()>) {
+    let end = match pairs.last() {
+        Some(event) => key_after(event.key()),
+        None => return (start, Box::new(future::ok(()))),
+    };
+    let copied = pairs.iter().map(|event| event.key().to_vec()).collect();
+    let mut batch = WriteBatch::new();
+    let mut expiring = Vec::new();
+    for event in pairs {
+        match event {
+            WatchEvent::Set {
+                key,
+                value,
+                expires_at: None,
+                ..
+            } => batch.set(key, value),
+            event => expiring.push(event),
+        }
+    }
+    let engine = engine.clone();
+    let install = remove_stale(&engine, start, Some(end.clone()), copied).and_then(move |()| {
+        engine.write_batch(batch).and_then(move |()| {
+            stream::iter_ok(expiring).for_each(move |event| apply_change(&engine, event))
+        })
+    });
+    (end, Box::new(install))
+}
+
+/// Removes the local keys in the range `[start, end)` that are not in `copied`.
+///
+/// The keys are read in pages of `STALE_SCAN_LIMIT` keys.
+fn remove_stale<E: KvsEngine>(
+    engine: &E,
+    start: Vec<u8>,
+    end: Option<Vec<u8>>,
+    copied: BTreeSet<Vec<u8>>,
+) -> KvsFuture<()> {
+    let engine = engine.clone();
+    Box::new(future::loop_fn((start, copied), move |(start, copied)| {
+        let engine = engine.clone();
+        engine
+            .scan(start, end.clone(), Some(STALE_SCAN_LIMIT))
+            .map(|(key, _)| key)
+            .collect()
+            .and_then(move |keys| {
+                let next = match keys.last() {
+                    Some(key) if keys.len() == STALE_SCAN_LIMIT => Some(key_after(key)),
+                    _ => None,
+                };
+                let mut batch = WriteBatch::new();
+                for key in keys {
+                    if !copied.contains(&key) {
+                        batch.remove(key);
+                    }
+                }
+                engine.write_batch(batch).map(move |()| match next {
+                    Some(next) => Loop::Continue((next, copied)),
+                    None => Loop::Break(()),
+                })
+            })
+    }))
+}
+
+/// Returns the smallest key after `key`.
+fn key_after(key: &[u8]) -> Vec<u8> {
+    let mut next = key.to_vec();
+    next.push(0);
+    next
+}
+
+/// Applies a change of the primary to `engine`.
+fn apply_change<E: KvsEngine>(engine: &E, event: WatchEvent) -> KvsFuture<()> {
+    match event {
+        WatchEvent::Set {
+            key,
+            value,
+            expires_at: None,
+            ..
+        } => engine.set(key, value),
+        WatchEvent::Set {
+            key,
+            value,
+            expires_at: Some(expires_at),
+            ..
+        } => {
+            // the key expires at the same time as on the primary
+            let ttl = Duration::from_millis(expires_at.saturating_sub(now_millis()));
+            engine.set_with_ttl(key, value, ttl)
+        }
+        WatchEvent::Remove { key, .. } => {
+            // the key may have expired on the replica already
+            let mut batch = WriteBatch::new();
+            batch.remove(key);
+            engine.write_batch(batch)
+        }
+    }
+}
@@ -0,0 +1,425 @@
+//! A front-end speaking the RESP protocol of Redis.
+//!
+//! Commands are arrays of bulk strings, or inline commands separated by spaces as
+//! typed in a terminal. `GET`, `SET`, `DEL`, `EXISTS`, `MGET`, `SCAN` and `PING`
+//! are mapped onto the `KvsEngine` trait. The commands of a connection are run one
+//! after another, so pipelined commands are answered in order.
+
+use crate::replica::Replication;
+use crate::{KvsEngine, KvsError, Result};
+use bytes::BytesMut;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::codec::{Decoder, Encoder, FramedRead, FramedWrite};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+
+/// The maximum length of a bulk string in a command
+const MAX_BULK_LENGTH: usize = 64 * 1024 * 1024;
+
+/// The maximum number of arguments of a command
+const MAX_ARGS: usize = 1024 * 1024;
+
+/// The maximum length of an inline command
+const MAX_INLINE_LENGTH: usize = 64 * 1024;
+
+/// The number of keys of a `MGET` or `EXISTS` read at the same time
+const READ_PARALLELISM: usize = 64;
+
+/// The number of keys `SCAN` reads if `COUNT` is not given
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// A reply to a command.
+#[derive(Debug)]
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    /// A bulk string, or the null bulk string if `None`
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Reply>),
+}
+
+type ReplyFuture = Box<dyn Future<Item = Reply, Error = KvsError> + Send>;
+
+/// Serves the RESP commands of a client.
+pub(crate) fn serve<E: KvsEngine>(
+    engine: E,
+    tcp: TcpStream,
+    replication: Arc<Replication>,
+) -> impl Future<Item = (), Error = KvsError> {
+    let (read_half, write_half) = tcp.split();
+    let commands = FramedRead::new(read_half, RespCodec);
+    let replies = commands.and_then(move |args| {
+        execute(&engine, &replication, args).or_else(|e| Ok(Reply::Error(format!("ERR {}", e))))
+    });
+    FramedWrite::new(write_half, RespCodec)
+        .send_all(replies)
+        .map(|_| ())
+}
+
+fn execute<E: KvsEngine>(
+    engine: &E,
+    replication: &Replication,
+    mut args: Vec<Vec<u8>>,
+) -> ReplyFuture {
+    let name = String::from_utf8_lossy(&args.remove(0)).to_ascii_uppercase();
+    let is_write = name == "SET" || name == "DEL";
+    if is_write && replication.is_running() {
+        return reply(Reply::Error(
+            "READONLY You can't write against a read only replica.".to_owned(),
+        ));
+    }
+    match (name.as_str(), args.len()) {
+        ("PING", 0) => reply(Reply::Simple("PONG")),
+        ("PING", 1) => reply(Reply::Bulk(args.pop())),
+        ("GET", 1) => Box::new(engine.get(args.remove(0)).map(Reply::Bulk)),
+        ("SET", n) if n >= 2 => set(engine, args),
+        ("DEL", n) if n >= 1 => del(engine, args),
+        ("EXISTS", n) if n >= 1 => Box::new(
+            read_values(engine, args)
+                .fold(0, |n, value| -> Result<i64> {
+                    Ok(n + value.is_some() as i64)
+                })
+                .map(Reply::Integer),
+        ),
+        ("MGET", n) if n >= 1 => Box::new(
+            read_values(engine, args)
+                .map(Reply::Bulk)
+                .collect()
+                .map(Reply::Array),
+        ),
+        ("SCAN", n) if n >= 1 => scan(engine, args),
+        ("PING", _)
+        | ("GET", _)
+        | ("SET", _)
+        | ("DEL", _)
+        | ("EXISTS", _)
+        | ("MGET", _)
+        | ("SCAN", _) => reply(Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name.to_ascii_lowercase()
+        ))),
+        _ => reply(Reply::Error(format!("ERR unknown command '{}'", name))),
+    }
+}
+
+/// `SET key value [EX seconds | PX milliseconds] [NX]`
+fn set<E: KvsEngine>(engine: &E, args: Vec<Vec<u8>>) -> ReplyFuture {
+    let mut args = args.into_iter();
+    let (key, value) = (args.next().unwrap(), args.next().unwrap());
+    let mut ttl = None;
+    let mut if_absent = false;
+    while let Some(option) = args.next() {
+        let option = String::from_utf8_lossy(&option).to_ascii_uppercase();
+        match option.as_str() {
+            "NX" => if_absent = true,
+            "EX" | "PX" => {
+                let n = match args.next().as_ref().and_then(|n| parse_number(n)) {
+                    Some(n) if n > 0 => n as u64,
+                    _ => return invalid("ERR invalid expire time in 'set' command"),
+                };
+                ttl = Some(if option == "EX" {
+                    Duration::from_secs(n)
+                } else {
+                    Duration::from_millis(n)
+                });
+            }
+            _ => return invalid("ERR syntax error"),
+        }
+    }
+    match (if_absent, ttl) {
+        (false, None) => Box::new(engine.set(key, value).map(|()| Reply::Simple("OK"))),
+        (false, Some(ttl)) => Box::new(
+            engine
+                .set_with_ttl(key, value, ttl)
+                .map(|()| Reply::Simple("OK")),
+        ),
+        (true, None) => Box::new(engine.set_if_absent(key, value).map(|set| {
+            if set {
+                Reply::Simple("OK")
+            } else {
+                Reply::Bulk(None)
+            }
+        })),
+        (true, Some(_)) => invalid("ERR NX with an expire time is not supported"),
+    }
+}
+
+/// `DEL key [key ...]` replies with the number of keys removed.
+fn del<E: KvsEngine>(engine: &E, keys: Vec<Vec<u8>>) -> ReplyFuture {
+    let engine = engine.clone();
+    // the keys are removed one by one, so a key given twice is counted once
+    let removed = stream::iter_ok(keys).and_then(move |key| {
+        engine.remove(key).then(|res| match res {
+            Ok(()) => Ok(1),
+            Err(KvsError::KeyNotFound) => Ok(0),
+            Err(e) => Err(e),
+        })
+    });
+    Box::new(
+        removed
+            .fold(0, |n, removed| -> Result<i64> { Ok(n + removed) })
+            .map(Reply::Integer),
+    )
+}
+
+/// `SCAN cursor [MATCH pattern] [COUNT count]`
+///
+/// The cursor is `0` to start and then the hex-encoded last key scanned, so every
+/// call scans at most `COUNT` keys after it without reading their values. Keys
+/// written between two calls are returned if they sort after the cursor. `MATCH`
+/// is applied to the keys read, and supports the `*` and `?` wildcards.
+fn scan<E: KvsEngine>(engine: &E, args: Vec<Vec<u8>>) -> ReplyFuture {
+    let mut args = args.into_iter();
+    let start = match args.next() {
+        Some(ref cursor) if cursor == b"0" => Vec::new(),
+        Some(cursor) => match hex::decode(cursor) {
+            // the smallest key after the last key scanned
+            Ok(mut start) => {
+                start.push(0);
+                start
+            }
+            Err(_) => return invalid("ERR invalid cursor"),
+        },
+        None => return invalid("ERR invalid cursor"),
+    };
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    while let Some(option) = args.next() {
+        let option = String::from_utf8_lossy(&option).to_ascii_uppercase();
+        match (option.as_str(), args.next()) {
+            ("MATCH", Some(arg)) => pattern = Some(arg),
+            ("COUNT", Some(arg)) => match parse_number(&arg) {
+                Some(n) if n > 0 => count = n,
+                _ => return invalid("ERR value is not an integer or out of range"),
+            },
+            _ => return invalid("ERR syntax error"),
+        }
+    }
+    Box::new(
+        engine
+            .scan_keys(start, None, Some(count))
+            .collect()
+            .map(move |keys| {
+                let cursor = match keys.last() {
+                    Some(last) if keys.len() == count => hex::encode(last),
+                    _ => "0".to_owned(),
+                };
+                let keys = keys
+                    .into_iter()
+                    .filter(|key| pattern.as_ref().map_or(true, |p| glob_match(p, key)))
+                    .map(|key| Reply::Bulk(Some(key)))
+                    .collect();
+                Reply::Array(vec![
+                    Reply::Bulk(Some(cursor.into_bytes())),
+                    Reply::Array(keys),
+                ])
+            }),
+    )
+}
+
+/// Reads the values of the keys in order.
+fn read_values<E: KvsEngine>(
+    engine: &E,
+    keys: Vec<Vec<u8>>,
+) -> impl Stream<Item = Option<Vec<u8>>, Error = KvsError> {
+    let engine = engine.clone();
+    stream::iter_ok(keys)
+        .map(move |key| engine.get(key))
+        .buffered(READ_PARALLELISM)
+}
+
+fn reply(reply: Reply) -> ReplyFuture {
+    Box::new(future::ok(reply))
+}
+
+fn invalid(msg: &str) -> ReplyFuture {
+    reply(Reply::Error(msg.to_owned()))
+}
+
+fn parse_number(s: &[u8]) -> Option<usize> {
+    std::str::from_utf8(s).ok()?.parse().ok()
+}
+
+/// Returns whether `s` matches a glob pattern with the `*` and `?` wildcards. A
+/// backslash makes the next byte match literally.
+fn glob_match(pattern: &[u8], s: &[u8]) -> bool {
+    let (mut p, mut i) = (0, 0);
+    // the position after the last star, and the position in `s` it matches up to
+    let mut star = None;
+    while i < s.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                star = Some((p, i));
+            }
+            Some(b'?') => {
+                p += 1;
+                i += 1;
+            }
+            Some(b'\\') if pattern.get(p + 1) == Some(&s[i]) => {
+                p += 2;
+                i += 1;
+            }
+            Some(&c) if c != b'\\' && c == s[i] => {
+                p += 1;
+                i += 1;
+            }
+            // let the last star match one more byte
+            _ => match star {
+                Some((star_p, star_i)) => {
+                    p = star_p;
+                    i = star_i + 1;
+                    star = Some((star_p, i));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Decodes RESP commands and encodes replies.
+struct RespCodec;
+
+impl Decoder for RespCodec {
+    type Item = Vec<Vec<u8>>;
+    type Error = KvsError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Vec<Vec<u8>>>> {
+        loop {
+            if src.is_empty() {
+                return Ok(None);
+            }
+            let parsed = if src[0] == b'*' {
+                parse_array(src)?
+            } else {
+                parse_inline(src)?
+            };
+            match parsed {
+                Some((args, len)) => {
+                    src.split_to(len);
+                    // empty lines are ignored
+                    if !args.is_empty() {
+                        return Ok(Some(args));
+                    }
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+impl Encoder for RespCodec {
+    type Item = Reply;
+    type Error = KvsError;
+
+    fn encode(&mut self, reply: Reply, dst: &mut BytesMut) -> Result<()> {
+        reply.write_to(dst);
+        Ok(())
+    }
+}
+
+impl Reply {
+    fn write_to(&self, dst: &mut BytesMut) {
+        match self {
+            Reply::Simple(s) => {
+                dst.extend_from_slice(b"+");
+                dst.extend_from_slice(s.as_bytes());
+            }
+            Reply::Error(msg) => {
+                dst.extend_from_slice(b"-");
+                dst.extend_from_slice(msg.replace(['\r', '\n'], " ").as_bytes());
+            }
+            Reply::Integer(n) => dst.extend_from_slice(format!(":{}", n).as_bytes()),
+            Reply::Bulk(None) => dst.extend_from_slice(b"$-1"),
+            Reply::Bulk(Some(bytes)) => {
+                dst.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+                dst.extend_from_slice(bytes);
+            }
+            Reply::Array(items) => {
+                dst.extend_from_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.write_to(dst);
+                }
+                return;
+            }
+        }
+        dst.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Parses a command written as an array of bulk strings.
+///
+/// Returns the arguments and the length of the command, or `None` if the command
+/// is incomplete.
+fn parse_array(buf: &[u8]) -> Result<Option<(Vec<Vec<u8>>, usize)>> {
+    let (count, mut pos) = match parse_header(buf, 0, b'*')? {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    if count > MAX_ARGS {
+        return Err(protocol_error("invalid multibulk length"));
+    }
+    let mut args = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        let (len, start) = match parse_header(buf, pos, b'$')? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        if len > MAX_BULK_LENGTH {
+            return Err(protocol_error("invalid bulk length"));
+        }
+        let end = start + len;
+        if buf.len() < end + 2 {
+            return Ok(None);
+        }
+        if &buf[end..end + 2] != b"\r\n" {
+            return Err(protocol_error("expected CRLF after a bulk string"));
+        }
+        args.push(buf[start..end].to_vec());
+        pos = end + 2;
+    }
+    Ok(Some((args, pos)))
+}
+
+/// Parses a line with `prefix` and a length at `pos`.
+///
+/// Returns the length and the position after the line, or `None` if the line is
+/// incomplete.
+fn parse_header(buf: &[u8], pos: usize, prefix: u8) -> Result<Option<(usize, usize)>> {
+    let end = match buf[pos..].windows(2).position(|w| w == b"\r\n") {
+        Some(len) => pos + len,
+        // a length has at most 20 digits
+        None if buf.len() - pos > 32 => return Err(protocol_error("too long length line")),
+        None => return Ok(None),
+    };
+    if buf.get(pos) != Some(&prefix) {
+        return Err(protocol_error(&format!("expected '{}'", prefix as char)));
+    }
+    match parse_number(&buf[pos + 1..end]) {
+        Some(n) => Ok(Some((n, end + 2))),
+        None => Err(protocol_error("invalid length")),
+    }
+}
+
+/// Parses an inline command, which is a line of arguments separated by spaces.
+fn parse_inline(buf: &[u8]) -> Result<Option<(Vec<Vec<u8>>, usize)>> {
+    match buf.iter().position(|&b| b == b'\n') {
+        Some(end) => {
+            let args = buf[..end]
+                .split(u8::is_ascii_whitespace)
+                .filter(|arg| !arg.is_empty())
+                .map(<[u8]>::to_vec)
+                .collect();
+            Ok(Some((args, end + 1)))
+        }
+        None if buf.len() > MAX_INLINE_LENGTH => Err(protocol_error("too big inline request")),
+        None => Ok(None),
+    }
+}
+
+fn protocol_error(msg: &str) -> KvsError {
+    KvsError::StringError(format!("Protocol error: {}", msg))
+}
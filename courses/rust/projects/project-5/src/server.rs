@@ -1,64 +1,661 @@
-use crate::common::{Request, Response};
-use crate::{KvsEngine, KvsError, Result};
+use crate::common::{Frame, MessageCodec, Request, Response};
+use crate::replica::Replication;
+use crate::{http, resp};
+use crate::{KvsEngine, KvsError, Result, WatchSeq, WriteBatch};
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
 use std::net::SocketAddr;
-use tokio::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::codec::{FramedRead, FramedWrite};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::prelude::future::Shared;
 use tokio::prelude::*;
-use tokio_serde_json::{ReadJson, WriteJson};
+use tokio::sync::{mpsc, oneshot};
+
+/// The number of responses buffered for a connection
+const RESPONSE_BUFFER: usize = 1024;
+
+/// The number of keys of a multi-get or multi-set served at the same time
+const MULTI_PARALLELISM: usize = 64;
+
+/// The size in bytes of the keys and values in a chunk of a scan
+const SCAN_CHUNK_SIZE: usize = 1024 * 1024;
 
 /// The server of a key value store.
 pub struct KvsServer<E: KvsEngine> {
     engine: E,
+    primary: Option<SocketAddr>,
+    resp_addr: Option<SocketAddr>,
+    http_addr: Option<SocketAddr>,
+    backup_dir: Option<PathBuf>,
 }
 
 impl<E: KvsEngine> KvsServer<E> {
     /// Create a `KvsServer` with a given storage engine.
     pub fn new(engine: E) -> Self {
-        KvsServer { engine }
+        KvsServer {
+            engine,
+            primary: None,
+            resp_addr: None,
+            http_addr: None,
+            backup_dir: None,
+        }
+    }
+
+    /// Make the server a read-only replica of the server at `primary`.
+    ///
+    /// The replica replaces its data with a copy of the data of the primary and
+    /// then applies the changes written to the primary, which must use the `kvs`
+    /// engine. It rejects writes until it is promoted.
+    pub fn replica_of(mut self, primary: SocketAddr) -> Self {
+        self.primary = Some(primary);
+        self
+    }
+
+    /// Also serve the RESP protocol of Redis on `resp_addr`.
+    ///
+    /// `GET`, `SET`, `DEL`, `EXISTS`, `MGET`, `SCAN` and `PING` are supported.
+    pub fn resp_addr(mut self, resp_addr: SocketAddr) -> Self {
+        self.resp_addr = Some(resp_addr);
+        self
+    }
+
+    /// Also serve HTTP on `http_addr`.
+    ///
+    /// `GET`, `PUT` and `DELETE` on `/keys/{key}` read, write and remove a key, and
+    /// `GET /keys?prefix=...` lists the keys with a prefix.
+    pub fn http_addr(mut self, http_addr: SocketAddr) -> Self {
+        self.http_addr = Some(http_addr);
+        self
+    }
+
+    /// Allow clients to write backups into new directories in `backup_dir`.
+    ///
+    /// Backup requests are rejected if no backup directory is set.
+    pub fn backup_dir(mut self, backup_dir: PathBuf) -> Self {
+        self.backup_dir = Some(backup_dir);
+        self
     }
 
     /// Run the server listening on the given address
     pub fn run(self, addr: SocketAddr) -> Result<()> {
         let listener = TcpListener::bind(&addr)?;
-        let server = listener
-            .incoming()
-            .map_err(|e| error!("IO error: {}", e))
-            .for_each(move |tcp| {
-                let engine = self.engine.clone();
-                serve(engine, tcp).map_err(|e| error!("Error on serving client: {}", e))
-            });
+        let resp_listener = match self.resp_addr {
+            Some(resp_addr) => Some(TcpListener::bind(&resp_addr)?),
+            None => None,
+        };
+        let http_listener = match self.http_addr {
+            Some(http_addr) => Some(TcpListener::bind(&http_addr)?),
+            None => None,
+        };
+        let replication = Arc::new(Replication::default());
+        let engine = self.engine;
+        let primary = self.primary;
+        let backup_dir = Arc::new(self.backup_dir);
+        let server = future::lazy(move || {
+            if let Some(primary) = primary {
+                replication.start(engine.clone(), primary);
+            }
+            if let Some(resp_listener) = resp_listener {
+                tokio::spawn(serve_resp(
+                    engine.clone(),
+                    resp_listener,
+                    Arc::clone(&replication),
+                ));
+            }
+            if let Some(http_listener) = http_listener {
+                tokio::spawn(serve_http(
+                    engine.clone(),
+                    http_listener,
+                    Arc::clone(&replication),
+                ));
+            }
+            listener
+                .incoming()
+                .map_err(|e| error!("IO error: {}", e))
+                .for_each(move |tcp| {
+                    let engine = engine.clone();
+                    let replication = Arc::clone(&replication);
+                    let backup_dir = Arc::clone(&backup_dir);
+                    // connections are served concurrently, so that a connection running
+                    // a transaction does not block the others
+                    tokio::spawn(
+                        serve(engine, tcp, replication, backup_dir)
+                            .map_err(|e| error!("Error on serving client: {}", e)),
+                    );
+                    Ok(())
+                })
+        });
         tokio::run(server);
         Ok(())
     }
 }
 
-fn serve<E: KvsEngine>(engine: E, tcp: TcpStream) -> impl Future<Item = (), Error = KvsError> {
+fn serve_resp<E: KvsEngine>(
+    engine: E,
+    listener: TcpListener,
+    replication: Arc<Replication>,
+) -> impl Future<Item = (), Error = ()> {
+    listener
+        .incoming()
+        .map_err(|e| error!("IO error: {}", e))
+        .for_each(move |tcp| {
+            tokio::spawn(
+                resp::serve(engine.clone(), tcp, Arc::clone(&replication))
+                    .map_err(|e| error!("Error on serving RESP client: {}", e)),
+            );
+            Ok(())
+        })
+}
+
+fn serve_http<E: KvsEngine>(
+    engine: E,
+    listener: TcpListener,
+    replication: Arc<Replication>,
+) -> impl Future<Item = (), Error = ()> {
+    listener
+        .incoming()
+        .map_err(|e| error!("IO error: {}", e))
+        .for_each(move |tcp| {
+            tokio::spawn(
+                http::serve(engine.clone(), tcp, Arc::clone(&replication))
+                    .map_err(|e| error!("Error on serving HTTP client: {}", e)),
+            );
+            Ok(())
+        })
+}
+
+fn serve<E: KvsEngine>(
+    engine: E,
+    tcp: TcpStream,
+    replication: Arc<Replication>,
+    backup_dir: Arc<Option<PathBuf>>,
+) -> impl Future<Item = (), Error = KvsError> {
     let (read_half, write_half) = tcp.split();
-    let read_json = ReadJson::new(FramedRead::new(read_half, LengthDelimitedCodec::new()));
-    let resp_stream = read_json
-        .map_err(KvsError::from)
-        .and_then(
-            move |req| -> Box<dyn Future<Item = Response, Error = KvsError> + Send> {
-                match req {
-                    Request::Get { key } => Box::new(engine.get(key).map(Response::Get)),
-                    Request::Set { key, value } => {
-                        Box::new(engine.set(key, value).map(|_| Response::Set))
+    let requests = FramedRead::new(read_half, ServerCodec::new());
+    let (sender, receiver) = mpsc::channel(RESPONSE_BUFFER);
+    // the transactions running on the connection
+    let txns = SharedTransactions::default();
+    let order = Arc::new(Mutex::new(RequestOrder::default()));
+    // the requests are served concurrently, and their responses are written as they
+    // are ready
+    let serve_requests = requests.for_each(
+        move |Frame {
+                  id,
+                  txn,
+                  message: req,
+              }| {
+            let (earlier, ticket) = RequestOrder::register(&order, request_keys(&req));
+            let engine = engine.clone();
+            let txns = Arc::clone(&txns);
+            let replication = Arc::clone(&replication);
+            let backup_dir = Arc::clone(&backup_dir);
+            let mut ticket = Some(ticket);
+            let resp_stream = earlier
+                .map(move |()| dispatch(&engine, &txns, txn, &replication, &backup_dir, req))
+                .flatten_stream()
+                .then(move |resp| -> std::result::Result<Frame<Response>, ()> {
+                    // the later requests on the keys wait for the first response only, so
+                    // that a watch does not hold them up
+                    ticket.take();
+                    let message = match resp {
+                        Ok(resp) => resp,
+                        Err(e) => Response::Err(format!("{}", e)),
+                    };
+                    Ok(Frame {
+                        id,
+                        txn: None,
+                        message,
+                    })
+                });
+            tokio::spawn(
+                resp_stream
+                    .forward(sender.clone().sink_map_err(|_| ()))
+                    .map(|_| ()),
+            );
+            Ok(())
+        },
+    );
+    let responses = FramedWrite::new(write_half, ServerCodec::new());
+    let write_responses = responses
+        .send_all(receiver.map_err(|e| KvsError::StringError(format!("{}", e))))
+        .map(|_| ());
+    serve_requests.join(write_responses).map(|_| ())
+}
+
+/// Serves a request in the transaction `txn` after the earlier requests it is
+/// ordered after.
+fn dispatch<E: KvsEngine>(
+    engine: &E,
+    txns: &SharedTransactions,
+    txn: Option<u64>,
+    replication: &Replication,
+    backup_dir: &Option<PathBuf>,
+    req: Request,
+) -> ResponseStream {
+    let resp = match (txn, req) {
+        (_, req) if is_write(&req) && replication.is_running() => {
+            error_response("The server is a read-only replica")
+        }
+        (_, Request::Promote) => promote(replication),
+        (Some(txn), req) => serve_in_transaction(engine, txns, txn, req),
+        (None, Request::Begin) => begin(txns),
+        (None, Request::Commit) | (None, Request::Abort) => {
+            error_response("No transaction is running")
+        }
+        (None, Request::Watch { prefix, from }) => return watch(engine, prefix, from),
+        (None, Request::Replicate) => return replicate(engine),
+        (None, Request::Backup { name }) => backup(engine, backup_dir, name),
+        (None, Request::Scan { start, end, limit }) => return scan(engine.scan(start, end, limit)),
+        (None, Request::ScanPrefix { prefix, limit }) => {
+            return scan(engine.scan_prefix(prefix, limit))
+        }
+        (None, req) => serve_request(engine, req),
+    };
+    Box::new(resp.into_stream())
+}
+
+fn serve_request<E: KvsEngine>(engine: &E, req: Request) -> ResponseFuture {
+    match req {
+        Request::Get { key } => Box::new(engine.get(key).map(Response::Get)),
+        Request::Set { key, value } => Box::new(engine.set(key, value).map(|_| Response::Set)),
+        Request::SetWithTtl { key, value, ttl } => {
+            Box::new(engine.set_with_ttl(key, value, ttl).map(|_| Response::Set))
+        }
+        Request::Remove { key } => Box::new(engine.remove(key).map(|_| Response::Remove)),
+        Request::CompareAndSwap { key, expected, new } => Box::new(
+            engine
+                .compare_and_swap(key, expected, new)
+                .map(Response::CompareAndSwap),
+        ),
+        Request::SetIfAbsent { key, value } => {
+            Box::new(engine.set_if_absent(key, value).map(Response::SetIfAbsent))
+        }
+        Request::MultiGet { keys } => {
+            let engine = engine.clone();
+            Box::new(
+                stream::iter_ok(keys)
+                    .map(move |key| engine.get(key))
+                    .buffered(MULTI_PARALLELISM)
+                    .collect()
+                    .map(Response::MultiGet),
+            )
+        }
+        Request::MultiSet { pairs } => {
+            // the last value of a key given twice wins
+            let pairs: BTreeMap<_, _> = pairs.into_iter().collect();
+            let engine = engine.clone();
+            Box::new(
+                stream::iter_ok(pairs)
+                    .map(move |(key, value)| engine.set(key, value))
+                    .buffer_unordered(MULTI_PARALLELISM)
+                    .for_each(|()| Ok(()))
+                    .map(|_| Response::MultiSet),
+            )
+        }
+        Request::WriteBatch { batch } => {
+            Box::new(engine.write_batch(batch).map(|_| Response::WriteBatch))
+        }
+        Request::Stats => Box::new(future::ok(Response::Stats(engine.stats()))),
+        Request::Begin
+        | Request::Commit
+        | Request::Abort
+        | Request::Scan { .. }
+        | Request::ScanPrefix { .. }
+        | Request::Watch { .. }
+        | Request::Replicate
+        | Request::Backup { .. }
+        | Request::Promote => unreachable!(),
+    }
+}
+
+/// Returns the keys of a request, or `None` if they are not known.
+fn request_keys(req: &Request) -> Option<Vec<Vec<u8>>> {
+    match req {
+        Request::Get { key }
+        | Request::Set { key, .. }
+        | Request::SetWithTtl { key, .. }
+        | Request::Remove { key }
+        | Request::CompareAndSwap { key, .. }
+        | Request::SetIfAbsent { key, .. } => Some(vec![key.clone()]),
+        Request::MultiGet { keys } => Some(keys.clone()),
+        Request::MultiSet { pairs } => Some(pairs.iter().map(|(key, _)| key.clone()).collect()),
+        Request::WriteBatch { batch } => Some(batch.keys().map(<[u8]>::to_vec).collect()),
+        _ => None,
+    }
+}
+
+/// Returns whether a request writes to the engine, which a replica rejects.
+///
+/// Transactions are rejected when they begin.
+fn is_write(req: &Request) -> bool {
+    matches!(
+        req,
+        Request::Set { .. }
+            | Request::SetWithTtl { .. }
+            | Request::Remove { .. }
+            | Request::CompareAndSwap { .. }
+            | Request::SetIfAbsent { .. }
+            | Request::MultiSet { .. }
+            | Request::WriteBatch { .. }
+            | Request::Begin
+    )
+}
+
+type ServerCodec = MessageCodec<Frame<Response>, Frame<Request>>;
+
+type ResponseFuture = Box<dyn Future<Item = Response, Error = KvsError> + Send>;
+
+type ResponseStream = Box<dyn Stream<Item = Response, Error = KvsError> + Send>;
+
+/// Streams the pairs of a scan in chunks of about `SCAN_CHUNK_SIZE` bytes and ends
+/// them with `ScanEnd`, so a large scan is not limited by the size of a frame.
+fn scan(
+    pairs: Box<dyn Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError> + Send>,
+) -> ResponseStream {
+    let chunks = ScanChunks {
+        pairs: pairs.fuse(),
+        chunk: Vec::new(),
+        size: 0,
+    };
+    Box::new(
+        chunks
+            .map(Response::Scan)
+            .chain(stream::once(Ok(Response::ScanEnd))),
+    )
+}
+
+/// Groups the pairs of a scan into chunks of about `SCAN_CHUNK_SIZE` bytes.
+struct ScanChunks<S> {
+    pairs: stream::Fuse<S>,
+    chunk: Vec<(Vec<u8>, Vec<u8>)>,
+    // the size of the keys and values in `chunk`
+    size: usize,
+}
+
+impl<S> Stream for ScanChunks<S>
+where
+    S: Stream<Item = (Vec<u8>, Vec<u8>), Error = KvsError>,
+{
+    type Item = Vec<(Vec<u8>, Vec<u8>)>;
+    type Error = KvsError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, KvsError> {
+        loop {
+            match self.pairs.poll()? {
+                Async::Ready(Some((key, value))) => {
+                    self.size += key.len() + value.len();
+                    self.chunk.push((key, value));
+                    if self.size >= SCAN_CHUNK_SIZE {
+                        self.size = 0;
+                        return Ok(Async::Ready(Some(mem::take(&mut self.chunk))));
                     }
-                    Request::Remove { key } => {
-                        Box::new(engine.remove(key).map(|_| Response::Remove))
+                }
+                // the last chunk may be smaller
+                Async::Ready(None) if !self.chunk.is_empty() => {
+                    self.size = 0;
+                    return Ok(Async::Ready(Some(mem::take(&mut self.chunk))));
+                }
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => return Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+/// Acknowledges a watch with the position it starts after and streams the changes.
+fn watch<E: KvsEngine>(engine: &E, prefix: Vec<u8>, from: Option<WatchSeq>) -> ResponseStream {
+    Box::new(
+        engine
+            .watch(prefix, from)
+            .map(|(start, changes)| {
+                stream::once(Ok(Response::Watch(start))).chain(changes.map(Response::Change))
+            })
+            .flatten_stream(),
+    )
+}
+
+/// Streams a copy of all pairs in chunks, acknowledges the watch with the position
+/// of the copy and streams the later changes.
+fn replicate<E: KvsEngine>(engine: &E) -> ResponseStream {
+    Box::new(
+        engine
+            .copy_and_watch()
+            .map(|(seq, copy, changes)| {
+                copy.map(Response::Copy)
+                    .chain(stream::once(Ok(Response::Watch(seq))))
+                    .chain(changes.map(Response::Change))
+            })
+            .flatten_stream(),
+    )
+}
+
+/// Writes a backup to the new directory `name` in the backup directory.
+fn backup<E: KvsEngine>(engine: &E, backup_dir: &Option<PathBuf>, name: String) -> ResponseFuture {
+    let backup_dir = match backup_dir {
+        Some(backup_dir) => backup_dir,
+        None => return error_response("Backups are not enabled on the server"),
+    };
+    // the backup must not be written outside the backup directory
+    let mut components = Path::new(&name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => (),
+        _ => return error_response(&format!("Invalid backup name: {}", name)),
+    }
+    Box::new(
+        engine
+            .backup_to(backup_dir.join(name))
+            .map(|_| Response::Backup),
+    )
+}
+
+fn promote(replication: &Replication) -> ResponseFuture {
+    if !replication.promote() {
+        return error_response("The server is not a replica");
+    }
+    Box::new(future::ok(Response::Promote))
+}
+
+/// Orders the requests of a connection on the same keys.
+///
+/// A request waits for the earlier requests on its keys. A request whose keys are
+/// not known, such as a scan or the beginning of a transaction, waits for all
+/// earlier requests, and all later requests wait for it.
+#[derive(Default)]
+struct RequestOrder {
+    next_id: u64,
+    // the last request on each key
+    keys: HashMap<Vec<u8>, (u64, Done)>,
+    // the last request whose keys are not known
+    barrier: Option<(u64, Done)>,
+}
+
+/// Resolves when the ticket of a request is dropped
+type Done = Shared<oneshot::Receiver<()>>;
+
+/// Makes the later requests on the keys of a request wait until it is dropped.
+struct Ticket {
+    order: Arc<Mutex<RequestOrder>>,
+    id: u64,
+    keys: Option<Vec<Vec<u8>>>,
+    _done: oneshot::Sender<()>,
+}
+
+impl RequestOrder {
+    /// Registers a request on `keys`, or on unknown keys if `keys` is `None`.
+    ///
+    /// Returns a future that resolves once the earlier requests it waits for are
+    /// done, and the ticket of the request.
+    fn register(
+        order: &Arc<Mutex<RequestOrder>>,
+        keys: Option<Vec<Vec<u8>>>,
+    ) -> (impl Future<Item = (), Error = KvsError>, Ticket) {
+        let mut this = order.lock().unwrap();
+        let id = this.next_id;
+        this.next_id += 1;
+        let (sender, receiver) = oneshot::channel();
+        let done = receiver.shared();
+        let mut earlier: Vec<Done> = this.barrier.iter().map(|(_, d)| d.clone()).collect();
+        match &keys {
+            Some(keys) => {
+                for key in keys {
+                    // a batch may write a key twice
+                    if let Some((prev, d)) = this.keys.insert(key.clone(), (id, done.clone())) {
+                        if prev != id {
+                            earlier.push(d);
+                        }
                     }
                 }
-            },
+            }
+            None => {
+                earlier.extend(this.keys.drain().map(|(_, (_, d))| d));
+                this.barrier = Some((id, done));
+            }
+        }
+        let earlier = future::join_all(
+            earlier
+                .into_iter()
+                .map(|d| d.then(|_| -> Result<()> { Ok(()) })),
         )
-        .then(|resp| -> Result<Response> {
-            match resp {
-                Ok(resp) => Ok(resp),
-                Err(e) => Ok(Response::Err(format!("{}", e))),
+        .map(|_| ());
+        let ticket = Ticket {
+            order: Arc::clone(order),
+            id,
+            keys,
+            _done: sender,
+        };
+        (earlier, ticket)
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        let mut order = self.order.lock().unwrap();
+        match self.keys.take() {
+            Some(keys) => {
+                for key in keys {
+                    if order.keys.get(&key).map(|(id, _)| *id) == Some(self.id) {
+                        order.keys.remove(&key);
+                    }
+                }
             }
-        });
-    let write_json = WriteJson::new(FramedWrite::new(write_half, LengthDelimitedCodec::new()));
-    write_json
-        .sink_map_err(KvsError::from)
-        .send_all(resp_stream)
-        .map(|_| ())
+            None => {
+                if order.barrier.as_ref().map(|(id, _)| *id) == Some(self.id) {
+                    order.barrier = None;
+                }
+            }
+        }
+    }
+}
+
+/// An optimistic transaction run over a connection.
+///
+/// The values read in the transaction are recorded and the writes are buffered
+/// until the transaction is committed with `KvsEngine::commit_transaction`.
+#[derive(Default)]
+struct ConnTransaction {
+    reads: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    // `None` removes the key
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+type SharedTransaction = Arc<Mutex<ConnTransaction>>;
+
+/// The transactions running on a connection.
+#[derive(Default)]
+struct Transactions {
+    next_id: u64,
+    // by transaction ID
+    running: HashMap<u64, SharedTransaction>,
+}
+
+type SharedTransactions = Arc<Mutex<Transactions>>;
+
+fn begin(txns: &SharedTransactions) -> ResponseFuture {
+    let mut txns = txns.lock().unwrap();
+    let id = txns.next_id;
+    txns.next_id += 1;
+    txns.running.insert(id, SharedTransaction::default());
+    Box::new(future::ok(Response::Begin(id)))
+}
+
+fn commit<E: KvsEngine>(engine: &E, txn: SharedTransaction) -> ResponseFuture {
+    let txn = mem::take(&mut *txn.lock().unwrap());
+    let mut batch = WriteBatch::new();
+    for (key, value) in txn.writes {
+        match value {
+            Some(value) => batch.set(key, value),
+            None => batch.remove(key),
+        }
+    }
+    Box::new(
+        engine
+            .commit_transaction(txn.reads.into_iter().collect(), batch)
+            .map(Response::Commit),
+    )
+}
+
+/// Serves a request in the running transaction `txn`.
+fn serve_in_transaction<E: KvsEngine>(
+    engine: &E,
+    txns: &SharedTransactions,
+    txn: u64,
+    req: Request,
+) -> ResponseFuture {
+    let txn = {
+        let mut txns = txns.lock().unwrap();
+        match req {
+            // a committed or aborted transaction stops running
+            Request::Commit | Request::Abort => txns.running.remove(&txn),
+            _ => txns.running.get(&txn).cloned(),
+        }
+    };
+    let txn = match txn {
+        Some(txn) => txn,
+        None => return error_response("No transaction is running"),
+    };
+    match req {
+        Request::Begin => error_response("A transaction is already running"),
+        Request::Commit => commit(engine, txn),
+        Request::Abort => Box::new(future::ok(Response::Abort)),
+        Request::Get { key } => Box::new(get_in_transaction(engine, &txn, key).map(Response::Get)),
+        Request::Set { key, value } => {
+            txn.lock().unwrap().writes.insert(key, Some(value));
+            Box::new(future::ok(Response::Set))
+        }
+        Request::Remove { key } => Box::new(
+            get_in_transaction(engine, &txn, key.clone()).and_then(move |value| {
+                if value.is_none() {
+                    return Err(KvsError::KeyNotFound);
+                }
+                txn.lock().unwrap().writes.insert(key, None);
+                Ok(Response::Remove)
+            }),
+        ),
+        _ => error_response("The request is not supported in a transaction"),
+    }
+}
+
+/// Gets the value of a key in a transaction and records it as read.
+fn get_in_transaction<E: KvsEngine>(
+    engine: &E,
+    txn: &SharedTransaction,
+    key: Vec<u8>,
+) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = KvsError> + Send> {
+    {
+        let txn = txn.lock().unwrap();
+        if let Some(value) = txn.writes.get(&key).or_else(|| txn.reads.get(&key)) {
+            return Box::new(future::ok(value.clone()));
+        }
+    }
+    let txn = Arc::clone(txn);
+    Box::new(engine.get(key.clone()).map(move |value| {
+        txn.lock().unwrap().reads.insert(key, value.clone());
+        value
+    }))
+}
+
+fn error_response(msg: &str) -> ResponseFuture {
+    Box::new(future::err(KvsError::StringError(msg.to_owned())))
 }
@@ -1,11 +1,14 @@
 use assert_cmd::prelude::*;
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{KvStore, KvsEngine};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
 use tempfile::TempDir;
+use tokio::prelude::*;
 
 // `kvs-client` with no args should exit with a non-zero code.
 #[test]
@@ -326,6 +329,521 @@ fn cli_access_server(engine: &str, addr: &str) {
     handle.join().unwrap();
 }
 
+fn cli_scan(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    for (key, value) in &[("b:1", "1"), ("a:1", "0"), ("b:3", "3"), ("b:2", "2")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", key, value, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--prefix", "b:", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("b:1\t1\nb:2\t2\nb:3\t3\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "a", "b:3", "--limit", "2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("a:1\t0\nb:1\t1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--prefix", "c:", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+fn cli_binary(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "00ff", "deadbeef", "--format", "hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "00ff", "--format", "hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("deadbeef\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "AP8=", "--format", "base64", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("3q2+7w==\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["scan", "--prefix", "00", "--format", "hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("00ff\tdeadbeef\n");
+
+    // Values that are not UTF-8 cannot be printed as strings
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "set", "dGV4dA==", "/wA=", "--format", "base64", "--addr", addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "text", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "74657874", "--format", "hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("ff00\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "not hex", "--format", "hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let content: Vec<u8> = (0..=255).collect();
+    fs::write(temp_dir.path().join("input.bin"), &content).unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "image", "--value-file", "input.bin", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "image", "--output", "output.bin", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    assert_eq!(
+        fs::read(temp_dir.path().join("output.bin")).unwrap(),
+        content
+    );
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+fn cli_ttl(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--ttl", "1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key2", "value2", "--ttl", "soon", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    thread::sleep(Duration::from_millis(1500));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+fn cli_cas(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["cas", "key1", "--new", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["cas", "key1", "--new", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("does not match"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&[
+            "cas",
+            "key1",
+            "--expected",
+            "value1",
+            "--new",
+            "value2",
+            "--addr",
+            addr,
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value2\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["cas", "key1", "--expected", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+fn cli_backup(engine: &str, addr: &str, restored_addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let backup_dir = TempDir::new().unwrap();
+    let backup_path = backup_dir.path().join("backup");
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", engine, "--addr", addr, "--backup-dir"])
+        .arg(backup_dir.path())
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["backup", "backup", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert!(backup_path.is_dir());
+
+    // the backup directory must not exist
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["backup", "backup", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    // backups are only written into the backup directory
+    for name in &["../escaped", "/tmp/escaped", "a/b", ".", ""] {
+        Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["backup", name, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure();
+    }
+    assert!(!backup_dir.path().join("../escaped").exists());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    let restored_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["restore", backup_path.to_str().unwrap(), "--engine", engine])
+        .current_dir(&restored_dir)
+        .assert()
+        .success();
+
+    // the data directory must be empty
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["restore", backup_path.to_str().unwrap(), "--engine", engine])
+        .current_dir(&restored_dir)
+        .assert()
+        .failure();
+
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--addr", restored_addr])
+        .current_dir(&restored_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", restored_addr])
+        .current_dir(&restored_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// `kvs-client stats` should print the cache counters of the server
+#[test]
+fn cli_stats() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4020";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&[
+            "--engine",
+            "kvs",
+            "--addr",
+            addr,
+            "--cache-capacity",
+            "1048576",
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    for _ in 0..2 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("value1\n");
+    }
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["stats", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("cache_hits\t1\ncache_misses\t1\ncache_size\t10\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// `kvs-migrate` should copy a data directory to another engine
+#[test]
+fn cli_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    let from = temp_dir.path().join("kvs");
+    let sled_dir = temp_dir.path().join("sled");
+    let kvs_dir = temp_dir.path().join("kvs2");
+    {
+        let store = KvStore::<RayonThreadPool>::open(&from, 1).unwrap();
+        for key_id in 0..2500 {
+            store
+                .set(
+                    format!("key{}", key_id).into_bytes(),
+                    format!("value{}", key_id).into_bytes(),
+                )
+                .wait()
+                .unwrap();
+        }
+        store.remove(b"key0".to_vec()).wait().unwrap();
+    }
+    fs::write(from.join("engine"), "kvs").unwrap();
+
+    Command::cargo_bin("kvs-migrate")
+        .unwrap()
+        .args(&[
+            from.to_str().unwrap(),
+            sled_dir.to_str().unwrap(),
+            "--engine",
+            "sled",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(sled_dir.join("engine")).unwrap(), "sled");
+
+    // the target directory must be empty
+    Command::cargo_bin("kvs-migrate")
+        .unwrap()
+        .args(&[
+            from.to_str().unwrap(),
+            sled_dir.to_str().unwrap(),
+            "--engine",
+            "sled",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("not empty"));
+
+    Command::cargo_bin("kvs-migrate")
+        .unwrap()
+        .args(&[
+            sled_dir.to_str().unwrap(),
+            kvs_dir.to_str().unwrap(),
+            "--engine",
+            "kvs",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    assert_eq!(fs::read_to_string(kvs_dir.join("engine")).unwrap(), "kvs");
+
+    let store = KvStore::<RayonThreadPool>::open(&kvs_dir, 1).unwrap();
+    assert_eq!(store.get(b"key0".to_vec()).wait().unwrap(), None);
+    for key_id in 1..2500 {
+        assert_eq!(
+            store
+                .get(format!("key{}", key_id).into_bytes())
+                .wait()
+                .unwrap(),
+            Some(format!("value{}", key_id).into_bytes())
+        );
+    }
+}
+
 #[test]
 fn cli_access_server_kvs_engine() {
     cli_access_server("kvs", "127.0.0.1:4004");
@@ -335,3 +853,324 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_scan_kvs_engine() {
+    cli_scan("kvs", "127.0.0.1:4006");
+}
+
+#[test]
+fn cli_scan_sled_engine() {
+    cli_scan("sled", "127.0.0.1:4007");
+}
+
+#[test]
+fn cli_binary_kvs_engine() {
+    cli_binary("kvs", "127.0.0.1:4008");
+}
+
+#[test]
+fn cli_binary_sled_engine() {
+    cli_binary("sled", "127.0.0.1:4009");
+}
+
+#[test]
+fn cli_ttl_kvs_engine() {
+    cli_ttl("kvs", "127.0.0.1:4010");
+}
+
+#[test]
+fn cli_ttl_sled_engine() {
+    cli_ttl("sled", "127.0.0.1:4011");
+}
+
+#[test]
+fn cli_cas_kvs_engine() {
+    cli_cas("kvs", "127.0.0.1:4012");
+}
+
+#[test]
+fn cli_cas_sled_engine() {
+    cli_cas("sled", "127.0.0.1:4013");
+}
+
+#[test]
+fn cli_backup_kvs_engine() {
+    cli_backup("kvs", "127.0.0.1:4016", "127.0.0.1:4017");
+}
+
+#[test]
+fn cli_backup_sled_engine() {
+    cli_backup("sled", "127.0.0.1:4018", "127.0.0.1:4019");
+}
+
+#[test]
+fn cli_access_server_lsm_engine() {
+    cli_access_server("lsm", "127.0.0.1:4021");
+}
+
+#[test]
+fn cli_scan_lsm_engine() {
+    cli_scan("lsm", "127.0.0.1:4022");
+}
+
+#[test]
+fn cli_backup_lsm_engine() {
+    cli_backup("lsm", "127.0.0.1:4023", "127.0.0.1:4024");
+}
+
+// A server started without a backup directory should reject backups
+#[test]
+fn cli_backup_disabled() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--addr", "127.0.0.1:4056"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["backup", "backup", "--addr", "127.0.0.1:4056"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Backups are not enabled on the server"));
+    assert!(!temp_dir.path().join("backup").exists());
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// The memory engine should run in any directory and leave no engine file
+#[test]
+fn cli_memory_engine() {
+    let addr = "127.0.0.1:4026";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "memory", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+    assert!(!temp_dir.path().join("engine").exists());
+
+    // a directory of another engine does not matter
+    fs::write(temp_dir.path().join("engine"), "sled").unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "memory", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+}
+
+// `kvs-client watch` should print the changes with their positions
+#[test]
+fn cli_watch() {
+    let addr = "127.0.0.1:4028";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let watcher = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["watch", "key", "--addr", addr])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    for args in &[
+        vec!["set", "key1", "value1"],
+        vec!["set", "other", "value"],
+        vec!["rm", "key1"],
+    ] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(&["--addr", addr])
+            .assert()
+            .success();
+    }
+    thread::sleep(Duration::from_secs(1));
+    server.kill().expect("server exited before killed");
+    let output = watcher.wait_with_output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<Vec<&str>> = stdout
+        .lines()
+        .map(|line| line.split('\t').collect())
+        .collect();
+    assert_eq!(lines.len(), 2, "unexpected output: {}", stdout);
+    assert_eq!(lines[0][1..], ["set", "key1", "value1"]);
+    assert_eq!(lines[1][1..], ["rm", "key1"]);
+
+    // the changes after the first one are printed again from its position
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let mut watcher = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["watch", "key", "--from", lines[0][0], "--addr", addr])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    watcher.kill().unwrap();
+    server.kill().expect("server exited before killed");
+    let output = watcher.wait_with_output().unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout.lines().collect::<Vec<_>>(),
+        [format!("{}\trm\tkey1", lines[1][0])]
+    );
+}
+
+#[test]
+fn cli_replication() {
+    let primary_addr = "127.0.0.1:4031";
+    let replica_addr = "127.0.0.1:4032";
+    let primary_dir = TempDir::new().unwrap();
+    let replica_dir = TempDir::new().unwrap();
+    let mut primary = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", primary_addr])
+        .current_dir(&primary_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", primary_addr])
+        .assert()
+        .success();
+
+    let mut replica = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", replica_addr])
+        .args(&["--replica-of", primary_addr])
+        .current_dir(&replica_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key2", "value2", "--addr", primary_addr])
+        .assert()
+        .success();
+    thread::sleep(Duration::from_millis(500));
+    for (key, value) in &[("key1", "value1"), ("key2", "value2")] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", key, "--addr", replica_addr])
+            .assert()
+            .success()
+            .stdout(format!("{}\n", value));
+    }
+
+    // the replica rejects writes until it is promoted
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key3", "value3", "--addr", replica_addr])
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["promote", "--addr", replica_addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key3", "value3", "--addr", replica_addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["promote", "--addr", replica_addr])
+        .assert()
+        .failure();
+
+    replica.kill().expect("server exited before killed");
+    primary.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_mget_mset() {
+    let addr = "127.0.0.1:4038";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["mset", "--addr", addr])
+        .with_stdin()
+        .buffer("key1\tvalue1\nkey2\tvalue\twith\ttabs\n")
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["mget", "--addr", addr])
+        .with_stdin()
+        .buffer("key2\nmissing\nkey1\n")
+        .assert()
+        .success()
+        .stdout("key2\tvalue\twith\ttabs\nkey1\tvalue1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["mset", "--format", "hex", "--addr", addr])
+        .with_stdin()
+        .buffer("6b657933\n")
+        .assert()
+        .failure();
+
+    server.kill().expect("server exited before killed");
+}
@@ -0,0 +1,191 @@
+use hyper::{Body, Client, Method, Request, StatusCode};
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{KvStore, KvsEngine, KvsServer, Result};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio::prelude::*;
+use tokio::runtime::Runtime;
+
+fn spawn_server<E: KvsEngine>(server: KvsServer<E>, addr: SocketAddr) {
+    thread::spawn(move || server.run(addr).unwrap());
+    thread::sleep(Duration::from_secs(1));
+}
+
+/// Sends a request and returns the status and the body of the response.
+fn request(runtime: &mut Runtime, method: Method, uri: &str, body: &[u8]) -> (StatusCode, Vec<u8>) {
+    let req = Request::builder()
+        .method(method)
+        .uri(uri)
+        .body(Body::from(body.to_vec()))
+        .unwrap();
+    let resp = Client::new().request(req).and_then(|resp| {
+        let status = resp.status();
+        resp.into_body()
+            .concat2()
+            .map(move |body| (status, body.to_vec()))
+    });
+    runtime.block_on(resp).unwrap()
+}
+
+#[test]
+fn http_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    let http_addr = "127.0.0.1:4048";
+    spawn_server(
+        KvsServer::new(engine).http_addr(http_addr.parse().unwrap()),
+        "127.0.0.1:4047".parse().unwrap(),
+    );
+    let mut runtime = Runtime::new()?;
+    let url = |path: &str| format!("http://{}{}", http_addr, path);
+
+    let (status, _) = request(&mut runtime, Method::PUT, &url("/keys/key1"), b"value1");
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, body) = request(&mut runtime, Method::GET, &url("/keys/key1"), b"");
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"value1");
+
+    // keys and values are arbitrary bytes
+    let (status, _) = request(
+        &mut runtime,
+        Method::PUT,
+        &url("/keys/a%2Fb%00"),
+        b"\xff\x00",
+    );
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, body) = request(&mut runtime, Method::GET, &url("/keys/a%2fb%00"), b"");
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"\xff\x00");
+
+    let (status, body) = request(&mut runtime, Method::GET, &url("/keys/missing"), b"");
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body, br#"{"error":"Key not found"}"#.as_ref());
+
+    let (status, _) = request(&mut runtime, Method::DELETE, &url("/keys/key1"), b"");
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = request(&mut runtime, Method::GET, &url("/keys/key1"), b"");
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, body) = request(&mut runtime, Method::DELETE, &url("/keys/key1"), b"");
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body, br#"{"error":"Key not found"}"#.as_ref());
+    Ok(())
+}
+
+#[test]
+fn http_list_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    let http_addr = "127.0.0.1:4050";
+    spawn_server(
+        KvsServer::new(engine).http_addr(http_addr.parse().unwrap()),
+        "127.0.0.1:4049".parse().unwrap(),
+    );
+    let mut runtime = Runtime::new()?;
+    let url = |path: &str| format!("http://{}{}", http_addr, path);
+
+    for key in &["user:1", "user:2", "user:3", "item 1"] {
+        let path = format!("/keys/{}", key.replace(' ', "%20"));
+        let (status, _) = request(&mut runtime, Method::PUT, &url(&path), b"value");
+        assert_eq!(status, StatusCode::NO_CONTENT);
+    }
+
+    let (status, body) = request(&mut runtime, Method::GET, &url("/keys?prefix=user%3A"), b"");
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, br#"["user%3A1","user%3A2","user%3A3"]"#.as_ref());
+    let (_, body) = request(
+        &mut runtime,
+        Method::GET,
+        &url("/keys?prefix=user:&limit=2"),
+        b"",
+    );
+    assert_eq!(body, br#"["user%3A1","user%3A2"]"#.as_ref());
+    let (_, body) = request(&mut runtime, Method::GET, &url("/keys?prefix=item+"), b"");
+    assert_eq!(body, br#"["item%201"]"#.as_ref());
+    let (_, body) = request(&mut runtime, Method::GET, &url("/keys"), b"");
+    assert_eq!(
+        body,
+        br#"["item%201","user%3A1","user%3A2","user%3A3"]"#.as_ref()
+    );
+    let (_, body) = request(&mut runtime, Method::GET, &url("/keys?prefix=none"), b"");
+    assert_eq!(body, b"[]");
+
+    // a listed key that is not UTF-8 can be read back through its path
+    let (status, _) = request(
+        &mut runtime,
+        Method::PUT,
+        &url("/keys/bin%FF%00"),
+        b"binary",
+    );
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (_, body) = request(&mut runtime, Method::GET, &url("/keys?prefix=bin"), b"");
+    assert_eq!(body, br#"["bin%FF%00"]"#.as_ref());
+    let (status, body) = request(&mut runtime, Method::GET, &url("/keys/bin%FF%00"), b"");
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, b"binary");
+    Ok(())
+}
+
+#[test]
+fn http_errors() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    let http_addr = "127.0.0.1:4052";
+    spawn_server(
+        KvsServer::new(engine).http_addr(http_addr.parse().unwrap()),
+        "127.0.0.1:4051".parse().unwrap(),
+    );
+    let mut runtime = Runtime::new()?;
+    let url = |path: &str| format!("http://{}{}", http_addr, path);
+
+    let (status, body) = request(&mut runtime, Method::GET, &url("/values/key"), b"");
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body, br#"{"error":"Not found"}"#.as_ref());
+    let (status, _) = request(&mut runtime, Method::POST, &url("/keys/key"), b"value");
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    let (status, _) = request(&mut runtime, Method::DELETE, &url("/keys"), b"");
+    assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+    let (status, body) = request(&mut runtime, Method::GET, &url("/keys/%zz"), b"");
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(
+        body,
+        br#"{"error":"Invalid percent-encoding in the key"}"#.as_ref()
+    );
+    let (status, _) = request(&mut runtime, Method::GET, &url("/keys?limit=x"), b"");
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = request(&mut runtime, Method::GET, &url("/keys?sort=asc"), b"");
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    Ok(())
+}
+
+#[test]
+fn http_replica_is_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary_addr = "127.0.0.1:4053".parse().unwrap();
+    spawn_server(
+        KvsServer::new(KvStore::<RayonThreadPool>::open(primary_dir.path(), 4)?),
+        primary_addr,
+    );
+    let engine = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    let http_addr = "127.0.0.1:4055";
+    spawn_server(
+        KvsServer::new(engine)
+            .replica_of(primary_addr)
+            .http_addr(http_addr.parse().unwrap()),
+        "127.0.0.1:4054".parse().unwrap(),
+    );
+    let mut runtime = Runtime::new()?;
+    let url = |path: &str| format!("http://{}{}", http_addr, path);
+
+    let (status, body) = request(&mut runtime, Method::PUT, &url("/keys/key"), b"value");
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(
+        body,
+        br#"{"error":"The server is a read-only replica"}"#.as_ref()
+    );
+    let (status, _) = request(&mut runtime, Method::GET, &url("/keys/key"), b"");
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    Ok(())
+}
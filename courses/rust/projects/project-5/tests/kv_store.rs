@@ -1,38 +1,72 @@
 use kvs::thread_pool::RayonThreadPool;
-use kvs::{KvStore, KvsEngine, KvsError, Result};
+use kvs::{
+    Compression, EngineStats, IndexBackend, KvStore, KvStoreOptions, KvsEngine, KvsError,
+    LsmKvsEngine, LsmOptions, MemoryKvsEngine, Result, SledKvsEngine, SyncPolicy, WatchEvent,
+    WriteBatch,
+};
+use std::fs::{self, OpenOptions};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use tokio::prelude::*;
 use tokio::runtime::Runtime;
 use walkdir::WalkDir;
 
+// Keys and values should be stored as arbitrary bytes
+#[test]
+fn binary_key_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = vec![0, 159, 146, 150, 255];
+    let value = vec![255, 0, 128, 10, 13, 0];
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(key.clone(), value.clone()).wait()?;
+    assert_eq!(store.get(key.clone()).wait()?, Some(value.clone()));
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get(key.clone()).wait()?, Some(value.clone()));
+    let pairs = store.scan_prefix(vec![0], None).collect().wait()?;
+    assert_eq!(pairs, vec![(key.clone(), value.clone())]);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::<RayonThreadPool>::new(sled::open(temp_dir.path())?, 1)?;
+    store.set(key.clone(), value.clone()).wait()?;
+    assert_eq!(store.get(key.clone()).wait()?, Some(value.clone()));
+    let pairs = store.scan_prefix(vec![0], None).collect().wait()?;
+    assert_eq!(pairs, vec![(key, value)]);
+
+    Ok(())
+}
+
 // Should get previously stored value
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
 
-    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
-    store.set("key2".to_owned(), "value2".to_owned()).wait()?;
+    store.set(b"key1".to_vec(), b"value1".to_vec()).wait()?;
+    store.set(b"key2".to_vec(), b"value2".to_vec()).wait()?;
 
     assert_eq!(
-        store.get("key1".to_owned()).wait()?,
-        Some("value1".to_owned())
+        store.get(b"key1".to_vec()).wait()?,
+        Some(b"value1".to_vec())
     );
     assert_eq!(
-        store.get("key2".to_owned()).wait()?,
-        Some("value2".to_owned())
+        store.get(b"key2".to_vec()).wait()?,
+        Some(b"value2".to_vec())
     );
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
     assert_eq!(
-        store.get("key1".to_owned()).wait()?,
-        Some("value1".to_owned())
+        store.get(b"key1".to_vec()).wait()?,
+        Some(b"value1".to_vec())
     );
     assert_eq!(
-        store.get("key2".to_owned()).wait()?,
-        Some("value2".to_owned())
+        store.get(b"key2".to_vec()).wait()?,
+        Some(b"value2".to_vec())
     );
 
     Ok(())
@@ -44,28 +78,28 @@ fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
 
-    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
+    store.set(b"key1".to_vec(), b"value1".to_vec()).wait()?;
     assert_eq!(
-        store.get("key1".to_owned()).wait()?,
-        Some("value1".to_owned())
+        store.get(b"key1".to_vec()).wait()?,
+        Some(b"value1".to_vec())
     );
-    store.set("key1".to_owned(), "value2".to_owned()).wait()?;
+    store.set(b"key1".to_vec(), b"value2".to_vec()).wait()?;
     assert_eq!(
-        store.get("key1".to_owned()).wait()?,
-        Some("value2".to_owned())
+        store.get(b"key1".to_vec()).wait()?,
+        Some(b"value2".to_vec())
     );
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
     assert_eq!(
-        store.get("key1".to_owned()).wait()?,
-        Some("value2".to_owned())
+        store.get(b"key1".to_vec()).wait()?,
+        Some(b"value2".to_vec())
     );
-    store.set("key1".to_owned(), "value3".to_owned()).wait()?;
+    store.set(b"key1".to_vec(), b"value3".to_vec()).wait()?;
     assert_eq!(
-        store.get("key1".to_owned()).wait()?,
-        Some("value3".to_owned())
+        store.get(b"key1".to_vec()).wait()?,
+        Some(b"value3".to_vec())
     );
 
     Ok(())
@@ -77,13 +111,13 @@ fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
 
-    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
-    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+    store.set(b"key1".to_vec(), b"value1".to_vec()).wait()?;
+    assert_eq!(store.get(b"key2".to_vec()).wait()?, None);
 
     // Open from disk again and check persistent data
     drop(store);
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
-    assert_eq!(store.get("key2".to_owned()).wait()?, None);
+    assert_eq!(store.get(b"key2".to_vec()).wait()?, None);
 
     Ok(())
 }
@@ -92,7 +126,7 @@ fn get_non_existent_value() -> Result<()> {
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
-    assert!(store.remove("key1".to_owned()).wait().is_err());
+    assert!(store.remove(b"key1".to_vec()).wait().is_err());
     Ok(())
 }
 
@@ -100,12 +134,743 @@ fn remove_non_existent_key() -> Result<()> {
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
-    store.set("key1".to_owned(), "value1".to_owned()).wait()?;
-    assert!(store.remove("key1".to_owned()).wait().is_ok());
-    assert_eq!(store.get("key1".to_owned()).wait()?, None);
+    store.set(b"key1".to_vec(), b"value1".to_vec()).wait()?;
+    assert!(store.remove(b"key1".to_vec()).wait().is_ok());
+    assert_eq!(store.get(b"key1".to_vec()).wait()?, None);
+    Ok(())
+}
+
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(b"key1".to_vec(), b"value1".to_vec()).wait()?;
+
+    let mut batch = WriteBatch::new();
+    batch.set(b"key2".to_vec(), b"value2".to_vec());
+    batch.remove(b"key1".to_vec());
+    batch.set(b"key3".to_vec(), b"value3".to_vec());
+    batch.remove(b"key4".to_vec());
+    store.write_batch(batch).wait()?;
+    assert_eq!(store.get(b"key1".to_vec()).wait()?, None);
+    assert_eq!(
+        store.get(b"key2".to_vec()).wait()?,
+        Some(b"value2".to_vec())
+    );
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get(b"key1".to_vec()).wait()?, None);
+    assert_eq!(
+        store.get(b"key2".to_vec()).wait()?,
+        Some(b"value2".to_vec())
+    );
+    assert_eq!(
+        store.get(b"key3".to_vec()).wait()?,
+        Some(b"value3".to_vec())
+    );
+
+    Ok(())
+}
+
+// A batch cut off by a crash should be ignored as a whole
+#[test]
+fn torn_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(b"key1".to_vec(), b"value1".to_vec()).wait()?;
+    let mut batch = WriteBatch::new();
+    batch.remove(b"key1".to_vec());
+    batch.set(b"key2".to_vec(), b"value2".to_vec());
+    batch.set(b"key3".to_vec(), b"value3".to_vec());
+    store.write_batch(batch).wait()?;
+    drop(store);
+
+    // Cut the last command of the batch in half
+    let log = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .max_by_key(|path| fs::metadata(path).unwrap().len())
+        .expect("no log file");
+    let file = OpenOptions::new().write(true).open(&log)?;
+    let len = file.metadata()?.len();
+    file.set_len(len - 10)?;
+    drop(file);
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get(b"key1".to_vec()).wait()?,
+        Some(b"value1".to_vec())
+    );
+    assert_eq!(store.get(b"key2".to_vec()).wait()?, None);
+    assert_eq!(store.get(b"key3".to_vec()).wait()?, None);
+
+    // New writes are not affected by the torn batch
+    store.set(b"key2".to_vec(), b"value4".to_vec()).wait()?;
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get(b"key2".to_vec()).wait()?,
+        Some(b"value4".to_vec())
+    );
+
+    Ok(())
+}
+
+// A corrupted record at the end of the log should be truncated on open
+#[test]
+fn corrupted_log_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(b"key1".to_vec(), b"value1".to_vec()).wait()?;
+    store.set(b"key2".to_vec(), b"value2".to_vec()).wait()?;
+    drop(store);
+
+    // Flip the last byte of the value of key2
+    let log = temp_dir.path().join("1.log");
+    let mut content = fs::read(&log)?;
+    let last = content.len() - 1;
+    content[last] ^= 0xff;
+    fs::write(&log, &content)?;
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get(b"key1".to_vec()).wait()?,
+        Some(b"value1".to_vec())
+    );
+    assert_eq!(store.get(b"key2".to_vec()).wait()?, None);
+    assert!(fs::metadata(&log)?.len() < content.len() as u64);
+
+    store.set(b"key2".to_vec(), b"value3".to_vec()).wait()?;
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get(b"key2".to_vec()).wait()?,
+        Some(b"value3".to_vec())
+    );
+
+    Ok(())
+}
+
+// Only a torn record at the end of the newest log is repaired, and other
+// corruption leaves the log files untouched
+#[test]
+fn corrupted_log_middle() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(b"key1".to_vec(), b"value1".to_vec()).wait()?;
+    store.set(b"key2".to_vec(), b"value2".to_vec()).wait()?;
+    drop(store);
+
+    // Flip a byte of the value of key1, which is followed by key2
+    let log = temp_dir.path().join("1.log");
+    let original = fs::read(&log)?;
+    let mut content = original.clone();
+    let pos = content.windows(6).position(|w| w == b"value1").unwrap();
+    content[pos] ^= 0xff;
+    fs::write(&log, &content)?;
+    match KvStore::<RayonThreadPool>::open(temp_dir.path(), 1) {
+        Err(KvsError::CorruptedLog(_)) => (),
+        res => panic!("unexpected result: {:?}", res.map(|_| ())),
+    }
+    assert_eq!(fs::read(&log)?, content);
+
+    // The last record of 1.log is not torn by a crash once 2.log is written
+    fs::write(&log, &original)?;
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(b"key3".to_vec(), b"value3".to_vec()).wait()?;
+    drop(store);
+    let last = content.len() - 1;
+    content = original;
+    content[last] ^= 0xff;
+    fs::write(&log, &content)?;
+    match KvStore::<RayonThreadPool>::open(temp_dir.path(), 1) {
+        Err(KvsError::CorruptedLog(_)) => (),
+        res => panic!("unexpected result: {:?}", res.map(|_| ())),
+    }
+    assert_eq!(fs::read(&log)?, content);
+
+    Ok(())
+}
+
+// Logs of JSON commands written by older versions should still be readable
+#[test]
+fn legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Remove":{"key":"key1"}}{"Set":{"key":"key3""#,
+    )?;
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get(b"key1".to_vec()).wait()?, None);
+    assert_eq!(
+        store.get(b"key2".to_vec()).wait()?,
+        Some(b"value2".to_vec())
+    );
+    assert_eq!(store.get(b"key3".to_vec()).wait()?, None);
+    store.set(b"key3".to_vec(), b"value3".to_vec()).wait()?;
+    drop(store);
+
+    assert!(fs::read(temp_dir.path().join("1.log"))?.starts_with(b"KVSL"));
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        store.get(b"key2".to_vec()).wait()?,
+        Some(b"value2".to_vec())
+    );
+    assert_eq!(
+        store.get(b"key3".to_vec()).wait()?,
+        Some(b"value3".to_vec())
+    );
+
+    Ok(())
+}
+
+#[test]
+fn sled_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::<RayonThreadPool>::new(sled::open(temp_dir.path())?, 1)?;
+    store.set(b"key1".to_vec(), b"value1".to_vec()).wait()?;
+
+    let mut batch = WriteBatch::new();
+    batch.set(b"key2".to_vec(), b"value2".to_vec());
+    batch.remove(b"key1".to_vec());
+    store.write_batch(batch).wait()?;
+    assert_eq!(store.get(b"key1".to_vec()).wait()?, None);
+    assert_eq!(
+        store.get(b"key2".to_vec()).wait()?,
+        Some(b"value2".to_vec())
+    );
+
+    Ok(())
+}
+
+// Keys set with a TTL should be missing after they expire, also after reopening
+#[test]
+fn ttl_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let ttl = Duration::from_millis(200);
+    store
+        .set_with_ttl(b"key1".to_vec(), b"value1".to_vec(), ttl)
+        .wait()?;
+    store
+        .set_with_ttl(
+            b"key2".to_vec(),
+            b"value2".to_vec(),
+            Duration::from_secs(3600),
+        )
+        .wait()?;
+    store
+        .set_with_ttl(b"key3".to_vec(), b"value3".to_vec(), ttl)
+        .wait()?;
+    // overwriting the key clears its expiry
+    store.set(b"key3".to_vec(), b"value3".to_vec()).wait()?;
+    assert_eq!(
+        store.get(b"key1".to_vec()).wait()?,
+        Some(b"value1".to_vec())
+    );
+
+    thread::sleep(Duration::from_millis(300));
+    let check = |store: &KvStore<RayonThreadPool>| -> Result<()> {
+        assert_eq!(store.get(b"key1".to_vec()).wait()?, None);
+        assert_eq!(
+            store.get(b"key2".to_vec()).wait()?,
+            Some(b"value2".to_vec())
+        );
+        assert_eq!(
+            store.get(b"key3".to_vec()).wait()?,
+            Some(b"value3".to_vec())
+        );
+        let pairs = store
+            .scan(b"key".to_vec(), None, Some(1))
+            .collect()
+            .wait()?;
+        assert_eq!(pairs, vec![(b"key2".to_vec(), b"value2".to_vec())]);
+        match store.remove(b"key1".to_vec()).wait() {
+            Err(KvsError::KeyNotFound) => Ok(()),
+            _ => panic!("expired key is removed"),
+        }
+    };
+    check(&store)?;
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    check(&store)?;
+
+    Ok(())
+}
+
+// Compaction should drop expired keys
+#[test]
+fn compaction_drops_expired() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(1024);
+    let store = open_with_compaction(&temp_dir, 1, options.clone())?;
+    for key_id in 0..100 {
+        store
+            .set_with_ttl(
+                format!("key{}", key_id).into_bytes(),
+                b"value".to_vec(),
+                Duration::from_millis(100),
+            )
+            .wait()?;
+    }
+    thread::sleep(Duration::from_millis(200));
+    // expired keys are counted as stale on the next write
+    store.set(b"live".to_vec(), b"value".to_vec()).wait()?;
+    // dropping the store waits for the background compaction
+    drop(store);
+    // keys may already expire while they are written, so more than one
+    // compaction can run and the compacted generation is not fixed
+    assert!(!temp_dir.path().join("1.log").exists());
+    assert!(log_size(&temp_dir) < 100);
+
+    let store = open_with_compaction(&temp_dir, 1, options)?;
+    assert_eq!(store.get(b"key0".to_vec()).wait()?, None);
+    assert_eq!(store.get(b"live".to_vec()).wait()?, Some(b"value".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn sled_ttl_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::<RayonThreadPool>::new(sled::open(temp_dir.path())?, 1)?;
+    store
+        .set_with_ttl(
+            b"key1".to_vec(),
+            b"value1".to_vec(),
+            Duration::from_millis(200),
+        )
+        .wait()?;
+    store
+        .set_with_ttl(
+            b"key2".to_vec(),
+            b"value2".to_vec(),
+            Duration::from_secs(3600),
+        )
+        .wait()?;
+    assert_eq!(
+        store.get(b"key1".to_vec()).wait()?,
+        Some(b"value1".to_vec())
+    );
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get(b"key1".to_vec()).wait()?, None);
+    let pairs = store.scan_prefix(b"key".to_vec(), None).collect().wait()?;
+    assert_eq!(pairs, vec![(b"key2".to_vec(), b"value2".to_vec())]);
+    match store.remove(b"key1".to_vec()).wait() {
+        Err(KvsError::KeyNotFound) => (),
+        _ => panic!("expired key is removed"),
+    }
+
+    // setting the key again clears its expiry
+    store.set(b"key1".to_vec(), b"value3".to_vec()).wait()?;
+    assert_eq!(
+        store.get(b"key1".to_vec()).wait()?,
+        Some(b"value3".to_vec())
+    );
+    store.remove(b"key1".to_vec()).wait()?;
+    Ok(())
+}
+
+fn check_compare_and_swap<E: KvsEngine>(store: E) -> Result<()> {
+    // a missing key only matches `None`
+    assert!(!store
+        .compare_and_swap(
+            b"key".to_vec(),
+            Some(b"value".to_vec()),
+            Some(b"value1".to_vec())
+        )
+        .wait()?);
+    assert!(store
+        .compare_and_swap(b"key".to_vec(), None, Some(b"value1".to_vec()))
+        .wait()?);
+    assert_eq!(store.get(b"key".to_vec()).wait()?, Some(b"value1".to_vec()));
+
+    assert!(!store
+        .compare_and_swap(
+            b"key".to_vec(),
+            Some(b"value".to_vec()),
+            Some(b"value2".to_vec())
+        )
+        .wait()?);
+    assert!(!store
+        .compare_and_swap(b"key".to_vec(), None, Some(b"value2".to_vec()))
+        .wait()?);
+    assert!(store
+        .compare_and_swap(
+            b"key".to_vec(),
+            Some(b"value1".to_vec()),
+            Some(b"value2".to_vec())
+        )
+        .wait()?);
+    assert_eq!(store.get(b"key".to_vec()).wait()?, Some(b"value2".to_vec()));
+
+    // a `None` new value removes the key
+    assert!(store
+        .compare_and_swap(b"key".to_vec(), Some(b"value2".to_vec()), None)
+        .wait()?);
+    assert_eq!(store.get(b"key".to_vec()).wait()?, None);
+
+    assert!(store
+        .set_if_absent(b"key".to_vec(), b"value3".to_vec())
+        .wait()?);
+    assert!(!store
+        .set_if_absent(b"key".to_vec(), b"value4".to_vec())
+        .wait()?);
+    assert_eq!(store.get(b"key".to_vec()).wait()?, Some(b"value3".to_vec()));
+
+    // an expired key is absent
+    store
+        .set_with_ttl(
+            b"ttl".to_vec(),
+            b"value".to_vec(),
+            Duration::from_millis(100),
+        )
+        .wait()?;
+    thread::sleep(Duration::from_millis(200));
+    assert!(!store
+        .compare_and_swap(b"ttl".to_vec(), Some(b"value".to_vec()), None)
+        .wait()?);
+    assert!(store
+        .set_if_absent(b"ttl".to_vec(), b"value1".to_vec())
+        .wait()?);
+    assert_eq!(store.get(b"ttl".to_vec()).wait()?, Some(b"value1".to_vec()));
+    Ok(())
+}
+
+// Values should only be swapped if the current value is the expected one
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_compare_and_swap(KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?)?;
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get(b"key".to_vec()).wait()?, Some(b"value3".to_vec()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = SledKvsEngine::<RayonThreadPool>::new(sled::open(temp_dir.path())?, 1)?;
+    check_compare_and_swap(store)
+}
+
+// Concurrent read-modify-write cycles with compare-and-swap should not lose updates
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_concurrent_compare_and_swap(KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?)
+}
+
+fn check_concurrent_compare_and_swap(store: KvStore<RayonThreadPool>) -> Result<()> {
+    store.set(b"counter".to_vec(), b"0".to_vec()).wait()?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    loop {
+                        let current = store.get(b"counter".to_vec()).wait()?.unwrap();
+                        let count: u32 = String::from_utf8(current.clone())?.parse().unwrap();
+                        let new = format!("{}", count + 1).into_bytes();
+                        if store
+                            .compare_and_swap(b"counter".to_vec(), Some(current), Some(new))
+                            .wait()?
+                        {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(
+        store.get(b"counter".to_vec()).wait()?,
+        Some(b"400".to_vec())
+    );
+    Ok(())
+}
+
+// Transactions should see their own writes and commit them atomically
+#[test]
+fn transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(b"from".to_vec(), b"value".to_vec()).wait()?;
+
+    store
+        .transaction(|txn| {
+            let value = txn.get(b"from".to_vec())?.unwrap();
+            txn.set(b"to".to_vec(), value);
+            txn.remove(b"from".to_vec())?;
+            assert_eq!(txn.get(b"from".to_vec())?, None);
+            assert_eq!(txn.get(b"to".to_vec())?, Some(b"value".to_vec()));
+            Ok(())
+        })
+        .wait()?;
+    assert_eq!(store.get(b"from".to_vec()).wait()?, None);
+    assert_eq!(store.get(b"to".to_vec()).wait()?, Some(b"value".to_vec()));
+
+    // an error aborts the transaction
+    let res = store
+        .transaction(|txn| {
+            txn.set(b"to".to_vec(), b"other".to_vec());
+            txn.remove(b"from".to_vec())
+        })
+        .wait();
+    match res {
+        Err(KvsError::KeyNotFound) => (),
+        _ => panic!("transaction is not aborted"),
+    }
+    assert_eq!(store.get(b"to".to_vec()).wait()?, Some(b"value".to_vec()));
+
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(store.get(b"from".to_vec()).wait()?, None);
+    assert_eq!(store.get(b"to".to_vec()).wait()?, Some(b"value".to_vec()));
+    Ok(())
+}
+
+// All reads of a transaction should see the store at the same point
+#[test]
+fn transaction_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 2)?;
+    store.set(b"a".to_vec(), b"1".to_vec()).wait()?;
+    store.set(b"b".to_vec(), b"1".to_vec()).wait()?;
+
+    let other = store.clone();
+    let attempts = Arc::new(Mutex::new(Vec::new()));
+    let seen = Arc::clone(&attempts);
+    store
+        .transaction(move |txn| {
+            let a = txn.get(b"a".to_vec())?;
+            if seen.lock().unwrap().is_empty() {
+                // both keys are changed after the first read of the first attempt
+                let mut batch = WriteBatch::new();
+                batch.set(b"a".to_vec(), b"2".to_vec());
+                batch.set(b"b".to_vec(), b"2".to_vec());
+                other.write_batch(batch).wait()?;
+            }
+            let b = txn.get(b"b".to_vec())?;
+            seen.lock().unwrap().push((a, b));
+            txn.set(b"c".to_vec(), b"1".to_vec());
+            Ok(())
+        })
+        .wait()?;
+
+    // the first attempt is retried because its reads have changed
+    let one = Some(b"1".to_vec());
+    let two = Some(b"2".to_vec());
+    assert_eq!(
+        *attempts.lock().unwrap(),
+        vec![(one.clone(), one.clone()), (two.clone(), two)]
+    );
+    assert_eq!(store.get(b"c".to_vec()).wait()?, one);
+    Ok(())
+}
+
+// Conflicting transactions should be retried so that no update is lost
+#[test]
+fn concurrent_transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 8)?;
+    store.set(b"a".to_vec(), b"1000".to_vec()).wait()?;
+    store.set(b"b".to_vec(), b"0".to_vec()).wait()?;
+
+    let parse = |value: Option<Vec<u8>>| -> u32 {
+        String::from_utf8(value.unwrap()).unwrap().parse().unwrap()
+    };
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..20 {
+                    // move one unit from `a` to `b`
+                    store
+                        .transaction(move |txn| {
+                            let a = parse(txn.get(b"a".to_vec())?);
+                            let b = parse(txn.get(b"b".to_vec())?);
+                            txn.set(b"a".to_vec(), format!("{}", a - 1).into_bytes());
+                            txn.set(b"b".to_vec(), format!("{}", b + 1).into_bytes());
+                            Ok(())
+                        })
+                        .wait()?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.get(b"a".to_vec()).wait()?, Some(b"840".to_vec()));
+    assert_eq!(store.get(b"b".to_vec()).wait()?, Some(b"160".to_vec()));
+    Ok(())
+}
+
+// Should list pairs in a key range in ascending key order
+#[test]
+fn scan_range() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for i in (0..10).rev() {
+        store
+            .set(
+                format!("key{}", i).into_bytes(),
+                format!("value{}", i).into_bytes(),
+            )
+            .wait()?;
+    }
+    store.remove(b"key4".to_vec()).wait()?;
+
+    let pairs = store
+        .scan(b"key2".to_vec(), Some(b"key6".to_vec()), None)
+        .collect()
+        .wait()?;
+    let expected: Vec<_> = [2, 3, 5]
+        .iter()
+        .map(|i| {
+            (
+                format!("key{}", i).into_bytes(),
+                format!("value{}", i).into_bytes(),
+            )
+        })
+        .collect();
+    assert_eq!(pairs, expected);
+
+    let pairs = store
+        .scan(b"key7".to_vec(), None, Some(2))
+        .collect()
+        .wait()?;
+    assert_eq!(
+        pairs,
+        vec![
+            (b"key7".to_vec(), b"value7".to_vec()),
+            (b"key8".to_vec(), b"value8".to_vec()),
+        ]
+    );
+
+    let pairs = store
+        .scan(b"key6".to_vec(), Some(b"key2".to_vec()), None)
+        .collect()
+        .wait()?;
+    assert!(pairs.is_empty());
+
+    // the keys are scanned without their values
+    store
+        .set_with_ttl(
+            b"key3".to_vec(),
+            b"value3".to_vec(),
+            Duration::from_millis(1),
+        )
+        .wait()?;
+    thread::sleep(Duration::from_millis(10));
+    let keys = store
+        .scan_keys(b"key2".to_vec(), Some(b"key7".to_vec()), Some(3))
+        .collect()
+        .wait()?;
+    assert_eq!(
+        keys,
+        vec![b"key2".to_vec(), b"key5".to_vec(), b"key6".to_vec()]
+    );
+
+    Ok(())
+}
+
+// Should list only pairs with the given key prefix
+#[test]
+fn scan_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(b"a".to_vec(), b"0".to_vec()).wait()?;
+    store.set(b"user:2".to_vec(), b"2".to_vec()).wait()?;
+    store.set(b"user:1".to_vec(), b"1".to_vec()).wait()?;
+    store.set(b"user;".to_vec(), b"3".to_vec()).wait()?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let pairs = store
+        .scan_prefix(b"user:".to_vec(), None)
+        .collect()
+        .wait()?;
+    assert_eq!(
+        pairs,
+        vec![
+            (b"user:1".to_vec(), b"1".to_vec()),
+            (b"user:2".to_vec(), b"2".to_vec()),
+        ]
+    );
+
+    let pairs = store
+        .scan_prefix(b"user:".to_vec(), Some(1))
+        .collect()
+        .wait()?;
+    assert_eq!(pairs, vec![(b"user:1".to_vec(), b"1".to_vec())]);
+    assert!(store
+        .scan_prefix(b"b".to_vec(), None)
+        .collect()
+        .wait()?
+        .is_empty());
+
+    Ok(())
+}
+
+fn check_scan_batches<E: KvsEngine>(store: E) -> Result<()> {
+    let mut batch = WriteBatch::new();
+    for i in 0..2500 {
+        batch.set(
+            format!("key{:04}", i).into_bytes(),
+            format!("{}", i).into_bytes(),
+        );
+    }
+    batch.set(b"other".to_vec(), b"value".to_vec());
+    store.write_batch(batch).wait()?;
+    store.remove(b"key1024".to_vec()).wait()?;
+    let keys = |pairs: Vec<(Vec<u8>, Vec<u8>)>| -> Vec<usize> {
+        pairs
+            .into_iter()
+            .map(|(key, value)| {
+                let i = String::from_utf8(value).unwrap().parse().unwrap();
+                assert_eq!(key, format!("key{:04}", i).into_bytes());
+                i
+            })
+            .collect()
+    };
+    let all: Vec<_> = (0..2500).filter(|&i| i != 1024).collect();
+
+    let pairs = store.scan_prefix(b"key".to_vec(), None).collect().wait()?;
+    assert_eq!(keys(pairs), all);
+    let pairs = store
+        .scan(b"key0010".to_vec(), Some(b"key2400".to_vec()), Some(2000))
+        .collect()
+        .wait()?;
+    assert_eq!(keys(pairs), all[10..2010]);
+    let pairs = store
+        .scan(b"key1000".to_vec(), None, None)
+        .collect()
+        .wait()?;
+    assert_eq!(pairs.len(), 1500);
+    assert_eq!(pairs.last().unwrap().0, b"other".to_vec());
     Ok(())
 }
 
+// Scans over more pairs than are read at a time should yield every pair once
+#[test]
+fn scan_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scan_batches(KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_scan_batches(SledKvsEngine::<RayonThreadPool>::new(
+        sled::open(temp_dir.path())?,
+        1,
+    )?)
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -127,8 +892,8 @@ fn compaction() -> Result<()> {
     let mut current_size = dir_size();
     for iter in 0..1000 {
         for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            let value = format!("{}", iter);
+            let key = format!("key{}", key_id).into_bytes();
+            let value = format!("{}", iter).into_bytes();
             store.set(key, value).wait()?;
         }
 
@@ -143,8 +908,11 @@ fn compaction() -> Result<()> {
         // reopen and check content
         let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
         for key_id in 0..1000 {
-            let key = format!("key{}", key_id);
-            assert_eq!(store.get(key).wait()?, Some(format!("{}", iter)));
+            let key = format!("key{}", key_id).into_bytes();
+            assert_eq!(
+                store.get(key).wait()?,
+                Some(format!("{}", iter).into_bytes())
+            );
         }
         return Ok(());
     }
@@ -152,8 +920,207 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+fn open_with_compaction(
+    temp_dir: &TempDir,
+    concurrency: u32,
+    options: KvStoreOptions,
+) -> Result<KvStore<RayonThreadPool>> {
+    KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), concurrency, options)
+}
+
+// Compaction should start after the configured number of stale bytes
 #[test]
-fn concurrent_set() -> Result<()> {
+fn compaction_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(1024);
+    let store = open_with_compaction(&temp_dir, 1, options.clone())?;
+    for iter in 0..100 {
+        store
+            .set(b"key".to_vec(), format!("{}", iter).into_bytes())
+            .wait()?;
+    }
+    // dropping the store waits for the background compaction
+    drop(store);
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    let store = open_with_compaction(&temp_dir, 1, options)?;
+    assert_eq!(store.get(b"key".to_vec()).wait()?, Some(b"99".to_vec()));
+    Ok(())
+}
+
+// Compaction should write a hint file that is used when the store is reopened
+#[test]
+fn compaction_hint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(1024);
+    let store = open_with_compaction(&temp_dir, 1, options.clone())?;
+    for iter in 0..10 {
+        for key_id in 0..10 {
+            store
+                .set(
+                    format!("key{}", key_id).into_bytes(),
+                    format!("{}", iter).into_bytes(),
+                )
+                .wait()?;
+        }
+    }
+    drop(store);
+
+    let hint = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension() == Some("hint".as_ref()))
+        .expect("no hint file");
+    let check = || -> Result<()> {
+        let store = open_with_compaction(&temp_dir, 1, options.clone())?;
+        for key_id in 0..10 {
+            assert_eq!(
+                store.get(format!("key{}", key_id).into_bytes()).wait()?,
+                Some(b"9".to_vec())
+            );
+        }
+        Ok(())
+    };
+    check()?;
+
+    // A corrupted hint file is ignored
+    let mut content = fs::read(&hint)?;
+    content[20] ^= 0xff;
+    fs::write(&hint, &content)?;
+    check()?;
+
+    // So is a missing one
+    fs::remove_file(&hint)?;
+    check()?;
+
+    Ok(())
+}
+
+// A compaction file cut off by a crash should be discarded on open
+#[test]
+fn torn_compaction_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for key_id in 0..10 {
+        store
+            .set(
+                format!("key{}", key_id).into_bytes(),
+                format!("value{}", key_id).into_bytes(),
+            )
+            .wait()?;
+    }
+    drop(store);
+
+    // A crash during a compaction of 1.log leaves the compaction file cut off in the
+    // middle of a record, and a newer write in 3.log
+    let content = fs::read(temp_dir.path().join("1.log"))?;
+    let partial = temp_dir.path().join("2.log.partial");
+    fs::write(&partial, &content[..content.len() - 5])?;
+    let other_dir = TempDir::new().expect("unable to create temporary working directory");
+    let other = KvStore::<RayonThreadPool>::open(other_dir.path(), 1)?;
+    other.set(b"key0".to_vec(), b"value".to_vec()).wait()?;
+    drop(other);
+    fs::copy(
+        other_dir.path().join("1.log"),
+        temp_dir.path().join("3.log"),
+    )?;
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert!(!partial.exists());
+    assert_eq!(store.get(b"key0".to_vec()).wait()?, Some(b"value".to_vec()));
+    for key_id in 1..10 {
+        assert_eq!(
+            store.get(format!("key{}", key_id).into_bytes()).wait()?,
+            Some(format!("value{}", key_id).into_bytes())
+        );
+    }
+    Ok(())
+}
+
+// Compaction should wait until stale commands make up the garbage ratio
+#[test]
+fn compaction_garbage_ratio() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(0)
+        .garbage_ratio(0.5);
+    let store = open_with_compaction(&temp_dir, 1, options.clone())?;
+    for key_id in 0..100 {
+        store
+            .set(format!("key{}", key_id).into_bytes(), b"0".to_vec())
+            .wait()?;
+    }
+    store.set(b"key0".to_vec(), b"1".to_vec()).wait()?;
+    drop(store);
+    assert!(temp_dir.path().join("1.log").exists());
+
+    let store = open_with_compaction(&temp_dir, 1, options.clone())?;
+    for iter in 1..3 {
+        for key_id in 0..100 {
+            store
+                .set(
+                    format!("key{}", key_id).into_bytes(),
+                    format!("{}", iter).into_bytes(),
+                )
+                .wait()?;
+        }
+    }
+    drop(store);
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    let store = open_with_compaction(&temp_dir, 1, options)?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id).into_bytes()).wait()?,
+            Some(b"2".to_vec())
+        );
+    }
+    Ok(())
+}
+
+// Writes during a background compaction should not be lost
+#[test]
+fn concurrent_set_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(4096);
+    let store = open_with_compaction(&temp_dir, 8, options.clone())?;
+    for iter in 0..10 {
+        let runtime = Runtime::new()?;
+        let executor = runtime.executor();
+        let writer = store.clone();
+        runtime.block_on_all(future::lazy(move || {
+            for key_id in 0..100 {
+                executor.spawn(
+                    writer
+                        .set(
+                            format!("key{}", key_id).into_bytes(),
+                            format!("{}", iter).into_bytes(),
+                        )
+                        .map_err(|_| ()),
+                );
+            }
+            future::ok::<(), KvsError>(())
+        }))?;
+    }
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id).into_bytes()).wait()?,
+            Some(b"9".to_vec())
+        );
+    }
+    drop(store);
+
+    let store = open_with_compaction(&temp_dir, 1, options)?;
+    for key_id in 0..100 {
+        assert_eq!(
+            store.get(format!("key{}", key_id).into_bytes()).wait()?,
+            Some(b"9".to_vec())
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
 
     // concurrent set in 8 threads
@@ -164,7 +1131,10 @@ fn concurrent_set() -> Result<()> {
         for i in 0..10000 {
             executor.spawn(
                 store
-                    .set(format!("key{}", i), format!("value{}", i))
+                    .set(
+                        format!("key{}", i).into_bytes(),
+                        format!("value{}", i).into_bytes(),
+                    )
                     .map_err(|_| ()),
             );
         }
@@ -175,8 +1145,8 @@ fn concurrent_set() -> Result<()> {
     let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
     for i in 0..10000 {
         assert_eq!(
-            store.get(format!("key{}", i)).wait()?,
-            Some(format!("value{}", i))
+            store.get(format!("key{}", i).into_bytes()).wait()?,
+            Some(format!("value{}", i).into_bytes())
         );
     }
 
@@ -190,7 +1160,10 @@ fn concurrent_get() -> Result<()> {
     // We only check concurrent get in this test, so we set sequentially here
     for i in 0..100 {
         store
-            .set(format!("key{}", i), format!("value{}", i))
+            .set(
+                format!("key{}", i).into_bytes(),
+                format!("value{}", i).into_bytes(),
+            )
             .wait()
             .unwrap();
     }
@@ -203,9 +1176,9 @@ fn concurrent_get() -> Result<()> {
                 let key_id = (i + thread_id) % 100;
                 executor.spawn(
                     store
-                        .get(format!("key{}", key_id))
+                        .get(format!("key{}", key_id).into_bytes())
                         .map(move |res| {
-                            assert_eq!(res, Some(format!("value{}", key_id)));
+                            assert_eq!(res, Some(format!("value{}", key_id).into_bytes()));
                         })
                         .map_err(|_| ()),
                 );
@@ -224,9 +1197,9 @@ fn concurrent_get() -> Result<()> {
                 let key_id = (i + thread_id) % 100;
                 executor.spawn(
                     store
-                        .get(format!("key{}", key_id))
+                        .get(format!("key{}", key_id).into_bytes())
                         .map(move |res| {
-                            assert_eq!(res, Some(format!("value{}", key_id)));
+                            assert_eq!(res, Some(format!("value{}", key_id).into_bytes()));
                         })
                         .map_err(|_| ()),
                 );
@@ -237,3 +1210,1078 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+fn open_with_sync_policy(
+    temp_dir: &TempDir,
+    concurrency: u32,
+    sync_policy: SyncPolicy,
+) -> Result<KvStore<RayonThreadPool>> {
+    let options = KvStoreOptions::new().sync_policy(sync_policy);
+    KvStore::<RayonThreadPool>::open_with_options(temp_dir.path(), concurrency, options)
+}
+
+// Writes are never synced explicitly by default
+#[test]
+fn sync_never() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_with_sync_policy(&temp_dir, 1, SyncPolicy::Never)?;
+    for i in 0..100 {
+        store
+            .set(
+                format!("key{}", i).into_bytes(),
+                format!("value{}", i).into_bytes(),
+            )
+            .wait()?;
+    }
+    assert_eq!(store.sync_count(), 0);
+    Ok(())
+}
+
+// Every write should be synced on its own
+#[test]
+fn sync_always() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_with_sync_policy(&temp_dir, 1, SyncPolicy::Always)?;
+    for i in 0..100 {
+        store
+            .set(
+                format!("key{}", i).into_bytes(),
+                format!("value{}", i).into_bytes(),
+            )
+            .wait()?;
+    }
+    store.remove(b"key0".to_vec()).wait()?;
+    assert_eq!(store.sync_count(), 101);
+
+    drop(store);
+    let store = open_with_sync_policy(&temp_dir, 1, SyncPolicy::Always)?;
+    assert_eq!(store.get(b"key0".to_vec()).wait()?, None);
+    assert_eq!(
+        store.get(b"key99".to_vec()).wait()?,
+        Some(b"value99".to_vec())
+    );
+    Ok(())
+}
+
+// Writes should be synced in the background instead of one by one
+#[test]
+fn sync_interval() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let interval = Duration::from_millis(50);
+    let store = open_with_sync_policy(&temp_dir, 1, SyncPolicy::Interval(interval))?;
+    for i in 0..100 {
+        store
+            .set(
+                format!("key{}", i).into_bytes(),
+                format!("value{}", i).into_bytes(),
+            )
+            .wait()?;
+    }
+    thread::sleep(interval * 3);
+    let sync_count = store.sync_count();
+    assert!(sync_count >= 1);
+    assert!(sync_count < 100);
+
+    // No more syncs without new writes
+    thread::sleep(interval * 3);
+    assert_eq!(store.sync_count(), sync_count);
+    Ok(())
+}
+
+// Concurrent writers should share syncs
+#[test]
+fn sync_group_commit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open_with_sync_policy(&temp_dir, 8, SyncPolicy::GroupCommit)?;
+    let runtime = Runtime::new()?;
+    let executor = runtime.executor();
+    let writer = store.clone();
+    runtime.block_on_all(future::lazy(move || {
+        for i in 0..1000 {
+            executor.spawn(
+                writer
+                    .set(
+                        format!("key{}", i).into_bytes(),
+                        format!("value{}", i).into_bytes(),
+                    )
+                    .map_err(|_| ()),
+            );
+        }
+        future::ok::<(), KvsError>(())
+    }))?;
+    let sync_count = store.sync_count();
+    assert!(sync_count >= 1);
+    assert!(sync_count <= 1000);
+
+    // Sequential writes are synced one by one
+    store.set(b"key".to_vec(), b"value".to_vec()).wait()?;
+    assert_eq!(store.sync_count(), sync_count + 1);
+
+    drop(store);
+    let store = open_with_sync_policy(&temp_dir, 1, SyncPolicy::GroupCommit)?;
+    for i in 0..1000 {
+        assert_eq!(
+            store.get(format!("key{}", i).into_bytes()).wait()?,
+            Some(format!("value{}", i).into_bytes())
+        );
+    }
+    Ok(())
+}
+
+// A snapshot should not see the writes after it
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(b"key1".to_vec(), b"value1".to_vec()).wait()?;
+    store.set(b"key2".to_vec(), b"value2".to_vec()).wait()?;
+
+    let snapshot = store.snapshot();
+    store.set(b"key1".to_vec(), b"value3".to_vec()).wait()?;
+    store.remove(b"key2".to_vec()).wait()?;
+    let mut batch = WriteBatch::new();
+    batch.set(b"key1".to_vec(), b"value4".to_vec());
+    batch.set(b"key3".to_vec(), b"value3".to_vec());
+    store.write_batch(batch).wait()?;
+
+    assert_eq!(
+        snapshot.get(b"key1".to_vec()).wait()?,
+        Some(b"value1".to_vec())
+    );
+    assert_eq!(
+        snapshot.get(b"key2".to_vec()).wait()?,
+        Some(b"value2".to_vec())
+    );
+    assert_eq!(snapshot.get(b"key3".to_vec()).wait()?, None);
+    assert_eq!(
+        snapshot
+            .scan(b"key".to_vec(), None, None)
+            .collect()
+            .wait()?,
+        vec![
+            (b"key1".to_vec(), b"value1".to_vec()),
+            (b"key2".to_vec(), b"value2".to_vec()),
+        ]
+    );
+    assert_eq!(
+        snapshot
+            .scan_prefix(b"key2".to_vec(), None)
+            .collect()
+            .wait()?,
+        vec![(b"key2".to_vec(), b"value2".to_vec())]
+    );
+
+    // the store sees the latest writes
+    assert_eq!(
+        store.get(b"key1".to_vec()).wait()?,
+        Some(b"value4".to_vec())
+    );
+    assert_eq!(store.get(b"key2".to_vec()).wait()?, None);
+
+    // a later snapshot sees the writes before it
+    let later = store.snapshot();
+    assert!(later.seq() > snapshot.seq());
+    drop(snapshot);
+    store.set(b"key3".to_vec(), b"value5".to_vec()).wait()?;
+    assert_eq!(
+        later.get(b"key1".to_vec()).wait()?,
+        Some(b"value4".to_vec())
+    );
+    assert_eq!(
+        later.get(b"key3".to_vec()).wait()?,
+        Some(b"value3".to_vec())
+    );
+    Ok(())
+}
+
+// Compaction should keep the log files a snapshot can read until it is dropped
+#[test]
+fn snapshot_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(1024);
+    let store = open_with_compaction(&temp_dir, 1, options)?;
+    store.set(b"key".to_vec(), b"value".to_vec()).wait()?;
+
+    let snapshot = store.snapshot();
+    for iter in 0..100 {
+        store
+            .set(b"key".to_vec(), format!("{}", iter).into_bytes())
+            .wait()?;
+    }
+    // wait for the background compaction
+    thread::sleep(Duration::from_millis(500));
+    assert!(temp_dir.path().join("1.log").exists());
+    assert_eq!(
+        snapshot.get(b"key".to_vec()).wait()?,
+        Some(b"value".to_vec())
+    );
+
+    drop(snapshot);
+    // dropping the store waits for the background compaction
+    drop(store);
+    assert!(!temp_dir.path().join("1.log").exists());
+    Ok(())
+}
+
+// A backup should contain the data at the time it started
+#[test]
+fn backup_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    for key_id in 0..100 {
+        store
+            .set(format!("key{}", key_id).into_bytes(), b"value".to_vec())
+            .wait()?;
+    }
+    store.remove(b"key0".to_vec()).wait()?;
+    store
+        .set_with_ttl(b"key1".to_vec(), b"ttl".to_vec(), Duration::from_secs(3600))
+        .wait()?;
+
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_path = backup_dir.path().join("backup");
+    store.backup_to(backup_path.clone()).wait()?;
+    store.set(b"key2".to_vec(), b"new".to_vec()).wait()?;
+    // the backup directory must not exist
+    assert!(store.backup_to(backup_path.clone()).wait().is_err());
+
+    let restored_dir = TempDir::new().expect("unable to create temporary working directory");
+    KvStore::<RayonThreadPool>::restore(&backup_path, restored_dir.path())?;
+    let restored = KvStore::<RayonThreadPool>::open(restored_dir.path(), 1)?;
+    assert_eq!(restored.get(b"key0".to_vec()).wait()?, None);
+    assert_eq!(
+        restored.get(b"key1".to_vec()).wait()?,
+        Some(b"ttl".to_vec())
+    );
+    assert_eq!(
+        restored.get(b"key2".to_vec()).wait()?,
+        Some(b"value".to_vec())
+    );
+    assert_eq!(
+        restored
+            .scan(Vec::new(), None, None)
+            .collect()
+            .wait()?
+            .len(),
+        99
+    );
+
+    // the data directory must be empty
+    drop(restored);
+    assert!(KvStore::<RayonThreadPool>::restore(&backup_path, restored_dir.path()).is_err());
+    Ok(())
+}
+
+// A damaged backup should not be restored
+#[test]
+fn restore_corrupted_backup() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(b"key".to_vec(), b"value".to_vec()).wait()?;
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_path = backup_dir.path().join("backup");
+    store.backup_to(backup_path.clone()).wait()?;
+
+    let log_path = backup_path.join("1.log");
+    let mut log = fs::read(&log_path)?;
+    let last = log.len() - 1;
+    log[last] ^= 0xff;
+    fs::write(&log_path, log)?;
+
+    let restored_dir = TempDir::new().expect("unable to create temporary working directory");
+    match KvStore::<RayonThreadPool>::restore(&backup_path, restored_dir.path()) {
+        Err(KvsError::CorruptedLog(_)) => (),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert!(!restored_dir.path().join("1.log").exists());
+    Ok(())
+}
+
+#[test]
+fn sled_backup_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::<RayonThreadPool>::new(sled::open(temp_dir.path())?, 1)?;
+    engine.set(b"key1".to_vec(), b"value1".to_vec()).wait()?;
+    engine
+        .set_with_ttl(
+            b"key2".to_vec(),
+            b"value2".to_vec(),
+            Duration::from_secs(3600),
+        )
+        .wait()?;
+
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_path = backup_dir.path().join("backup");
+    engine.backup_to(backup_path.clone()).wait()?;
+    engine.set(b"key1".to_vec(), b"new".to_vec()).wait()?;
+
+    let restored_dir = TempDir::new().expect("unable to create temporary working directory");
+    SledKvsEngine::<RayonThreadPool>::restore(&backup_path, restored_dir.path())?;
+    let restored = SledKvsEngine::<RayonThreadPool>::new(sled::open(restored_dir.path())?, 1)?;
+    assert_eq!(
+        restored.get(b"key1".to_vec()).wait()?,
+        Some(b"value1".to_vec())
+    );
+    assert_eq!(
+        restored.get(b"key2".to_vec()).wait()?,
+        Some(b"value2".to_vec())
+    );
+    drop(restored);
+
+    // the database must be empty
+    assert!(SledKvsEngine::<RayonThreadPool>::restore(&backup_path, restored_dir.path()).is_err());
+    Ok(())
+}
+
+// A sled backup taken while writes go on should be consistent
+#[test]
+fn sled_backup_during_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::<RayonThreadPool>::new(sled::open(temp_dir.path())?, 2)?;
+    engine.set(b"a".to_vec(), b"100".to_vec()).wait()?;
+    engine.set(b"b".to_vec(), b"0".to_vec()).wait()?;
+
+    // every batch moves one unit from `a` to `b`
+    let writer = engine.clone();
+    let handle = thread::spawn(move || -> Result<()> {
+        for i in 1..=100 {
+            let mut batch = WriteBatch::new();
+            batch.set(b"a".to_vec(), format!("{}", 100 - i).into_bytes());
+            batch.set(b"b".to_vec(), format!("{}", i).into_bytes());
+            writer.write_batch(batch).wait()?;
+        }
+        Ok(())
+    });
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_path = backup_dir.path().join("backup");
+    engine.backup_to(backup_path.clone()).wait()?;
+    handle.join().unwrap()?;
+
+    let restored_dir = TempDir::new().expect("unable to create temporary working directory");
+    SledKvsEngine::<RayonThreadPool>::restore(&backup_path, restored_dir.path())?;
+    let restored = SledKvsEngine::<RayonThreadPool>::new(sled::open(restored_dir.path())?, 1)?;
+    let parse = |value: Option<Vec<u8>>| -> u32 {
+        String::from_utf8(value.unwrap()).unwrap().parse().unwrap()
+    };
+    let a = parse(restored.get(b"a".to_vec()).wait()?);
+    let b = parse(restored.get(b"b".to_vec()).wait()?);
+    assert_eq!(a + b, 100);
+    Ok(())
+}
+
+// Values read by `get` should be served from the cache until the key changes
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().cache_capacity(1024 * 1024);
+    let store = open_with_compaction(&temp_dir, 1, options)?;
+    store.set(b"key1".to_vec(), b"value1".to_vec()).wait()?;
+
+    assert_eq!(
+        store.get(b"key1".to_vec()).wait()?,
+        Some(b"value1".to_vec())
+    );
+    assert_eq!(
+        store.get(b"key1".to_vec()).wait()?,
+        Some(b"value1".to_vec())
+    );
+    let stats = store.stats();
+    assert_eq!((stats.cache_hits, stats.cache_misses), (1, 1));
+    assert_eq!(stats.cache_size, 10);
+
+    store.set(b"key1".to_vec(), b"value2".to_vec()).wait()?;
+    assert_eq!(
+        store.get(b"key1".to_vec()).wait()?,
+        Some(b"value2".to_vec())
+    );
+    let mut batch = WriteBatch::new();
+    batch.set(b"key1".to_vec(), b"value3".to_vec());
+    store.write_batch(batch).wait()?;
+    assert_eq!(
+        store.get(b"key1".to_vec()).wait()?,
+        Some(b"value3".to_vec())
+    );
+    store.remove(b"key1".to_vec()).wait()?;
+    assert_eq!(store.get(b"key1".to_vec()).wait()?, None);
+    let stats = store.stats();
+    assert_eq!((stats.cache_hits, stats.cache_misses), (1, 3));
+    assert_eq!(stats.cache_size, 0);
+    Ok(())
+}
+
+// Cached values should stay valid after a compaction moves their records
+#[test]
+fn value_cache_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(1024)
+        .cache_capacity(1024 * 1024);
+    let store = open_with_compaction(&temp_dir, 1, options)?;
+    store.set(b"key".to_vec(), b"value".to_vec()).wait()?;
+    assert_eq!(store.get(b"key".to_vec()).wait()?, Some(b"value".to_vec()));
+    for iter in 0..100 {
+        store
+            .set(b"other".to_vec(), format!("{}", iter).into_bytes())
+            .wait()?;
+    }
+    // wait for the background compaction
+    thread::sleep(Duration::from_millis(500));
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    assert_eq!(store.get(b"key".to_vec()).wait()?, Some(b"value".to_vec()));
+    assert_eq!(store.stats().cache_hits, 1);
+    Ok(())
+}
+
+// The cache should not grow beyond its capacity
+#[test]
+fn value_cache_capacity() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().cache_capacity(16 * 1024);
+    let store = open_with_compaction(&temp_dir, 1, options)?;
+    let value = vec![0; 100];
+    for key_id in 0..1000 {
+        let key = format!("key{}", key_id).into_bytes();
+        store.set(key.clone(), value.clone()).wait()?;
+        assert_eq!(store.get(key).wait()?, Some(value.clone()));
+    }
+    let stats = store.stats();
+    assert!(stats.cache_size > 0);
+    assert!(stats.cache_size <= 16 * 1024);
+
+    // recently read keys are kept
+    assert_eq!(store.get(b"key999".to_vec()).wait()?, Some(value));
+    assert_eq!(store.stats().cache_hits, 1);
+
+    // the cache is disabled by default
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(b"key".to_vec(), b"value".to_vec()).wait()?;
+    store.get(b"key".to_vec()).wait()?;
+    assert_eq!(store.stats(), EngineStats::default());
+    Ok(())
+}
+
+fn log_size(temp_dir: &TempDir) -> u64 {
+    fs::read_dir(temp_dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("log".as_ref()))
+        .map(|path| fs::metadata(path).unwrap().len())
+        .sum()
+}
+
+// Compressed values should be readable with any compression setting
+#[test]
+fn compression() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let document = r#"{"name": "kvs", "tags": ["key", "value", "store"]}"#.repeat(100);
+    let document = document.into_bytes();
+
+    let options = KvStoreOptions::new().compression(Compression::Lz4);
+    let store = open_with_compaction(&temp_dir, 1, options)?;
+    store.set(b"lz4".to_vec(), document.clone()).wait()?;
+    assert!(log_size(&temp_dir) < document.len() as u64 / 2);
+    // small values are stored raw
+    store.set(b"small".to_vec(), b"value".to_vec()).wait()?;
+    assert_eq!(store.get(b"lz4".to_vec()).wait()?, Some(document.clone()));
+    drop(store);
+
+    let options = KvStoreOptions::new().compression(Compression::Zstd);
+    let store = open_with_compaction(&temp_dir, 1, options)?;
+    store
+        .set_with_ttl(
+            b"zstd".to_vec(),
+            document.clone(),
+            Duration::from_secs(3600),
+        )
+        .wait()?;
+    drop(store);
+
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(b"raw".to_vec(), document.clone()).wait()?;
+    for key in &[&b"lz4"[..], b"zstd", b"raw"] {
+        assert_eq!(store.get(key.to_vec()).wait()?, Some(document.clone()));
+    }
+    assert_eq!(
+        store.get(b"small".to_vec()).wait()?,
+        Some(b"value".to_vec())
+    );
+    Ok(())
+}
+
+// Compressed records should survive a compaction
+#[test]
+fn compression_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compression(Compression::Zstd)
+        .compression_threshold(0)
+        .compaction_threshold(1024);
+    let store = open_with_compaction(&temp_dir, 1, options.clone())?;
+    for iter in 0..1000 {
+        let value = format!("value{}", iter).repeat(10).into_bytes();
+        store
+            .set(format!("key{}", iter % 10).into_bytes(), value)
+            .wait()?;
+    }
+    drop(store);
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    let store = open_with_compaction(&temp_dir, 1, options)?;
+    for key_id in 0..10 {
+        let value = format!("value{}", 990 + key_id).repeat(10).into_bytes();
+        assert_eq!(
+            store.get(format!("key{}", key_id).into_bytes()).wait()?,
+            Some(value)
+        );
+    }
+    Ok(())
+}
+
+fn index_file(temp_dir: &TempDir) -> Option<std::path::PathBuf> {
+    fs::read_dir(temp_dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension() == Some("index".as_ref()))
+}
+
+// The disk index should be rebuilt by compactions and give the same results as
+// the memory index
+#[test]
+fn disk_index() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .index_backend(IndexBackend::Disk)
+        .index_memory_limit(100);
+    let store = open_with_compaction(&temp_dir, 1, options.clone())?;
+    for key_id in 0..1000 {
+        let key = format!("key{:04}", key_id).into_bytes();
+        store.set(key, format!("{}", key_id).into_bytes()).wait()?;
+    }
+    drop(store);
+    assert!(index_file(&temp_dir).is_some());
+
+    let store = open_with_compaction(&temp_dir, 1, options.clone())?;
+    for key_id in (0..1000).step_by(3) {
+        store
+            .remove(format!("key{:04}", key_id).into_bytes())
+            .wait()?;
+    }
+    let snapshot = store.snapshot();
+    for key_id in (0..1000).step_by(5) {
+        let key = format!("key{:04}", key_id).into_bytes();
+        store.set(key, b"new".to_vec()).wait()?;
+    }
+    assert_eq!(
+        snapshot.get(b"key0005".to_vec()).wait()?,
+        Some(b"5".to_vec())
+    );
+    drop(snapshot);
+    drop(store);
+
+    let expected = |key_id: usize| -> Option<(Vec<u8>, Vec<u8>)> {
+        let value = if key_id.is_multiple_of(5) {
+            b"new".to_vec()
+        } else if key_id.is_multiple_of(3) {
+            return None;
+        } else {
+            format!("{}", key_id).into_bytes()
+        };
+        Some((format!("key{:04}", key_id).into_bytes(), value))
+    };
+    for backend in &[IndexBackend::Disk, IndexBackend::Memory] {
+        let store = open_with_compaction(&temp_dir, 1, options.clone().index_backend(*backend))?;
+        for key_id in 0..1000 {
+            let key = format!("key{:04}", key_id).into_bytes();
+            let value = expected(key_id).map(|(_, value)| value);
+            assert_eq!(store.get(key).wait()?, value);
+        }
+        let pairs: Vec<_> = store
+            .scan(b"key0100".to_vec(), Some(b"key0200".to_vec()), None)
+            .collect()
+            .wait()?;
+        assert_eq!(pairs, (100..200).filter_map(expected).collect::<Vec<_>>());
+        let pairs: Vec<_> = store
+            .scan_prefix(b"key09".to_vec(), Some(10))
+            .collect()
+            .wait()?;
+        assert_eq!(
+            pairs,
+            (900..).filter_map(expected).take(10).collect::<Vec<_>>()
+        );
+    }
+    Ok(())
+}
+
+// Reads of the disk index should not miss a key while it is overwritten
+#[test]
+fn disk_index_concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().index_backend(IndexBackend::Disk);
+    check_concurrent_compare_and_swap(open_with_compaction(&temp_dir, 8, options)?)
+}
+
+// An index file with a damaged page table should be ignored and the log replayed
+#[test]
+fn disk_index_corrupted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .index_backend(IndexBackend::Disk)
+        .compaction_threshold(1024);
+    let store = open_with_compaction(&temp_dir, 1, options.clone())?;
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store
+                .set(
+                    format!("key{}", key_id).into_bytes(),
+                    format!("{}", iter).into_bytes(),
+                )
+                .wait()?;
+        }
+    }
+    drop(store);
+
+    let index = index_file(&temp_dir).expect("no index file");
+    let check = || -> Result<()> {
+        let store = open_with_compaction(&temp_dir, 1, options.clone())?;
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id).into_bytes()).wait()?,
+                Some(b"9".to_vec())
+            );
+        }
+        Ok(())
+    };
+    check()?;
+
+    let mut content = fs::read(&index)?;
+    let len = content.len();
+    content[len - 10] ^= 0xff;
+    fs::write(&index, &content)?;
+    check()?;
+
+    fs::remove_file(&index)?;
+    check()?;
+    Ok(())
+}
+
+fn open_lsm(temp_dir: &TempDir) -> Result<LsmKvsEngine<RayonThreadPool>> {
+    // tiny memtables and levels, so a few thousand writes span several levels
+    let options = LsmOptions::new()
+        .memtable_size(4 * 1024)
+        .table_size(4 * 1024)
+        .level0_limit(2)
+        .level_size(16 * 1024);
+    LsmKvsEngine::open_with_options(temp_dir.path(), 2, options)
+}
+
+fn lsm_files(temp_dir: &TempDir, extension: &str) -> usize {
+    fs::read_dir(temp_dir.path())
+        .unwrap()
+        .filter(|entry| entry.as_ref().unwrap().path().extension() == Some(extension.as_ref()))
+        .count()
+}
+
+// The LSM engine should support the operations of the other engines
+#[test]
+fn lsm_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_compare_and_swap(LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?)?;
+    let engine = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        engine.get(b"key".to_vec()).wait()?,
+        Some(b"value3".to_vec())
+    );
+
+    let mut batch = WriteBatch::new();
+    batch.set(b"key1".to_vec(), b"value1".to_vec());
+    batch.set(b"key2".to_vec(), b"value2".to_vec());
+    batch.remove(b"key".to_vec());
+    engine.write_batch(batch).wait()?;
+    let reads = vec![(b"key1".to_vec(), Some(b"other".to_vec()))];
+    let mut batch = WriteBatch::new();
+    batch.remove(b"key1".to_vec());
+    assert!(!engine.commit_transaction(reads, batch.clone()).wait()?);
+    let reads = vec![(b"key1".to_vec(), Some(b"value1".to_vec()))];
+    assert!(engine.commit_transaction(reads, batch).wait()?);
+    match engine.remove(b"key1".to_vec()).wait() {
+        Err(KvsError::KeyNotFound) => (),
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    drop(engine);
+    let engine = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let pairs = engine.scan(Vec::new(), None, None).collect().wait()?;
+    assert_eq!(
+        pairs,
+        vec![
+            (b"key2".to_vec(), b"value2".to_vec()),
+            (b"ttl".to_vec(), b"value1".to_vec()),
+        ]
+    );
+    Ok(())
+}
+
+// Flushes and compactions should keep the latest value of every key
+#[test]
+fn lsm_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open_lsm(&temp_dir)?;
+    for iter in 0..5 {
+        for key_id in 0..1000 {
+            engine
+                .set(
+                    format!("key{:04}", key_id).into_bytes(),
+                    format!("value{}", iter).into_bytes(),
+                )
+                .wait()?;
+        }
+    }
+    for key_id in (0..1000).step_by(3) {
+        engine
+            .remove(format!("key{:04}", key_id).into_bytes())
+            .wait()?;
+    }
+    engine
+        .set_with_ttl(
+            b"key0001".to_vec(),
+            b"ttl".to_vec(),
+            Duration::from_millis(100),
+        )
+        .wait()?;
+    thread::sleep(Duration::from_millis(200));
+
+    let expected = |key_id: usize| -> Option<(Vec<u8>, Vec<u8>)> {
+        if key_id.is_multiple_of(3) || key_id == 1 {
+            return None;
+        }
+        Some((format!("key{:04}", key_id).into_bytes(), b"value4".to_vec()))
+    };
+    let check = |engine: &LsmKvsEngine<RayonThreadPool>| -> Result<()> {
+        for key_id in 0..1000 {
+            let key = format!("key{:04}", key_id).into_bytes();
+            let value = expected(key_id).map(|(_, value)| value);
+            assert_eq!(engine.get(key).wait()?, value);
+        }
+        let pairs: Vec<_> = engine
+            .scan(b"key0100".to_vec(), Some(b"key0200".to_vec()), None)
+            .collect()
+            .wait()?;
+        assert_eq!(pairs, (100..200).filter_map(expected).collect::<Vec<_>>());
+        let pairs: Vec<_> = engine
+            .scan_prefix(b"key09".to_vec(), Some(10))
+            .collect()
+            .wait()?;
+        assert_eq!(
+            pairs,
+            (900..).filter_map(expected).take(10).collect::<Vec<_>>()
+        );
+        Ok(())
+    };
+    check(&engine)?;
+    drop(engine);
+
+    // the tables are spread over several levels
+    let manifest = fs::read(temp_dir.path().join("MANIFEST"))?;
+    let manifest: serde_json::Value = serde_json::from_slice(&manifest)?;
+    let levels = manifest["levels"].as_array().unwrap();
+    assert!(levels.len() > 2);
+    assert!(levels[0].as_array().unwrap().len() < 2);
+    let tables = levels
+        .iter()
+        .map(|level| level.as_array().unwrap().len())
+        .sum::<usize>();
+    assert_eq!(lsm_files(&temp_dir, "sst"), tables);
+    assert_eq!(lsm_files(&temp_dir, "wal"), 1);
+
+    let engine = open_lsm(&temp_dir)?;
+    check(&engine)
+}
+
+// A write cut off at the end of the write-ahead log should be ignored
+#[test]
+fn lsm_torn_wal() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    engine.set(b"key1".to_vec(), b"value1".to_vec()).wait()?;
+    let mut batch = WriteBatch::new();
+    batch.set(b"key2".to_vec(), b"value2".to_vec());
+    batch.set(b"key3".to_vec(), b"value3".to_vec());
+    engine.write_batch(batch).wait()?;
+    drop(engine);
+
+    let wal = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension() == Some("wal".as_ref()))
+        .expect("no write-ahead log");
+    let len = fs::metadata(&wal)?.len();
+    OpenOptions::new()
+        .write(true)
+        .open(&wal)?
+        .set_len(len - 3)?;
+
+    let engine = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(
+        engine.get(b"key1".to_vec()).wait()?,
+        Some(b"value1".to_vec())
+    );
+    assert_eq!(engine.get(b"key2".to_vec()).wait()?, None);
+    assert_eq!(engine.get(b"key3".to_vec()).wait()?, None);
+    engine.set(b"key2".to_vec(), b"new".to_vec()).wait()?;
+    drop(engine);
+    let engine = LsmKvsEngine::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    assert_eq!(engine.get(b"key2".to_vec()).wait()?, Some(b"new".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn lsm_backup_and_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = open_lsm(&temp_dir)?;
+    for key_id in 0..1000 {
+        engine
+            .set(format!("key{}", key_id).into_bytes(), b"value".to_vec())
+            .wait()?;
+    }
+    engine.remove(b"key0".to_vec()).wait()?;
+    engine
+        .set_with_ttl(b"key1".to_vec(), b"ttl".to_vec(), Duration::from_secs(3600))
+        .wait()?;
+
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_path = backup_dir.path().join("backup");
+    engine.backup_to(backup_path.clone()).wait()?;
+    engine.set(b"key2".to_vec(), b"new".to_vec()).wait()?;
+    assert!(engine.backup_to(backup_path.clone()).wait().is_err());
+
+    let restored_dir = TempDir::new().expect("unable to create temporary working directory");
+    LsmKvsEngine::<RayonThreadPool>::restore(&backup_path, restored_dir.path())?;
+    let restored = LsmKvsEngine::<RayonThreadPool>::open(restored_dir.path(), 1)?;
+    assert_eq!(restored.get(b"key0".to_vec()).wait()?, None);
+    assert_eq!(
+        restored.get(b"key1".to_vec()).wait()?,
+        Some(b"ttl".to_vec())
+    );
+    assert_eq!(
+        restored.get(b"key2".to_vec()).wait()?,
+        Some(b"value".to_vec())
+    );
+    assert_eq!(
+        restored
+            .scan(Vec::new(), None, None)
+            .collect()
+            .wait()?
+            .len(),
+        999
+    );
+
+    // the data directory must be empty
+    drop(restored);
+    assert!(LsmKvsEngine::<RayonThreadPool>::restore(&backup_path, restored_dir.path()).is_err());
+
+    // a damaged table is not restored
+    let table = fs::read_dir(&backup_path)?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension() == Some("sst".as_ref()))
+        .expect("no table");
+    let mut content = fs::read(&table)?;
+    content[20] ^= 0xff;
+    fs::write(&table, content)?;
+    let restored_dir = TempDir::new().expect("unable to create temporary working directory");
+    match LsmKvsEngine::<RayonThreadPool>::restore(&backup_path, restored_dir.path()) {
+        Err(KvsError::CorruptedLog(_)) => (),
+        res => panic!("unexpected result: {:?}", res),
+    }
+    assert!(!restored_dir.path().join("MANIFEST").exists());
+    Ok(())
+}
+
+// The memory engine should support the operations of the other engines
+#[test]
+fn memory_engine() -> Result<()> {
+    let engine = MemoryKvsEngine::new();
+    check_compare_and_swap(engine.clone())?;
+    assert_eq!(
+        engine.get(b"key".to_vec()).wait()?,
+        Some(b"value3".to_vec())
+    );
+
+    let mut batch = WriteBatch::new();
+    batch.set(b"key1".to_vec(), b"value1".to_vec());
+    batch.set(b"key2".to_vec(), b"value2".to_vec());
+    batch.remove(b"key".to_vec());
+    engine.write_batch(batch).wait()?;
+    let reads = vec![(b"key1".to_vec(), Some(b"other".to_vec()))];
+    let mut batch = WriteBatch::new();
+    batch.remove(b"key1".to_vec());
+    assert!(!engine.commit_transaction(reads, batch.clone()).wait()?);
+    let reads = vec![(b"key1".to_vec(), Some(b"value1".to_vec()))];
+    assert!(engine.commit_transaction(reads, batch).wait()?);
+    match engine.remove(b"key1".to_vec()).wait() {
+        Err(KvsError::KeyNotFound) => (),
+        res => panic!("unexpected result: {:?}", res),
+    }
+
+    let pairs = engine.scan(Vec::new(), None, None).collect().wait()?;
+    assert_eq!(
+        pairs,
+        vec![
+            (b"key2".to_vec(), b"value2".to_vec()),
+            (b"ttl".to_vec(), b"value1".to_vec()),
+        ]
+    );
+    let pairs = engine.scan_prefix(b"key".to_vec(), None).collect().wait()?;
+    assert_eq!(pairs, vec![(b"key2".to_vec(), b"value2".to_vec())]);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    assert!(engine
+        .backup_to(temp_dir.path().join("backup"))
+        .wait()
+        .is_err());
+    Ok(())
+}
+
+// A memory engine with a size limit should evict the least recently used keys
+#[test]
+fn memory_engine_eviction() -> Result<()> {
+    // every pair takes 9 bytes, so the engine holds 3 of them
+    let engine = MemoryKvsEngine::with_capacity(27);
+    for key_id in 0..3 {
+        engine
+            .set(format!("key{}", key_id).into_bytes(), b"value".to_vec())
+            .wait()?;
+    }
+    assert_eq!(engine.size(), 27);
+
+    // reading key0 makes key1 the least recently used key
+    assert_eq!(
+        engine.get(b"key0".to_vec()).wait()?,
+        Some(b"value".to_vec())
+    );
+    engine.set(b"key3".to_vec(), b"value".to_vec()).wait()?;
+    assert_eq!(engine.size(), 27);
+    assert_eq!(engine.get(b"key1".to_vec()).wait()?, None);
+    for key in &["key0", "key2", "key3"] {
+        assert_eq!(
+            engine.get(key.as_bytes().to_vec()).wait()?,
+            Some(b"value".to_vec())
+        );
+    }
+
+    // overwriting a key replaces its size
+    engine.set(b"key0".to_vec(), b"v".to_vec()).wait()?;
+    assert_eq!(engine.size(), 23);
+
+    // a pair larger than the limit does not stay
+    engine.set(b"big".to_vec(), vec![0; 64]).wait()?;
+    assert_eq!(engine.get(b"big".to_vec()).wait()?, None);
+    assert_eq!(engine.size(), 0);
+    Ok(())
+}
+
+// Watchers should receive the changes of the keys with their prefix in commit order
+#[test]
+fn watch_changes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    store.set(b"a0".to_vec(), b"value0".to_vec()).wait()?;
+    let (start, changes) = store.watch(b"a".to_vec(), None).wait()?;
+
+    store.set(b"a1".to_vec(), b"value1".to_vec()).wait()?;
+    store.set(b"b1".to_vec(), b"value1".to_vec()).wait()?;
+    store.remove(b"a1".to_vec()).wait()?;
+    let mut batch = WriteBatch::new();
+    batch.set(b"a2".to_vec(), b"value2".to_vec());
+    batch.set(b"b2".to_vec(), b"value2".to_vec());
+    batch.remove(b"a0".to_vec());
+    store.write_batch(batch).wait()?;
+    store
+        .set_with_ttl(
+            b"a3".to_vec(),
+            b"value3".to_vec(),
+            Duration::from_secs(3600),
+        )
+        .wait()?;
+
+    let events = changes.take(5).collect().wait()?;
+    let summary: Vec<_> = events
+        .iter()
+        .map(|event| match event {
+            WatchEvent::Set {
+                key,
+                value,
+                expires_at,
+                ..
+            } => (key.clone(), Some(value.clone()), expires_at.is_some()),
+            WatchEvent::Remove { key, .. } => (key.clone(), None, false),
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (b"a1".to_vec(), Some(b"value1".to_vec()), false),
+            (b"a1".to_vec(), None, false),
+            (b"a2".to_vec(), Some(b"value2".to_vec()), false),
+            (b"a0".to_vec(), None, false),
+            (b"a3".to_vec(), Some(b"value3".to_vec()), true),
+        ]
+    );
+    assert!(start < events[0].seq());
+    assert!(events.windows(2).all(|pair| pair[0].seq() < pair[1].seq()));
+
+    // a watcher resumes after the last change it has seen
+    let (_, changes) = store.watch(b"a".to_vec(), Some(events[0].seq())).wait()?;
+    assert_eq!(changes.take(4).collect().wait()?, events[1..].to_vec());
+    match store.watch(Vec::new(), Some("1:0".parse()?)).wait() {
+        Err(KvsError::InvalidArgument(_)) => (),
+        res => panic!("unexpected result: {:?}", res.map(|(start, _)| start)),
+    }
+
+    // positions stay valid after the store is reopened
+    drop(store);
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 1)?;
+    let (_, changes) = store.watch(b"a".to_vec(), Some(start)).wait()?;
+    store.set(b"a4".to_vec(), b"value4".to_vec()).wait()?;
+    let resumed = changes.take(6).collect().wait()?;
+    assert_eq!(resumed[..5], events[..]);
+    assert_eq!(resumed[5].key(), b"a4");
+    assert!(resumed[5].seq().gen > start.gen);
+
+    // only the kvs engine keeps a log to watch
+    assert!(MemoryKvsEngine::new()
+        .watch(Vec::new(), None)
+        .wait()
+        .is_err());
+    Ok(())
+}
+
+// Resuming a watch should fail once the changes after the position are compacted
+#[test]
+fn watch_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(1024);
+    let store = open_with_compaction(&temp_dir, 1, options.clone())?;
+    let (start, _) = store.watch(Vec::new(), None).wait()?;
+    for iter in 0..100 {
+        store
+            .set(b"key".to_vec(), format!("value{}", iter).into_bytes())
+            .wait()?;
+    }
+    // dropping the store waits for the compaction
+    drop(store);
+
+    let store = open_with_compaction(&temp_dir, 1, options)?;
+    match store.watch(Vec::new(), Some(start)).wait() {
+        Err(KvsError::StringError(msg)) => assert!(msg.contains("compacted")),
+        res => panic!("unexpected result: {:?}", res.map(|(start, _)| start)),
+    }
+
+    // changes after the compaction can be resumed
+    let (start, _) = store.watch(Vec::new(), None).wait()?;
+    store.set(b"key".to_vec(), b"value".to_vec()).wait()?;
+    let (_, changes) = store.watch(Vec::new(), Some(start)).wait()?;
+    let events = changes.take(1).collect().wait()?;
+    assert_eq!(events[0].key(), b"key");
+    Ok(())
+}
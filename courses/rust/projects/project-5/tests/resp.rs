@@ -0,0 +1,211 @@
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{KvStore, KvsServer, Result};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn spawn_server(addr: SocketAddr, resp_addr: SocketAddr) -> Result<TempDir> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    thread::spawn(move || {
+        KvsServer::new(engine)
+            .resp_addr(resp_addr)
+            .run(addr)
+            .unwrap()
+    });
+    thread::sleep(Duration::from_secs(1));
+    Ok(temp_dir)
+}
+
+/// Encodes a command as an array of bulk strings.
+fn command(args: &[&str]) -> Vec<u8> {
+    let mut buf = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        buf.extend_from_slice(format!("${}\r\n{}\r\n", arg.len(), arg).as_bytes());
+    }
+    buf
+}
+
+/// Sends a command and checks the exact reply.
+fn assert_reply(stream: &mut TcpStream, args: &[&str], expected: &str) {
+    stream.write_all(&command(args)).unwrap();
+    assert_received(stream, expected);
+}
+
+fn assert_received(stream: &mut TcpStream, expected: &str) {
+    let mut buf = vec![0; expected.len()];
+    stream.read_exact(&mut buf).unwrap();
+    assert_eq!(String::from_utf8_lossy(&buf), expected);
+}
+
+#[test]
+fn resp_commands() -> Result<()> {
+    let addr = "127.0.0.1:4039".parse().unwrap();
+    let resp_addr = "127.0.0.1:4040".parse().unwrap();
+    let _temp_dir = spawn_server(addr, resp_addr)?;
+    let mut stream = TcpStream::connect(resp_addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    assert_reply(&mut stream, &["PING"], "+PONG\r\n");
+    assert_reply(&mut stream, &["ping", "hello"], "$5\r\nhello\r\n");
+
+    assert_reply(&mut stream, &["SET", "key1", "value1"], "+OK\r\n");
+    assert_reply(&mut stream, &["GET", "key1"], "$6\r\nvalue1\r\n");
+    assert_reply(&mut stream, &["GET", "missing"], "$-1\r\n");
+    assert_reply(&mut stream, &["SET", "key1", "value2", "NX"], "$-1\r\n");
+    assert_reply(&mut stream, &["SET", "key2", "value2", "nx"], "+OK\r\n");
+    assert_reply(&mut stream, &["GET", "key2"], "$6\r\nvalue2\r\n");
+
+    assert_reply(
+        &mut stream,
+        &["EXISTS", "key1", "missing", "key2"],
+        ":2\r\n",
+    );
+    assert_reply(
+        &mut stream,
+        &["MGET", "key1", "missing", "key2"],
+        "*3\r\n$6\r\nvalue1\r\n$-1\r\n$6\r\nvalue2\r\n",
+    );
+
+    assert_reply(&mut stream, &["DEL", "key1", "missing", "key1"], ":1\r\n");
+    assert_reply(&mut stream, &["GET", "key1"], "$-1\r\n");
+
+    // an expired key is not found
+    assert_reply(
+        &mut stream,
+        &["SET", "key3", "value3", "PX", "100"],
+        "+OK\r\n",
+    );
+    assert_reply(&mut stream, &["GET", "key3"], "$6\r\nvalue3\r\n");
+    thread::sleep(Duration::from_millis(200));
+    assert_reply(&mut stream, &["GET", "key3"], "$-1\r\n");
+    assert_reply(
+        &mut stream,
+        &["SET", "key4", "value4", "EX", "100"],
+        "+OK\r\n",
+    );
+    assert_reply(&mut stream, &["GET", "key4"], "$6\r\nvalue4\r\n");
+    Ok(())
+}
+
+#[test]
+fn resp_errors() -> Result<()> {
+    let addr = "127.0.0.1:4041".parse().unwrap();
+    let resp_addr = "127.0.0.1:4042".parse().unwrap();
+    let _temp_dir = spawn_server(addr, resp_addr)?;
+    let mut stream = TcpStream::connect(resp_addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    assert_reply(
+        &mut stream,
+        &["FLUSHALL"],
+        "-ERR unknown command 'FLUSHALL'\r\n",
+    );
+    assert_reply(
+        &mut stream,
+        &["GET"],
+        "-ERR wrong number of arguments for 'get' command\r\n",
+    );
+    assert_reply(
+        &mut stream,
+        &["SET", "key"],
+        "-ERR wrong number of arguments for 'set' command\r\n",
+    );
+    assert_reply(
+        &mut stream,
+        &["SET", "key", "value", "XX"],
+        "-ERR syntax error\r\n",
+    );
+    assert_reply(
+        &mut stream,
+        &["SET", "key", "value", "EX", "0"],
+        "-ERR invalid expire time in 'set' command\r\n",
+    );
+    assert_reply(&mut stream, &["SCAN", "x"], "-ERR invalid cursor\r\n");
+
+    // the connection is still usable after errors
+    assert_reply(&mut stream, &["GET", "key"], "$-1\r\n");
+    Ok(())
+}
+
+#[test]
+fn resp_inline_and_pipelined() -> Result<()> {
+    let addr = "127.0.0.1:4043".parse().unwrap();
+    let resp_addr = "127.0.0.1:4044".parse().unwrap();
+    let _temp_dir = spawn_server(addr, resp_addr)?;
+    let mut stream = TcpStream::connect(resp_addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    // inline commands as typed in a terminal, empty lines are ignored
+    stream.write_all(b"PING\r\n\r\nSET key value\r\nGET key\n")?;
+    assert_received(&mut stream, "+PONG\r\n+OK\r\n$5\r\nvalue\r\n");
+
+    // pipelined commands are answered in order
+    let mut pipeline = Vec::new();
+    for i in 0..100 {
+        pipeline.extend(command(&["SET", "key", &format!("value{}", i)]));
+        pipeline.extend(command(&["GET", "key"]));
+    }
+    stream.write_all(&pipeline)?;
+    for i in 0..100 {
+        let value = format!("value{}", i);
+        assert_received(
+            &mut stream,
+            &format!("+OK\r\n${}\r\n{}\r\n", value.len(), value),
+        );
+    }
+
+    // a command split across writes
+    let command = command(&["GET", "key"]);
+    let (first, second) = command.split_at(7);
+    stream.write_all(first)?;
+    stream.flush()?;
+    thread::sleep(Duration::from_millis(100));
+    stream.write_all(second)?;
+    assert_received(&mut stream, "$7\r\nvalue99\r\n");
+    Ok(())
+}
+
+#[test]
+fn resp_scan() -> Result<()> {
+    let addr = "127.0.0.1:4045".parse().unwrap();
+    let resp_addr = "127.0.0.1:4046".parse().unwrap();
+    let _temp_dir = spawn_server(addr, resp_addr)?;
+    let mut stream = TcpStream::connect(resp_addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    for key in &["a1", "a2", "b1", "b2", "c1"] {
+        assert_reply(&mut stream, &["SET", key, "value"], "+OK\r\n");
+    }
+    assert_reply(
+        &mut stream,
+        &["SCAN", "0", "COUNT", "2"],
+        "*2\r\n$4\r\n6132\r\n*2\r\n$2\r\na1\r\n$2\r\na2\r\n",
+    );
+    // the cursor is the last key scanned, so a key written after it is scanned
+    assert_reply(&mut stream, &["SET", "a3", "value"], "+OK\r\n");
+    assert_reply(
+        &mut stream,
+        &["SCAN", "6132", "COUNT", "2"],
+        "*2\r\n$4\r\n6231\r\n*2\r\n$2\r\na3\r\n$2\r\nb1\r\n",
+    );
+    assert_reply(
+        &mut stream,
+        &["SCAN", "6231", "COUNT", "3"],
+        "*2\r\n$1\r\n0\r\n*2\r\n$2\r\nb2\r\n$2\r\nc1\r\n",
+    );
+    // the pattern is applied to the keys scanned
+    assert_reply(
+        &mut stream,
+        &["SCAN", "0", "MATCH", "?1"],
+        "*2\r\n$1\r\n0\r\n*3\r\n$2\r\na1\r\n$2\r\nb1\r\n$2\r\nc1\r\n",
+    );
+    assert_reply(
+        &mut stream,
+        &["SCAN", "0", "MATCH", "b*", "COUNT", "4"],
+        "*2\r\n$4\r\n6231\r\n*1\r\n$2\r\nb1\r\n",
+    );
+    Ok(())
+}
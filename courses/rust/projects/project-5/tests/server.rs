@@ -0,0 +1,461 @@
+use assert_cmd::prelude::*;
+use kvs::thread_pool::RayonThreadPool;
+use kvs::{
+    ClientPoolOptions, KvStore, KvsClient, KvsClientPool, KvsEngine, KvsServer, MemoryKvsEngine,
+    Result, SledKvsEngine, WatchEvent, WriteBatch,
+};
+use std::net::{SocketAddr, TcpListener};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::prelude::*;
+
+fn spawn_server<E: KvsEngine>(engine: E, addr: SocketAddr) {
+    thread::spawn(move || KvsServer::new(engine).run(addr).unwrap());
+    thread::sleep(Duration::from_secs(1));
+}
+
+fn transaction(addr: SocketAddr) -> Result<()> {
+    let (client, other) = KvsClient::connect(addr)
+        .join(KvsClient::connect(addr))
+        .wait()?;
+    let client = client.set(b"key1".to_vec(), b"value1".to_vec()).wait()?;
+
+    // the writes are applied at commit
+    let client = client.begin().wait()?;
+    let (value, client) = client.get(b"key1".to_vec()).wait()?;
+    assert_eq!(value, Some(b"value1".to_vec()));
+    let client = client.set(b"key2".to_vec(), b"value2".to_vec()).wait()?;
+    let client = client.remove(b"key1".to_vec()).wait()?;
+    let (value, client) = client.get(b"key1".to_vec()).wait()?;
+    assert_eq!(value, None);
+    let (value, other) = other.get(b"key2".to_vec()).wait()?;
+    assert_eq!(value, None);
+    let (committed, client) = client.commit().wait()?;
+    assert!(committed);
+    let (value, other) = other.get(b"key2".to_vec()).wait()?;
+    assert_eq!(value, Some(b"value2".to_vec()));
+
+    // a transaction whose reads have changed is not committed
+    let client = client.begin().wait()?;
+    let (_, client) = client.get(b"key2".to_vec()).wait()?;
+    let other = other.set(b"key2".to_vec(), b"other".to_vec()).wait()?;
+    let client = client.set(b"key2".to_vec(), b"value3".to_vec()).wait()?;
+    let (committed, client) = client.commit().wait()?;
+    assert!(!committed);
+    let (value, other) = other.get(b"key2".to_vec()).wait()?;
+    assert_eq!(value, Some(b"other".to_vec()));
+
+    // an aborted transaction leaves no writes
+    let client = client.begin().wait()?;
+    let client = client.set(b"key3".to_vec(), b"value3".to_vec()).wait()?;
+    let client = client.abort().wait()?;
+    let (value, _) = client.get(b"key3".to_vec()).wait()?;
+    assert_eq!(value, None);
+    let (value, _) = other.get(b"key3".to_vec()).wait()?;
+    assert_eq!(value, None);
+
+    // the clients sharing a connection run their own transactions
+    let client = KvsClient::connect(addr).wait()?;
+    let (txn, other_txn) = client.clone().begin().join(client.clone().begin()).wait()?;
+    let txn = txn.set(b"key4".to_vec(), b"value4".to_vec()).wait()?;
+    let (value, other_txn) = other_txn.get(b"key4".to_vec()).wait()?;
+    assert_eq!(value, None);
+    let (value, client) = client.get(b"key4".to_vec()).wait()?;
+    assert_eq!(value, None);
+    let client = client.set(b"key5".to_vec(), b"value5".to_vec()).wait()?;
+    let other_txn = other_txn.abort().wait()?;
+    let (value, _) = other_txn.get(b"key5".to_vec()).wait()?;
+    assert_eq!(value, Some(b"value5".to_vec()));
+    let (committed, _) = txn.clone().commit().wait()?;
+    assert!(committed);
+    let (value, _) = client.get(b"key4".to_vec()).wait()?;
+    assert_eq!(value, Some(b"value4".to_vec()));
+    // a transaction stops running when it is committed
+    assert!(txn.get(b"key4".to_vec()).wait().is_err());
+
+    // a transaction must be running to commit, and cannot begin another one
+    let client = KvsClient::connect(addr).wait()?;
+    assert!(client.commit().wait().is_err());
+    let client = KvsClient::connect(addr).wait()?;
+    let client = client.begin().wait()?;
+    assert!(client.begin().wait().is_err());
+    Ok(())
+}
+
+#[test]
+fn transaction_kvs_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4014".parse().unwrap();
+    spawn_server(KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?, addr);
+    transaction(addr)
+}
+
+#[test]
+fn transaction_sled_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4015".parse().unwrap();
+    let engine = SledKvsEngine::<RayonThreadPool>::new(sled::open(temp_dir.path())?, 4)?;
+    spawn_server(engine, addr);
+    transaction(addr)
+}
+
+#[test]
+fn transaction_memory_engine() -> Result<()> {
+    let addr = "127.0.0.1:4025".parse().unwrap();
+    spawn_server(MemoryKvsEngine::new(), addr);
+    transaction(addr)
+}
+
+// A watch should stream the changes over its connection and resume after a reconnect
+#[test]
+fn watch_over_network() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4027".parse().unwrap();
+    spawn_server(KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?, addr);
+
+    let (start, changes) = KvsClient::connect(addr)
+        .and_then(|client| client.watch(b"key".to_vec(), None))
+        .wait()?;
+    KvsClient::connect(addr)
+        .and_then(|client| client.set(b"key1".to_vec(), b"value1".to_vec()))
+        .and_then(|client| client.set(b"other".to_vec(), b"value".to_vec()))
+        .and_then(|client| client.remove(b"key1".to_vec()))
+        .wait()?;
+    let events = changes.take(2).collect().wait()?;
+    match &events[..] {
+        [WatchEvent::Set { key, value, .. }, WatchEvent::Remove { key: removed, .. }] => {
+            assert_eq!(key, b"key1");
+            assert_eq!(value, b"value1");
+            assert_eq!(removed, b"key1");
+        }
+        _ => panic!("unexpected events: {:?}", events),
+    }
+
+    let (_, changes) = KvsClient::connect(addr)
+        .and_then(move |client| client.watch(b"key".to_vec(), Some(start)))
+        .wait()?;
+    assert_eq!(changes.take(2).collect().wait()?, events);
+
+    // a position before the first record is rejected
+    let res = KvsClient::connect(addr)
+        .and_then(|client| client.watch(Vec::new(), Some("1:0".parse().unwrap())))
+        .wait();
+    assert!(res.is_err());
+    Ok(())
+}
+
+#[test]
+fn replication() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary_addr = "127.0.0.1:4029".parse().unwrap();
+    let replica_addr = "127.0.0.1:4030".parse().unwrap();
+    let replica = KvStore::<RayonThreadPool>::open(replica_dir.path(), 4)?;
+    replica.set(b"stale".to_vec(), b"value".to_vec()).wait()?;
+
+    // the replica retries until the primary is up
+    thread::spawn(move || {
+        KvsServer::new(replica)
+            .replica_of(primary_addr)
+            .run(replica_addr)
+            .unwrap()
+    });
+    let primary = KvStore::<RayonThreadPool>::open(primary_dir.path(), 4)?;
+    primary.set(b"key1".to_vec(), b"value1".to_vec()).wait()?;
+    primary
+        .set_with_ttl(
+            b"key2".to_vec(),
+            b"value2".to_vec(),
+            Duration::from_secs(60),
+        )
+        .wait()?;
+    spawn_server(primary, primary_addr);
+    thread::sleep(Duration::from_secs(2));
+
+    // the replica installs a copy of the primary
+    let client = KvsClient::connect(replica_addr).wait()?;
+    let (pairs, client) = client.scan(Vec::new(), None, None).wait()?;
+    assert_eq!(
+        pairs,
+        vec![
+            (b"key1".to_vec(), b"value1".to_vec()),
+            (b"key2".to_vec(), b"value2".to_vec()),
+        ]
+    );
+
+    // and then applies the changes written to the primary
+    KvsClient::connect(primary_addr)
+        .and_then(|client| client.set(b"key3".to_vec(), b"value3".to_vec()))
+        .and_then(|client| client.remove(b"key1".to_vec()))
+        .wait()?;
+    thread::sleep(Duration::from_millis(500));
+    let (pairs, client) = client.scan(Vec::new(), None, None).wait()?;
+    assert_eq!(
+        pairs,
+        vec![
+            (b"key2".to_vec(), b"value2".to_vec()),
+            (b"key3".to_vec(), b"value3".to_vec()),
+        ]
+    );
+
+    // a replica is read-only until it is promoted
+    assert!(KvsClient::connect(replica_addr)
+        .and_then(|client| client.set(b"key4".to_vec(), b"value4".to_vec()))
+        .wait()
+        .is_err());
+    let client = client.promote().wait()?;
+    let client = client.set(b"key4".to_vec(), b"value4".to_vec()).wait()?;
+    let (value, client) = client.get(b"key4".to_vec()).wait()?;
+    assert_eq!(value, Some(b"value4".to_vec()));
+    assert!(client.promote().wait().is_err());
+
+    // the promoted replica no longer applies the changes of the primary
+    KvsClient::connect(primary_addr)
+        .and_then(|client| client.set(b"key5".to_vec(), b"value5".to_vec()))
+        .wait()?;
+    thread::sleep(Duration::from_millis(500));
+    let (value, _) = KvsClient::connect(replica_addr)
+        .and_then(|client| client.get(b"key5".to_vec()))
+        .wait()?;
+    assert_eq!(value, None);
+    Ok(())
+}
+
+#[test]
+fn replication_in_chunks() -> Result<()> {
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary_addr = "127.0.0.1:4057".parse().unwrap();
+    let replica_addr = "127.0.0.1:4058".parse().unwrap();
+
+    // stale keys of the replica sort before, between and after the copied keys
+    let replica = KvStore::<RayonThreadPool>::open(replica_dir.path(), 4)?;
+    for key in &["a", "key01000", "key01000x", "key02999x", "zzz"] {
+        replica
+            .set(key.as_bytes().to_vec(), b"stale".to_vec())
+            .wait()?;
+    }
+    thread::spawn(move || {
+        KvsServer::new(replica)
+            .replica_of(primary_addr)
+            .run(replica_addr)
+            .unwrap()
+    });
+
+    // the copy is larger than a chunk in both pairs and bytes
+    let primary = KvStore::<RayonThreadPool>::open(primary_dir.path(), 4)?;
+    let mut batch = WriteBatch::new();
+    for i in 0..3000 {
+        batch.set(format!("key{:05}", i).into_bytes(), vec![i as u8; 1024]);
+    }
+    primary.write_batch(batch).wait()?;
+    spawn_server(primary, primary_addr);
+    thread::sleep(Duration::from_secs(3));
+
+    let (pairs, _) = KvsClient::connect(replica_addr)
+        .and_then(|client| client.scan(Vec::new(), None, None))
+        .wait()?;
+    assert_eq!(pairs.len(), 3000);
+    for (i, (key, value)) in pairs.into_iter().enumerate() {
+        assert_eq!(key, format!("key{:05}", i).into_bytes());
+        assert_eq!(value, vec![i as u8; 1024]);
+    }
+    Ok(())
+}
+
+// A scan larger than a frame should arrive in chunks
+#[test]
+fn scan_in_chunks() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4059".parse().unwrap();
+    let store = KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?;
+    let mut batch = WriteBatch::new();
+    for i in 0..66 {
+        batch.set(
+            format!("key{:02}", i).into_bytes(),
+            vec![i as u8; 1024 * 1024],
+        );
+    }
+    store.write_batch(batch).wait()?;
+    spawn_server(store, addr);
+
+    let client = KvsClient::connect(addr).wait()?;
+    let (pairs, client) = client.scan(Vec::new(), None, None).wait()?;
+    assert_eq!(pairs.len(), 66);
+    for (i, (key, value)) in pairs.into_iter().enumerate() {
+        assert_eq!(key, format!("key{:02}", i).into_bytes());
+        assert_eq!(value, vec![i as u8; 1024 * 1024]);
+    }
+
+    // the connection is still open
+    let (pairs, _) = client.scan_prefix(b"key6".to_vec(), Some(2)).wait()?;
+    let keys: Vec<_> = pairs.into_iter().map(|(key, _)| key).collect();
+    assert_eq!(keys, vec![b"key60".to_vec(), b"key61".to_vec()]);
+    Ok(())
+}
+
+#[test]
+fn pipelined_requests() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4033".parse().unwrap();
+    spawn_server(KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?, addr);
+    let client = KvsClient::connect(addr).wait()?;
+
+    // the requests are sent without waiting, and the ones on a key are served in order
+    let sets = (0..100).map(|i| {
+        client.clone().set(
+            format!("key{}", i % 10).into_bytes(),
+            format!("value{}", i).into_bytes(),
+        )
+    });
+    future::join_all(sets).wait()?;
+    let gets = (0..10).map(|i| client.clone().get(format!("key{}", i).into_bytes()));
+    let values = future::join_all(gets).wait()?;
+    for (i, (value, _)) in values.into_iter().enumerate() {
+        assert_eq!(value, Some(format!("value{}", 90 + i).into_bytes()));
+    }
+
+    // a watch shares the connection with the other requests
+    let (_, changes) = client.clone().watch(b"key".to_vec(), None).wait()?;
+    let client = client.set(b"key1".to_vec(), b"new".to_vec()).wait()?;
+    let (value, _) = client.get(b"key1".to_vec()).wait()?;
+    assert_eq!(value, Some(b"new".to_vec()));
+    match changes.into_future().wait().map_err(|(e, _)| e)? {
+        (Some(WatchEvent::Set { key, value, .. }), _) => {
+            assert_eq!(key, b"key1");
+            assert_eq!(value, b"new");
+        }
+        (event, _) => panic!("unexpected event: {:?}", event),
+    }
+    Ok(())
+}
+
+#[test]
+fn client_pool() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4034".parse().unwrap();
+    let options = ClientPoolOptions::new()
+        .size(2)
+        .backoff(Duration::from_millis(10), Duration::from_millis(100));
+    let pool = KvsClientPool::new(addr, options)?;
+
+    // the requests wait until the pool connects to the server
+    spawn_server(KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?, addr);
+    pool.set(b"key1".to_vec(), b"value1".to_vec()).wait()?;
+    let gets = (0..10).map(|_| pool.get(b"key1".to_vec()));
+    for value in future::join_all(gets).wait()? {
+        assert_eq!(value, Some(b"value1".to_vec()));
+    }
+    assert!(pool.remove(b"key2".to_vec()).wait().is_err());
+
+    // the futures of the pool can be run by another runtime
+    let mut runtime = tokio::runtime::Runtime::new()?;
+    let value = runtime.block_on(pool.get(b"key1".to_vec()))?;
+    assert_eq!(value, Some(b"value1".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn client_pool_timeout() -> Result<()> {
+    // the server accepts connections but never answers
+    let listener = TcpListener::bind("127.0.0.1:4035")?;
+    let options = ClientPoolOptions::new()
+        .size(1)
+        .request_timeout(Duration::from_millis(200))
+        .get_retries(2);
+    let pool = KvsClientPool::new(listener.local_addr()?, options)?;
+    let start = Instant::now();
+    assert!(pool.get(b"key1".to_vec()).wait().is_err());
+    // the get is tried three times
+    assert!(start.elapsed() >= Duration::from_millis(600));
+    assert!(pool
+        .set(b"key1".to_vec(), b"value1".to_vec())
+        .wait()
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn client_pool_reconnect() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4036";
+    let start_server = || {
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child
+    };
+    let mut server = start_server();
+    let options = ClientPoolOptions::new()
+        .size(2)
+        .health_check_interval(Duration::from_millis(100))
+        .backoff(Duration::from_millis(10), Duration::from_millis(100));
+    let pool = KvsClientPool::new(addr.parse().unwrap(), options).unwrap();
+    pool.set(b"key1".to_vec(), b"value1".to_vec())
+        .wait()
+        .unwrap();
+
+    // the connections are reopened after the server restarts
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+    let mut server = start_server();
+    assert_eq!(
+        pool.get(b"key1".to_vec()).wait().unwrap(),
+        Some(b"value1".to_vec())
+    );
+    pool.set(b"key2".to_vec(), b"value2".to_vec())
+        .wait()
+        .unwrap();
+    assert_eq!(
+        pool.get(b"key2".to_vec()).wait().unwrap(),
+        Some(b"value2".to_vec())
+    );
+    server.kill().expect("server exited before killed");
+    server.wait().unwrap();
+}
+
+#[test]
+fn multi_get_and_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = "127.0.0.1:4037".parse().unwrap();
+    spawn_server(KvStore::<RayonThreadPool>::open(temp_dir.path(), 4)?, addr);
+
+    let pairs: Vec<_> = (0..1000)
+        .map(|i| {
+            (
+                format!("key{}", i).into_bytes(),
+                format!("value{}", i).into_bytes(),
+            )
+        })
+        .collect();
+    let client = KvsClient::connect(addr).wait()?;
+    let client = client.multi_set(pairs.clone()).wait()?;
+    // the values are returned in the order of the keys
+    let mut keys: Vec<_> = pairs.iter().rev().map(|(key, _)| key.clone()).collect();
+    keys.push(b"missing".to_vec());
+    let (values, client) = client.multi_get(keys).wait()?;
+    let mut expected: Vec<_> = pairs
+        .into_iter()
+        .rev()
+        .map(|(_, value)| Some(value))
+        .collect();
+    expected.push(None);
+    assert_eq!(values, expected);
+
+    // the last value of a key given twice wins
+    let client = client
+        .multi_set(vec![
+            (b"key1".to_vec(), b"first".to_vec()),
+            (b"key1".to_vec(), b"second".to_vec()),
+        ])
+        .wait()?;
+    let (value, _) = client.get(b"key1".to_vec()).wait()?;
+    assert_eq!(value, Some(b"second".to_vec()));
+    Ok(())
+}